@@ -0,0 +1,44 @@
+#!/bin/sh
+# Rebuilds the filesystem images the tests read, with the real tools so we aren't only testing
+# against images we made ourselves. Run from this directory
+set -e
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+# A USTAR archive with a hard link and a name long enough to need the prefix field
+long=$(printf 'a%.0s' $(seq 60))/$(printf 'b%.0s' $(seq 60))
+mkdir -p "$work/tar/games/gba" "$work/tar/$long"
+printf 'hello tar\n' > "$work/tar/games/gba/hello.txt"
+printf 'long name\n' > "$work/tar/$long/file.txt"
+printf 'shared data\n' > "$work/tar/games/first.bin"
+ln "$work/tar/games/first.bin" "$work/tar/games/second.bin"
+(cd "$work/tar" && tar --format=ustar -b 1 --sort=name --mtime=@1700000000 --owner=0 \
+    --group=0 --numeric-owner -cf - games aaaa*) > ustar.tar
+
+# A ZIP with a stored file and a deflated one big enough to go around the deflate window a few
+# times, and the same again forced to ZIP64
+mkdir -p "$work/zip/roms"
+printf 'stored, not squashed\n' > "$work/zip/roms/stored.txt"
+seq 1 20000 > "$work/zip/roms/deflated.txt"
+touch -d @1700000000 "$work/zip/roms/stored.txt" "$work/zip/roms/deflated.txt"
+(cd "$work/zip" && zip -q -X -0 "$work/zip.zip" roms/stored.txt \
+    && zip -q -X -9 "$work/zip.zip" roms/deflated.txt \
+    && zip -q -X -fz -0 "$work/zip64.zip" roms/stored.txt \
+    && zip -q -X -fz -9 "$work/zip64.zip" roms/deflated.txt)
+cp "$work/zip.zip" "$work/zip64.zip" .
+
+# A small ext2 image with a file big enough to need a indirect block, and fast, slow, absolute and
+# looping symlinks
+mkdir -p "$work/ext2/games/gba"
+printf 'hello ext2\n' > "$work/ext2/hello.txt"
+seq 1 4000 > "$work/ext2/games/gba/big.bin"
+ln -s gba/big.bin "$work/ext2/games/latest"
+ln -s "../$(printf './%.0s' $(seq 35))hello.txt" "$work/ext2/games/hello"
+ln -s /games/gba/big.bin "$work/ext2/absolute"
+ln -s loop "$work/ext2/loop"
+find "$work/ext2" -exec touch -h -d @1700000000 {} +
+rm -f ext2.img
+E2FSPROGS_FAKE_TIME=1700000000 mke2fs -q -F -t ext2 -b 1024 -N 32 -m 0 \
+    -O ^resize_inode,^dir_index -U 00000000-0000-0000-0000-000000000000 \
+    -E hash_seed=00000000-0000-0000-0000-000000000000 -d "$work/ext2" ext2.img 256
@@ -0,0 +1,511 @@
+use crate::device::EmuRsDevice;
+use crate::disk::{EmuRsDiskDriver, EmuRsDiskProperties};
+use crate::driver::{EmuRsDriver, EmuRsDriverPreference};
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use blake2::{Blake2s256, Digest};
+use core::cell::RefCell;
+use tinyvec::TinyVec;
+
+// Saves on battery backed memory rot quietly when the battery dies, so this keeps a checksum of
+// every block to notice it happening
+//
+// The disk underneath is laid out as the blocks, then a tag for each block, then a header in the
+// very last bytes so it can be found without knowing anything else
+
+const HEADER_MAGIC: &[u8; 8] = b"EMURSSUM";
+const HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmuRsChecksumKind {
+    /// Small and quick, good for catching rot
+    Crc32,
+    /// Takes a lot more space but can't be fooled by chance
+    Blake2s,
+}
+
+impl EmuRsChecksumKind {
+    pub fn tag_size(&self) -> usize {
+        return match self {
+            EmuRsChecksumKind::Crc32 => 4,
+            EmuRsChecksumKind::Blake2s => 32,
+        };
+    }
+
+    /// The block number goes into the tag too, so a block that ended up in the wrong place
+    /// doesn't pass
+    fn tag(&self, block: usize, data: &[u8], tag: &mut [u8]) {
+        let block = (block as u32).to_le_bytes();
+
+        match self {
+            EmuRsChecksumKind::Crc32 => {
+                let mut hasher = crc32fast::Hasher::new();
+                hasher.update(&block);
+                hasher.update(data);
+                tag.copy_from_slice(&hasher.finalize().to_le_bytes());
+            }
+            EmuRsChecksumKind::Blake2s => {
+                let mut hasher = Blake2s256::new();
+                hasher.update(block);
+                hasher.update(data);
+                tag.copy_from_slice(&hasher.finalize());
+            }
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        return match id {
+            0 => Some(EmuRsChecksumKind::Crc32),
+            1 => Some(EmuRsChecksumKind::Blake2s),
+            _ => None,
+        };
+    }
+
+    fn id(&self) -> u8 {
+        return match self {
+            EmuRsChecksumKind::Crc32 => 0,
+            EmuRsChecksumKind::Blake2s => 1,
+        };
+    }
+}
+
+/// A disk that checks every block it reads against the checksum it was written with
+///
+/// Reads of a block that doesn't match fail with [EmuRsErrorReason::ChecksumMismatch]. Writing a
+/// whole block over a bad one fixes it, but writing only part of it fails the same way since the
+/// rest can't be trusted
+pub struct EmuRsChecksumDisk {
+    disk: Rc<RefCell<dyn EmuRsDiskDriver>>,
+    kind: EmuRsChecksumKind,
+    block_size: usize,
+    block_count: usize,
+    /// The block being worked on and its tag
+    block: Vec<u8>,
+    tag: Vec<u8>,
+    expected_tag: Vec<u8>,
+}
+
+impl EmuRsChecksumDisk {
+    /// Set up checksums on a disk, taking what is on it now as correct. Space for the checksums
+    /// comes off of the end, so anything there is lost
+    pub fn format(
+        disk: Rc<RefCell<dyn EmuRsDiskDriver>>,
+        kind: EmuRsChecksumKind,
+        block_size: usize,
+    ) -> Result<Self, EmuRsError> {
+        let total_size = disk.borrow_mut().get_total_size();
+
+        if block_size == 0 || block_size > u32::MAX as usize {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported,
+            });
+        }
+
+        if total_size < HEADER_SIZE + block_size + kind.tag_size() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::OutOfSpace,
+            });
+        }
+
+        let mut checksum_disk = Self::new(disk, kind, block_size, total_size);
+        for block in 0..checksum_disk.block_count {
+            checksum_disk.read_block(block)?;
+            checksum_disk.write_block(block)?;
+        }
+
+        // The header goes last, so a format that gets cut off doesn't leave a disk that opens
+        // with half of its tags missing
+        let mut header = [0; HEADER_SIZE];
+        header[..8].copy_from_slice(HEADER_MAGIC);
+        header[8] = kind.id();
+        header[12..16].copy_from_slice(&(block_size as u32).to_le_bytes());
+        checksum_disk
+            .disk
+            .borrow_mut()
+            .write(&header, total_size - HEADER_SIZE)?;
+
+        return Ok(checksum_disk);
+    }
+
+    /// Open a disk that was formatted with checksums before
+    pub fn open(disk: Rc<RefCell<dyn EmuRsDiskDriver>>) -> Result<Self, EmuRsError> {
+        let total_size = disk.borrow_mut().get_total_size();
+
+        let corrupted = EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        };
+
+        if total_size < HEADER_SIZE {
+            return Err(corrupted);
+        }
+
+        let mut header = [0; HEADER_SIZE];
+        disk.borrow_mut()
+            .read(&mut header, total_size - HEADER_SIZE)?;
+
+        if &header[..8] != HEADER_MAGIC {
+            return Err(corrupted);
+        }
+
+        let kind = EmuRsChecksumKind::from_id(header[8]).ok_or(corrupted.clone())?;
+        let block_size = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+
+        let smallest = (HEADER_SIZE + kind.tag_size()).checked_add(block_size);
+        if block_size == 0 || smallest.is_none_or(|smallest| total_size < smallest) {
+            return Err(corrupted);
+        }
+
+        return Ok(Self::new(disk, kind, block_size, total_size));
+    }
+
+    fn new(
+        disk: Rc<RefCell<dyn EmuRsDiskDriver>>,
+        kind: EmuRsChecksumKind,
+        block_size: usize,
+        total_size: usize,
+    ) -> Self {
+        return Self {
+            disk,
+            kind,
+            block_size,
+            block_count: (total_size - HEADER_SIZE) / (block_size + kind.tag_size()),
+            block: vec![0; block_size],
+            tag: vec![0; kind.tag_size()],
+            expected_tag: vec![0; kind.tag_size()],
+        };
+    }
+
+    pub fn kind(&self) -> EmuRsChecksumKind {
+        return self.kind;
+    }
+
+    pub fn block_count(&self) -> usize {
+        return self.block_count;
+    }
+
+    /// Check every block, returning the numbers of the ones that are bad
+    pub fn scrub(&mut self) -> Result<Vec<usize>, EmuRsError> {
+        let mut bad = Vec::new();
+
+        for block in 0..self.block_count {
+            match self.read_verified_block(block) {
+                Ok(()) => {}
+                Err(EmuRsError {
+                    reason: EmuRsErrorReason::ChecksumMismatch(_),
+                }) => bad.push(block),
+                Err(error) => return Err(error),
+            }
+        }
+
+        return Ok(bad);
+    }
+
+    fn tag_offset(&self, block: usize) -> usize {
+        return self.block_count * self.block_size + block * self.kind.tag_size();
+    }
+
+    /// Read a block into the buffer without checking it
+    fn read_block(&mut self, block: usize) -> Result<(), EmuRsError> {
+        return self
+            .disk
+            .borrow_mut()
+            .read(&mut self.block, block * self.block_size);
+    }
+
+    fn read_verified_block(&mut self, block: usize) -> Result<(), EmuRsError> {
+        self.read_block(block)?;
+
+        let tag_offset = self.tag_offset(block);
+        self.disk
+            .borrow_mut()
+            .read(&mut self.expected_tag, tag_offset)?;
+
+        self.kind.tag(block, &self.block, &mut self.tag);
+        if self.tag != self.expected_tag {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::ChecksumMismatch(block),
+            });
+        }
+
+        return Ok(());
+    }
+
+    /// Write the buffer out as a block along with its new tag
+    fn write_block(&mut self, block: usize) -> Result<(), EmuRsError> {
+        self.kind.tag(block, &self.block, &mut self.tag);
+
+        let mut disk = self.disk.borrow_mut();
+        disk.write(&self.block, block * self.block_size)?;
+        disk.write(&self.tag, self.tag_offset(block))?;
+        return Ok(());
+    }
+
+    fn check_bounds(&self, offset: usize, length: usize) -> Result<(), EmuRsError> {
+        if offset + length > self.block_count * self.block_size {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit,
+            });
+        }
+
+        return Ok(());
+    }
+}
+
+impl EmuRsDriver for EmuRsChecksumDisk {
+    fn name(&self) -> &'static str {
+        return "Checksum Disk";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        // The disk it is on already claimed the hardware
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsDiskDriver for EmuRsChecksumDisk {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        self.check_bounds(offset, buffer.len())?;
+
+        let mut done = 0;
+        while done < buffer.len() {
+            let block = (offset + done) / self.block_size;
+            let within = (offset + done) % self.block_size;
+            let length = (self.block_size - within).min(buffer.len() - done);
+
+            self.read_verified_block(block)?;
+            buffer[done..done + length].copy_from_slice(&self.block[within..within + length]);
+            done += length;
+        }
+
+        return Ok(());
+    }
+
+    fn write(&mut self, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        self.check_bounds(offset, buffer.len())?;
+
+        let mut done = 0;
+        while done < buffer.len() {
+            let block = (offset + done) / self.block_size;
+            let within = (offset + done) % self.block_size;
+            let length = (self.block_size - within).min(buffer.len() - done);
+
+            // The rest of the block gets tagged along with the new part, so it has to be right
+            if length != self.block_size {
+                self.read_verified_block(block)?;
+            }
+
+            self.block[within..within + length].copy_from_slice(&buffer[done..done + length]);
+            self.write_block(block)?;
+            done += length;
+        }
+
+        return Ok(());
+    }
+
+    fn get_sector_size(&mut self) -> usize {
+        return self.block_size;
+    }
+
+    fn get_total_size(&mut self) -> usize {
+        return self.block_count * self.block_size;
+    }
+
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        return self.disk.borrow_mut().get_properties();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+
+    const KINDS: [EmuRsChecksumKind; 2] = [EmuRsChecksumKind::Crc32, EmuRsChecksumKind::Blake2s];
+
+    /// A disk with something other than zeros on it, and checksums on top
+    fn checksum_disk(kind: EmuRsChecksumKind) -> (Rc<RefCell<VecDisk>>, EmuRsChecksumDisk) {
+        let disk = Rc::new(RefCell::new(VecDisk(
+            (0..8192).map(|index| index as u8).collect(),
+        )));
+        let checksum_disk = EmuRsChecksumDisk::format(disk.clone(), kind, 256).unwrap();
+        return (disk, checksum_disk);
+    }
+
+    fn is_mismatch(result: Result<(), EmuRsError>, block: usize) -> bool {
+        return matches!(
+            result,
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::ChecksumMismatch(bad)
+            }) if bad == block
+        );
+    }
+
+    /// Fails every write after the first few, like the power going out
+    struct FailingDisk {
+        disk: VecDisk,
+        writes_left: usize,
+    }
+
+    impl EmuRsDriver for FailingDisk {
+        fn name(&self) -> &'static str {
+            return "Failing Disk";
+        }
+
+        fn get_preference(&mut self) -> EmuRsDriverPreference {
+            return EmuRsDriverPreference::Preferred;
+        }
+
+        fn get_claimed(&mut self) -> EmuRsDevice {
+            return EmuRsDevice {
+                memory: TinyVec::new(),
+            };
+        }
+    }
+
+    impl EmuRsDiskDriver for FailingDisk {
+        fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+            return self.disk.read(buffer, offset);
+        }
+
+        fn write(&mut self, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+            if self.writes_left == 0 {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::HardwareFault,
+                });
+            }
+
+            self.writes_left -= 1;
+            return self.disk.write(buffer, offset);
+        }
+
+        fn get_sector_size(&mut self) -> usize {
+            return 1;
+        }
+
+        fn get_total_size(&mut self) -> usize {
+            return self.disk.get_total_size();
+        }
+    }
+
+    #[test]
+    fn formatting_keeps_what_was_there() {
+        for kind in KINDS {
+            let (_, mut checksum_disk) = checksum_disk(kind);
+            let size = checksum_disk.get_total_size();
+
+            let mut buffer = vec![0; size];
+            checksum_disk.read(&mut buffer, 0).unwrap();
+            assert!(buffer
+                .iter()
+                .enumerate()
+                .all(|(index, byte)| *byte == index as u8));
+        }
+    }
+
+    #[test]
+    fn scrub_finds_rotten_blocks() {
+        for kind in KINDS {
+            let (disk, mut checksum_disk) = checksum_disk(kind);
+            assert_eq!(checksum_disk.scrub().unwrap(), []);
+
+            disk.borrow_mut().0[3 * 256 + 17] ^= 0x10;
+            disk.borrow_mut().0[5 * 256] ^= 0x01;
+            assert_eq!(checksum_disk.scrub().unwrap(), [3, 5]);
+
+            // Only reads that touch a bad block fail
+            assert!(is_mismatch(
+                checksum_disk.read(&mut [0; 10], 3 * 256 + 250),
+                3
+            ));
+            assert!(checksum_disk.read(&mut [0; 256], 4 * 256).is_ok());
+        }
+    }
+
+    #[test]
+    fn blocks_in_the_wrong_place_are_caught() {
+        let (disk, mut checksum_disk) = checksum_disk(EmuRsChecksumKind::Crc32);
+
+        // Swap blocks 1 and 2 along with their tags, so each tag still matches its data
+        let tags = checksum_disk.tag_offset(0);
+        let mut disk = disk.borrow_mut();
+        for (start, size) in [(256, 256), (tags + 4, 4)] {
+            let first = disk.0[start..start + size].to_vec();
+            disk.0.copy_within(start + size..start + size * 2, start);
+            disk.0[start + size..start + size * 2].copy_from_slice(&first);
+        }
+        drop(disk);
+
+        assert_eq!(checksum_disk.scrub().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn partial_writes_keep_the_rest_of_the_block() {
+        for kind in KINDS {
+            let (disk, mut checksum_disk) = checksum_disk(kind);
+            let mut expected = disk.borrow().0[..checksum_disk.get_total_size()].to_vec();
+
+            // Across the end of block 0 and into block 1
+            checksum_disk.write(&[0xee; 20], 250).unwrap();
+            expected[250..270].fill(0xee);
+
+            let mut buffer = vec![0; expected.len()];
+            checksum_disk.read(&mut buffer, 0).unwrap();
+            assert_eq!(buffer, expected);
+
+            // Everything still checks out after opening it again
+            let mut reopened = EmuRsChecksumDisk::open(disk.clone()).unwrap();
+            assert_eq!(reopened.kind(), kind);
+            assert_eq!(reopened.scrub().unwrap(), []);
+        }
+    }
+
+    #[test]
+    fn only_whole_writes_fix_a_bad_block() {
+        let (disk, mut checksum_disk) = checksum_disk(EmuRsChecksumKind::Crc32);
+        disk.borrow_mut().0[2 * 256 + 100] ^= 0xff;
+
+        // The rest of the block can't be trusted, so it can't be tagged again
+        assert!(is_mismatch(checksum_disk.write(&[1; 10], 2 * 256), 2));
+
+        checksum_disk.write(&[2; 256], 2 * 256).unwrap();
+        assert_eq!(checksum_disk.scrub().unwrap(), []);
+    }
+
+    #[test]
+    fn format_writes_the_header_last() {
+        let failing = Rc::new(RefCell::new(FailingDisk {
+            disk: VecDisk(vec![0; 8192]),
+            writes_left: 3,
+        }));
+
+        assert!(EmuRsChecksumDisk::format(failing.clone(), EmuRsChecksumKind::Crc32, 256).is_err());
+
+        let disk = Rc::new(RefCell::new(VecDisk(failing.borrow().disk.0.clone())));
+        assert!(matches!(
+            EmuRsChecksumDisk::open(disk).map(|_| ()),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+    }
+
+    #[test]
+    fn open_rejects_headers_that_dont_fit() {
+        let (disk, _) = checksum_disk(EmuRsChecksumKind::Crc32);
+        let header = 8192 - HEADER_SIZE;
+
+        disk.borrow_mut().0[header + 12..header + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(EmuRsChecksumDisk::open(disk.clone()).is_err());
+
+        disk.borrow_mut().0[header] = b'X';
+        assert!(EmuRsChecksumDisk::open(disk).is_err());
+    }
+}
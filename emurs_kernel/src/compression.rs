@@ -0,0 +1,416 @@
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// https://problemkaputt.de/gbatek.htm#biosdecompressionfunctions
+
+/// LZ77 can only reach back this far, so that is all of the output that has to be kept around
+const WINDOW_SIZE: usize = 4096;
+/// How much compressed data is pulled in at once
+const INPUT_CHUNK_SIZE: usize = 256;
+
+/// Reads `buffer.len()` bytes at a offset from wherever, a disk or a file, for code that doesn't
+/// care which
+pub type EmuRsReadSource<'a> = dyn FnMut(&mut [u8], usize) -> Result<(), EmuRsError> + 'a;
+
+/// The compression formats the GBA BIOS (and the DS BIOS, for the extended LZ77) knows how to undo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmuRsCompressionKind {
+    Lz77,
+    /// The DS version of LZ77, which allows much longer copies
+    Lz11,
+    /// Symbols are either 4 or 8 bits
+    Huffman(u8),
+    Rle,
+    Diff8,
+    Diff16,
+}
+
+/// The word at the start of every compressed file
+#[derive(Debug, Clone, Copy)]
+pub struct EmuRsCompressionHeader {
+    pub kind: EmuRsCompressionKind,
+    pub size: usize,
+    /// Where the compressed data starts
+    pub data_offset: usize,
+}
+
+impl EmuRsCompressionHeader {
+    /// Parse the header from the start of a file. Returns [None] if it isn't a format we know
+    ///
+    /// DS tools put the size in a second word when it won't fit in 24 bits, so give this 8 bytes if there are that many
+    pub fn parse(header: &[u8]) -> Option<Self> {
+        if header.len() < 4 {
+            return None;
+        }
+
+        let kind = match header[0] {
+            0x10 => EmuRsCompressionKind::Lz77,
+            0x11 => EmuRsCompressionKind::Lz11,
+            0x24 => EmuRsCompressionKind::Huffman(4),
+            0x28 => EmuRsCompressionKind::Huffman(8),
+            0x30 => EmuRsCompressionKind::Rle,
+            0x81 => EmuRsCompressionKind::Diff8,
+            0x82 => EmuRsCompressionKind::Diff16,
+            _ => return None,
+        };
+
+        let size = u32::from_le_bytes([header[1], header[2], header[3], 0]) as usize;
+
+        if size == 0 && header.len() >= 8 {
+            return Some(Self {
+                kind,
+                size: u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize,
+                data_offset: 8,
+            });
+        }
+
+        return Some(Self {
+            kind,
+            size,
+            data_offset: 4,
+        });
+    }
+}
+
+/// Compressed data read from wherever it lives, a chunk at a time
+struct EmuRsCompressedInput {
+    buffer: [u8; INPUT_CHUNK_SIZE],
+    start: usize,
+    length: usize,
+    /// Where the next chunk comes from
+    position: usize,
+    end: usize,
+}
+
+impl EmuRsCompressedInput {
+    fn byte(&mut self, source: &mut EmuRsReadSource) -> Result<u8, EmuRsError> {
+        if self.start == self.length {
+            // Running out of input before the output is done means the data is broken
+            if self.position >= self.end {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem,
+                });
+            }
+
+            self.length = (self.end - self.position).min(INPUT_CHUNK_SIZE);
+            self.start = 0;
+            source(&mut self.buffer[..self.length], self.position)?;
+            self.position += self.length;
+        }
+
+        self.start += 1;
+        return Ok(self.buffer[self.start - 1]);
+    }
+}
+
+enum EmuRsDecompressorState {
+    Lz {
+        flags: u8,
+        flags_left: u8,
+        copy_length: usize,
+        copy_distance: usize,
+    },
+    Rle {
+        literal_length: usize,
+        run_length: usize,
+        run_byte: u8,
+    },
+    Huffman {
+        /// Loaded the first time a byte is asked for
+        tree: Vec<u8>,
+        bits: u32,
+        bits_left: u8,
+    },
+    Diff {
+        previous: u16,
+        /// The top half of a 16 bit unit that has not been handed out yet
+        high_byte: Option<u8>,
+    },
+}
+
+/// Decompresses a file from start to end, without needing all of it in memory
+///
+/// Compressed data is asked for through a `source` closure that fills a buffer from an offset in the compressed file
+pub struct EmuRsDecompressor {
+    header: EmuRsCompressionHeader,
+    input: EmuRsCompressedInput,
+    window: Box<[u8; WINDOW_SIZE]>,
+    /// How many bytes have been decompressed
+    position: usize,
+    state: EmuRsDecompressorState,
+}
+
+impl EmuRsDecompressor {
+    /// `compressed_size` is the size of the whole file, header included
+    pub fn new(header: EmuRsCompressionHeader, compressed_size: usize) -> Self {
+        return Self {
+            header,
+            input: EmuRsCompressedInput {
+                buffer: [0; INPUT_CHUNK_SIZE],
+                start: 0,
+                length: 0,
+                position: header.data_offset,
+                end: compressed_size,
+            },
+            window: Box::new([0; WINDOW_SIZE]),
+            position: 0,
+            state: match header.kind {
+                EmuRsCompressionKind::Lz77 | EmuRsCompressionKind::Lz11 => {
+                    EmuRsDecompressorState::Lz {
+                        flags: 0,
+                        flags_left: 0,
+                        copy_length: 0,
+                        copy_distance: 0,
+                    }
+                }
+                EmuRsCompressionKind::Huffman(_) => EmuRsDecompressorState::Huffman {
+                    tree: Vec::new(),
+                    bits: 0,
+                    bits_left: 0,
+                },
+                EmuRsCompressionKind::Rle => EmuRsDecompressorState::Rle {
+                    literal_length: 0,
+                    run_length: 0,
+                    run_byte: 0,
+                },
+                EmuRsCompressionKind::Diff8 | EmuRsCompressionKind::Diff16 => {
+                    EmuRsDecompressorState::Diff {
+                        previous: 0,
+                        high_byte: None,
+                    }
+                }
+            },
+        };
+    }
+
+    pub fn header(&self) -> &EmuRsCompressionHeader {
+        return &self.header;
+    }
+
+    /// How far into the decompressed data we are
+    pub fn position(&self) -> usize {
+        return self.position;
+    }
+
+    /// Fill `buffer` with the decompressed data at `offset`. It can't be behind [Self::position], start a new decompressor for that
+    pub fn read(
+        &mut self,
+        buffer: &mut [u8],
+        offset: usize,
+        source: &mut EmuRsReadSource,
+    ) -> Result<(), EmuRsError> {
+        if offset < self.position {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        if offset + buffer.len() > self.header.size {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfFileHit,
+            });
+        }
+
+        while self.position < offset {
+            self.next_byte(source)?;
+        }
+
+        for byte in buffer.iter_mut() {
+            *byte = self.next_byte(source)?;
+        }
+
+        return Ok(());
+    }
+
+    fn next_byte(&mut self, source: &mut EmuRsReadSource) -> Result<u8, EmuRsError> {
+        let input = &mut self.input;
+        let corrupted = EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        };
+
+        let byte = match &mut self.state {
+            EmuRsDecompressorState::Lz {
+                flags,
+                flags_left,
+                copy_length,
+                copy_distance,
+            } => {
+                if *copy_length == 0 {
+                    // Every flag byte says which of the next 8 blocks are copies, highest bit first
+                    if *flags_left == 0 {
+                        *flags = input.byte(source)?;
+                        *flags_left = 8;
+                    }
+
+                    *flags_left -= 1;
+                    let is_copy = *flags & 0x80 != 0;
+                    *flags <<= 1;
+
+                    if !is_copy {
+                        let byte = input.byte(source)?;
+                        self.window[self.position % WINDOW_SIZE] = byte;
+                        self.position += 1;
+                        return Ok(byte);
+                    }
+
+                    let first = input.byte(source)? as usize;
+
+                    let (length, high_distance) = if self.header.kind == EmuRsCompressionKind::Lz77
+                    {
+                        ((first >> 4) + 3, first & 0xf)
+                    } else {
+                        match first >> 4 {
+                            0 => {
+                                let second = input.byte(source)? as usize;
+                                ((((first & 0xf) << 4) | (second >> 4)) + 0x11, second & 0xf)
+                            }
+                            1 => {
+                                let second = input.byte(source)? as usize;
+                                let third = input.byte(source)? as usize;
+                                (
+                                    (((first & 0xf) << 12) | (second << 4) | (third >> 4)) + 0x111,
+                                    third & 0xf,
+                                )
+                            }
+                            _ => ((first >> 4) + 1, first & 0xf),
+                        }
+                    };
+
+                    let distance = ((high_distance << 8) | input.byte(source)? as usize) + 1;
+
+                    if distance > self.position {
+                        return Err(corrupted);
+                    }
+
+                    *copy_length = length;
+                    *copy_distance = distance;
+                }
+
+                *copy_length -= 1;
+                self.window[(self.position - *copy_distance) % WINDOW_SIZE]
+            }
+            EmuRsDecompressorState::Rle {
+                literal_length,
+                run_length,
+                run_byte,
+            } => {
+                if *literal_length == 0 && *run_length == 0 {
+                    let flag = input.byte(source)?;
+
+                    if flag & 0x80 != 0 {
+                        *run_length = (flag & 0x7f) as usize + 3;
+                        *run_byte = input.byte(source)?;
+                    } else {
+                        *literal_length = (flag & 0x7f) as usize + 1;
+                    }
+                }
+
+                if *run_length != 0 {
+                    *run_length -= 1;
+                    *run_byte
+                } else {
+                    *literal_length -= 1;
+                    input.byte(source)?
+                }
+            }
+            EmuRsDecompressorState::Huffman {
+                tree,
+                bits,
+                bits_left,
+            } => {
+                if tree.is_empty() {
+                    // The first byte is how big the tree is, in pairs of bytes minus one
+                    let size = (input.byte(source)? as usize + 1) * 2;
+                    tree.reserve(size);
+                    tree.push(0);
+
+                    for _ in 1..size {
+                        tree.push(input.byte(source)?);
+                    }
+                }
+
+                let symbol_bits = match self.header.kind {
+                    EmuRsCompressionKind::Huffman(symbol_bits @ (4 | 8)) => symbol_bits,
+                    _ => return Err(corrupted),
+                };
+                let mut byte = 0;
+
+                // Symbols fill up a byte from the lowest bits
+                for symbol in 0..8 / symbol_bits {
+                    let mut node = 1;
+
+                    loop {
+                        // The bitstream is little endian words, read from the top bit down
+                        if *bits_left == 0 {
+                            let mut word = [0; 4];
+
+                            for part in word.iter_mut() {
+                                *part = input.byte(source)?;
+                            }
+
+                            *bits = u32::from_le_bytes(word);
+                            *bits_left = 32;
+                        }
+
+                        *bits_left -= 1;
+                        let bit = ((*bits >> *bits_left) & 1) as usize;
+                        let value = *tree.get(node).ok_or(corrupted.clone())?;
+                        let child = (node & !1) + (value & 0x3f) as usize * 2 + 2 + bit;
+                        let is_data = value & (0x80 >> bit) != 0;
+
+                        if child >= tree.len() {
+                            return Err(corrupted);
+                        }
+
+                        if is_data {
+                            byte |= (tree[child] & (0xff >> (8 - symbol_bits)))
+                                << (symbol * symbol_bits);
+                            break;
+                        }
+
+                        node = child;
+                    }
+                }
+
+                byte
+            }
+            EmuRsDecompressorState::Diff {
+                previous,
+                high_byte,
+            } => match high_byte.take() {
+                Some(byte) => byte,
+                None if self.header.kind == EmuRsCompressionKind::Diff8 => {
+                    *previous = (*previous as u8).wrapping_add(input.byte(source)?) as u16;
+                    *previous as u8
+                }
+                None => {
+                    let unit = u16::from_le_bytes([input.byte(source)?, input.byte(source)?]);
+                    *previous = previous.wrapping_add(unit);
+                    *high_byte = Some((*previous >> 8) as u8);
+                    *previous as u8
+                }
+            },
+        };
+
+        self.window[self.position % WINDOW_SIZE] = byte;
+        self.position += 1;
+        return Ok(byte);
+    }
+}
+
+/// Decompress something that is already all in memory, like a asset in the cartridge
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, EmuRsError> {
+    let header = EmuRsCompressionHeader::parse(data).ok_or(EmuRsError {
+        reason: EmuRsErrorReason::OperationNotSupported,
+    })?;
+    let mut output = vec![0; header.size];
+
+    EmuRsDecompressor::new(header, data.len()).read(&mut output, 0, &mut |buffer, offset| {
+        buffer.copy_from_slice(&data[offset..offset + buffer.len()]);
+        return Ok(());
+    })?;
+
+    return Ok(output);
+}
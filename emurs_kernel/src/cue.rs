@@ -0,0 +1,853 @@
+use crate::device::EmuRsDevice;
+use crate::disk::{EmuRsDiskDriver, EmuRsDiskProperties};
+use crate::driver::{EmuRsDriver, EmuRsDriverPreference};
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::vfs::EmuRsPath;
+use crate::EmuRsContext;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use tinyvec::TinyVec;
+
+// https://kodi.wiki/view/Cue_sheets
+// https://problemkaputt.de/psxspx-cdrom-disk-images-cue-bin-other.htm
+// https://problemkaputt.de/psxspx-cdrom-sector-encoding.htm
+
+/// A whole sector as it comes off the disc, which is also how audio is stored
+pub const RAW_SECTOR_SIZE: usize = 2352;
+/// What is left of a data sector once the headers and error correction are taken off
+pub const DATA_SECTOR_SIZE: usize = 2048;
+/// Frames per second of CD time
+const FRAMES_PER_SECOND: u32 = 75;
+/// MSF addresses count the two seconds of lead in before the first track, LBAs don't
+pub const LEAD_IN_FRAMES: u32 = 2 * FRAMES_PER_SECOND;
+
+/// A address on a disc in minutes, seconds and frames, which is how CD drives are asked for sectors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EmuRsMsf {
+    pub minute: u8,
+    pub second: u8,
+    pub frame: u8,
+}
+
+impl EmuRsMsf {
+    pub fn from_lba(lba: u32) -> Self {
+        let frames = lba + LEAD_IN_FRAMES;
+
+        return Self {
+            minute: (frames / (60 * FRAMES_PER_SECOND)) as u8,
+            second: (frames / FRAMES_PER_SECOND % 60) as u8,
+            frame: (frames % FRAMES_PER_SECOND) as u8,
+        };
+    }
+
+    /// The sector this is, or [None] if it is in the lead in
+    pub fn to_lba(self) -> Option<u32> {
+        return self.frames().checked_sub(LEAD_IN_FRAMES);
+    }
+
+    fn frames(self) -> u32 {
+        return (u32::from(self.minute) * 60 + u32::from(self.second)) * FRAMES_PER_SECOND
+            + u32::from(self.frame);
+    }
+}
+
+/// How the sectors of a track are stored in the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmuRsCueTrackMode {
+    /// Raw CD-DA, 588 stereo 16 bit little endian samples a sector
+    Audio,
+    /// `MODE1/2048`, just the data
+    Mode1,
+    /// `MODE1/2352`, with the sync, header and error correction
+    Mode1Raw,
+    /// `MODE2/2336`, with the XA subheader but no sync or header
+    Mode2,
+    /// `MODE2/2352` or `CDI/2352`, the whole sector
+    Mode2Raw,
+}
+
+impl EmuRsCueTrackMode {
+    /// How many bytes a sector takes up in the image
+    pub fn sector_size(&self) -> usize {
+        return match self {
+            EmuRsCueTrackMode::Mode1 => DATA_SECTOR_SIZE,
+            EmuRsCueTrackMode::Mode2 => 2336,
+            _ => RAW_SECTOR_SIZE,
+        };
+    }
+
+    /// Where the 2048 bytes of data start in a sector, or [None] for audio
+    ///
+    /// Mode 2 is assumed to be form 1, which is what filesystems are stored in
+    pub fn data_offset(&self) -> Option<usize> {
+        return match self {
+            EmuRsCueTrackMode::Audio => None,
+            EmuRsCueTrackMode::Mode1 => Some(0),
+            EmuRsCueTrackMode::Mode1Raw => Some(16),
+            EmuRsCueTrackMode::Mode2 => Some(8),
+            EmuRsCueTrackMode::Mode2Raw => Some(24),
+        };
+    }
+
+    pub fn is_data(&self) -> bool {
+        return self.data_offset().is_some();
+    }
+}
+
+/// A track out of a cue sheet and where it ended up on the disc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmuRsCueTrack {
+    pub number: u8,
+    pub mode: EmuRsCueTrackMode,
+    /// The image the track is in
+    pub file: EmuRsPath,
+    /// Where `INDEX 01` is in the image, in bytes
+    pub file_offset: usize,
+    /// The LBA of `INDEX 01`, where the track really starts
+    pub start: u32,
+    /// Sectors from `INDEX 01` to the end of the track
+    pub length: u32,
+    /// Sectors of gap before `INDEX 01`. The ones from `PREGAP` are silence that isn't in the
+    /// image, the ones from `INDEX 00` are right before the track in the image
+    pub pregap: u32,
+    pub stored_pregap: u32,
+    /// Sectors of silence after the track, from `POSTGAP`
+    pub postgap: u32,
+}
+
+impl EmuRsCueTrack {
+    /// Whether the sector at `lba` belongs to this track, counting its gaps
+    pub fn contains(&self, lba: u32) -> bool {
+        return lba + self.pregap >= self.start && lba < self.start + self.length + self.postgap;
+    }
+}
+
+/// The tracks of a cue sheet as they come one after another on the disc
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EmuRsCueSheet {
+    pub tracks: Vec<EmuRsCueTrack>,
+}
+
+/// A track as the cue sheet says it, before it is placed on the disc
+struct EmuRsCueEntry {
+    number: u8,
+    mode: EmuRsCueTrackMode,
+    file: usize,
+    index0: Option<u32>,
+    index1: Option<u32>,
+    pregap: u32,
+    postgap: u32,
+}
+
+impl EmuRsCueSheet {
+    /// Parse a cue sheet that lives in `directory`. `file_size` gives the size of a image, which
+    /// is the only way to know how long the last track in it is
+    pub fn parse(
+        text: &str,
+        directory: &EmuRsPath,
+        file_size: &mut dyn FnMut(&EmuRsPath) -> Result<usize, EmuRsError>,
+    ) -> Result<Self, EmuRsError> {
+        let mut files: Vec<EmuRsPath> = Vec::new();
+        let mut entries: Vec<EmuRsCueEntry> = Vec::new();
+
+        for line in text.lines() {
+            let words = split_line(line.trim_start_matches('\u{feff}'));
+            let command = match words.first() {
+                Some(command) => command.to_ascii_uppercase(),
+                None => continue,
+            };
+
+            match command.as_str() {
+                "FILE" => {
+                    let (name, kind) = match (words.get(1), words.get(2)) {
+                        (Some(name), Some(kind)) => (name, kind),
+                        _ => return Err(corrupted()),
+                    };
+
+                    // Audio in WAVE or MP3 files would need decoding, and MOTOROLA is big endian
+                    if !kind.eq_ignore_ascii_case("BINARY") {
+                        return Err(EmuRsError {
+                            reason: EmuRsErrorReason::OperationNotSupported,
+                        });
+                    }
+
+                    let mut path = directory.clone();
+                    for segment in name
+                        .split(['/', '\\'])
+                        .filter(|segment| !segment.is_empty())
+                    {
+                        path = path.join_segment(segment);
+                    }
+                    files.push(path.normalize()?);
+                }
+                "TRACK" => {
+                    let number = words
+                        .get(1)
+                        .and_then(|number| number.parse().ok())
+                        .ok_or_else(corrupted)?;
+                    let mode = match words
+                        .get(2)
+                        .map(|mode| mode.to_ascii_uppercase())
+                        .as_deref()
+                    {
+                        Some("AUDIO") => EmuRsCueTrackMode::Audio,
+                        Some("MODE1/2048") => EmuRsCueTrackMode::Mode1,
+                        Some("MODE1/2352") => EmuRsCueTrackMode::Mode1Raw,
+                        Some("MODE2/2336") => EmuRsCueTrackMode::Mode2,
+                        Some("MODE2/2352") | Some("CDI/2352") => EmuRsCueTrackMode::Mode2Raw,
+                        Some(_) => {
+                            return Err(EmuRsError {
+                                reason: EmuRsErrorReason::OperationNotSupported,
+                            })
+                        }
+                        None => return Err(corrupted()),
+                    };
+
+                    if files.is_empty() {
+                        return Err(corrupted());
+                    }
+
+                    entries.push(EmuRsCueEntry {
+                        number,
+                        mode,
+                        file: files.len() - 1,
+                        index0: None,
+                        index1: None,
+                        pregap: 0,
+                        postgap: 0,
+                    });
+                }
+                "INDEX" | "PREGAP" | "POSTGAP" => {
+                    let entry = entries.last_mut().ok_or_else(corrupted)?;
+                    let time = parse_time(words.last().map_or("", |time| time.as_str()))?;
+
+                    match (
+                        command.as_str(),
+                        words.get(1).map(|index| index.parse::<u8>()),
+                    ) {
+                        ("INDEX", Some(Ok(0))) => entry.index0 = Some(time),
+                        ("INDEX", Some(Ok(1))) => entry.index1 = Some(time),
+                        // Indexes past 1 are only for skipping around inside of a track
+                        ("INDEX", Some(Ok(_))) => {}
+                        ("INDEX", _) => return Err(corrupted()),
+                        ("PREGAP", _) => entry.pregap = time,
+                        _ => entry.postgap = time,
+                    }
+                }
+                // Titles, performers, flags and the like don't change where anything is
+                _ => {}
+            }
+        }
+
+        if entries.is_empty() {
+            return Err(corrupted());
+        }
+
+        let mut tracks = Vec::new();
+        // Where the image being placed starts on the disc, plus any gaps that aren't in it
+        let mut disc_offset = 0;
+
+        for (file_index, file) in files.iter().enumerate() {
+            let in_file: Vec<&EmuRsCueEntry> = entries
+                .iter()
+                .filter(|entry| entry.file == file_index)
+                .collect();
+
+            if in_file.is_empty() {
+                continue;
+            }
+
+            let size = file_size(file)?;
+            // Times in the cue sheet count sectors, which can be different sizes in one image
+            let mut frame = 0;
+            let mut byte = 0;
+            let mut sector_size = in_file[0].mode.sector_size();
+
+            for (position, entry) in in_file.iter().enumerate() {
+                let index1 = entry.index1.ok_or_else(corrupted)?;
+                let first = entry.index0.unwrap_or(index1);
+
+                if first < frame || index1 < first {
+                    return Err(corrupted());
+                }
+
+                byte += (first - frame) as usize * sector_size;
+                sector_size = entry.mode.sector_size();
+                byte += (index1 - first) as usize * sector_size;
+                frame = index1;
+
+                let length = match in_file.get(position + 1) {
+                    Some(next) => next
+                        .index0
+                        .or(next.index1)
+                        .and_then(|next_start| next_start.checked_sub(index1))
+                        .ok_or_else(corrupted)?,
+                    None => (size.saturating_sub(byte) / sector_size) as u32,
+                };
+
+                disc_offset += entry.pregap;
+                tracks.push(EmuRsCueTrack {
+                    number: entry.number,
+                    mode: entry.mode,
+                    file: file.clone(),
+                    file_offset: byte,
+                    start: disc_offset + index1,
+                    length,
+                    pregap: entry.pregap + (index1 - first),
+                    stored_pregap: index1 - first,
+                    postgap: entry.postgap,
+                });
+                disc_offset += entry.postgap;
+            }
+
+            // The next image carries on from the end of the last track in this one
+            disc_offset += frame + tracks.last().unwrap().length;
+        }
+
+        return Ok(Self { tracks });
+    }
+
+    pub fn track(&self, number: u8) -> Option<&EmuRsCueTrack> {
+        return self.tracks.iter().find(|track| track.number == number);
+    }
+
+    /// The track a sector is in, counting gaps as part of the track they are next to
+    pub fn track_at(&self, lba: u32) -> Option<&EmuRsCueTrack> {
+        return self.tracks.iter().find(|track| track.contains(lba));
+    }
+
+    /// The first sector past the last track
+    pub fn lead_out(&self) -> u32 {
+        return self.tracks.last().map_or(0, |track| {
+            return track.start + track.length + track.postgap;
+        });
+    }
+}
+
+/// A cue sheet whose images are read out of the VFS
+///
+/// Like [crate::disk::EmuRsLoopDisk] the sheet is found in the namespace that is in when it is
+/// opened, and everything after that goes through the global view
+pub struct EmuRsCueDisc {
+    pub os_context: Rc<EmuRsContext>,
+    pub sheet: EmuRsCueSheet,
+}
+
+impl EmuRsCueDisc {
+    pub fn open(os_context: Rc<EmuRsContext>, path: &EmuRsPath) -> Result<Self, EmuRsError> {
+        let sheet = {
+            let fs = os_context.fs.borrow();
+            let path = fs.namespace().resolve(path)?;
+            let fs = fs.global();
+
+            let mut text = vec![0; fs.metadata(&path)?.size.unwrap_or(0)];
+            fs.read(&path, &mut text, 0)?;
+            let text = String::from_utf8_lossy(&text);
+
+            EmuRsCueSheet::parse(&text, &path.parent().unwrap_or_default(), &mut |file| {
+                return fs.metadata(file)?.size.ok_or(EmuRsError {
+                    reason: EmuRsErrorReason::FileNotFound,
+                });
+            })?
+        };
+
+        return Ok(Self { os_context, sheet });
+    }
+
+    /// Read the start of the sector at `msf` as it is stored in the image, which for audio is
+    /// raw CD-DA. Gaps that aren't in the image read as silence
+    pub fn read_sector(
+        &self,
+        msf: EmuRsMsf,
+        buffer: &mut [u8],
+    ) -> Result<&EmuRsCueTrack, EmuRsError> {
+        let lba = msf.to_lba().ok_or(EmuRsError {
+            reason: EmuRsErrorReason::EndOfDiskHit,
+        })?;
+        let track = self.sheet.track_at(lba).ok_or(EmuRsError {
+            reason: EmuRsErrorReason::EndOfDiskHit,
+        })?;
+
+        let sector_size = track.mode.sector_size();
+        if buffer.len() > sector_size {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit,
+            });
+        }
+
+        let stored = lba + track.stored_pregap >= track.start && lba < track.start + track.length;
+        if !stored {
+            buffer.fill(0);
+            return Ok(track);
+        }
+
+        // Counting from the start of the part of the pregap that is stored
+        let stored_start = track.file_offset - track.stored_pregap as usize * sector_size;
+        let offset =
+            stored_start + (lba + track.stored_pregap - track.start) as usize * sector_size;
+        self.os_context
+            .fs
+            .borrow()
+            .global()
+            .read(&track.file, buffer, offset)?;
+
+        return Ok(track);
+    }
+
+    /// A disk for a track. Data tracks read as 2048 byte sectors, so they can be mounted as ISO
+    /// 9660, and audio tracks read as raw CD-DA
+    pub fn track_disk(&self, number: u8) -> Result<EmuRsCueTrackDisk, EmuRsError> {
+        let track = self.sheet.track(number).ok_or(EmuRsError {
+            reason: EmuRsErrorReason::FileNotFound,
+        })?;
+
+        return Ok(EmuRsCueTrackDisk {
+            os_context: self.os_context.clone(),
+            track: track.clone(),
+        });
+    }
+
+    /// A disk for every data track
+    pub fn data_disks(&self) -> Vec<EmuRsCueTrackDisk> {
+        return self
+            .sheet
+            .tracks
+            .iter()
+            .filter(|track| track.mode.is_data())
+            .map(|track| EmuRsCueTrackDisk {
+                os_context: self.os_context.clone(),
+                track: track.clone(),
+            })
+            .collect();
+    }
+}
+
+/// One track of a disc image as a disk, without its gaps
+pub struct EmuRsCueTrackDisk {
+    os_context: Rc<EmuRsContext>,
+    track: EmuRsCueTrack,
+}
+
+impl EmuRsCueTrackDisk {
+    pub fn track(&self) -> &EmuRsCueTrack {
+        return &self.track;
+    }
+}
+
+impl EmuRsDriver for EmuRsCueTrackDisk {
+    fn name(&self) -> &'static str {
+        return "CUE Track Disk";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsDiskDriver for EmuRsCueTrackDisk {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        let total_size = self.get_total_size();
+        if offset
+            .checked_add(buffer.len())
+            .is_none_or(|end| end > total_size)
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit,
+            });
+        }
+
+        let sector_size = self.get_sector_size();
+        let stored_size = self.track.mode.sector_size();
+        let data_offset = self.track.mode.data_offset().unwrap_or(0);
+        let fs = self.os_context.fs.borrow();
+        let fs = fs.global();
+        let mut done = 0;
+
+        // Data sectors have headers and error correction between them, so go a sector at a time
+        while done < buffer.len() {
+            let sector = (offset + done) / sector_size;
+            let within = (offset + done) % sector_size;
+            let length = (sector_size - within).min(buffer.len() - done);
+
+            fs.read(
+                &self.track.file,
+                &mut buffer[done..done + length],
+                self.track.file_offset + sector * stored_size + data_offset + within,
+            )?;
+            done += length;
+        }
+
+        return Ok(());
+    }
+
+    fn get_sector_size(&mut self) -> usize {
+        return match self.track.mode {
+            EmuRsCueTrackMode::Audio => RAW_SECTOR_SIZE,
+            _ => DATA_SECTOR_SIZE,
+        };
+    }
+
+    fn get_total_size(&mut self) -> usize {
+        return self.track.length as usize * self.get_sector_size();
+    }
+
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        return EmuRsDiskProperties {
+            read_only: true,
+            ..Default::default()
+        };
+    }
+}
+
+fn corrupted() -> EmuRsError {
+    return EmuRsError {
+        reason: EmuRsErrorReason::CorruptedFilesystem,
+    };
+}
+
+/// `mm:ss:ff` as a count of sectors
+fn parse_time(time: &str) -> Result<u32, EmuRsError> {
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+
+    return match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(minute)), Some(Some(second)), Some(Some(frame)), None)
+            if second < 60 && frame < FRAMES_PER_SECOND =>
+        {
+            minute
+                .checked_mul(60)
+                .and_then(|seconds| seconds.checked_add(second))
+                .and_then(|seconds| seconds.checked_mul(FRAMES_PER_SECOND))
+                .and_then(|frames| frames.checked_add(frame))
+                .ok_or_else(corrupted)
+        }
+        _ => Err(corrupted()),
+    };
+}
+
+/// Split a line of a cue sheet into words, keeping quoted names together
+fn split_line(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut characters = line.trim().chars().peekable();
+
+    while let Some(&character) = characters.peek() {
+        if character.is_whitespace() {
+            characters.next();
+            continue;
+        }
+
+        let mut word = String::new();
+        if character == '"' {
+            characters.next();
+            for character in characters.by_ref() {
+                if character == '"' {
+                    break;
+                }
+                word.push(character);
+            }
+        } else {
+            while let Some(&character) = characters.peek() {
+                if character.is_whitespace() {
+                    break;
+                }
+                word.push(character);
+                characters.next();
+            }
+        }
+
+        words.push(word);
+    }
+
+    return words;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use crate::drivers::cborfs::EmuRsCborFs;
+    use crate::vfs::EmuRsFsDriver;
+    use crate::EmuRsContextBuilder;
+    use core::cell::RefCell;
+    use core::str::FromStr;
+
+    fn path(path: &str) -> EmuRsPath {
+        return EmuRsPath::from_str(path).unwrap();
+    }
+
+    /// Parse `text` from `/games`, with `sizes` giving how many bytes each image is by name
+    fn parse(text: &str, sizes: &[(&str, usize)]) -> EmuRsCueSheet {
+        return EmuRsCueSheet::parse(text, &path("/games"), &mut |file| {
+            return sizes
+                .iter()
+                .find(|(name, _)| *file == path(name))
+                .map(|(_, size)| *size)
+                .ok_or(EmuRsError {
+                    reason: EmuRsErrorReason::FileNotFound,
+                });
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn pregaps_and_index_00() {
+        let sheet = parse(
+            "FILE \"game.bin\" BINARY
+              TRACK 01 MODE2/2352
+                INDEX 01 00:00:00
+              TRACK 02 AUDIO
+                PREGAP 00:02:00
+                INDEX 01 00:10:00
+              TRACK 03 AUDIO
+                INDEX 00 00:20:00
+                INDEX 01 00:22:00",
+            &[("/games/game.bin", 1750 * RAW_SECTOR_SIZE)],
+        );
+
+        let [data, silent, stored] = &sheet.tracks[..] else {
+            panic!("expected 3 tracks, got {:?}", sheet.tracks);
+        };
+
+        assert_eq!((data.start, data.length, data.file_offset), (0, 750, 0));
+
+        // The PREGAP isn't in the image, so the track moves along the disc but not the image
+        assert_eq!((silent.start, silent.length), (900, 750));
+        assert_eq!((silent.pregap, silent.stored_pregap), (150, 0));
+        assert_eq!(silent.file_offset, 750 * RAW_SECTOR_SIZE);
+
+        // INDEX 00 is in the image, so everything after the PREGAP stays shifted by it
+        assert_eq!((stored.start, stored.length), (1800, 100));
+        assert_eq!((stored.pregap, stored.stored_pregap), (150, 150));
+        assert_eq!(stored.file_offset, 1650 * RAW_SECTOR_SIZE);
+
+        assert_eq!(sheet.track_at(800).map(|track| track.number), Some(2));
+        assert_eq!(sheet.track_at(1700).map(|track| track.number), Some(3));
+        assert_eq!(sheet.lead_out(), 1900);
+    }
+
+    #[test]
+    fn every_file_carries_on_from_the_last() {
+        let sheet = parse(
+            "FILE \"track1.bin\" BINARY
+              TRACK 01 MODE1/2352
+                INDEX 01 00:00:00
+            FILE \"track2.bin\" BINARY
+              TRACK 02 AUDIO
+                INDEX 00 00:00:00
+                INDEX 01 00:02:00
+            FILE \"disc\\track3.bin\" BINARY
+              TRACK 03 AUDIO
+                INDEX 01 00:00:00",
+            &[
+                ("/games/track1.bin", 1000 * RAW_SECTOR_SIZE),
+                ("/games/track2.bin", 650 * RAW_SECTOR_SIZE),
+                ("/games/disc/track3.bin", 300 * RAW_SECTOR_SIZE),
+            ],
+        );
+
+        let placed: Vec<(EmuRsPath, usize, u32, u32)> = sheet
+            .tracks
+            .iter()
+            .map(|track| {
+                (
+                    track.file.clone(),
+                    track.file_offset,
+                    track.start,
+                    track.length,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            placed,
+            [
+                (path("/games/track1.bin"), 0, 0, 1000),
+                (path("/games/track2.bin"), 150 * RAW_SECTOR_SIZE, 1150, 500),
+                (path("/games/disc/track3.bin"), 0, 1650, 300),
+            ]
+        );
+        assert_eq!(sheet.lead_out(), 1950);
+    }
+
+    #[test]
+    fn sectors_before_a_track_are_its_own_size() {
+        let data_size = 300 * DATA_SECTOR_SIZE;
+        let sheet = parse(
+            "FILE \"mixed.bin\" BINARY
+              TRACK 01 MODE1/2048
+                INDEX 01 00:00:00
+              TRACK 02 AUDIO
+                INDEX 00 00:04:00
+                INDEX 01 00:06:00",
+            &[("/games/mixed.bin", data_size + 350 * RAW_SECTOR_SIZE)],
+        );
+
+        let [data, audio] = &sheet.tracks[..] else {
+            panic!("expected 2 tracks, got {:?}", sheet.tracks);
+        };
+
+        assert_eq!((data.mode, data.length), (EmuRsCueTrackMode::Mode1, 300));
+
+        // The 2048 byte sectors run up to INDEX 00, the gap after it is already audio
+        assert_eq!(audio.file_offset, data_size + 150 * RAW_SECTOR_SIZE);
+        assert_eq!((audio.start, audio.length), (450, 200));
+        assert_eq!(audio.stored_pregap, 150);
+    }
+
+    /// What the data in sector `sector` of track `track` is, so every byte is different enough
+    fn data(track: u8, sector: usize) -> Vec<u8> {
+        return (0..DATA_SECTOR_SIZE)
+            .map(|index| ((sector * DATA_SECTOR_SIZE + index) % 251) as u8 ^ track)
+            .collect();
+    }
+
+    /// A mode 1 track of 3 sectors, a mode 2 track of 2 and a audio track of 2 after a gap that
+    /// isn't in the image. Headers and error correction are filled with 0xee
+    fn disc() -> EmuRsCueDisc {
+        let mut image = Vec::new();
+        for (track, sectors, header, trailer) in [(1, 3, 16, 288), (2, 2, 24, 280)] {
+            for sector in 0..sectors {
+                image.extend(vec![0xee; header]);
+                image.extend(data(track, sector));
+                image.extend(vec![0xee; trailer]);
+            }
+        }
+        for sector in 0..2 {
+            image.extend((0..RAW_SECTOR_SIZE).map(|index| (index as u8).wrapping_add(sector)));
+        }
+
+        let cue = "FILE \"game.bin\" BINARY
+              TRACK 01 MODE1/2352
+                INDEX 01 00:00:00
+              TRACK 02 MODE2/2352
+                INDEX 01 00:00:03
+              TRACK 03 AUDIO
+                PREGAP 00:00:02
+                INDEX 01 00:00:05";
+
+        let context = EmuRsContextBuilder::default().done();
+        let disk = Rc::new(RefCell::new(VecDisk(vec![0; 65536])));
+        EmuRsCborFs::format(&mut *disk.borrow_mut()).unwrap();
+        let mut root = EmuRsCborFs::default();
+        root.mount(disk).unwrap();
+        context
+            .fs
+            .borrow_mut()
+            .mount(&EmuRsPath::default(), Rc::new(RefCell::new(root)))
+            .unwrap();
+
+        {
+            let fs = context.fs.borrow();
+            fs.create_directory(&path("/games")).unwrap();
+            for (name, contents) in [
+                ("/games/game.bin", image.as_slice()),
+                ("/games/game.cue", cue.as_bytes()),
+            ] {
+                fs.create(&path(name)).unwrap();
+                fs.write(&path(name), contents, 0).unwrap();
+            }
+        }
+
+        return EmuRsCueDisc::open(context, &path("/games/game.cue")).unwrap();
+    }
+
+    #[test]
+    fn data_tracks_read_across_sectors() {
+        let disc = disc();
+        assert_eq!(disc.data_disks().len(), 2);
+
+        for (track, sectors) in [(1, 3), (2, 2)] {
+            let mut disk = disc.track_disk(track).unwrap();
+            let expected: Vec<u8> = (0..sectors)
+                .flat_map(|sector| data(track, sector))
+                .collect();
+            assert_eq!(disk.get_total_size(), expected.len());
+
+            // Straddling the headers and error correction between two sectors
+            let mut buffer = vec![0; 100];
+            disk.read(&mut buffer, 2000).unwrap();
+            assert_eq!(buffer, expected[2000..2100]);
+
+            let mut buffer = vec![0; expected.len()];
+            disk.read(&mut buffer, 0).unwrap();
+            assert_eq!(buffer, expected);
+
+            let mut buffer = vec![0; 10];
+            for offset in [expected.len() - 5, usize::MAX] {
+                assert!(matches!(
+                    disk.read(&mut buffer, offset),
+                    Err(EmuRsError {
+                        reason: EmuRsErrorReason::EndOfDiskHit
+                    })
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn reads_sectors_by_msf() {
+        let disc = disc();
+        let mut buffer = vec![0xff; RAW_SECTOR_SIZE];
+
+        // The audio track starts at 7, after the two sectors of silence, so this is its second
+        let track = disc
+            .read_sector(EmuRsMsf::from_lba(8), &mut buffer)
+            .unwrap();
+        assert_eq!(track.number, 3);
+        assert!(buffer
+            .iter()
+            .enumerate()
+            .all(|(index, byte)| *byte == (index as u8).wrapping_add(1)));
+
+        let track = disc
+            .read_sector(EmuRsMsf::from_lba(6), &mut buffer)
+            .unwrap();
+        assert_eq!(track.number, 3);
+        assert!(buffer.iter().all(|byte| *byte == 0));
+
+        // Data sectors come back whole, headers and all
+        let track = disc
+            .read_sector(EmuRsMsf::from_lba(1), &mut buffer)
+            .unwrap();
+        assert_eq!(track.number, 1);
+        assert_eq!(buffer[..16], [0xee; 16]);
+        assert_eq!(buffer[16..16 + DATA_SECTOR_SIZE], data(1, 1));
+
+        for msf in [
+            EmuRsMsf {
+                minute: 0,
+                second: 1,
+                frame: 0,
+            },
+            EmuRsMsf::from_lba(9),
+        ] {
+            assert!(matches!(
+                disc.read_sector(msf, &mut buffer),
+                Err(EmuRsError {
+                    reason: EmuRsErrorReason::EndOfDiskHit
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn huge_times_are_corrupted() {
+        for time in ["4294967295:00:00", "71582789:00:00", "00:60:00", "00:00:75"] {
+            assert!(matches!(
+                parse_time(time),
+                Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem
+                })
+            ));
+        }
+
+        assert_eq!(parse_time("01:02:03").unwrap(), (60 + 2) * 75 + 3);
+    }
+}
@@ -0,0 +1,679 @@
+use crate::drivers::gamefs::EmuRsFileDigests;
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::romdb::{EmuRsRomDatabaseWriter, EmuRsRomRecord};
+use crate::vfs::EmuRsPath;
+use crate::EmuRsContext;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// DAT files are how ROM sets like No-Intro describe every known dump, either as Logiqx XML or in
+// the older clrmamepro format. They identify ROMs by CRC32, MD5 and SHA-1 instead of BLAKE2s, so
+// a entry can only go in roms.db once a real file has been found that matches it
+
+/// A single file a DAT knows about
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EmuRsDatRom {
+    pub name: String,
+    pub size: Option<usize>,
+    pub crc32: Option<u32>,
+    pub md5: Option<[u8; 16]>,
+    pub sha1: Option<[u8; 20]>,
+}
+
+impl EmuRsDatRom {
+    /// Compare against the strongest digest the DAT gives us
+    pub fn matches(&self, digests: &EmuRsFileDigests) -> bool {
+        if self.size.is_some_and(|size| size != digests.size) {
+            return false;
+        }
+
+        if let Some(sha1) = self.sha1 {
+            return sha1 == digests.sha1;
+        }
+
+        if let Some(md5) = self.md5 {
+            return md5 == digests.md5;
+        }
+
+        return self.crc32 == Some(digests.crc32);
+    }
+}
+
+/// A game, which can be made of more than one file
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EmuRsDatGame {
+    pub name: String,
+    pub description: String,
+    /// BIOS sets are what we call firmware
+    pub bios: bool,
+    pub roms: Vec<EmuRsDatRom>,
+}
+
+impl EmuRsDatGame {
+    /// Split a No-Intro style name like `Title (USA) (Rev 1)` into a roms.db record
+    pub fn record(&self, hash: [u8; 32], system: &str) -> EmuRsRomRecord {
+        let (name, firmware) = match self.name.strip_prefix("[BIOS]") {
+            Some(name) => (name.trim_start(), true),
+            None => (self.name.as_str(), self.bios),
+        };
+
+        let (title, tags) = name.split_once(" (").unwrap_or((name, ""));
+        let mut tags = tags
+            .split('(')
+            .map(|tag| tag.trim().trim_end_matches(')'))
+            .filter(|tag| !tag.is_empty());
+
+        // No-Intro always puts the region first
+        let region = tags.next().unwrap_or_default();
+        let revision = tags
+            .find(|tag| {
+                return tag.starts_with("Rev ")
+                    || (tag.starts_with('v')
+                        && tag[1..].starts_with(|c: char| c.is_ascii_digit()));
+            })
+            .unwrap_or_default();
+
+        return EmuRsRomRecord {
+            hash,
+            title: title.trim().into(),
+            system: system.into(),
+            region: region.into(),
+            revision: revision.into(),
+            firmware,
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EmuRsDat {
+    /// Usually the system, like `Nintendo - Game Boy Advance`
+    pub name: String,
+    pub description: String,
+    pub games: Vec<EmuRsDatGame>,
+}
+
+impl EmuRsDat {
+    /// Works out which of the two formats it is by itself
+    pub fn parse(text: &str) -> Result<Self, EmuRsError> {
+        let text = text.trim_start_matches('\u{feff}').trim_start();
+
+        if text.starts_with('<') {
+            return parse_logiqx(text);
+        }
+
+        return parse_clrmamepro(text);
+    }
+
+    /// Read a DAT out of the VFS. The path is from the root of the VFS, whatever namespace is in
+    pub fn open(os_context: &EmuRsContext, path: &EmuRsPath) -> Result<Self, EmuRsError> {
+        let fs = os_context.fs.borrow();
+        let fs = fs.global();
+        let mut bytes = vec![0; fs.metadata(path)?.size.unwrap_or(0)];
+        fs.read(path, &mut bytes, 0)?;
+
+        // Older DATs are often Latin-1, which only ever shows up in names
+        return Self::parse(&String::from_utf8_lossy(&bytes));
+    }
+
+    /// Find the ROM a file is, if it is in here
+    pub fn find(&self, digests: &EmuRsFileDigests) -> Option<(&EmuRsDatGame, &EmuRsDatRom)> {
+        return self
+            .games
+            .iter()
+            .flat_map(|game| game.roms.iter().map(move |rom| (game, rom)))
+            .find(|(_, rom)| rom.matches(digests));
+    }
+
+    /// Add a record to `database` for every file in `files` this DAT knows, returning how many
+    ///
+    /// `system` is the short name the records get, since DATs only have long ones
+    pub fn import(
+        &self,
+        system: &str,
+        files: &[EmuRsFileDigests],
+        database: &mut EmuRsRomDatabaseWriter,
+    ) -> usize {
+        // Almost every ROM has a CRC32, so that narrows things down without a linear search
+        let mut by_crc32: BTreeMap<u32, Vec<(&EmuRsDatGame, &EmuRsDatRom)>> = BTreeMap::new();
+        let mut without_crc32 = Vec::new();
+
+        for game in self.games.iter() {
+            for rom in game.roms.iter() {
+                match rom.crc32 {
+                    Some(crc32) => by_crc32.entry(crc32).or_default().push((game, rom)),
+                    None => without_crc32.push((game, rom)),
+                }
+            }
+        }
+
+        let mut imported = 0;
+
+        for file in files {
+            let found = by_crc32
+                .get(&file.crc32)
+                .into_iter()
+                .flatten()
+                .chain(without_crc32.iter())
+                .find(|(_, rom)| rom.matches(file));
+
+            if let Some((game, _)) = found {
+                database.add(game.record(file.blake2s, system));
+                imported += 1;
+            }
+        }
+
+        return imported;
+    }
+}
+
+fn corrupted() -> EmuRsError {
+    return EmuRsError {
+        reason: EmuRsErrorReason::CorruptedFilesystem,
+    };
+}
+
+fn parse_rom(
+    name: Option<String>,
+    size: Option<&str>,
+    crc32: Option<&str>,
+    md5: Option<&str>,
+    sha1: Option<&str>,
+) -> EmuRsDatRom {
+    // Anything that doesn't parse is treated like it isn't there, since there are other digests
+    return EmuRsDatRom {
+        name: name.unwrap_or_default(),
+        size: size.and_then(|size| size.parse().ok()),
+        crc32: crc32.and_then(|crc32| u32::from_str_radix(crc32, 16).ok()),
+        md5: md5.and_then(parse_hex),
+        sha1: sha1.and_then(parse_hex),
+    };
+}
+
+fn parse_hex<const SIZE: usize>(text: &str) -> Option<[u8; SIZE]> {
+    if text.len() != SIZE * 2 {
+        return None;
+    }
+
+    let mut bytes = [0; SIZE];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+
+    return Some(bytes);
+}
+
+// Logiqx XML. This only understands as much XML as DATs use, which is tags, attributes, text and
+// entities
+
+fn parse_logiqx(text: &str) -> Result<EmuRsDat, EmuRsError> {
+    let mut dat = EmuRsDat::default();
+    let mut game: Option<EmuRsDatGame> = None;
+    let mut in_header = false;
+    let mut rest = text;
+
+    while let Some(start) = rest.find('<') {
+        // The text right before a closing tag is what that tag holds
+        let content = unescape(rest[..start].trim());
+        rest = &rest[start..];
+
+        // Comments, declarations and the doctype
+        let skipped = [("<!--", "-->"), ("<?", "?>"), ("<!", ">")]
+            .into_iter()
+            .find(|(open, _)| rest.starts_with(open));
+
+        if let Some((_, close)) = skipped {
+            let end = rest.find(close).ok_or_else(corrupted)?;
+            rest = &rest[end + close.len()..];
+            continue;
+        }
+
+        let end = tag_end(rest).ok_or_else(corrupted)?;
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        if let Some(name) = tag.strip_prefix('/') {
+            match name.trim() {
+                "game" | "machine" => {
+                    if let Some(game) = game.take() {
+                        dat.games.push(game);
+                    }
+                }
+                "header" => in_header = false,
+                "name" if in_header => dat.name = content,
+                "description" => match game.as_mut() {
+                    Some(game) => game.description = content,
+                    None if in_header => dat.description = content,
+                    None => {}
+                },
+                _ => {}
+            }
+
+            continue;
+        }
+
+        let self_closing = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let (name, attributes) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let attributes = parse_attributes(attributes)?;
+        let attribute = |key: &str| {
+            return attributes
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.as_str());
+        };
+
+        match name {
+            "header" => in_header = !self_closing,
+            "game" | "machine" => {
+                let new_game = EmuRsDatGame {
+                    name: attribute("name").unwrap_or_default().into(),
+                    bios: attribute("isbios") == Some("yes"),
+                    ..Default::default()
+                };
+
+                if self_closing {
+                    dat.games.push(new_game);
+                } else {
+                    game = Some(new_game);
+                }
+            }
+            "rom" => {
+                if let Some(game) = game.as_mut() {
+                    game.roms.push(parse_rom(
+                        attribute("name").map(String::from),
+                        attribute("size"),
+                        attribute("crc"),
+                        attribute("md5"),
+                        attribute("sha1"),
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    return Ok(dat);
+}
+
+/// Where a tag ends, skipping over any `>` inside of attribute values
+fn tag_end(text: &str) -> Option<usize> {
+    let mut quote = None;
+
+    for (index, character) in text.char_indices() {
+        match (character, quote) {
+            ('"' | '\'', None) => quote = Some(character),
+            (character, Some(open)) if character == open => quote = None,
+            ('>', None) => return Some(index),
+            _ => {}
+        }
+    }
+
+    return None;
+}
+
+fn parse_attributes(mut text: &str) -> Result<Vec<(&str, String)>, EmuRsError> {
+    let mut attributes = Vec::new();
+
+    loop {
+        text = text.trim_start();
+        if text.is_empty() {
+            return Ok(attributes);
+        }
+
+        let (name, value) = text.split_once('=').ok_or_else(corrupted)?;
+        let value = value.trim_start();
+        let quote = value
+            .chars()
+            .next()
+            .filter(|quote| *quote == '"' || *quote == '\'');
+        let quote = quote.ok_or_else(corrupted)?;
+        let end = value[1..].find(quote).ok_or_else(corrupted)? + 1;
+
+        attributes.push((name.trim(), unescape(&value[1..end])));
+        text = &value[end + 1..];
+    }
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest.find(';').map(|end| (&rest[1..end], end));
+        let character = entity.and_then(|(entity, _)| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .and_then(|code| u32::from_str_radix(code, 16).ok())
+                .or_else(|| entity.strip_prefix('#')?.parse().ok())
+                .and_then(char::from_u32),
+        });
+
+        // Not a entity after all, so leave it as it is
+        match (character, entity) {
+            (Some(character), Some((_, end))) => {
+                unescaped.push(character);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    unescaped.push_str(rest);
+    return unescaped;
+}
+
+// clrmamepro, which is blocks of `key value` pairs in brackets, like
+//
+// game (
+//     name "Title (USA)"
+//     rom ( name "Title (USA).gba" size 4194304 crc 8a3a0e4b )
+// )
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmuRsClrMameToken<'a> {
+    Open,
+    Close,
+    Word(&'a str),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum EmuRsClrMameItem<'a> {
+    Value(&'a str),
+    Block(Vec<(&'a str, EmuRsClrMameItem<'a>)>),
+}
+
+fn parse_clrmamepro(text: &str) -> Result<EmuRsDat, EmuRsError> {
+    let tokens = tokenize(text)?;
+    let items = parse_block(&mut tokens.iter(), false)?;
+    let mut dat = EmuRsDat::default();
+
+    for (key, item) in items.iter() {
+        let block = match item {
+            EmuRsClrMameItem::Block(block) => block,
+            EmuRsClrMameItem::Value(_) => continue,
+        };
+
+        match *key {
+            "clrmamepro" => {
+                dat.name = value(block, "name").unwrap_or_default().into();
+                dat.description = value(block, "description").unwrap_or_default().into();
+            }
+            // MAME lists BIOS sets as resources
+            "game" | "machine" | "resource" => {
+                let roms = block.iter().filter_map(|(key, item)| match (*key, item) {
+                    ("rom", EmuRsClrMameItem::Block(rom)) => Some(parse_rom(
+                        value(rom, "name").map(String::from),
+                        value(rom, "size"),
+                        value(rom, "crc"),
+                        value(rom, "md5"),
+                        value(rom, "sha1"),
+                    )),
+                    _ => None,
+                });
+
+                dat.games.push(EmuRsDatGame {
+                    name: value(block, "name").unwrap_or_default().into(),
+                    description: value(block, "description").unwrap_or_default().into(),
+                    bios: *key == "resource",
+                    roms: roms.collect(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    return Ok(dat);
+}
+
+fn value<'a>(block: &[(&'a str, EmuRsClrMameItem<'a>)], key: &str) -> Option<&'a str> {
+    return block.iter().find_map(|(name, item)| match item {
+        EmuRsClrMameItem::Value(value) if *name == key => Some(*value),
+        _ => None,
+    });
+}
+
+fn tokenize(text: &str) -> Result<Vec<EmuRsClrMameToken<'_>>, EmuRsError> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+
+    while let Some(character) = rest.chars().next() {
+        match character {
+            '(' => {
+                tokens.push(EmuRsClrMameToken::Open);
+                rest = &rest[1..];
+            }
+            ')' => {
+                tokens.push(EmuRsClrMameToken::Close);
+                rest = &rest[1..];
+            }
+            // There's no escaping, a quote always ends the string
+            '"' => {
+                let end = rest[1..].find('"').ok_or_else(corrupted)? + 1;
+                tokens.push(EmuRsClrMameToken::Word(&rest[1..end]));
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                let end = rest
+                    .find(|character: char| {
+                        return character.is_whitespace() || character == '(' || character == ')';
+                    })
+                    .unwrap_or(rest.len());
+                tokens.push(EmuRsClrMameToken::Word(&rest[..end]));
+                rest = &rest[end..];
+            }
+        }
+
+        rest = rest.trim_start();
+    }
+
+    return Ok(tokens);
+}
+
+/// The whole file is a block too, just without the brackets
+fn parse_block<'a>(
+    tokens: &mut core::slice::Iter<EmuRsClrMameToken<'a>>,
+    nested: bool,
+) -> Result<Vec<(&'a str, EmuRsClrMameItem<'a>)>, EmuRsError> {
+    let mut items = Vec::new();
+
+    loop {
+        let key = match tokens.next() {
+            Some(EmuRsClrMameToken::Word(key)) => *key,
+            Some(EmuRsClrMameToken::Close) if nested => return Ok(items),
+            None if !nested => return Ok(items),
+            _ => return Err(corrupted()),
+        };
+
+        let item = match tokens.next() {
+            Some(EmuRsClrMameToken::Word(value)) => EmuRsClrMameItem::Value(value),
+            Some(EmuRsClrMameToken::Open) => EmuRsClrMameItem::Block(parse_block(tokens, true)?),
+            _ => return Err(corrupted()),
+        };
+
+        items.push((key, item));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGIQX: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE datafile PUBLIC "-//Logiqx//DTD ROM Management Datafile//EN" "http://www.logiqx.com/Dats/datafile.dtd">
+<datafile>
+    <header>
+        <name>Nintendo - Game Boy Advance</name>
+        <description>Nintendo - Game Boy Advance &amp; friends</description>
+    </header>
+    <!-- A comment with a <game> in it -->
+    <game name="Pok&#233;mon &quot;Ruby&quot; (USA) (Rev 1)">
+        <description>Pok&#xe9;mon Ruby &lt;USA&gt;</description>
+        <rom name="disc 1 > 2.gba" size="16777216" crc="F0815EE7" md5="53D591215DE2CAB847D14FBCF8D516F4" sha1="5B64EACF892920518DB4EC664E62A086DD5F5BC8"/>
+        <rom name='second.sav' size='131072' crc='00000000'/>
+    </game>
+    <game name="[BIOS] Game Boy Advance (World)" isbios="yes">
+        <rom name="gba_bios.bin" size="16384" crc="81977335"/>
+    </game>
+    <machine name="empty"/>
+</datafile>
+"#;
+
+    #[test]
+    fn parses_logiqx() {
+        // Windows tools like to start the file with a BOM
+        let dat = EmuRsDat::parse(&(String::from("\u{feff}") + LOGIQX)).unwrap();
+        assert_eq!(dat.name, "Nintendo - Game Boy Advance");
+        assert_eq!(dat.description, "Nintendo - Game Boy Advance & friends");
+        assert_eq!(dat.games.len(), 3);
+
+        let game = &dat.games[0];
+        assert_eq!(game.name, "Pok\u{e9}mon \"Ruby\" (USA) (Rev 1)");
+        assert_eq!(game.description, "Pok\u{e9}mon Ruby <USA>");
+        assert!(!game.bios);
+        assert_eq!(
+            game.roms,
+            [
+                EmuRsDatRom {
+                    name: "disc 1 > 2.gba".into(),
+                    size: Some(16777216),
+                    crc32: Some(0xf0815ee7),
+                    md5: parse_hex("53D591215DE2CAB847D14FBCF8D516F4"),
+                    sha1: parse_hex("5B64EACF892920518DB4EC664E62A086DD5F5BC8"),
+                },
+                EmuRsDatRom {
+                    name: "second.sav".into(),
+                    size: Some(131072),
+                    crc32: Some(0),
+                    md5: None,
+                    sha1: None,
+                }
+            ]
+        );
+
+        assert!(dat.games[1].bios);
+        assert_eq!(dat.games[1].roms.len(), 1);
+        assert_eq!(dat.games[2].name, "empty");
+        assert!(dat.games[2].roms.is_empty());
+
+        let record = dat.games[0].record([0; 32], "gba");
+        assert_eq!(record.title, "Pok\u{e9}mon \"Ruby\"");
+        assert_eq!(record.region, "USA");
+        assert_eq!(record.revision, "Rev 1");
+        assert!(!record.firmware);
+
+        let record = dat.games[1].record([0; 32], "gba");
+        assert_eq!(record.title, "Game Boy Advance");
+        assert!(record.firmware);
+    }
+
+    #[test]
+    fn rejects_malformed_logiqx() {
+        for text in [
+            // A tag that never ends
+            "<datafile><game name=\"a\"",
+            // A attribute without quotes
+            "<datafile><game name=a></game></datafile>",
+            // A attribute that never ends
+            "<datafile><game name=\"a></game></datafile>",
+            // A comment that never ends
+            "<datafile><!-- </datafile>",
+        ] {
+            assert!(EmuRsDat::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn unescapes_entities() {
+        assert_eq!(unescape("a &amp; b"), "a & b");
+        assert_eq!(unescape("&lt;&gt;&quot;&apos;"), "<>\"'");
+        assert_eq!(unescape("&#65;&#x42;&#X43;"), "AB&#X43;");
+        // Things that only look like entities are left alone
+        assert_eq!(unescape("R&D"), "R&D");
+        assert_eq!(unescape("&nbsp; &bogus"), "&nbsp; &bogus");
+        assert_eq!(unescape("&#xd800;"), "&#xd800;");
+    }
+
+    #[test]
+    fn tokenizes_clrmamepro() {
+        use EmuRsClrMameToken::*;
+
+        assert_eq!(
+            tokenize("game (\n\tname \"Title (USA)\"\n\trom ( size 16 )\n)").unwrap(),
+            [
+                Word("game"),
+                Open,
+                Word("name"),
+                Word("Title (USA)"),
+                Word("rom"),
+                Open,
+                Word("size"),
+                Word("16"),
+                Close,
+                Close
+            ]
+        );
+        assert_eq!(tokenize("a\"\"b").unwrap(), [Word("a\"\"b")]);
+        assert_eq!(tokenize("\"\"").unwrap(), [Word("")]);
+        assert!(tokenize("name \"Title").is_err());
+    }
+
+    #[test]
+    fn parses_clrmamepro() {
+        let text = r#"
+clrmamepro (
+    name "Nintendo - Game Boy"
+    description "Nintendo - Game Boy"
+)
+
+game (
+    name "Tetris (World) (Rev 1)"
+    description "Tetris (World) (Rev 1)"
+    rom ( name "Tetris (World) (Rev 1).gb" size 32768 crc 46DF91AD md5 084F1E457749CDEC86183189BD88CE69 )
+    rom ( name "extra.bin" size nonsense crc XYZ )
+)
+
+resource (
+    name "gb_bios"
+    rom ( name "dmg_boot.bin" size 256 crc 59C8598E )
+)
+"#;
+
+        let dat = EmuRsDat::parse(text).unwrap();
+        assert_eq!(dat.name, "Nintendo - Game Boy");
+        assert_eq!(dat.games.len(), 2);
+        assert_eq!(dat.games[0].roms.len(), 2);
+        assert_eq!(dat.games[0].roms[0].crc32, Some(0x46df91ad));
+        assert!(dat.games[0].roms[0].md5.is_some());
+
+        // Bad values are as good as missing
+        assert_eq!(dat.games[0].roms[1].size, None);
+        assert_eq!(dat.games[0].roms[1].crc32, None);
+        assert!(dat.games[1].bios);
+
+        let digests = EmuRsFileDigests {
+            size: 256,
+            crc32: 0x59c8598e,
+            ..Default::default()
+        };
+        let (game, rom) = dat.find(&digests).unwrap();
+        assert_eq!(game.name, "gb_bios");
+        assert_eq!(rom.name, "dmg_boot.bin");
+
+        assert!(EmuRsDat::parse("game ( name )").is_err());
+        assert!(EmuRsDat::parse("game ( name \"a\"").is_err());
+        assert!(EmuRsDat::parse(")").is_err());
+    }
+}
@@ -1,17 +1,31 @@
-use core::intrinsics::size_of;
-
-
-
 use crate::device::EmuRsDevice;
 use crate::driver::EmuRsDriver;
 use crate::driver::EmuRsDriverPreference;
 use crate::error::EmuRsError;
 use crate::error::EmuRsErrorReason;
+use crate::vfs::EmuRsPath;
+use crate::EmuRsContext;
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use tinyvec::TinyVec;
 
-
-
+/// What a disk is like, so filesystems can avoid doing what it is bad at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EmuRsDiskProperties {
+    /// Every write will fail
+    pub read_only: bool,
+    /// How much flash gets erased at once, which is the least a write really costs. [None] if
+    /// sectors can be written by themselves
+    pub erase_block_size: Option<usize>,
+    /// Writes take long enough that they should be batched up instead of made as they happen
+    pub slow_writes: bool,
+}
 
 /// The disk implementation for filesystems to write and read
+///
+/// Reads and writes can be at any offset and any length. Hardware that can only do whole sectors
+/// should implement [EmuRsBlockDevice] and be wrapped in a [EmuRsBlockDisk]
 /// IMPORTANT: Disks MUST return failure if they cannot fill the entire buffer. This is a hard requirement
 pub trait EmuRsDiskDriver: EmuRsDriver {
     fn write(&mut self, _buffer: &[u8], _offset: usize) -> Result<(), EmuRsError> {
@@ -24,8 +38,212 @@ pub trait EmuRsDiskDriver: EmuRsDriver {
             reason: EmuRsErrorReason::OperationNotSupported,
         });
     }
+    /// The size of the sectors underneath. Accesses that line up with them are the cheapest
     fn get_sector_size(&mut self) -> usize;
     fn get_total_size(&mut self) -> usize;
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        return EmuRsDiskProperties::default();
+    }
+}
+
+/// A disk that can only be read and written a whole sector at a time, which is most real hardware
+///
+/// Buffers are always a whole number of sectors long. Anything else fails with
+/// [EmuRsErrorReason::UnalignedAccess], and going past the last sector with
+/// [EmuRsErrorReason::EndOfDiskHit]
+pub trait EmuRsBlockDevice: EmuRsDriver {
+    fn read_sectors(&mut self, sector: usize, buffer: &mut [u8]) -> Result<(), EmuRsError>;
+    fn write_sectors(&mut self, _sector: usize, _buffer: &[u8]) -> Result<(), EmuRsError> {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::OperationNotSupported,
+        });
+    }
+    fn get_sector_size(&mut self) -> usize;
+    fn get_sector_count(&mut self) -> usize;
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        return EmuRsDiskProperties::default();
+    }
+}
+
+/// Check a access against the [EmuRsBlockDevice] contract, for implementations to start with
+pub fn check_sector_access(
+    device: &mut dyn EmuRsBlockDevice,
+    sector: usize,
+    length: usize,
+) -> Result<(), EmuRsError> {
+    let sector_size = device.get_sector_size();
+
+    // Nothing lines up with sectors that have no size
+    if sector_size == 0 {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::OperationNotSupported,
+        });
+    }
+
+    if !length.is_multiple_of(sector_size) {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::UnalignedAccess,
+        });
+    }
+
+    let end = sector.checked_add(length / sector_size);
+    if end.is_none_or(|end| end > device.get_sector_count()) {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::EndOfDiskHit,
+        });
+    }
+
+    return Ok(());
+}
+
+/// Lets a [EmuRsBlockDevice] be used as a [EmuRsDiskDriver], reading whole sectors and writing
+/// back the parts of them that weren't changed
+#[derive(Default)]
+pub struct EmuRsBlockDisk<DEVICE: EmuRsBlockDevice> {
+    device: DEVICE,
+    /// Somewhere to put the sectors a access only covers part of
+    scratch: Vec<u8>,
+}
+
+impl<DEVICE: EmuRsBlockDevice> EmuRsBlockDisk<DEVICE> {
+    pub fn new(device: DEVICE) -> Self {
+        return Self {
+            device,
+            scratch: Vec::new(),
+        };
+    }
+
+    pub fn device(&mut self) -> &mut DEVICE {
+        return &mut self.device;
+    }
+
+    pub fn into_inner(self) -> DEVICE {
+        return self.device;
+    }
+
+    fn check_bounds(&mut self, offset: usize, length: usize) -> Result<(), EmuRsError> {
+        // Every access gets split up by the sector size
+        if self.device.get_sector_size() == 0 {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported,
+            });
+        }
+
+        if offset + length > self.get_total_size() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit,
+            });
+        }
+
+        return Ok(());
+    }
+
+    /// Read a sector into the scratch buffer
+    fn read_scratch(&mut self, sector: usize) -> Result<(), EmuRsError> {
+        let sector_size = self.device.get_sector_size();
+        if self.scratch.len() != sector_size {
+            self.scratch = vec![0; sector_size];
+        }
+
+        return self.device.read_sectors(sector, &mut self.scratch);
+    }
+}
+
+impl<DEVICE: EmuRsBlockDevice> EmuRsDriver for EmuRsBlockDisk<DEVICE> {
+    fn name(&self) -> &'static str {
+        return self.device.name();
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return self.device.get_preference();
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        return self.device.get_claimed();
+    }
+
+    fn init(&mut self, context: Rc<EmuRsContext>) {
+        self.device.init(context);
+    }
+}
+
+impl<DEVICE: EmuRsBlockDevice> EmuRsDiskDriver for EmuRsBlockDisk<DEVICE> {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        self.check_bounds(offset, buffer.len())?;
+
+        let sector_size = self.device.get_sector_size();
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let sector = (offset + done) / sector_size;
+            let within = (offset + done) % sector_size;
+            let remaining = buffer.len() - done;
+
+            // Whole sectors go straight into the buffer
+            if within == 0 && remaining >= sector_size {
+                let length = remaining - remaining % sector_size;
+                self.device
+                    .read_sectors(sector, &mut buffer[done..done + length])?;
+                done += length;
+                continue;
+            }
+
+            let length = (sector_size - within).min(remaining);
+            self.read_scratch(sector)?;
+            buffer[done..done + length].copy_from_slice(&self.scratch[within..within + length]);
+            done += length;
+        }
+
+        return Ok(());
+    }
+
+    fn write(&mut self, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        if self.device.get_properties().read_only {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported,
+            });
+        }
+
+        self.check_bounds(offset, buffer.len())?;
+
+        let sector_size = self.device.get_sector_size();
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let sector = (offset + done) / sector_size;
+            let within = (offset + done) % sector_size;
+            let remaining = buffer.len() - done;
+
+            if within == 0 && remaining >= sector_size {
+                let length = remaining - remaining % sector_size;
+                self.device
+                    .write_sectors(sector, &buffer[done..done + length])?;
+                done += length;
+                continue;
+            }
+
+            // Only part of the sector changes, so the rest of it has to be read first
+            let length = (sector_size - within).min(remaining);
+            self.read_scratch(sector)?;
+            self.scratch[within..within + length].copy_from_slice(&buffer[done..done + length]);
+            self.device.write_sectors(sector, &self.scratch)?;
+            done += length;
+        }
+
+        return Ok(());
+    }
+
+    fn get_sector_size(&mut self) -> usize {
+        return self.device.get_sector_size();
+    }
+
+    fn get_total_size(&mut self) -> usize {
+        return self.device.get_sector_size() * self.device.get_sector_count();
+    }
+
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        return self.device.get_properties();
+    }
 }
 
 /// A disk that just points somewhere in memory. Useful for the GBA save slot
@@ -63,8 +281,8 @@ impl<'owner, T: EmuRsMemoryDisk + EmuRsDriver> EmuRsDiskDriver for T {
     }
 
     fn get_sector_size(&mut self) -> usize {
-        // FIXME: This is probably not so smart
-        return size_of::<usize>();
+        // Memory can be read and written a byte at a time
+        return 1;
     }
 
     fn get_total_size(&mut self) -> usize {
@@ -107,11 +325,335 @@ impl<'owner> EmuRsDiskDriver for EmuRsInternalDisk<'owner> {
     }
 
     fn get_sector_size(&mut self) -> usize {
-        // FIXME: This is probably not so smart
-        return size_of::<usize>();
+        return 1;
     }
 
     fn get_total_size(&mut self) -> usize {
         return self.data.len();
     }
+
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        return EmuRsDiskProperties {
+            read_only: true,
+            ..Default::default()
+        };
+    }
+}
+
+/// A disk backed by a file in the VFS, so images inside of other filesystems can be mounted
+///
+/// The path is resolved in the namespace that is in when the disk is made and is global from then
+/// on, so the disk keeps working whatever program is running when it gets read
+pub struct EmuRsLoopDisk {
+    pub os_context: Rc<EmuRsContext>,
+    pub path: EmuRsPath,
+    size: usize,
+}
+
+impl EmuRsLoopDisk {
+    pub fn new(os_context: Rc<EmuRsContext>, path: EmuRsPath) -> Result<Self, EmuRsError> {
+        let path = os_context.fs.borrow().namespace().resolve(&path)?;
+        let size = os_context
+            .fs
+            .borrow()
+            .global()
+            .metadata(&path)?
+            .size
+            .ok_or(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported,
+            })?;
+
+        return Ok(Self {
+            os_context,
+            path,
+            size,
+        });
+    }
+}
+
+impl EmuRsDriver for EmuRsLoopDisk {
+    fn name(&self) -> &'static str {
+        return "Loop Disk";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsDiskDriver for EmuRsLoopDisk {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        if offset + buffer.len() > self.size {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit,
+            });
+        }
+
+        return self
+            .os_context
+            .fs
+            .borrow()
+            .global()
+            .read(&self.path, buffer, offset);
+    }
+
+    fn write(&mut self, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        if offset + buffer.len() > self.size {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit,
+            });
+        }
+
+        return self
+            .os_context
+            .fs
+            .borrow()
+            .global()
+            .write(&self.path, buffer, offset);
+    }
+
+    fn get_sector_size(&mut self) -> usize {
+        return 1;
+    }
+
+    fn get_total_size(&mut self) -> usize {
+        return self.size;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A disk that is just a [Vec], for testing whatever sits on top of a disk
+    pub struct VecDisk(pub Vec<u8>);
+
+    impl EmuRsDriver for VecDisk {
+        fn name(&self) -> &'static str {
+            return "Vec Disk";
+        }
+
+        fn get_preference(&mut self) -> EmuRsDriverPreference {
+            return EmuRsDriverPreference::Preferred;
+        }
+
+        fn get_claimed(&mut self) -> EmuRsDevice {
+            return EmuRsDevice {
+                memory: TinyVec::new(),
+            };
+        }
+    }
+
+    impl EmuRsDiskDriver for VecDisk {
+        fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+            let data = self
+                .0
+                .get(offset..offset + buffer.len())
+                .ok_or(EmuRsError {
+                    reason: EmuRsErrorReason::EndOfDiskHit,
+                })?;
+
+            buffer.copy_from_slice(data);
+            return Ok(());
+        }
+
+        fn write(&mut self, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+            let data = self
+                .0
+                .get_mut(offset..offset + buffer.len())
+                .ok_or(EmuRsError {
+                    reason: EmuRsErrorReason::EndOfDiskHit,
+                })?;
+
+            data.copy_from_slice(buffer);
+            return Ok(());
+        }
+
+        fn get_sector_size(&mut self) -> usize {
+            return 1;
+        }
+
+        fn get_total_size(&mut self) -> usize {
+            return self.0.len();
+        }
+    }
+
+    /// A [EmuRsBlockDevice] over a [Vec] that holds to the contract, counting what it is asked
+    pub struct VecBlockDevice {
+        pub data: Vec<u8>,
+        pub sector_size: usize,
+        pub sector_reads: usize,
+        pub sector_writes: usize,
+    }
+
+    impl VecBlockDevice {
+        pub fn new(sector_size: usize, sector_count: usize) -> Self {
+            return Self {
+                // Something other than zeros, so reads from the wrong place show up
+                data: (0..sector_size * sector_count)
+                    .map(|index| index as u8)
+                    .collect(),
+                sector_size,
+                sector_reads: 0,
+                sector_writes: 0,
+            };
+        }
+    }
+
+    impl EmuRsDriver for VecBlockDevice {
+        fn name(&self) -> &'static str {
+            return "Vec Block Device";
+        }
+
+        fn get_preference(&mut self) -> EmuRsDriverPreference {
+            return EmuRsDriverPreference::Preferred;
+        }
+
+        fn get_claimed(&mut self) -> EmuRsDevice {
+            return EmuRsDevice {
+                memory: TinyVec::new(),
+            };
+        }
+    }
+
+    impl EmuRsBlockDevice for VecBlockDevice {
+        fn read_sectors(&mut self, sector: usize, buffer: &mut [u8]) -> Result<(), EmuRsError> {
+            check_sector_access(self, sector, buffer.len())?;
+
+            let start = sector * self.sector_size;
+            buffer.copy_from_slice(&self.data[start..start + buffer.len()]);
+            self.sector_reads += buffer.len() / self.sector_size;
+            return Ok(());
+        }
+
+        fn write_sectors(&mut self, sector: usize, buffer: &[u8]) -> Result<(), EmuRsError> {
+            check_sector_access(self, sector, buffer.len())?;
+
+            let start = sector * self.sector_size;
+            self.data[start..start + buffer.len()].copy_from_slice(buffer);
+            self.sector_writes += buffer.len() / self.sector_size;
+            return Ok(());
+        }
+
+        fn get_sector_size(&mut self) -> usize {
+            return self.sector_size;
+        }
+
+        fn get_sector_count(&mut self) -> usize {
+            if self.sector_size == 0 {
+                return 0;
+            }
+
+            return self.data.len() / self.sector_size;
+        }
+    }
+
+    #[test]
+    fn reads_across_sectors() {
+        let mut disk = EmuRsBlockDisk::new(VecBlockDevice::new(16, 8));
+        let expected = disk.device().data.clone();
+
+        // Every start and length that fits, so every way of straddling sectors gets hit
+        for offset in 0..48 {
+            for length in 0..80 {
+                let mut buffer = vec![0; length];
+                disk.read(&mut buffer, offset).unwrap();
+                assert_eq!(buffer, expected[offset..offset + length]);
+            }
+        }
+    }
+
+    #[test]
+    fn writes_across_sectors_keep_the_rest_of_them() {
+        let mut disk = EmuRsBlockDisk::new(VecBlockDevice::new(16, 8));
+        let mut expected = disk.device().data.clone();
+
+        // Starts partway through sector 0, covers all of 1 and 2, ends partway through 3
+        disk.write(&[0xaa; 50], 5).unwrap();
+        expected[5..55].fill(0xaa);
+        assert_eq!(disk.device().data, expected);
+        assert_eq!(disk.device().sector_writes, 4);
+
+        // All in the middle of one sector
+        disk.write(&[0xbb; 3], 70).unwrap();
+        expected[70..73].fill(0xbb);
+        assert_eq!(disk.device().data, expected);
+
+        let mut buffer = vec![0; 128];
+        disk.read(&mut buffer, 0).unwrap();
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn whole_sectors_skip_the_scratch_buffer() {
+        let mut disk = EmuRsBlockDisk::new(VecBlockDevice::new(16, 8));
+
+        disk.write(&[0xcc; 64], 32).unwrap();
+        assert_eq!(disk.device().sector_reads, 0);
+
+        let mut buffer = vec![0; 64];
+        disk.read(&mut buffer, 32).unwrap();
+        assert_eq!(buffer, [0xcc; 64]);
+        assert_eq!(disk.device().sector_reads, 4);
+    }
+
+    #[test]
+    fn accesses_past_the_end_fail() {
+        let mut disk = EmuRsBlockDisk::new(VecBlockDevice::new(16, 8));
+
+        assert!(matches!(
+            disk.read(&mut [0; 2], 127),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit
+            })
+        ));
+        assert!(matches!(
+            disk.write(&[0; 17], 112),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit
+            })
+        ));
+        assert!(matches!(
+            check_sector_access(disk.device(), usize::MAX, 16),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit
+            })
+        ));
+        assert!(matches!(
+            check_sector_access(disk.device(), 0, 8),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::UnalignedAccess
+            })
+        ));
+    }
+
+    #[test]
+    fn zero_sized_sectors_are_refused() {
+        let mut disk = EmuRsBlockDisk::new(VecBlockDevice::new(0, 8));
+
+        assert!(matches!(
+            check_sector_access(disk.device(), 0, 0),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported
+            })
+        ));
+        assert!(matches!(
+            disk.read(&mut [0; 1], 0),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported
+            })
+        ));
+        assert!(matches!(
+            disk.write(&[0; 1], 0),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported
+            })
+        ));
+    }
 }
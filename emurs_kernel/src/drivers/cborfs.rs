@@ -0,0 +1,911 @@
+use crate::device::EmuRsDevice;
+use crate::disk::EmuRsDiskDriver;
+use crate::driver::{EmuRsDriver, EmuRsDriverPreference};
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::vfs::{EmuRsFileKind, EmuRsFileMetadata, EmuRsFsDriver, EmuRsPath};
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tinyvec::TinyVec;
+
+// A log structured filesystem for small save media
+//
+// The disk is split into two regions and only one is in use at a time. A region starts with a header holding its generation,
+// then a checkpoint of every inode and directory entry, then a log of changes made since then.
+// When the log runs out of space the live state is written into the other region and its header is written last,
+// so losing power at any point leaves at least one complete region. Records that fail their checksum mark the end of the log
+
+/// The inode of the root directory
+const ROOT_INODE: u64 = 0;
+const REGION_MAGIC: [u8; 4] = *b"CBFS";
+const REGION_HEADER_SIZE: usize = 12;
+const RECORD_MAGIC: [u8; 2] = [0xcb, 0x0f];
+const RECORD_HEADER_SIZE: usize = 12;
+/// How much file data gets moved at once while compacting
+const COPY_CHUNK_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmuRsCborInode {
+    pub kind: EmuRsCborInodeKind,
+    pub size: u64,
+    pub modification_time: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum EmuRsCborInodeKind {
+    File,
+    Folder,
+}
+
+/// A entry in the log. [EmuRsCborRecord::Data] is followed by the raw bytes it describes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum EmuRsCborRecord {
+    /// Every inode and directory entry. Always the first record in a region
+    Checkpoint {
+        next_inode: u64,
+        inodes: BTreeMap<u64, EmuRsCborInode>,
+        entries: Vec<(u64, String, u64)>,
+    },
+    /// A new inode along with the directory entry pointing at it
+    Create {
+        parent: u64,
+        name: String,
+        id: u64,
+        inode: EmuRsCborInode,
+    },
+    /// Remove a directory entry and the inode it points at
+    Delete { parent: u64, name: String },
+    /// Bytes written to a file at a offset
+    Data { id: u64, offset: u64 },
+}
+
+/// A piece of file data somewhere in the log
+#[derive(Debug, Clone, Copy)]
+struct EmuRsCborExtent {
+    file_offset: usize,
+    disk_offset: usize,
+    length: usize,
+}
+
+#[derive(Default)]
+pub struct EmuRsCborFs {
+    disk: Option<Rc<RefCell<dyn EmuRsDiskDriver>>>,
+    inodes: BTreeMap<u64, EmuRsCborInode>,
+    directories: BTreeMap<u64, BTreeMap<String, u64>>,
+    /// Extents are kept in the order they were written so later ones win
+    extents: BTreeMap<u64, Vec<EmuRsCborExtent>>,
+    next_inode: u64,
+    active_region: usize,
+    generation: u32,
+    /// Where the next record goes
+    log_end: usize,
+}
+
+impl EmuRsCborFs {
+    /// Write a empty filesystem to the disk, destroying whatever was on it
+    pub fn format(disk: &mut dyn EmuRsDiskDriver) -> Result<(), EmuRsError> {
+        let region_size = disk.get_total_size() / 2;
+
+        // Invalidate the second region first so it can never win over the fresh one
+        disk.write(&[0; REGION_HEADER_SIZE], region_size)?;
+
+        let mut inodes = BTreeMap::new();
+        inodes.insert(
+            ROOT_INODE,
+            EmuRsCborInode {
+                kind: EmuRsCborInodeKind::Folder,
+                size: 0,
+                modification_time: None,
+            },
+        );
+
+        let checkpoint = EmuRsCborRecord::Checkpoint {
+            next_inode: ROOT_INODE + 1,
+            inodes,
+            entries: Vec::new(),
+        };
+        write_record(disk, REGION_HEADER_SIZE, region_size, &checkpoint, &[])?;
+
+        return write_region_header(disk, 0, 1);
+    }
+
+    fn region_size(&self) -> usize {
+        return self.disk.as_ref().unwrap().borrow_mut().get_total_size() / 2;
+    }
+
+    fn region_start(&self, region: usize) -> usize {
+        return region * self.region_size();
+    }
+
+    /// Apply a record to the in memory state. `data_offset` is where the data of a [EmuRsCborRecord::Data] is on the disk
+    fn apply(&mut self, record: EmuRsCborRecord, data_offset: usize, data_length: usize) {
+        match record {
+            EmuRsCborRecord::Checkpoint {
+                next_inode,
+                inodes,
+                entries,
+            } => {
+                self.next_inode = next_inode;
+                self.inodes = inodes;
+                self.directories.clear();
+                self.extents.clear();
+
+                for (parent, name, id) in entries {
+                    self.directories.entry(parent).or_default().insert(name, id);
+                }
+            }
+            EmuRsCborRecord::Create {
+                parent,
+                name,
+                id,
+                inode,
+            } => {
+                self.next_inode = self.next_inode.max(id + 1);
+                self.inodes.insert(id, inode);
+                self.directories.entry(parent).or_default().insert(name, id);
+            }
+            EmuRsCborRecord::Delete { parent, name } => {
+                if let Some(id) = self
+                    .directories
+                    .get_mut(&parent)
+                    .and_then(|directory| directory.remove(&name))
+                {
+                    self.inodes.remove(&id);
+                    self.extents.remove(&id);
+                    self.directories.remove(&id);
+                }
+            }
+            EmuRsCborRecord::Data { id, offset } => {
+                if let Some(inode) = self.inodes.get_mut(&id) {
+                    inode.size = inode.size.max(offset + data_length as u64);
+                    self.extents.entry(id).or_default().push(EmuRsCborExtent {
+                        file_offset: offset as usize,
+                        disk_offset: data_offset,
+                        length: data_length,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Replay the log of a region, returning where it ends
+    fn replay(&mut self, region: usize) -> Result<usize, EmuRsError> {
+        let region_start = self.region_start(region);
+        let region_end = region_start + self.region_size();
+        let disk = self.disk.clone().unwrap();
+        let mut offset = region_start + REGION_HEADER_SIZE;
+        let mut first = true;
+
+        while let Some((record, data_offset, data_length, next)) =
+            read_record(&mut *disk.borrow_mut(), offset, region_end)?
+        {
+            // The checkpoint is what makes a region usable at all
+            if first && !matches!(record, EmuRsCborRecord::Checkpoint { .. }) {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem,
+                });
+            }
+
+            self.apply(record, data_offset, data_length);
+            offset = next;
+            first = false;
+        }
+
+        if first {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        return Ok(offset);
+    }
+
+    /// Add a record to the log, compacting into the other region if this one is full
+    fn append(&mut self, record: EmuRsCborRecord, data: &[u8]) -> Result<(), EmuRsError> {
+        let encoded_length = encode(&record)?.len();
+        let needed = RECORD_HEADER_SIZE + encoded_length + data.len();
+
+        if self.log_end + needed > self.region_start(self.active_region) + self.region_size() {
+            self.compact()?;
+
+            if self.log_end + needed > self.region_start(self.active_region) + self.region_size() {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::OutOfSpace,
+                });
+            }
+        }
+
+        let region_end = self.region_start(self.active_region) + self.region_size();
+        let disk = self.disk.clone().unwrap();
+        let mut disk = disk.borrow_mut();
+        let data_offset = self.log_end + RECORD_HEADER_SIZE + encoded_length;
+        let end = write_record(&mut *disk, self.log_end, region_end, &record, data)?;
+        drop(disk);
+
+        self.log_end = end;
+        self.apply(record, data_offset, data.len());
+        return Ok(());
+    }
+
+    /// Write the live state into the other region and switch to it
+    fn compact(&mut self) -> Result<(), EmuRsError> {
+        let new_region = 1 - self.active_region;
+        let region_start = self.region_start(new_region);
+        let region_end = region_start + self.region_size();
+        let disk = self.disk.clone().unwrap();
+        let mut disk = disk.borrow_mut();
+
+        disk.write(&[0; REGION_HEADER_SIZE], region_start)?;
+
+        let checkpoint = EmuRsCborRecord::Checkpoint {
+            next_inode: self.next_inode,
+            inodes: self
+                .inodes
+                .iter()
+                .map(|(id, inode)| {
+                    let mut inode = inode.clone();
+
+                    // Data records set the size when replayed
+                    if inode.kind == EmuRsCborInodeKind::File {
+                        inode.size = 0;
+                    }
+
+                    return (*id, inode);
+                })
+                .collect(),
+            entries: self
+                .directories
+                .iter()
+                .flat_map(|(parent, directory)| {
+                    return directory
+                        .iter()
+                        .map(|(name, id)| (*parent, name.clone(), *id));
+                })
+                .collect(),
+        };
+        let mut offset = write_record(
+            &mut *disk,
+            region_start + REGION_HEADER_SIZE,
+            region_end,
+            &checkpoint,
+            &[],
+        )?;
+        let mut new_extents = BTreeMap::new();
+
+        for (id, inode) in self.inodes.iter() {
+            if inode.kind != EmuRsCborInodeKind::File || inode.size == 0 {
+                continue;
+            }
+
+            let record = EmuRsCborRecord::Data { id: *id, offset: 0 };
+            let encoded = encode(&record)?;
+            let size = inode.size as usize;
+            let data_offset = offset + RECORD_HEADER_SIZE + encoded.len();
+
+            if data_offset + size > region_end {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::OutOfSpace,
+                });
+            }
+
+            // Flatten every extent into one contiguous copy while working out the checksum
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&encoded);
+            let mut chunk = [0; COPY_CHUNK_SIZE];
+            let mut copied = 0;
+
+            while copied < size {
+                let length = (size - copied).min(COPY_CHUNK_SIZE);
+                read_extents(
+                    &mut *disk,
+                    self.extents.get(id).map_or(&[], Vec::as_slice),
+                    &mut chunk[..length],
+                    copied,
+                )?;
+                hasher.update(&chunk[..length]);
+                disk.write(&chunk[..length], data_offset + copied)?;
+                copied += length;
+            }
+
+            disk.write(&encoded, offset + RECORD_HEADER_SIZE)?;
+            disk.write(
+                &record_header(encoded.len(), size, hasher.finalize())?,
+                offset,
+            )?;
+
+            new_extents.insert(
+                *id,
+                alloc::vec![EmuRsCborExtent {
+                    file_offset: 0,
+                    disk_offset: data_offset,
+                    length: size,
+                }],
+            );
+            offset = data_offset + size;
+        }
+
+        invalidate_record(&mut *disk, offset, region_end)?;
+
+        // This is the moment the new region takes over
+        write_region_header(&mut *disk, region_start, self.generation.wrapping_add(1))?;
+
+        self.generation = self.generation.wrapping_add(1);
+        self.active_region = new_region;
+        self.log_end = offset;
+        self.extents = new_extents;
+        return Ok(());
+    }
+
+    /// Walk a path down from the root directory
+    fn lookup(&self, file: &EmuRsPath) -> Result<u64, EmuRsError> {
+        let path = file.normalize()?;
+        let mut id = ROOT_INODE;
+
+        for segment in path.segments.iter().skip(1) {
+            id = *self
+                .directories
+                .get(&id)
+                .and_then(|directory| directory.get(segment))
+                .ok_or(EmuRsError {
+                    reason: EmuRsErrorReason::FileNotFound,
+                })?;
+        }
+
+        return Ok(id);
+    }
+
+    fn inode(&self, id: u64) -> Result<&EmuRsCborInode, EmuRsError> {
+        return self.inodes.get(&id).ok_or(EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        });
+    }
+
+    fn create_inode(
+        &mut self,
+        file: &EmuRsPath,
+        kind: EmuRsCborInodeKind,
+    ) -> Result<(), EmuRsError> {
+        let path = file.normalize()?;
+        let parent_path = path.parent().ok_or(EmuRsError {
+            reason: EmuRsErrorReason::InvalidPath,
+        })?;
+        let parent = self.lookup(&parent_path)?;
+
+        if self.inode(parent)?.kind != EmuRsCborInodeKind::Folder {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        if self.lookup(&path).is_ok() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::FileAlreadyExists,
+            });
+        }
+
+        return self.append(
+            EmuRsCborRecord::Create {
+                parent,
+                name: path.file_name(),
+                id: self.next_inode,
+                inode: EmuRsCborInode {
+                    kind,
+                    size: 0,
+                    modification_time: None,
+                },
+            },
+            &[],
+        );
+    }
+}
+
+impl EmuRsDriver for EmuRsCborFs {
+    fn name(&self) -> &'static str {
+        return "CBOR Filesystem";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsFsDriver for EmuRsCborFs {
+    /// Pick the newest region that is intact and replay its log
+    fn mount(&mut self, disk: Rc<RefCell<dyn EmuRsDiskDriver>>) -> Result<(), EmuRsError> {
+        let region_size = disk.borrow_mut().get_total_size() / 2;
+        let mut generations = [None, None];
+
+        for (region, generation) in generations.iter_mut().enumerate() {
+            *generation = read_region_header(&mut *disk.borrow_mut(), region * region_size)?;
+        }
+
+        let mut regions: TinyVec<[(u32, usize); 2]> = generations
+            .iter()
+            .enumerate()
+            .filter_map(|(region, generation)| generation.map(|generation| (generation, region)))
+            .collect();
+        // Newest first. The generation wraps around, so it is compared like a serial number
+        regions.sort_by(|(a, _), (b, _)| (b.wrapping_sub(*a) as i32).cmp(&0));
+
+        self.disk = Some(disk);
+
+        for (generation, region) in regions {
+            if let Ok(log_end) = self.replay(region) {
+                self.active_region = region;
+                self.generation = generation;
+                self.log_end = log_end;
+                return Ok(());
+            }
+        }
+
+        self.disk = None;
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        });
+    }
+
+    fn read(
+        &mut self,
+        file: &EmuRsPath,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        let id = self.lookup(file)?;
+        let inode = self.inode(id)?;
+
+        if inode.kind != EmuRsCborInodeKind::File {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        if offset + buffer.len() > inode.size as usize {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfFileHit,
+            });
+        }
+
+        return read_extents(
+            &mut *self.disk.as_ref().unwrap().borrow_mut(),
+            self.extents.get(&id).map_or(&[], Vec::as_slice),
+            buffer,
+            offset,
+        );
+    }
+
+    fn write(&mut self, file: &EmuRsPath, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        let id = self.lookup(file)?;
+
+        if self.inode(id)?.kind != EmuRsCborInodeKind::File {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        return self.append(
+            EmuRsCborRecord::Data {
+                id,
+                offset: offset as u64,
+            },
+            buffer,
+        );
+    }
+
+    fn create(&mut self, file: &EmuRsPath) -> Result<(), EmuRsError> {
+        return self.create_inode(file, EmuRsCborInodeKind::File);
+    }
+
+    fn create_directory(&mut self, file: &EmuRsPath) -> Result<(), EmuRsError> {
+        return self.create_inode(file, EmuRsCborInodeKind::Folder);
+    }
+
+    fn delete(&mut self, file: &EmuRsPath) -> Result<(), EmuRsError> {
+        let path = file.normalize()?;
+        let id = self.lookup(&path)?;
+
+        if id == ROOT_INODE {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        if self
+            .directories
+            .get(&id)
+            .is_some_and(|directory| !directory.is_empty())
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::DirectoryNotEmpty,
+            });
+        }
+
+        return self.append(
+            EmuRsCborRecord::Delete {
+                parent: self.lookup(&path.parent().unwrap())?,
+                name: path.file_name(),
+            },
+            &[],
+        );
+    }
+
+    fn list_directory(&mut self, file: &EmuRsPath) -> Result<TinyVec<[EmuRsPath; 10]>, EmuRsError> {
+        let path = file.normalize()?;
+        let id = self.lookup(&path)?;
+
+        if self.inode(id)?.kind != EmuRsCborInodeKind::Folder {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        return Ok(self
+            .directories
+            .get(&id)
+            .map(|directory| {
+                return directory
+                    .keys()
+                    .map(|name| path.join_segment(name))
+                    .collect();
+            })
+            .unwrap_or_default());
+    }
+
+    fn metadata(&mut self, file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
+        let inode = self.inode(self.lookup(file)?)?;
+
+        return Ok(EmuRsFileMetadata {
+            size: Some(inode.size as usize),
+            modification_time: inode
+                .modification_time
+                .and_then(|time| OffsetDateTime::from_unix_timestamp(time).ok()),
+            kind: Some(match inode.kind {
+                EmuRsCborInodeKind::File => EmuRsFileKind::File,
+                EmuRsCborInodeKind::Folder => EmuRsFileKind::Folder,
+            }),
+            rom: None,
+        });
+    }
+}
+
+fn encode(record: &EmuRsCborRecord) -> Result<Vec<u8>, EmuRsError> {
+    let mut encoded = Vec::new();
+
+    ciborium::ser::into_writer(record, &mut encoded).map_err(|_| EmuRsError {
+        reason: EmuRsErrorReason::Unknown,
+    })?;
+
+    return Ok(encoded);
+}
+
+/// Records start with a magic number, the length of the CBOR and the data, and a CRC32 of both
+fn record_header(
+    cbor_length: usize,
+    data_length: usize,
+    checksum: u32,
+) -> Result<[u8; RECORD_HEADER_SIZE], EmuRsError> {
+    let cbor_length: u16 = cbor_length.try_into().map_err(|_| EmuRsError {
+        reason: EmuRsErrorReason::OutOfSpace,
+    })?;
+    let data_length: u32 = data_length.try_into().map_err(|_| EmuRsError {
+        reason: EmuRsErrorReason::OutOfSpace,
+    })?;
+
+    let mut header = [0; RECORD_HEADER_SIZE];
+    header[0..2].copy_from_slice(&RECORD_MAGIC);
+    header[2..4].copy_from_slice(&cbor_length.to_le_bytes());
+    header[4..8].copy_from_slice(&data_length.to_le_bytes());
+    header[8..12].copy_from_slice(&checksum.to_le_bytes());
+    return Ok(header);
+}
+
+/// Write a record, returning where the next one goes. The header is written last so a torn write fails its checksum
+///
+/// The header slot after the record is cleared before anything else. The region may have been used before, so if that
+/// was left for later losing power in between would leave the old log's records after this one, and mount would replay them
+fn write_record(
+    disk: &mut dyn EmuRsDiskDriver,
+    offset: usize,
+    region_end: usize,
+    record: &EmuRsCborRecord,
+    data: &[u8],
+) -> Result<usize, EmuRsError> {
+    let encoded = encode(record)?;
+    let end = offset + RECORD_HEADER_SIZE + encoded.len() + data.len();
+
+    if end > region_end {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::OutOfSpace,
+        });
+    }
+
+    invalidate_record(disk, end, region_end)?;
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&encoded);
+    hasher.update(data);
+
+    disk.write(&encoded, offset + RECORD_HEADER_SIZE)?;
+    disk.write(data, offset + RECORD_HEADER_SIZE + encoded.len())?;
+    disk.write(
+        &record_header(encoded.len(), data.len(), hasher.finalize())?,
+        offset,
+    )?;
+
+    return Ok(end);
+}
+
+/// Zero out the header slot after the end of the log if there is room for one
+fn invalidate_record(
+    disk: &mut dyn EmuRsDiskDriver,
+    offset: usize,
+    region_end: usize,
+) -> Result<(), EmuRsError> {
+    if offset + RECORD_HEADER_SIZE <= region_end {
+        disk.write(&[0; RECORD_HEADER_SIZE], offset)?;
+    }
+
+    return Ok(());
+}
+
+/// Read the record at `offset`, returning it with where its data is, how long the data is and where the next record is
+///
+/// Returns [None] when there is no valid record there, which is the end of the log
+fn read_record(
+    disk: &mut dyn EmuRsDiskDriver,
+    offset: usize,
+    region_end: usize,
+) -> Result<Option<(EmuRsCborRecord, usize, usize, usize)>, EmuRsError> {
+    if offset + RECORD_HEADER_SIZE > region_end {
+        return Ok(None);
+    }
+
+    let mut header = [0; RECORD_HEADER_SIZE];
+    disk.read(&mut header, offset)?;
+
+    if header[0..2] != RECORD_MAGIC {
+        return Ok(None);
+    }
+
+    let cbor_length = u16::from_le_bytes(header[2..4].try_into().unwrap()) as usize;
+    let data_length = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    let data_offset = offset + RECORD_HEADER_SIZE + cbor_length;
+    let end = data_offset + data_length;
+
+    if end > region_end {
+        return Ok(None);
+    }
+
+    let mut encoded = alloc::vec![0; cbor_length];
+    disk.read(&mut encoded, offset + RECORD_HEADER_SIZE)?;
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&encoded);
+    let mut chunk = [0; COPY_CHUNK_SIZE];
+    let mut checked = 0;
+
+    while checked < data_length {
+        let length = (data_length - checked).min(COPY_CHUNK_SIZE);
+        disk.read(&mut chunk[..length], data_offset + checked)?;
+        hasher.update(&chunk[..length]);
+        checked += length;
+    }
+
+    if hasher.finalize() != checksum {
+        return Ok(None);
+    }
+
+    return Ok(ciborium::de::from_reader(encoded.as_slice())
+        .ok()
+        .map(|record| (record, data_offset, data_length, end)));
+}
+
+fn write_region_header(
+    disk: &mut dyn EmuRsDiskDriver,
+    offset: usize,
+    generation: u32,
+) -> Result<(), EmuRsError> {
+    let mut header = [0; REGION_HEADER_SIZE];
+    header[0..4].copy_from_slice(&REGION_MAGIC);
+    header[4..8].copy_from_slice(&generation.to_le_bytes());
+    let checksum = crc32fast::hash(&header[0..8]);
+    header[8..12].copy_from_slice(&checksum.to_le_bytes());
+    return disk.write(&header, offset);
+}
+
+/// The generation of the region at `offset`, or [None] if it is not valid
+fn read_region_header(
+    disk: &mut dyn EmuRsDiskDriver,
+    offset: usize,
+) -> Result<Option<u32>, EmuRsError> {
+    let mut header = [0; REGION_HEADER_SIZE];
+    disk.read(&mut header, offset)?;
+
+    if header[0..4] != REGION_MAGIC
+        || crc32fast::hash(&header[0..8]) != u32::from_le_bytes(header[8..12].try_into().unwrap())
+    {
+        return Ok(None);
+    }
+
+    return Ok(Some(u32::from_le_bytes(header[4..8].try_into().unwrap())));
+}
+
+/// Assemble part of a file out of its extents. Anything no extent covers is a hole and reads as zeros
+fn read_extents(
+    disk: &mut dyn EmuRsDiskDriver,
+    extents: &[EmuRsCborExtent],
+    buffer: &mut [u8],
+    offset: usize,
+) -> Result<(), EmuRsError> {
+    buffer.fill(0);
+
+    for extent in extents {
+        let start = extent.file_offset.max(offset);
+        let end = (extent.file_offset + extent.length).min(offset + buffer.len());
+
+        if start >= end {
+            continue;
+        }
+
+        disk.read(
+            &mut buffer[start - offset..end - offset],
+            extent.disk_offset + (start - extent.file_offset),
+        )?;
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use crate::fault::{EmuRsFaultConfig, EmuRsFaultDisk};
+    use alloc::format;
+    use alloc::vec;
+
+    type Files = BTreeMap<String, Vec<u8>>;
+
+    fn path(name: &str) -> EmuRsPath {
+        return EmuRsPath::default().join_segment(name);
+    }
+
+    fn formatted_disk(size: usize) -> Rc<RefCell<VecDisk>> {
+        let disk = Rc::new(RefCell::new(VecDisk(vec![0xff; size])));
+        EmuRsCborFs::format(&mut *disk.borrow_mut()).unwrap();
+        return disk;
+    }
+
+    fn mount(disk: Rc<RefCell<dyn EmuRsDiskDriver>>) -> EmuRsCborFs {
+        let mut fs = EmuRsCborFs::default();
+        fs.mount(disk).unwrap();
+        return fs;
+    }
+
+    /// Every file in the root directory and what is in it
+    fn contents(fs: &mut EmuRsCborFs) -> Files {
+        let mut files = Files::new();
+
+        for file in fs.list_directory(&EmuRsPath::default()).unwrap() {
+            let mut data = vec![0; fs.metadata(&file).unwrap().size.unwrap()];
+            fs.read(&file, &mut data, 0).unwrap();
+            files.insert(file.file_name(), data);
+        }
+
+        return files;
+    }
+
+    /// Do something to both the filesystem and what we expect to be on it
+    fn step(fs: &mut EmuRsCborFs, files: &mut Files, index: usize) -> Result<(), EmuRsError> {
+        let name = format!("save{}", index % 5);
+
+        match (files.contains_key(&name), index % 7) {
+            (false, _) => {
+                fs.create(&path(&name))?;
+                files.insert(name, Vec::new());
+            }
+            (true, 0) => {
+                fs.delete(&path(&name))?;
+                files.remove(&name);
+            }
+            (true, _) => {
+                let data = vec![index as u8; 20 + index % 50];
+                let offset = index % 30;
+                fs.write(&path(&name), &data, offset)?;
+
+                let file = files.get_mut(&name).unwrap();
+                if file.len() < offset + data.len() {
+                    file.resize(offset + data.len(), 0);
+                }
+                file[offset..offset + data.len()].copy_from_slice(&data);
+            }
+        }
+
+        return Ok(());
+    }
+
+    #[test]
+    fn survives_losing_power_anywhere() {
+        for seed in 0..40 {
+            let disk = formatted_disk(2048);
+            let config = EmuRsFaultConfig {
+                torn_write_rate: 0.02,
+                ..Default::default()
+            };
+            let fault_disk = Rc::new(RefCell::new(EmuRsFaultDisk::new(
+                disk.clone(),
+                config,
+                seed,
+            )));
+            let mut fs = mount(fault_disk.clone());
+            let mut files = Files::new();
+
+            for index in 0..300 {
+                let before = files.clone();
+
+                if step(&mut fs, &mut files, index).is_ok() {
+                    continue;
+                }
+
+                // Whatever was going on when the power went either happened or it didn't
+                fault_disk.borrow_mut().power_on();
+                fs = mount(fault_disk.clone());
+                let found = contents(&mut fs);
+                assert!(
+                    found == before || found == files,
+                    "seed {} step {} came back wrong",
+                    seed,
+                    index
+                );
+                files = found;
+            }
+
+            assert!(fault_disk.borrow().stats().torn_writes > 0);
+            assert_eq!(contents(&mut mount(disk)), files);
+        }
+    }
+
+    #[test]
+    fn compaction_keeps_the_live_files() {
+        let disk = formatted_disk(2048);
+        let mut fs = mount(disk.clone());
+        let mut files = Files::new();
+
+        for index in 0..200 {
+            step(&mut fs, &mut files, index).unwrap();
+        }
+
+        // Each region only holds a few dozen records, so this went around a lot
+        assert!(fs.generation > 5);
+        assert_eq!(contents(&mut fs), files);
+        assert_eq!(contents(&mut mount(disk)), files);
+    }
+
+    #[test]
+    fn newer_region_wins_after_the_generation_wraps() {
+        let disk = formatted_disk(2048);
+        write_region_header(&mut *disk.borrow_mut(), 0, u32::MAX - 2).unwrap();
+
+        let mut fs = mount(disk.clone());
+        let mut files = Files::new();
+        let mut index = 0;
+
+        // Go until the other region is left holding u32::MAX
+        while fs.generation != 0 {
+            step(&mut fs, &mut files, index).unwrap();
+            index += 1;
+        }
+
+        assert_eq!(contents(&mut mount(disk)), files);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::compression::{EmuRsCompressionHeader, EmuRsDecompressor};
+use crate::device::EmuRsDevice;
+use crate::disk::EmuRsDiskDriver;
+use crate::driver::EmuRsDriverPreference;
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::vfs::{EmuRsFileKind, EmuRsFileMetadata};
+use crate::{
+    driver::EmuRsDriver,
+    vfs::{EmuRsFsDriver, EmuRsPath},
+};
+use alloc::format;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use tinyvec::TinyVec;
+
+/// Files with these extensions are treated as compressed, and show up without it
+pub const COMPRESSED_EXTENSIONS: [&str; 4] = ["lz", "rl", "huff", "diff"];
+
+/// Wraps another filesystem so files compressed with the BIOS formats read as if they weren't
+///
+/// `tiles.bin.lz` shows up as `tiles.bin`. Compressed files can't be written but can still be deleted
+pub struct EmuRsCompressedFs {
+    inner: Rc<RefCell<dyn EmuRsFsDriver>>,
+    /// Only one file is decompressed at a time so the memory use stays small
+    stream: Option<(EmuRsPath, EmuRsDecompressor)>,
+}
+
+impl EmuRsCompressedFs {
+    pub fn new(inner: Rc<RefCell<dyn EmuRsFsDriver>>) -> Self {
+        return Self {
+            inner,
+            stream: None,
+        };
+    }
+
+    /// Find the compressed file behind a path, if there is one
+    fn compressed_path(&self, file: &EmuRsPath) -> Result<Option<EmuRsPath>, EmuRsError> {
+        let path = file.normalize()?;
+
+        if self.inner.borrow_mut().metadata(&path).is_ok() {
+            return Ok(None);
+        }
+
+        let name = match path.segments.last() {
+            Some(name) if !path.is_root() => name,
+            _ => return Ok(None),
+        };
+
+        for extension in COMPRESSED_EXTENSIONS {
+            let candidate = path
+                .parent()
+                .unwrap_or_default()
+                .join_segment(&format!("{}.{}", name, extension));
+
+            if self.inner.borrow_mut().metadata(&candidate).is_ok() {
+                return Ok(Some(candidate));
+            }
+        }
+
+        return Ok(None);
+    }
+
+    fn header(
+        &self,
+        compressed: &EmuRsPath,
+    ) -> Result<(EmuRsCompressionHeader, usize), EmuRsError> {
+        let mut inner = self.inner.borrow_mut();
+        let compressed_size = inner.metadata(compressed)?.size.unwrap_or(0);
+        let mut header = [0; 8];
+        let header_size = compressed_size.min(header.len());
+        inner.read(compressed, &mut header[..header_size], 0)?;
+
+        let header = EmuRsCompressionHeader::parse(&header[..header_size]).ok_or(EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        })?;
+
+        return Ok((header, compressed_size));
+    }
+}
+
+impl EmuRsDriver for EmuRsCompressedFs {
+    fn name(&self) -> &'static str {
+        return "BIOS Compression Filesystem";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        // Filesystems only talk to disks, never hardware
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsFsDriver for EmuRsCompressedFs {
+    fn mount(&mut self, disk: Rc<RefCell<dyn EmuRsDiskDriver>>) -> Result<(), EmuRsError> {
+        self.stream = None;
+        return self.inner.borrow_mut().mount(disk);
+    }
+
+    fn read(
+        &mut self,
+        file: &EmuRsPath,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        let compressed = match self.compressed_path(file)? {
+            Some(compressed) => compressed,
+            None => return self.inner.borrow_mut().read(file, buffer, offset),
+        };
+
+        let reusable = self.stream.as_ref().is_some_and(|(path, stream)| {
+            return *path == compressed && stream.position() <= offset;
+        });
+
+        if !reusable {
+            let (header, compressed_size) = self.header(&compressed)?;
+            self.stream = Some((
+                compressed.clone(),
+                EmuRsDecompressor::new(header, compressed_size),
+            ));
+        }
+
+        let inner = self.inner.clone();
+        let (_, stream) = self.stream.as_mut().unwrap();
+        let result = stream.read(buffer, offset, &mut |buffer, offset| {
+            return inner.borrow_mut().read(&compressed, buffer, offset);
+        });
+
+        // Don't carry on from a stream that went wrong
+        if result.is_err() {
+            self.stream = None;
+        }
+
+        return result;
+    }
+
+    fn write(&mut self, file: &EmuRsPath, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        if self.compressed_path(file)?.is_some() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported,
+            });
+        }
+
+        return self.inner.borrow_mut().write(file, buffer, offset);
+    }
+
+    fn delete(&mut self, file: &EmuRsPath) -> Result<(), EmuRsError> {
+        self.stream = None;
+
+        return match self.compressed_path(file)? {
+            Some(compressed) => self.inner.borrow_mut().delete(&compressed),
+            None => self.inner.borrow_mut().delete(file),
+        };
+    }
+
+    fn create(&mut self, file: &EmuRsPath) -> Result<(), EmuRsError> {
+        if self.compressed_path(file)?.is_some() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::FileAlreadyExists,
+            });
+        }
+
+        return self.inner.borrow_mut().create(file);
+    }
+
+    fn create_directory(&mut self, file: &EmuRsPath) -> Result<(), EmuRsError> {
+        if self.compressed_path(file)?.is_some() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::FileAlreadyExists,
+            });
+        }
+
+        return self.inner.borrow_mut().create_directory(file);
+    }
+
+    fn list_directory(&mut self, file: &EmuRsPath) -> Result<TinyVec<[EmuRsPath; 10]>, EmuRsError> {
+        let mut entries = TinyVec::new();
+
+        for mut entry in self.inner.borrow_mut().list_directory(file)? {
+            if let Some(name) = entry.segments.last_mut() {
+                if let Some((stem, extension)) = name.rsplit_once('.') {
+                    if COMPRESSED_EXTENSIONS.contains(&extension) {
+                        *name = stem.into();
+                    }
+                }
+            }
+
+            // `foo` next to `foo.lz`, or the same file compressed two ways, is still one file
+            if !entries.contains(&entry) {
+                entries.push(entry);
+            }
+        }
+
+        return Ok(entries);
+    }
+
+    fn metadata(&mut self, file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
+        let compressed = match self.compressed_path(file)? {
+            Some(compressed) => compressed,
+            None => return self.inner.borrow_mut().metadata(file),
+        };
+
+        let (header, _) = self.header(&compressed)?;
+
+        return Ok(EmuRsFileMetadata {
+            size: Some(header.size),
+            kind: Some(EmuRsFileKind::File),
+            ..self.inner.borrow_mut().metadata(&compressed)?
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use crate::drivers::cborfs::EmuRsCborFs;
+    use alloc::string::String;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn lists_each_name_once() {
+        let disk = Rc::new(RefCell::new(VecDisk(vec![0; 4096])));
+        EmuRsCborFs::format(&mut *disk.borrow_mut()).unwrap();
+        let mut inner = EmuRsCborFs::default();
+        inner.mount(disk).unwrap();
+
+        for name in ["foo", "foo.lz", "bar.rl", "bar.lz", "baz.huff"] {
+            inner
+                .create(&EmuRsPath::default().join_segment(name))
+                .unwrap();
+        }
+
+        let mut fs = EmuRsCompressedFs::new(Rc::new(RefCell::new(inner)));
+        let names: Vec<String> = fs
+            .list_directory(&EmuRsPath::default())
+            .unwrap()
+            .iter()
+            .map(EmuRsPath::file_name)
+            .collect();
+
+        assert_eq!(names, ["bar", "baz", "foo"]);
+    }
+}
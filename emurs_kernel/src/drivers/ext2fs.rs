@@ -0,0 +1,647 @@
+use crate::device::EmuRsDevice;
+use crate::disk::EmuRsDiskDriver;
+use crate::driver::EmuRsDriverPreference;
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::vfs::{EmuRsFileKind, EmuRsFileMetadata};
+use crate::{
+    driver::EmuRsDriver,
+    vfs::{EmuRsFsDriver, EmuRsPath},
+};
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use time::OffsetDateTime;
+use tinyvec::TinyVec;
+
+// https://www.nongnu.org/ext2-doc/ext2.html
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// The journal needs replaying, which only matters for writing
+const INCOMPAT_RECOVER: u32 = 0x0004;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_RECOVER | INCOMPAT_FLEX_BG;
+
+const MODE_TYPE_MASK: u16 = 0xf000;
+const MODE_FILE: u16 = 0x8000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_SYMLINK: u16 = 0xa000;
+
+/// Pointers to blocks in the inode before the indirect ones start
+const DIRECT_BLOCKS: usize = 12;
+/// Symlinks shorter than this are kept in the inode instead of a block
+const FAST_SYMLINK_SIZE: usize = 60;
+/// Same limit Linux uses, so loops of symlinks give up instead of spinning forever
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+/// The parts of a inode we care about
+#[derive(Debug, Clone)]
+struct EmuRsExt2Inode {
+    mode: u16,
+    size: usize,
+    modification_time: u32,
+    sector_count: u32,
+    file_acl: u32,
+    blocks: [u32; 15],
+}
+
+impl EmuRsExt2Inode {
+    fn kind(&self) -> EmuRsFileKind {
+        return match self.mode & MODE_TYPE_MASK {
+            MODE_FILE => EmuRsFileKind::File,
+            MODE_DIRECTORY => EmuRsFileKind::Folder,
+            // Character and block devices, fifos and sockets. Symlinks are followed before anyone sees them
+            _ => EmuRsFileKind::Device,
+        };
+    }
+
+    fn is_symlink(&self) -> bool {
+        return self.mode & MODE_TYPE_MASK == MODE_SYMLINK;
+    }
+}
+
+/// A read only ext2 filesystem. Nothing is indexed, paths are looked up through the directories every time
+#[derive(Default)]
+pub struct EmuRsExt2Fs {
+    disk: Option<Rc<RefCell<dyn EmuRsDiskDriver>>>,
+    block_size: usize,
+    inode_size: usize,
+    inodes_per_group: u32,
+    /// Where the inode table of every block group starts
+    inode_tables: Vec<u32>,
+    /// Old filesystems keep the upper byte of the name length where the file type is now
+    has_file_type: bool,
+}
+
+impl EmuRsExt2Fs {
+    fn disk_read(&self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        return self
+            .disk
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .read(buffer, offset);
+    }
+
+    fn inode(&self, number: u32) -> Result<EmuRsExt2Inode, EmuRsError> {
+        let corrupted = EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        };
+
+        if number == 0 {
+            return Err(corrupted);
+        }
+
+        let group = ((number - 1) / self.inodes_per_group) as usize;
+        let index = ((number - 1) % self.inodes_per_group) as usize;
+        let table = *self.inode_tables.get(group).ok_or(corrupted)?;
+
+        let mut inode = [0; 128];
+        self.disk_read(
+            &mut inode,
+            table as usize * self.block_size + index * self.inode_size,
+        )?;
+
+        let mode = read_u16(&inode, 0);
+        let mut size = read_u32(&inode, 4) as u64;
+
+        // Files over 4GiB keep the top of their size where directories keep their ACL
+        if mode & MODE_TYPE_MASK == MODE_FILE {
+            size |= (read_u32(&inode, 108) as u64) << 32;
+        }
+
+        let mut blocks = [0; 15];
+        for (index, block) in blocks.iter_mut().enumerate() {
+            *block = read_u32(&inode, 40 + index * 4);
+        }
+
+        return Ok(EmuRsExt2Inode {
+            mode,
+            // Not that a file that big would fit anywhere we can run
+            size: usize::try_from(size).unwrap_or(usize::MAX),
+            modification_time: read_u32(&inode, 16),
+            sector_count: read_u32(&inode, 28),
+            file_acl: read_u32(&inode, 104),
+            blocks,
+        });
+    }
+
+    /// Which block on the disk holds the `index`th block of a file. Zero means it is a hole
+    fn map_block(&self, inode: &EmuRsExt2Inode, index: usize) -> Result<u32, EmuRsError> {
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.blocks[index]);
+        }
+
+        let pointers_per_block = self.block_size / 4;
+        let mut index = index - DIRECT_BLOCKS;
+        let mut span = 1;
+
+        // Single, double and triple indirect blocks
+        for level in 0..3 {
+            span *= pointers_per_block;
+
+            if index < span {
+                let mut block = inode.blocks[DIRECT_BLOCKS + level];
+
+                for _ in 0..=level {
+                    if block == 0 {
+                        return Ok(0);
+                    }
+
+                    span /= pointers_per_block;
+                    let mut pointer = [0; 4];
+                    self.disk_read(
+                        &mut pointer,
+                        block as usize * self.block_size + (index / span) * 4,
+                    )?;
+                    block = u32::from_le_bytes(pointer);
+                    index %= span;
+                }
+
+                return Ok(block);
+            }
+
+            index -= span;
+        }
+
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        });
+    }
+
+    fn read_inode_data(
+        &self,
+        inode: &EmuRsExt2Inode,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        if offset
+            .checked_add(buffer.len())
+            .is_none_or(|end| end > inode.size)
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfFileHit,
+            });
+        }
+
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done;
+            let within_block = position % self.block_size;
+            let length = (self.block_size - within_block).min(buffer.len() - done);
+            let chunk = &mut buffer[done..done + length];
+
+            match self.map_block(inode, position / self.block_size)? {
+                0 => chunk.fill(0),
+                block => self.disk_read(chunk, block as usize * self.block_size + within_block)?,
+            }
+
+            done += length;
+        }
+
+        return Ok(());
+    }
+
+    /// Every entry in a directory as its name and inode number
+    fn read_directory(&self, inode: &EmuRsExt2Inode) -> Result<Vec<(String, u32)>, EmuRsError> {
+        let mut data = vec![0; inode.size];
+        self.read_inode_data(inode, &mut data, 0)?;
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        while offset + 8 <= data.len() {
+            let number = read_u32(&data, offset);
+            let record_length = read_u16(&data, offset + 4) as usize;
+            let name_length = if self.has_file_type {
+                data[offset + 6] as usize
+            } else {
+                read_u16(&data, offset + 6) as usize
+            };
+
+            if record_length < 8 || offset + 8 + name_length > data.len() {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem,
+                });
+            }
+
+            // Deleted entries are left behind with no inode
+            if number != 0 {
+                entries.push((
+                    String::from_utf8_lossy(&data[offset + 8..offset + 8 + name_length]).into(),
+                    number,
+                ));
+            }
+
+            offset += record_length;
+        }
+
+        return Ok(entries);
+    }
+
+    fn read_symlink(&self, inode: &EmuRsExt2Inode) -> Result<String, EmuRsError> {
+        let acl_sectors = if inode.file_acl != 0 {
+            self.block_size as u32 / 512
+        } else {
+            0
+        };
+
+        // Short targets live where the block pointers would be
+        if inode.size < FAST_SYMLINK_SIZE && inode.sector_count == acl_sectors {
+            let target: Vec<u8> = inode
+                .blocks
+                .iter()
+                .flat_map(|block| block.to_le_bytes())
+                .take(inode.size)
+                .collect();
+
+            return Ok(String::from_utf8_lossy(&target).into());
+        }
+
+        let mut target = vec![0; inode.size];
+        self.read_inode_data(inode, &mut target, 0)?;
+        return Ok(String::from_utf8_lossy(&target).into());
+    }
+
+    /// Find the inode behind a path, following symlinks along the way
+    fn lookup(&self, file: &EmuRsPath) -> Result<EmuRsExt2Inode, EmuRsError> {
+        let path = file.normalize()?;
+        let mut remaining: Vec<String> = path.segments[1..].iter().rev().cloned().collect();
+        let mut number = ROOT_INODE;
+        let mut inode = self.inode(number)?;
+        let mut symlinks_followed = 0;
+
+        while let Some(name) = remaining.pop() {
+            if inode.kind() != EmuRsFileKind::Folder {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::InvalidPath,
+                });
+            }
+
+            let directory_number = number;
+            number = self
+                .read_directory(&inode)?
+                .into_iter()
+                .find(|(entry_name, _)| *entry_name == name)
+                .map(|(_, number)| number)
+                .ok_or(EmuRsError {
+                    reason: EmuRsErrorReason::FileNotFound,
+                })?;
+            inode = self.inode(number)?;
+
+            if inode.is_symlink() {
+                symlinks_followed += 1;
+
+                if symlinks_followed > MAX_SYMLINK_DEPTH {
+                    return Err(EmuRsError {
+                        reason: EmuRsErrorReason::InvalidPath,
+                    });
+                }
+
+                let target = self.read_symlink(&inode)?;
+
+                // Absolute targets start over from the root of this filesystem, relative ones from the directory the link is in
+                number = if target.starts_with('/') {
+                    ROOT_INODE
+                } else {
+                    directory_number
+                };
+                inode = self.inode(number)?;
+
+                remaining.extend(
+                    target
+                        .split('/')
+                        .rev()
+                        .filter(|segment| !segment.is_empty() && *segment != ".")
+                        .map(String::from),
+                );
+            }
+        }
+
+        return Ok(inode);
+    }
+}
+
+impl EmuRsDriver for EmuRsExt2Fs {
+    fn name(&self) -> &'static str {
+        return "ext2 Filesystem";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        // Filesystems only talk to disks, never hardware
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsFsDriver for EmuRsExt2Fs {
+    fn mount(&mut self, disk: Rc<RefCell<dyn EmuRsDiskDriver>>) -> Result<(), EmuRsError> {
+        let mut superblock = [0; SUPERBLOCK_SIZE];
+        disk.borrow_mut().read(&mut superblock, SUPERBLOCK_OFFSET)?;
+
+        if read_u16(&superblock, 56) != EXT2_MAGIC {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        let revision = read_u32(&superblock, 76);
+        let incompatible_features = if revision >= 1 {
+            read_u32(&superblock, 96)
+        } else {
+            0
+        };
+
+        // Extents and 64 bit block numbers are ext4, which is a different driver
+        if incompatible_features & !SUPPORTED_INCOMPAT != 0 {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported,
+            });
+        }
+
+        let log_block_size = read_u32(&superblock, 24);
+        let blocks_count = read_u32(&superblock, 4);
+        let first_data_block = read_u32(&superblock, 20);
+        let blocks_per_group = read_u32(&superblock, 32);
+        let inodes_per_group = read_u32(&superblock, 40);
+
+        if log_block_size > 6 || blocks_per_group == 0 || inodes_per_group == 0 {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        self.block_size = 1024 << log_block_size;
+        self.inode_size = if revision >= 1 {
+            read_u16(&superblock, 88) as usize
+        } else {
+            128
+        };
+        self.inodes_per_group = inodes_per_group;
+        self.has_file_type = incompatible_features & INCOMPAT_FILETYPE != 0;
+
+        if self.inode_size < 128 {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        let corrupted = EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        };
+        let group_count = blocks_count
+            .checked_sub(first_data_block)
+            .ok_or(corrupted.clone())?
+            .div_ceil(blocks_per_group) as usize;
+
+        // Don't believe a block count that makes the descriptors bigger than the disk
+        let total_size = disk.borrow_mut().get_total_size();
+        let descriptors_size = group_count
+            .checked_mul(32)
+            .filter(|size| *size <= total_size)
+            .ok_or(corrupted)?;
+
+        // The group descriptors are in the block right after the superblock
+        let mut descriptors = vec![0; descriptors_size];
+        disk.borrow_mut().read(
+            &mut descriptors,
+            (first_data_block as usize + 1) * self.block_size,
+        )?;
+
+        self.inode_tables = descriptors
+            .chunks_exact(32)
+            .map(|descriptor| read_u32(descriptor, 8))
+            .collect();
+        self.disk = Some(disk);
+
+        if self.inode(ROOT_INODE)?.kind() != EmuRsFileKind::Folder {
+            self.disk = None;
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        return Ok(());
+    }
+
+    fn read(
+        &mut self,
+        file: &EmuRsPath,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        let inode = self.lookup(file)?;
+
+        if inode.kind() != EmuRsFileKind::File {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        return self.read_inode_data(&inode, buffer, offset);
+    }
+
+    fn list_directory(&mut self, file: &EmuRsPath) -> Result<TinyVec<[EmuRsPath; 10]>, EmuRsError> {
+        let directory = file.normalize()?;
+        let inode = self.lookup(&directory)?;
+
+        if inode.kind() != EmuRsFileKind::Folder {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        return Ok(self
+            .read_directory(&inode)?
+            .into_iter()
+            .filter(|(name, _)| {
+                return name != "." && name != "..";
+            })
+            .map(|(name, _)| directory.join_segment(&name))
+            .filter(|path| path.is_valid())
+            .collect());
+    }
+
+    fn metadata(&mut self, file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
+        let inode = self.lookup(file)?;
+
+        return Ok(EmuRsFileMetadata {
+            size: Some(inode.size),
+            modification_time: OffsetDateTime::from_unix_timestamp(inode.modification_time as i64)
+                .ok(),
+            kind: Some(inode.kind()),
+            rom: None,
+        });
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    return u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use alloc::format;
+    use alloc::string::ToString;
+    use core::str::FromStr;
+
+    /// Made by `fixtures/make.sh` with mke2fs
+    const HOST_IMAGE: &[u8] = include_bytes!("../../fixtures/ext2.img");
+
+    fn mount() -> EmuRsExt2Fs {
+        let mut fs = EmuRsExt2Fs::default();
+        fs.mount(Rc::new(RefCell::new(VecDisk(HOST_IMAGE.to_vec()))))
+            .unwrap();
+        return fs;
+    }
+
+    fn path(path: &str) -> EmuRsPath {
+        return EmuRsPath::from_str(path).unwrap();
+    }
+
+    fn read_all(fs: &mut EmuRsExt2Fs, file: &str) -> Vec<u8> {
+        let mut data = vec![0; fs.metadata(&path(file)).unwrap().size.unwrap()];
+        fs.read(&path(file), &mut data, 0).unwrap();
+        return data;
+    }
+
+    /// What `seq 1 4000` wrote into big.bin
+    fn big() -> Vec<u8> {
+        return (1..=4000)
+            .flat_map(|number| format!("{}\n", number).into_bytes())
+            .collect();
+    }
+
+    #[test]
+    fn reads_files() {
+        let mut fs = mount();
+
+        assert_eq!(read_all(&mut fs, "ROOT/hello.txt"), b"hello ext2\n");
+
+        // Bigger than the direct blocks, so the end of it is found through a indirect block
+        let expected = big();
+        assert!(expected.len() > DIRECT_BLOCKS * 1024);
+        assert_eq!(read_all(&mut fs, "ROOT/games/gba/big.bin"), expected);
+
+        let mut buffer = vec![0; 100];
+        fs.read(&path("ROOT/games/gba/big.bin"), &mut buffer, 12250)
+            .unwrap();
+        assert_eq!(buffer, expected[12250..12350]);
+
+        for offset in [expected.len() - 50, usize::MAX] {
+            assert!(matches!(
+                fs.read(&path("ROOT/games/gba/big.bin"), &mut buffer, offset),
+                Err(EmuRsError {
+                    reason: EmuRsErrorReason::EndOfFileHit
+                })
+            ));
+        }
+
+        let metadata = fs.metadata(&path("ROOT/hello.txt")).unwrap();
+        assert_eq!(metadata.kind, Some(EmuRsFileKind::File));
+        assert_eq!(
+            metadata.modification_time.unwrap().unix_timestamp(),
+            1700000000
+        );
+    }
+
+    #[test]
+    fn lists_directories() {
+        let mut fs = mount();
+        let mut listing = |directory: &str| {
+            let mut listed: Vec<String> = fs
+                .list_directory(&path(directory))
+                .unwrap()
+                .iter()
+                .map(|path| path.to_string())
+                .collect();
+            listed.sort();
+            return listed;
+        };
+
+        assert_eq!(
+            listing("ROOT"),
+            [
+                "ROOT/absolute",
+                "ROOT/games",
+                "ROOT/hello.txt",
+                "ROOT/loop",
+                "ROOT/lost+found"
+            ]
+        );
+        assert_eq!(
+            listing("ROOT/games"),
+            ["ROOT/games/gba", "ROOT/games/hello", "ROOT/games/latest"]
+        );
+        assert_eq!(listing("ROOT/games/gba"), ["ROOT/games/gba/big.bin"]);
+
+        assert!(matches!(
+            fs.list_directory(&path("ROOT/hello.txt")),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath
+            })
+        ));
+        assert!(matches!(
+            fs.list_directory(&path("ROOT/nothing")),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::FileNotFound
+            })
+        ));
+    }
+
+    #[test]
+    fn follows_symlinks() {
+        let mut fs = mount();
+
+        // Relative and in the inode
+        assert_eq!(read_all(&mut fs, "ROOT/games/latest"), big());
+        // Absolute, from the root of the filesystem
+        assert_eq!(read_all(&mut fs, "ROOT/absolute"), big());
+        // Too long for the inode, so kept in a block of its own
+        assert_eq!(read_all(&mut fs, "ROOT/games/hello"), b"hello ext2\n");
+
+        assert!(matches!(
+            fs.metadata(&path("ROOT/loop")),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_a_superblock_with_more_groups_than_blocks() {
+        let mut image = vec![0; 8192];
+        let superblock = &mut image[SUPERBLOCK_OFFSET..];
+        superblock[4..8].copy_from_slice(&10u32.to_le_bytes());
+        superblock[20..24].copy_from_slice(&20u32.to_le_bytes());
+        superblock[32..36].copy_from_slice(&8192u32.to_le_bytes());
+        superblock[40..44].copy_from_slice(&16u32.to_le_bytes());
+        superblock[56..58].copy_from_slice(&EXT2_MAGIC.to_le_bytes());
+
+        let mut fs = EmuRsExt2Fs::default();
+        let result = fs.mount(Rc::new(RefCell::new(VecDisk(image))));
+        assert!(matches!(
+            result,
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+    }
+}
@@ -0,0 +1,1539 @@
+use crate::device::EmuRsDevice;
+use crate::disk::EmuRsDiskDriver;
+use crate::driver::{EmuRsDriver, EmuRsDriverPreference};
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::vfs::{EmuRsFileKind, EmuRsFileMetadata, EmuRsFsDriver, EmuRsPath};
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+use tinyvec::TinyVec;
+
+// https://academy.cba.mit.edu/classes/networking_communications/SD/FAT.pdf
+// https://wiki.osdev.org/FAT
+
+const DIRECTORY_ENTRY_SIZE: usize = 32;
+const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+const ATTRIBUTE_HIDDEN: u8 = 0x02;
+const ATTRIBUTE_SYSTEM: u8 = 0x04;
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+const ATTRIBUTE_LONG_NAME: u8 =
+    ATTRIBUTE_READ_ONLY | ATTRIBUTE_HIDDEN | ATTRIBUTE_SYSTEM | ATTRIBUTE_VOLUME_ID;
+/// Marks a directory slot as free but not the end of the directory
+const DELETED_ENTRY: u8 = 0xe5;
+/// How many UCS-2 characters fit in one long file name entry
+const LONG_NAME_CHARACTERS: usize = 13;
+/// Where the characters are inside of a long file name entry
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARACTERS] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Used for new entries until there is a clock. This is 1980-01-01
+const DEFAULT_DATE: u16 = 0x0021;
+const FSINFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x61417272;
+const FSINFO_UNKNOWN: u32 = 0xffffffff;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmuRsFatKind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl EmuRsFatKind {
+    fn end_of_chain(&self) -> u32 {
+        return match self {
+            EmuRsFatKind::Fat12 => 0xfff,
+            EmuRsFatKind::Fat16 => 0xffff,
+            EmuRsFatKind::Fat32 => 0x0fffffff,
+        };
+    }
+
+    fn is_end_of_chain(&self, entry: u32) -> bool {
+        return entry >= self.end_of_chain() - 7;
+    }
+}
+
+/// The layout of the volume worked out from the BIOS parameter block
+#[derive(Debug, Clone)]
+pub struct EmuRsFatVolume {
+    pub kind: EmuRsFatKind,
+    pub bytes_per_sector: usize,
+    pub sectors_per_cluster: usize,
+    pub reserved_sectors: usize,
+    pub fat_count: usize,
+    pub fat_sectors: usize,
+    pub root_entry_count: usize,
+    pub cluster_count: u32,
+    /// FAT32 keeps the root directory in a cluster chain, everything else has a fixed area for it
+    pub root_cluster: u32,
+    pub fsinfo_sector: Option<usize>,
+    /// Where the copy of the boot sector and FSInfo sector are kept, FAT32 only
+    pub backup_boot_sector: Option<usize>,
+}
+
+impl EmuRsFatVolume {
+    pub fn parse(boot_sector: &[u8; 512]) -> Result<Self, EmuRsError> {
+        let invalid = EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        };
+
+        if boot_sector[510..512] != [0x55, 0xaa] {
+            return Err(invalid);
+        }
+
+        let bytes_per_sector = read_u16(boot_sector, 11) as usize;
+        let sectors_per_cluster = boot_sector[13] as usize;
+        let reserved_sectors = read_u16(boot_sector, 14) as usize;
+        let fat_count = boot_sector[16] as usize;
+        let root_entry_count = read_u16(boot_sector, 17) as usize;
+        let total_sectors = match read_u16(boot_sector, 19) {
+            0 => read_u32(boot_sector, 32) as usize,
+            sectors => sectors as usize,
+        };
+        let fat_sectors = match read_u16(boot_sector, 22) {
+            0 => read_u32(boot_sector, 36) as usize,
+            sectors => sectors as usize,
+        };
+
+        if !bytes_per_sector.is_power_of_two()
+            || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return Err(invalid);
+        }
+
+        let root_sectors = (root_entry_count * DIRECTORY_ENTRY_SIZE).div_ceil(bytes_per_sector);
+        let data_sectors = total_sectors
+            .checked_sub(reserved_sectors + fat_count * fat_sectors + root_sectors)
+            .ok_or(invalid.clone())?;
+        let cluster_count = (data_sectors / sectors_per_cluster) as u32;
+
+        // The cluster count is the only thing that decides the FAT type
+        let kind = match cluster_count {
+            0..=4084 => EmuRsFatKind::Fat12,
+            4085..=65524 => EmuRsFatKind::Fat16,
+            _ => EmuRsFatKind::Fat32,
+        };
+
+        if kind == EmuRsFatKind::Fat32 {
+            return Ok(Self {
+                kind,
+                bytes_per_sector,
+                sectors_per_cluster,
+                reserved_sectors,
+                fat_count,
+                fat_sectors,
+                root_entry_count: 0,
+                cluster_count,
+                root_cluster: read_u32(boot_sector, 44),
+                fsinfo_sector: match read_u16(boot_sector, 48) {
+                    0 | 0xffff => None,
+                    sector => Some(sector as usize),
+                },
+                backup_boot_sector: match read_u16(boot_sector, 50) {
+                    0 | 0xffff => None,
+                    sector => Some(sector as usize),
+                },
+            });
+        }
+
+        if root_entry_count == 0 {
+            return Err(invalid);
+        }
+
+        return Ok(Self {
+            kind,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            fat_sectors,
+            root_entry_count,
+            cluster_count,
+            root_cluster: 0,
+            fsinfo_sector: None,
+            backup_boot_sector: None,
+        });
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        return self.bytes_per_sector * self.sectors_per_cluster;
+    }
+
+    fn fat_offset(&self, copy: usize) -> usize {
+        return (self.reserved_sectors + copy * self.fat_sectors) * self.bytes_per_sector;
+    }
+
+    fn root_directory_offset(&self) -> usize {
+        return self.fat_offset(self.fat_count);
+    }
+
+    fn data_offset(&self) -> usize {
+        return self.root_directory_offset() + self.root_entry_count * DIRECTORY_ENTRY_SIZE;
+    }
+
+    pub fn cluster_offset(&self, cluster: u32) -> usize {
+        return self.data_offset() + (cluster as usize - 2) * self.cluster_size();
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        return (2..self.cluster_count + 2).contains(&cluster);
+    }
+}
+
+/// Where the entries of a directory are
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EmuRsFatDirectory {
+    /// The root directory of FAT12 and FAT16
+    FixedRoot,
+    Chain(u32),
+}
+
+/// A file or directory as found in its parent directory
+#[derive(Debug, Clone)]
+struct EmuRsFatEntry {
+    name: String,
+    short_name: [u8; 11],
+    attributes: u8,
+    first_cluster: u32,
+    size: usize,
+    write_time: u16,
+    write_date: u16,
+    /// Which slots of the parent directory this entry covers, long file name entries included
+    first_slot: usize,
+    short_slot: usize,
+    /// The short entry as it is on the disk, so what we don't touch is written back as it was
+    slot: [u8; DIRECTORY_ENTRY_SIZE],
+}
+
+impl EmuRsFatEntry {
+    fn kind(&self) -> EmuRsFileKind {
+        if self.attributes & ATTRIBUTE_DIRECTORY != 0 {
+            return EmuRsFileKind::Folder;
+        }
+
+        return EmuRsFileKind::File;
+    }
+
+    fn directory(&self) -> EmuRsFatDirectory {
+        return EmuRsFatDirectory::Chain(self.first_cluster);
+    }
+
+    /// The entry as it was read with the parts we change patched in. The case of the name and the
+    /// creation and access times are left the way they were
+    fn to_slot(&self) -> [u8; DIRECTORY_ENTRY_SIZE] {
+        let mut slot = self.slot;
+        slot[0..11].copy_from_slice(&self.short_name);
+        slot[11] = self.attributes;
+        slot[20..22].copy_from_slice(&((self.first_cluster >> 16) as u16).to_le_bytes());
+        slot[22..24].copy_from_slice(&self.write_time.to_le_bytes());
+        slot[24..26].copy_from_slice(&self.write_date.to_le_bytes());
+        slot[26..28].copy_from_slice(&(self.first_cluster as u16).to_le_bytes());
+        slot[28..32].copy_from_slice(&(self.size as u32).to_le_bytes());
+        return slot;
+    }
+
+    fn modification_time(&self) -> Option<OffsetDateTime> {
+        return dos_date_time(self.write_date, self.write_time);
+    }
+}
+
+/// A FAT12, FAT16 or FAT32 filesystem, with long file names
+#[derive(Default)]
+pub struct EmuRsFatFs {
+    disk: Option<Rc<RefCell<dyn EmuRsDiskDriver>>>,
+    volume: Option<EmuRsFatVolume>,
+    /// Where to start looking for free clusters
+    next_free: u32,
+}
+
+impl EmuRsFatFs {
+    fn volume(&self) -> &EmuRsFatVolume {
+        return self.volume.as_ref().unwrap();
+    }
+
+    fn disk_read(&self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        return self
+            .disk
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .read(buffer, offset);
+    }
+
+    fn disk_write(&self, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        return self
+            .disk
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .write(buffer, offset);
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, EmuRsError> {
+        let volume = self.volume();
+        let fat_offset = volume.fat_offset(0);
+
+        return match volume.kind {
+            EmuRsFatKind::Fat12 => {
+                let mut bytes = [0; 2];
+                self.disk_read(&mut bytes, fat_offset + (cluster + cluster / 2) as usize)?;
+                let entry = u16::from_le_bytes(bytes) as u32;
+
+                if cluster % 2 == 1 {
+                    Ok(entry >> 4)
+                } else {
+                    Ok(entry & 0xfff)
+                }
+            }
+            EmuRsFatKind::Fat16 => {
+                let mut bytes = [0; 2];
+                self.disk_read(&mut bytes, fat_offset + cluster as usize * 2)?;
+                Ok(u16::from_le_bytes(bytes) as u32)
+            }
+            EmuRsFatKind::Fat32 => {
+                let mut bytes = [0; 4];
+                self.disk_read(&mut bytes, fat_offset + cluster as usize * 4)?;
+                Ok(u32::from_le_bytes(bytes) & 0x0fffffff)
+            }
+        };
+    }
+
+    /// Set a entry in every copy of the FAT
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), EmuRsError> {
+        let volume = self.volume();
+
+        for copy in 0..volume.fat_count {
+            let fat_offset = volume.fat_offset(copy);
+
+            match volume.kind {
+                EmuRsFatKind::Fat12 => {
+                    // Entries share a byte so the neighbour has to be kept
+                    let offset = fat_offset + (cluster + cluster / 2) as usize;
+                    let mut bytes = [0; 2];
+                    self.disk_read(&mut bytes, offset)?;
+                    let mut entry = u16::from_le_bytes(bytes);
+
+                    if cluster % 2 == 1 {
+                        entry = (entry & 0x000f) | ((value as u16) << 4);
+                    } else {
+                        entry = (entry & 0xf000) | (value as u16 & 0x0fff);
+                    }
+
+                    self.disk_write(&entry.to_le_bytes(), offset)?;
+                }
+                EmuRsFatKind::Fat16 => {
+                    self.disk_write(
+                        &(value as u16).to_le_bytes(),
+                        fat_offset + cluster as usize * 2,
+                    )?;
+                }
+                EmuRsFatKind::Fat32 => {
+                    // The top four bits are reserved and have to be left alone
+                    let offset = fat_offset + cluster as usize * 4;
+                    let mut bytes = [0; 4];
+                    self.disk_read(&mut bytes, offset)?;
+                    let entry = (u32::from_le_bytes(bytes) & 0xf0000000) | (value & 0x0fffffff);
+                    self.disk_write(&entry.to_le_bytes(), offset)?;
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Every cluster in a chain, in order
+    fn chain(&self, first_cluster: u32) -> Result<Vec<u32>, EmuRsError> {
+        let volume = self.volume();
+        let mut clusters = Vec::new();
+        let mut cluster = first_cluster;
+
+        while volume.is_valid_cluster(cluster) {
+            // A chain longer than the disk has a loop in it
+            if clusters.len() > volume.cluster_count as usize {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem,
+                });
+            }
+
+            clusters.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+        }
+
+        if !clusters.is_empty() && !volume.kind.is_end_of_chain(cluster) {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        return Ok(clusters);
+    }
+
+    /// Find a free cluster, zero it and link it after `previous`
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> Result<u32, EmuRsError> {
+        let volume = self.volume().clone();
+        let start = if volume.is_valid_cluster(self.next_free) {
+            self.next_free
+        } else {
+            2
+        };
+        let mut cluster = start;
+
+        loop {
+            if self.fat_entry(cluster)? == 0 {
+                break;
+            }
+
+            cluster += 1;
+            if !volume.is_valid_cluster(cluster) {
+                cluster = 2;
+            }
+
+            if cluster == start {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::OutOfSpace,
+                });
+            }
+        }
+
+        self.set_fat_entry(cluster, volume.kind.end_of_chain())?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+
+        let zeros = [0; 512];
+        for offset in (0..volume.cluster_size()).step_by(zeros.len()) {
+            self.disk_write(&zeros, volume.cluster_offset(cluster) + offset)?;
+        }
+
+        self.next_free = cluster + 1;
+        self.update_fsinfo(-1)?;
+        return Ok(cluster);
+    }
+
+    fn free_chain(&mut self, first_cluster: u32) -> Result<(), EmuRsError> {
+        let clusters = self.chain(first_cluster)?;
+
+        for cluster in clusters.iter() {
+            self.set_fat_entry(*cluster, 0)?;
+        }
+
+        self.update_fsinfo(clusters.len() as i64)?;
+        return Ok(());
+    }
+
+    /// Keep the FAT32 free cluster count and hint up to date, in the backup too. A unknown count is left unknown
+    fn update_fsinfo(&mut self, free_change: i64) -> Result<(), EmuRsError> {
+        let volume = self.volume().clone();
+        let sector = match volume.fsinfo_sector {
+            Some(sector) => sector,
+            None => return Ok(()),
+        };
+        let offset = sector * volume.bytes_per_sector;
+        let mut info = [0; 512];
+        self.disk_read(&mut info, offset)?;
+
+        if read_u32(&info, 0) != FSINFO_LEAD_SIGNATURE
+            || read_u32(&info, 484) != FSINFO_STRUCT_SIGNATURE
+        {
+            return Ok(());
+        }
+
+        let free_count = read_u32(&info, 488);
+        if free_count != FSINFO_UNKNOWN {
+            let free_count =
+                (free_count as i64 + free_change).clamp(0, volume.cluster_count as i64);
+            info[488..492].copy_from_slice(&(free_count as u32).to_le_bytes());
+        }
+
+        info[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+        self.disk_write(&info[484..496], offset + 484)?;
+
+        // The backup is kept the same as the real one, as long as it looks like a FSInfo sector
+        if let Some(backup) = volume.backup_boot_sector {
+            let backup_offset = (backup + sector) * volume.bytes_per_sector;
+            let mut backup_info = [0; 512];
+            self.disk_read(&mut backup_info, backup_offset)?;
+
+            if read_u32(&backup_info, 0) == FSINFO_LEAD_SIGNATURE
+                && read_u32(&backup_info, 484) == FSINFO_STRUCT_SIGNATURE
+            {
+                self.disk_write(&info[484..496], backup_offset + 484)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Cut a chain back to its first `keep` clusters, freeing the rest
+    fn truncate_chain(&mut self, first_cluster: u32, keep: usize) -> Result<(), EmuRsError> {
+        if keep == 0 {
+            return self.free_chain(first_cluster);
+        }
+
+        let clusters = self.chain(first_cluster)?;
+        if clusters.len() <= keep {
+            return Ok(());
+        }
+
+        self.set_fat_entry(clusters[keep - 1], self.volume().kind.end_of_chain())?;
+        for cluster in clusters[keep..].iter() {
+            self.set_fat_entry(*cluster, 0)?;
+        }
+
+        return self.update_fsinfo((clusters.len() - keep) as i64);
+    }
+
+    /// The disk offset of every slot in a directory
+    fn directory_slots(&self, directory: EmuRsFatDirectory) -> Result<Vec<usize>, EmuRsError> {
+        let volume = self.volume();
+
+        return match directory {
+            EmuRsFatDirectory::FixedRoot => Ok((0..volume.root_entry_count)
+                .map(|slot| volume.root_directory_offset() + slot * DIRECTORY_ENTRY_SIZE)
+                .collect()),
+            EmuRsFatDirectory::Chain(first_cluster) => Ok(self
+                .chain(first_cluster)?
+                .into_iter()
+                .flat_map(|cluster| {
+                    let start = volume.cluster_offset(cluster);
+
+                    return (0..volume.cluster_size())
+                        .step_by(DIRECTORY_ENTRY_SIZE)
+                        .map(move |offset| start + offset);
+                })
+                .collect()),
+        };
+    }
+
+    /// Read every entry of a directory, putting long file names back together
+    fn read_directory(
+        &self,
+        directory: EmuRsFatDirectory,
+    ) -> Result<Vec<EmuRsFatEntry>, EmuRsError> {
+        let mut entries = Vec::new();
+        let mut long_name: Vec<u16> = Vec::new();
+        let mut long_name_start = 0;
+        let mut long_name_checksum = None;
+
+        for (index, offset) in self.directory_slots(directory)?.into_iter().enumerate() {
+            let mut slot = [0; DIRECTORY_ENTRY_SIZE];
+            self.disk_read(&mut slot, offset)?;
+
+            match slot[0] {
+                0 => break,
+                DELETED_ENTRY => {
+                    long_name.clear();
+                    continue;
+                }
+                _ => {}
+            }
+
+            if slot[11] & 0x3f == ATTRIBUTE_LONG_NAME {
+                // Long names are stored backwards with the last part first
+                if slot[0] & 0x40 != 0 {
+                    long_name.clear();
+                    long_name_start = index;
+                    long_name_checksum = Some(slot[13]);
+                }
+
+                let characters = LONG_NAME_OFFSETS
+                    .iter()
+                    .map(|offset| read_u16(&slot, *offset))
+                    .take_while(|character| *character != 0 && *character != 0xffff);
+                let mut part: Vec<u16> = characters.collect();
+                part.extend_from_slice(&long_name);
+                long_name = part;
+                continue;
+            }
+
+            let mut short_name = [0; 11];
+            short_name.copy_from_slice(&slot[0..11]);
+
+            if slot[11] & ATTRIBUTE_VOLUME_ID != 0 || short_name[0] == b'.' {
+                long_name.clear();
+                continue;
+            }
+
+            let has_long_name = !long_name.is_empty()
+                && long_name_checksum == Some(short_name_checksum(&short_name));
+
+            entries.push(EmuRsFatEntry {
+                name: if has_long_name {
+                    String::from_utf16_lossy(&long_name)
+                } else {
+                    display_short_name(&short_name, slot[12])
+                },
+                short_name,
+                attributes: slot[11],
+                first_cluster: ((read_u16(&slot, 20) as u32) << 16) | read_u16(&slot, 26) as u32,
+                size: read_u32(&slot, 28) as usize,
+                write_time: read_u16(&slot, 22),
+                write_date: read_u16(&slot, 24),
+                first_slot: if has_long_name {
+                    long_name_start
+                } else {
+                    index
+                },
+                short_slot: index,
+                slot,
+            });
+            long_name.clear();
+        }
+
+        return Ok(entries);
+    }
+
+    fn find_entry(
+        &self,
+        directory: EmuRsFatDirectory,
+        name: &str,
+    ) -> Result<EmuRsFatEntry, EmuRsError> {
+        return self
+            .read_directory(directory)?
+            .into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name))
+            .ok_or(EmuRsError {
+                reason: EmuRsErrorReason::FileNotFound,
+            });
+    }
+
+    fn root_directory(&self) -> EmuRsFatDirectory {
+        let volume = self.volume();
+
+        if volume.kind == EmuRsFatKind::Fat32 {
+            return EmuRsFatDirectory::Chain(volume.root_cluster);
+        }
+
+        return EmuRsFatDirectory::FixedRoot;
+    }
+
+    /// Walk down to a path, returning the directory it is in and its entry. The root directory has no entry
+    fn lookup(
+        &self,
+        file: &EmuRsPath,
+    ) -> Result<(EmuRsFatDirectory, Option<EmuRsFatEntry>), EmuRsError> {
+        let path = file.normalize()?;
+        let mut directory = self.root_directory();
+        let mut entry: Option<EmuRsFatEntry> = None;
+
+        for segment in path.segments.iter().skip(1) {
+            if let Some(entry) = entry.clone() {
+                if entry.kind() != EmuRsFileKind::Folder {
+                    return Err(EmuRsError {
+                        reason: EmuRsErrorReason::FileNotFound,
+                    });
+                }
+
+                directory = entry.directory();
+            }
+
+            entry = Some(self.find_entry(directory, segment)?);
+        }
+
+        return Ok((directory, entry));
+    }
+
+    /// The directory a folder path points at
+    fn lookup_directory(&self, file: &EmuRsPath) -> Result<EmuRsFatDirectory, EmuRsError> {
+        return match self.lookup(file)? {
+            (directory, None) => Ok(directory),
+            (_, Some(entry)) if entry.kind() == EmuRsFileKind::Folder => Ok(entry.directory()),
+            _ => Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            }),
+        };
+    }
+
+    fn write_entry(
+        &self,
+        directory: EmuRsFatDirectory,
+        entry: &EmuRsFatEntry,
+    ) -> Result<(), EmuRsError> {
+        let offset = self.directory_slots(directory)?[entry.short_slot];
+        return self.disk_write(&entry.to_slot(), offset);
+    }
+
+    /// Find `count` free slots in a row, growing the directory if it is a chain
+    fn find_free_slots(
+        &mut self,
+        directory: EmuRsFatDirectory,
+        count: usize,
+    ) -> Result<Vec<usize>, EmuRsError> {
+        loop {
+            let slots = self.directory_slots(directory)?;
+            let mut run_start = 0;
+            let mut run_length = 0;
+
+            for (index, offset) in slots.iter().enumerate() {
+                let mut first_byte = [0];
+                self.disk_read(&mut first_byte, *offset)?;
+
+                if first_byte[0] == 0 || first_byte[0] == DELETED_ENTRY {
+                    if run_length == 0 {
+                        run_start = index;
+                    }
+                    run_length += 1;
+
+                    if run_length == count {
+                        return Ok(slots[run_start..run_start + count].to_vec());
+                    }
+                } else {
+                    run_length = 0;
+                }
+            }
+
+            match directory {
+                EmuRsFatDirectory::FixedRoot => {
+                    return Err(EmuRsError {
+                        reason: EmuRsErrorReason::OutOfSpace,
+                    });
+                }
+                EmuRsFatDirectory::Chain(first_cluster) => {
+                    // Even the root directory has a cluster, so a empty chain is broken
+                    let last = self
+                        .chain(first_cluster)?
+                        .last()
+                        .copied()
+                        .ok_or(EmuRsError {
+                            reason: EmuRsErrorReason::CorruptedFilesystem,
+                        })?;
+                    self.allocate_cluster(Some(last))?;
+                }
+            }
+        }
+    }
+
+    /// Add a entry to a directory with a long file name if the name needs one
+    fn create_entry(
+        &mut self,
+        file: &EmuRsPath,
+        attributes: u8,
+        first_cluster: u32,
+    ) -> Result<EmuRsFatEntry, EmuRsError> {
+        let path = file.normalize()?;
+        let name = path.file_name();
+        let directory = self.lookup_directory(&path.parent().ok_or(EmuRsError {
+            reason: EmuRsErrorReason::InvalidPath,
+        })?)?;
+        let existing = self.read_directory(directory)?;
+
+        if name.is_empty()
+            || name.encode_utf16().count() > 255
+            || name.contains(['\\', ':', '*', '?', '"', '<', '>', '|'])
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        if existing
+            .iter()
+            .any(|entry| entry.name.eq_ignore_ascii_case(&name))
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::FileAlreadyExists,
+            });
+        }
+
+        let (short_name, needs_long_name) = generate_short_name(&name, |candidate| {
+            return existing.iter().any(|entry| entry.short_name == *candidate);
+        })?;
+        let long_name: Vec<u16> = name.encode_utf16().collect();
+        let long_entries = if needs_long_name {
+            long_name.len().div_ceil(LONG_NAME_CHARACTERS)
+        } else {
+            0
+        };
+        let slots = self.find_free_slots(directory, long_entries + 1)?;
+        let checksum = short_name_checksum(&short_name);
+
+        for (index, offset) in slots[..long_entries].iter().enumerate() {
+            let order = long_entries - index;
+            let mut slot = [0; DIRECTORY_ENTRY_SIZE];
+            slot[0] = order as u8 | if index == 0 { 0x40 } else { 0 };
+            slot[11] = ATTRIBUTE_LONG_NAME;
+            slot[13] = checksum;
+
+            for (character_index, character_offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                let position = (order - 1) * LONG_NAME_CHARACTERS + character_index;
+                let character = match position.cmp(&long_name.len()) {
+                    core::cmp::Ordering::Less => long_name[position],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                };
+
+                slot[*character_offset..*character_offset + 2]
+                    .copy_from_slice(&character.to_le_bytes());
+            }
+
+            self.disk_write(&slot, *offset)?;
+        }
+
+        let all_slots = self.directory_slots(directory)?;
+        let short_offset = slots[long_entries];
+
+        // Created and last accessed now, which is all the same until there is a clock
+        let mut slot = [0; DIRECTORY_ENTRY_SIZE];
+        slot[16..18].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+        slot[18..20].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+
+        let entry = EmuRsFatEntry {
+            name,
+            short_name,
+            attributes,
+            first_cluster,
+            size: 0,
+            // FIXME: There is no clock yet
+            write_time: 0,
+            write_date: DEFAULT_DATE,
+            first_slot: all_slots
+                .iter()
+                .position(|offset| *offset == slots[0])
+                .unwrap(),
+            short_slot: all_slots
+                .iter()
+                .position(|offset| *offset == short_offset)
+                .unwrap(),
+            slot,
+        };
+
+        self.disk_write(&entry.to_slot(), short_offset)?;
+        return Ok(entry);
+    }
+
+    /// Read or write part of a file, allocating clusters for writes that go past the end of its chain
+    fn transfer(
+        &mut self,
+        entry: &mut EmuRsFatEntry,
+        mut buffer: Transfer,
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        let cluster_size = self.volume().cluster_size();
+        let length = buffer.len();
+        let mut clusters = if entry.first_cluster == 0 {
+            Vec::new()
+        } else {
+            self.chain(entry.first_cluster)?
+        };
+        let needed_clusters = (offset + length).div_ceil(cluster_size);
+
+        // Only writes get to grow the chain
+        if clusters.len() < needed_clusters && matches!(buffer, Transfer::Read(_)) {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        while clusters.len() < needed_clusters {
+            let cluster = self.allocate_cluster(clusters.last().copied())?;
+
+            if clusters.is_empty() {
+                entry.first_cluster = cluster;
+            }
+
+            clusters.push(cluster);
+        }
+
+        let mut done = 0;
+        while done < length {
+            let position = offset + done;
+            let cluster = clusters[position / cluster_size];
+            let within = position % cluster_size;
+            let chunk = (cluster_size - within).min(length - done);
+            let disk_offset = self.volume().cluster_offset(cluster) + within;
+
+            match &mut buffer {
+                Transfer::Read(buffer) => {
+                    self.disk_read(&mut buffer[done..done + chunk], disk_offset)?
+                }
+                Transfer::Write(buffer) => {
+                    self.disk_write(&buffer[done..done + chunk], disk_offset)?
+                }
+            }
+
+            done += chunk;
+        }
+
+        return Ok(());
+    }
+
+    /// Write to a file, filling the gap between the old end and the write with zeros
+    fn write_data(
+        &mut self,
+        entry: &mut EmuRsFatEntry,
+        buffer: &[u8],
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        if offset > entry.size {
+            let old_size = entry.size;
+            let gap = offset - old_size;
+            let zeros = [0; 512];
+            let mut written = 0;
+
+            while written < gap {
+                let chunk = (gap - written).min(zeros.len());
+                self.transfer(entry, Transfer::Write(&zeros[..chunk]), old_size + written)?;
+                written += chunk;
+            }
+        }
+
+        return self.transfer(entry, Transfer::Write(buffer), offset);
+    }
+}
+
+enum Transfer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl<'a> Transfer<'a> {
+    fn len(&self) -> usize {
+        return match self {
+            Transfer::Read(buffer) => buffer.len(),
+            Transfer::Write(buffer) => buffer.len(),
+        };
+    }
+}
+
+impl EmuRsDriver for EmuRsFatFs {
+    fn name(&self) -> &'static str {
+        return "FAT Filesystem";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsFsDriver for EmuRsFatFs {
+    fn mount(&mut self, disk: Rc<RefCell<dyn EmuRsDiskDriver>>) -> Result<(), EmuRsError> {
+        let mut boot_sector = [0; 512];
+        disk.borrow_mut().read(&mut boot_sector, 0)?;
+        let volume = EmuRsFatVolume::parse(&boot_sector)?;
+
+        if volume.data_offset() + volume.cluster_count as usize * volume.cluster_size()
+            > disk.borrow_mut().get_total_size()
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        self.volume = Some(volume);
+        self.disk = Some(disk);
+        self.next_free = 2;
+
+        // Pick up where the last allocation left off if the FSInfo sector knows
+        if let Some(sector) = self.volume().fsinfo_sector {
+            let mut info = [0; 512];
+            self.disk_read(&mut info, sector * self.volume().bytes_per_sector)?;
+
+            if read_u32(&info, 0) == FSINFO_LEAD_SIGNATURE {
+                self.next_free = read_u32(&info, 492);
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn read(
+        &mut self,
+        file: &EmuRsPath,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        let mut entry = match self.lookup(file)? {
+            (_, Some(entry)) if entry.kind() == EmuRsFileKind::File => entry,
+            _ => {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::InvalidPath,
+                })
+            }
+        };
+
+        if offset
+            .checked_add(buffer.len())
+            .is_none_or(|end| end > entry.size)
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfFileHit,
+            });
+        }
+
+        return self.transfer(&mut entry, Transfer::Read(buffer), offset);
+    }
+
+    fn write(&mut self, file: &EmuRsPath, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        let (directory, mut entry) = match self.lookup(file)? {
+            (directory, Some(entry)) if entry.kind() == EmuRsFileKind::File => (directory, entry),
+            _ => {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::InvalidPath,
+                })
+            }
+        };
+
+        if entry.attributes & ATTRIBUTE_READ_ONLY != 0 {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported,
+            });
+        }
+
+        let end = offset.checked_add(buffer.len()).ok_or(EmuRsError {
+            reason: EmuRsErrorReason::OutOfSpace,
+        })?;
+        let old_clusters = match entry.first_cluster {
+            0 => 0,
+            first_cluster => self.chain(first_cluster)?.len(),
+        };
+
+        // The entry on the disk still has the old size, so give back anything a failed write took
+        if let Err(error) = self.write_data(&mut entry, buffer, offset) {
+            if entry.first_cluster != 0 {
+                self.truncate_chain(entry.first_cluster, old_clusters)?;
+            }
+
+            return Err(error);
+        }
+
+        entry.size = entry.size.max(end);
+        entry.attributes |= ATTRIBUTE_ARCHIVE;
+        return self.write_entry(directory, &entry);
+    }
+
+    fn create(&mut self, file: &EmuRsPath) -> Result<(), EmuRsError> {
+        self.create_entry(file, ATTRIBUTE_ARCHIVE, 0)?;
+        return Ok(());
+    }
+
+    fn create_directory(&mut self, file: &EmuRsPath) -> Result<(), EmuRsError> {
+        let path = file.normalize()?;
+        let parent = match self.lookup_directory(&path.parent().ok_or(EmuRsError {
+            reason: EmuRsErrorReason::InvalidPath,
+        })?)? {
+            EmuRsFatDirectory::FixedRoot => 0,
+            EmuRsFatDirectory::Chain(cluster)
+                if self.root_directory() == EmuRsFatDirectory::Chain(cluster) =>
+            {
+                0
+            }
+            EmuRsFatDirectory::Chain(cluster) => cluster,
+        };
+
+        // Make sure the name is free before taking a cluster for it
+        if self.lookup(&path).is_ok() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::FileAlreadyExists,
+            });
+        }
+
+        let cluster = self.allocate_cluster(None)?;
+        let entry = match self.create_entry(&path, ATTRIBUTE_DIRECTORY, cluster) {
+            Ok(entry) => entry,
+            Err(error) => {
+                self.free_chain(cluster)?;
+                return Err(error);
+            }
+        };
+
+        let mut dot = entry.clone();
+        dot.short_name = *b".          ";
+        let mut dot_dot = entry.clone();
+        dot_dot.short_name = *b"..         ";
+        dot_dot.first_cluster = parent;
+
+        let offset = self.volume().cluster_offset(cluster);
+        self.disk_write(&dot.to_slot(), offset)?;
+        return self.disk_write(&dot_dot.to_slot(), offset + DIRECTORY_ENTRY_SIZE);
+    }
+
+    fn delete(&mut self, file: &EmuRsPath) -> Result<(), EmuRsError> {
+        let (directory, entry) = match self.lookup(file)? {
+            (directory, Some(entry)) => (directory, entry),
+            (_, None) => {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::InvalidPath,
+                })
+            }
+        };
+
+        if entry.kind() == EmuRsFileKind::Folder
+            && !self.read_directory(entry.directory())?.is_empty()
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::DirectoryNotEmpty,
+            });
+        }
+
+        let slots = self.directory_slots(directory)?;
+        for offset in slots[entry.first_slot..=entry.short_slot].iter() {
+            self.disk_write(&[DELETED_ENTRY], *offset)?;
+        }
+
+        if entry.first_cluster != 0 {
+            self.free_chain(entry.first_cluster)?;
+        }
+
+        return Ok(());
+    }
+
+    fn list_directory(&mut self, file: &EmuRsPath) -> Result<TinyVec<[EmuRsPath; 10]>, EmuRsError> {
+        let path = file.normalize()?;
+        let directory = self.lookup_directory(&path)?;
+
+        return Ok(self
+            .read_directory(directory)?
+            .into_iter()
+            .map(|entry| path.join_segment(&entry.name))
+            .collect());
+    }
+
+    fn metadata(&mut self, file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
+        return Ok(match self.lookup(file)? {
+            (_, None) => EmuRsFileMetadata {
+                size: Some(0),
+                modification_time: None,
+                kind: Some(EmuRsFileKind::Folder),
+                rom: None,
+            },
+            (_, Some(entry)) => EmuRsFileMetadata {
+                size: Some(entry.size),
+                modification_time: entry.modification_time(),
+                kind: Some(entry.kind()),
+                rom: None,
+            },
+        });
+    }
+}
+
+/// Decode the packed date and time MS-DOS used, which ZIP archives use too
+pub(crate) fn dos_date_time(date: u16, time: u16) -> Option<OffsetDateTime> {
+    let date = Date::from_calendar_date(
+        1980 + (date >> 9) as i32,
+        Month::try_from(((date >> 5) & 0xf) as u8).ok()?,
+        (date & 0x1f) as u8,
+    )
+    .ok()?;
+    let time = Time::from_hms(
+        (time >> 11) as u8,
+        ((time >> 5) & 0x3f) as u8,
+        ((time & 0x1f) * 2) as u8,
+    )
+    .ok()?;
+
+    // DOS has no idea of time zones
+    return Some(PrimitiveDateTime::new(date, time).assume_utc());
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    return u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    return short_name.iter().fold(0_u8, |sum, byte| {
+        return sum.rotate_right(1).wrapping_add(*byte);
+    });
+}
+
+/// Turn `NAME    EXT` into `NAME.EXT`, honouring the lowercase flags Windows NT sets
+fn display_short_name(short_name: &[u8; 11], case_flags: u8) -> String {
+    let convert = |bytes: &[u8], lowercase: bool| {
+        let mut part: String = String::from_utf8_lossy(bytes).trim_end().into();
+
+        if lowercase {
+            part = part.to_lowercase();
+        }
+
+        return part;
+    };
+
+    // 0x05 stands in for a real 0xe5 as the first byte
+    let mut base = *short_name;
+    if base[0] == 0x05 {
+        base[0] = DELETED_ENTRY;
+    }
+
+    let name: String = convert(&base[0..8], case_flags & 0x08 != 0);
+    let extension: String = convert(&base[8..11], case_flags & 0x10 != 0);
+
+    if extension.is_empty() {
+        return name;
+    }
+
+    return format!("{}.{}", name, extension);
+}
+
+/// Make a 8.3 name for a long name, returning it and if a long file name entry is needed as well
+///
+/// `is_taken` is asked about every candidate so a unique numeric tail like `~1` can be picked
+fn generate_short_name(
+    name: &str,
+    is_taken: impl Fn(&[u8; 11]) -> bool,
+) -> Result<([u8; 11], bool), EmuRsError> {
+    let (base, extension) = match name.rfind('.') {
+        Some(index) if index != 0 => (&name[..index], &name[index + 1..]),
+        _ => (name, ""),
+    };
+    let mut lossy = false;
+    let mut clean = |part: &str, length: usize| {
+        let mut cleaned = Vec::new();
+
+        for character in part.chars() {
+            match character {
+                ' ' | '.' => lossy = true,
+                'a'..='z' => {
+                    lossy = true;
+                    cleaned.push(character.to_ascii_uppercase() as u8);
+                }
+                'A'..='Z'
+                | '0'..='9'
+                | '$'
+                | '%'
+                | '\''
+                | '-'
+                | '_'
+                | '@'
+                | '~'
+                | '`'
+                | '!'
+                | '('
+                | ')'
+                | '{'
+                | '}'
+                | '^'
+                | '#'
+                | '&' => cleaned.push(character as u8),
+                _ => {
+                    lossy = true;
+                    cleaned.push(b'_');
+                }
+            }
+        }
+
+        if cleaned.len() > length {
+            lossy = true;
+            cleaned.truncate(length);
+        }
+
+        return cleaned;
+    };
+    let base = clean(base, 8);
+    let extension = clean(extension, 3);
+
+    let mut short_name = [b' '; 11];
+    short_name[8..8 + extension.len()].copy_from_slice(&extension);
+
+    if !lossy && !base.is_empty() {
+        short_name[0..base.len()].copy_from_slice(&base);
+
+        if !is_taken(&short_name) {
+            return Ok((short_name, false));
+        }
+    }
+
+    for number in 1..1000000 {
+        let tail = format!("~{}", number);
+        let kept = base.len().min(8 - tail.len());
+
+        short_name[0..8].fill(b' ');
+        short_name[0..kept].copy_from_slice(&base[..kept]);
+        short_name[kept..kept + tail.len()].copy_from_slice(tail.as_bytes());
+
+        if !is_taken(&short_name) {
+            return Ok((short_name, true));
+        }
+    }
+
+    return Err(EmuRsError {
+        reason: EmuRsErrorReason::OutOfSpace,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use alloc::vec;
+
+    /// Lay out a empty volume the same way mkfs.fat does for a disk of `sectors` sectors
+    fn mkfs(kind: EmuRsFatKind, sectors: usize, sectors_per_cluster: usize) -> Vec<u8> {
+        let (reserved, root_entries, entry_bits) = match kind {
+            EmuRsFatKind::Fat12 => (1, 224, 12),
+            EmuRsFatKind::Fat16 => (4, 512, 16),
+            EmuRsFatKind::Fat32 => (32, 0, 32),
+        };
+        let root_sectors = root_entries * DIRECTORY_ENTRY_SIZE / 512;
+
+        // The FAT has to cover the clusters that are left once it is taken out
+        let mut fat_sectors = 1;
+        loop {
+            let clusters =
+                (sectors - reserved - 2 * fat_sectors - root_sectors) / sectors_per_cluster;
+            let needed = ((clusters + 2) * entry_bits).div_ceil(8 * 512);
+            if needed <= fat_sectors {
+                break;
+            }
+            fat_sectors = needed;
+        }
+
+        let mut image = vec![0; sectors * 512];
+        let boot = &mut image[..512];
+        boot[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        boot[3..11].copy_from_slice(b"mkfs.fat");
+        boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+        boot[13] = sectors_per_cluster as u8;
+        boot[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+        boot[16] = 2;
+        boot[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+        boot[21] = 0xf8;
+        boot[24..26].copy_from_slice(&32u16.to_le_bytes());
+        boot[26..28].copy_from_slice(&64u16.to_le_bytes());
+
+        if sectors < 0x10000 && kind != EmuRsFatKind::Fat32 {
+            boot[19..21].copy_from_slice(&(sectors as u16).to_le_bytes());
+        } else {
+            boot[32..36].copy_from_slice(&(sectors as u32).to_le_bytes());
+        }
+
+        if kind == EmuRsFatKind::Fat32 {
+            boot[36..40].copy_from_slice(&(fat_sectors as u32).to_le_bytes());
+            boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+            boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+            boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+            boot[64] = 0x80;
+            boot[66] = 0x29;
+            boot[71..82].copy_from_slice(b"NO NAME    ");
+            boot[82..90].copy_from_slice(b"FAT32   ");
+        } else {
+            boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+            boot[36] = 0x80;
+            boot[38] = 0x29;
+            boot[43..54].copy_from_slice(b"NO NAME    ");
+            boot[54..62].copy_from_slice(if kind == EmuRsFatKind::Fat12 {
+                b"FAT12   "
+            } else {
+                b"FAT16   "
+            });
+        }
+        boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        if kind == EmuRsFatKind::Fat32 {
+            let clusters = (sectors - reserved - 2 * fat_sectors) / sectors_per_cluster;
+            let info = &mut image[512..1024];
+            info[0..4].copy_from_slice(&FSINFO_LEAD_SIGNATURE.to_le_bytes());
+            info[484..488].copy_from_slice(&FSINFO_STRUCT_SIGNATURE.to_le_bytes());
+            info[488..492].copy_from_slice(&(clusters as u32 - 1).to_le_bytes());
+            info[492..496].copy_from_slice(&2u32.to_le_bytes());
+            info[508..512].copy_from_slice(&[0, 0, 0x55, 0xaa]);
+            image.copy_within(0..1024, 6 * 512);
+        }
+
+        // The media byte and a end of chain go in the first two entries, and the FAT32 root
+        // directory gets the first cluster
+        let first_entries: &[u8] = match kind {
+            EmuRsFatKind::Fat12 => &[0xf8, 0xff, 0xff],
+            EmuRsFatKind::Fat16 => &[0xf8, 0xff, 0xff, 0xff],
+            EmuRsFatKind::Fat32 => &[
+                0xf8, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f, 0xf8, 0xff, 0xff, 0x0f,
+            ],
+        };
+        for copy in 0..2 {
+            let offset = (reserved + copy * fat_sectors) * 512;
+            image[offset..offset + first_entries.len()].copy_from_slice(first_entries);
+        }
+
+        return image;
+    }
+
+    fn mount(image: Vec<u8>) -> (Rc<RefCell<VecDisk>>, EmuRsFatFs) {
+        let disk = Rc::new(RefCell::new(VecDisk(image)));
+        let mut fs = EmuRsFatFs::default();
+        fs.mount(disk.clone()).unwrap();
+        return (disk, fs);
+    }
+
+    fn path(path: &str) -> EmuRsPath {
+        return path.parse().unwrap();
+    }
+
+    fn names(fs: &mut EmuRsFatFs, directory: &str) -> Vec<String> {
+        return fs
+            .list_directory(&path(directory))
+            .unwrap()
+            .iter()
+            .map(EmuRsPath::file_name)
+            .collect();
+    }
+
+    #[test]
+    fn reads_and_writes_every_kind() {
+        let images = [
+            // A 1.44M floppy
+            (EmuRsFatKind::Fat12, mkfs(EmuRsFatKind::Fat12, 2880, 1)),
+            (EmuRsFatKind::Fat16, mkfs(EmuRsFatKind::Fat16, 32768, 4)),
+            (EmuRsFatKind::Fat32, mkfs(EmuRsFatKind::Fat32, 81920, 1)),
+        ];
+
+        for (kind, image) in images {
+            let (disk, mut fs) = mount(image);
+            assert_eq!(fs.volume().kind, kind);
+
+            let data: Vec<u8> = (0..3000).map(|index| (index * 7) as u8).collect();
+            fs.create_directory(&path("/Saves")).unwrap();
+            fs.create(&path("/Saves/Pokemon - Emerald Version.sav"))
+                .unwrap();
+            fs.write(&path("/Saves/Pokemon - Emerald Version.sav"), &data, 100)
+                .unwrap();
+            fs.create(&path("/GAME.SAV")).unwrap();
+            fs.write(&path("/GAME.SAV"), b"hello", 0).unwrap();
+
+            // Everything has to come back from the disk alone
+            let mut fs = EmuRsFatFs::default();
+            fs.mount(disk.clone()).unwrap();
+
+            assert_eq!(names(&mut fs, "/"), ["Saves", "GAME.SAV"]);
+            assert_eq!(names(&mut fs, "/Saves"), ["Pokemon - Emerald Version.sav"]);
+
+            let file = path("/Saves/Pokemon - Emerald Version.sav");
+            assert_eq!(fs.metadata(&file).unwrap().size, Some(3100));
+            let mut buffer = vec![0xff; 3100];
+            fs.read(&file, &mut buffer, 0).unwrap();
+            assert!(buffer[..100].iter().all(|byte| *byte == 0));
+            assert_eq!(buffer[100..], data);
+
+            let first_cluster = fs.lookup(&file).unwrap().1.unwrap().first_cluster;
+            fs.delete(&file).unwrap();
+            assert!(fs.metadata(&file).is_err());
+            assert_eq!(fs.fat_entry(first_cluster).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn writes_keep_the_rest_of_the_entry() {
+        let (disk, mut fs) = mount(mkfs(EmuRsFatKind::Fat16, 32768, 4));
+        let file = path("/GAME.SAV");
+        fs.create(&file).unwrap();
+
+        // Lowercase name, a creation time and a access date, like another OS would leave them
+        let short_slot = fs.lookup(&file).unwrap().1.unwrap().short_slot;
+        let offset = fs.directory_slots(EmuRsFatDirectory::FixedRoot).unwrap()[short_slot];
+        let created = [0x18, 0x64, 0x2f, 0x8b, 0x57, 0x59, 0x59, 0x59];
+        disk.borrow_mut().0[offset + 12..offset + 20].copy_from_slice(&created);
+
+        fs.write(&file, &[1; 600], 0).unwrap();
+        fs.write(&file, &[2; 10], 590).unwrap();
+
+        assert_eq!(disk.borrow().0[offset + 12..offset + 20], created);
+        assert_eq!(names(&mut fs, "/"), ["game.sav"]);
+        assert_eq!(fs.metadata(&file).unwrap().size, Some(600));
+    }
+
+    fn free_clusters(fs: &EmuRsFatFs) -> usize {
+        return (2..fs.volume().cluster_count + 2)
+            .filter(|cluster| fs.fat_entry(*cluster).unwrap() == 0)
+            .count();
+    }
+
+    #[test]
+    fn failed_writes_give_their_clusters_back() {
+        for kind in [EmuRsFatKind::Fat12, EmuRsFatKind::Fat32] {
+            let image = match kind {
+                EmuRsFatKind::Fat32 => mkfs(kind, 70000, 1),
+                _ => mkfs(kind, 400, 1),
+            };
+            let (_, mut fs) = mount(image);
+            let free = free_clusters(&fs);
+            let too_much = vec![1; free * fs.volume().cluster_size() + 1];
+
+            // A new file gets its whole chain back
+            let empty = path("/EMPTY.SAV");
+            fs.create(&empty).unwrap();
+            let before = free_clusters(&fs);
+            assert!(matches!(
+                fs.write(&empty, &too_much, 0),
+                Err(EmuRsError {
+                    reason: EmuRsErrorReason::OutOfSpace
+                })
+            ));
+            assert_eq!(free_clusters(&fs), before);
+            assert_eq!(fs.lookup(&empty).unwrap().1.unwrap().first_cluster, 0);
+
+            // One that already had data keeps it and only loses what the write added
+            let file = path("/GAME.SAV");
+            fs.create(&file).unwrap();
+            fs.write(&file, &[7; 1000], 0).unwrap();
+            let before = free_clusters(&fs);
+            assert!(fs.write(&file, &too_much, 500).is_err());
+            assert!(fs.write(&file, &[1; 10], usize::MAX).is_err());
+            assert_eq!(free_clusters(&fs), before);
+
+            assert_eq!(fs.metadata(&file).unwrap().size, Some(1000));
+            let mut buffer = vec![0; 1000];
+            fs.read(&file, &mut buffer, 0).unwrap();
+            assert!(buffer.iter().all(|byte| *byte == 7));
+
+            // Which is all still usable afterwards
+            fs.write(&file, &[8; 2000], 1000).unwrap();
+            assert_eq!(fs.metadata(&file).unwrap().size, Some(3000));
+        }
+    }
+
+    #[test]
+    fn keeps_the_backup_fsinfo_in_step() {
+        let (disk, mut fs) = mount(mkfs(EmuRsFatKind::Fat32, 70000, 1));
+        let free = |sector: usize| read_u32(&disk.borrow().0, sector * 512 + 488);
+        let initial = free(1);
+        assert_eq!(free(7), initial);
+
+        let file = path("/GAME.SAV");
+        fs.create(&file).unwrap();
+        fs.write(&file, &[1; 3000], 0).unwrap();
+
+        // Six clusters for the file, none for the root directory which still has room
+        assert_eq!(free(1), initial - 6);
+        assert_eq!(
+            disk.borrow().0[512 + 484..512 + 496],
+            disk.borrow().0[7 * 512 + 484..7 * 512 + 496]
+        );
+
+        fs.delete(&file).unwrap();
+        assert_eq!(free(1), initial);
+        assert_eq!(free(7), initial);
+    }
+
+    #[test]
+    fn directories_without_clusters_are_corrupted() {
+        let (disk, mut fs) = mount(mkfs(EmuRsFatKind::Fat16, 32768, 4));
+        fs.create_directory(&path("/Saves")).unwrap();
+
+        // Point the directory at nothing, which used to panic when it needed to grow
+        let short_slot = fs.lookup(&path("/Saves")).unwrap().1.unwrap().short_slot;
+        let offset = fs.directory_slots(EmuRsFatDirectory::FixedRoot).unwrap()[short_slot];
+        disk.borrow_mut().0[offset + 26..offset + 28].fill(0);
+
+        assert!(matches!(
+            fs.create(&path("/Saves/GAME.SAV")),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+    }
+}
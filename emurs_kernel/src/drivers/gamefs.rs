@@ -1,7 +1,12 @@
+use crate::compression::EmuRsReadSource;
 use crate::driver::EmuRsDriverPreference;
 use crate::error::EmuRsErrorReason;
+use crate::media::{is_playlist, EmuRsPlaylist};
+use crate::patch::{apply_patch_file, find_patch, is_patch, patched_path};
+use crate::romdb::{EmuRsRomDatabase, EmuRsRomRecord};
+use crate::romheader::EmuRsRomInfo;
+use crate::vfs::EmuRsFileKind;
 use crate::vfs::EmuRsFileMetadata;
-use crate::vfs::{EmuRsFileKind};
 use crate::EmuRsContext;
 use crate::{device::EmuRsDevice, error::EmuRsError};
 use crate::{
@@ -9,78 +14,558 @@ use crate::{
     vfs::{EmuRsFsDriver, EmuRsPath},
 };
 
-use alloc::collections::{BTreeMap};
+use alloc::collections::{BTreeMap, BTreeSet};
 
 use alloc::rc::Rc;
-use alloc::string::String;
+use alloc::string::{String, ToString};
+use alloc::vec;
 use alloc::vec::Vec;
 use blake2::Blake2s256;
 use blake2::Digest;
+use md5::Md5;
+use sha1::Sha1;
 
 use core::fmt::Write;
 use core::str::FromStr;
+use serde::{Deserialize, Serialize};
 use tinyvec::TinyVec;
 
-// I bet this will inflate the heap quickly
 // Mounted rom database
 
-#[derive(Default)]
+/// How much of a file is read at once while hashing it
+const HASH_CHUNK_SIZE: usize = 4096;
+
+/// Everything worked out about a file while scanning it
+///
+/// BLAKE2s is what names the file, the rest are what DAT files use to identify ROMs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct EmuRsFileDigests {
+    pub size: usize,
+    pub blake2s: [u8; 32],
+    pub crc32: u32,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+}
+
+/// How we tell if a file changed without reading it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct EmuRsGameFsStamp {
+    size: usize,
+    /// In nanoseconds since the unix epoch
+    modification_time: Option<i128>,
+}
+
+/// Where the patched version of a ROM comes from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct EmuRsGameFsPatch {
+    rom: String,
+    patch: String,
+    stamp: EmuRsGameFsStamp,
+    /// Patches for some other version of the ROM are remembered so they aren't tried every time
+    applies: bool,
+}
+
+/// What we knew about a file the last time it was hashed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EmuRsGameFsIndexEntry {
+    stamp: EmuRsGameFsStamp,
+    /// Set for the patched version of a ROM, which is what the digests and info are of
+    patch: Option<EmuRsGameFsPatch>,
+    digests: EmuRsFileDigests,
+    /// What the header says, if it is a ROM we can recognize
+    info: Option<EmuRsRomInfo>,
+}
+
+#[derive(Default, Clone)]
 pub struct EmuRsGameFs {
     pub search_paths: Vec<EmuRsPath>,
+    /// Where to keep the hash index between boots. It can't be inside of this filesystem
+    pub index_path: Option<EmuRsPath>,
+    /// The roms.db known ROMs are named from, usually `ROOT/roms.db`
+    pub database_path: Option<EmuRsPath>,
     pub os_context: Option<Rc<EmuRsContext>>,
+    /// Hashes of every file in the search paths, keyed by the path of the file
+    index: BTreeMap<String, EmuRsGameFsIndexEntry>,
+    index_loaded: bool,
+    /// Every file in the search paths and roms.db as of the last scan, so looking things up
+    /// doesn't build everything again when nothing changed
+    seen: Option<Vec<(EmuRsPath, EmuRsGameFsStamp)>>,
+    /// The other way around, so hash named files can be found
+    hashtable: BTreeMap<[u8; 32], EmuRsPath>,
+    /// What roms.db says about the files it knows
+    known: BTreeMap<[u8; 32], EmuRsRomRecord>,
+    /// Files that roms.db knows or that have a header we recognize go in a folder for their
+    /// system under a readable name instead of their hash
+    names: BTreeMap<EmuRsPath, [u8; 32]>,
+    /// The last patched ROM read, since patching means reading the whole ROM
+    patched: Option<(EmuRsPath, Vec<u8>)>,
+    /// Playlists in the search paths, keyed by where they show up in this filesystem along with
+    /// where the real one is
+    playlists: BTreeMap<EmuRsPath, (EmuRsPath, EmuRsPlaylist)>,
+    /// Images that are a disc of some playlist, which only show up as part of it
+    discs: BTreeSet<EmuRsPath>,
 }
 
 impl EmuRsGameFs {
-    /// Hash the files in the search directories and return our findings
-    fn get_hashtable(&self) -> BTreeMap<[u8; 32], EmuRsPath> {
-        let mut files = BTreeMap::new();
-        for path in self.search_paths.iter() {
+    fn context(&self) -> &EmuRsContext {
+        return self.os_context.as_ref().unwrap();
+    }
+
+    /// Hash any files in the search directories that are new or changed since we last looked
+    ///
+    /// Everything gets stat'd every time, but the rest is only done again if some size or
+    /// modification time is different
+    fn refresh(&mut self) -> Result<(), EmuRsError> {
+        let mut listings = Vec::new();
+        let mut seen = Vec::new();
+
+        for directory in self.search_paths.iter() {
             let directory_contents = self
-                .os_context
-                .as_ref()
-                .unwrap()
+                .context()
                 .fs
-                .borrow_mut()
-                .list_directory(&path)
+                .borrow()
+                .global()
+                .list_directory(directory)?;
+
+            seen.extend(directory_contents.iter().filter_map(|file| {
+                return self.stamp(file).map(|stamp| (file.clone(), stamp));
+            }));
+            listings.push(directory_contents);
+        }
+
+        // Names come from roms.db, so a new one means naming everything again
+        if let Some(database_path) = self.database_path.as_ref() {
+            if let Some(stamp) = self.stamp(database_path) {
+                seen.push((database_path.clone(), stamp));
+            }
+        }
+
+        if self.seen.as_ref() == Some(&seen) {
+            return Ok(());
+        }
+
+        if !self.index_loaded {
+            self.load_index();
+            self.index_loaded = true;
+        }
+
+        let stamps: BTreeMap<_, _> = seen.iter().cloned().collect();
+        let mut index = BTreeMap::new();
+        let mut playlists = Vec::new();
+        let mut changed = false;
+
+        for directory_contents in listings.iter() {
+            for file in directory_contents.iter() {
+                // Patches get applied to their ROM instead of showing up by themselves
+                if is_patch(file) {
+                    continue;
+                }
+
+                // Playlists are tiny and name their discs by path, so there is nothing to hash
+                if is_playlist(file) {
+                    playlists.push(file.clone());
+                    continue;
+                }
+
+                // Folders and anything we can't stat are skipped
+                let stamp = match stamps.get(file) {
+                    Some(stamp) => *stamp,
+                    None => continue,
+                };
+                let key = file.to_string();
+
+                // Files that look the same as last time are assumed to be the same
+                let entry = match self.index.get(&key) {
+                    Some(entry) if entry.stamp == stamp && entry.patch.is_none() => entry.clone(),
+                    _ => {
+                        changed = true;
+                        let fs = self.context().fs.borrow();
+                        let fs = fs.global();
+                        let (digests, info) = scan(stamp.size, &mut |buffer, offset| {
+                            return fs.read(file, buffer, offset);
+                        })?;
+
+                        EmuRsGameFsIndexEntry {
+                            stamp,
+                            patch: None,
+                            digests,
+                            info,
+                        }
+                    }
+                };
+
+                index.insert(key.clone(), entry);
+
+                // A ROM with a patch next to it shows up a second time, patched
+                let patch_path = match find_patch(directory_contents, file) {
+                    Some(patch_path) => patch_path,
+                    None => continue,
+                };
+
+                let patch = EmuRsGameFsPatch {
+                    rom: key,
+                    patch: patch_path.to_string(),
+                    stamp: match stamps.get(&patch_path) {
+                        Some(patch_stamp) => *patch_stamp,
+                        None => continue,
+                    },
+                    applies: true,
+                };
+                let patched_key = patched_path(file).to_string();
+
+                let entry = match self.index.get(&patched_key) {
+                    Some(entry)
+                        if entry.stamp == stamp
+                            && entry.patch.as_ref().is_some_and(|old| {
+                                return old.patch == patch.patch && old.stamp == patch.stamp;
+                            }) =>
+                    {
+                        entry.clone()
+                    }
+                    _ => {
+                        changed = true;
+                        self.scan_patched(stamp, patch)?
+                    }
+                };
+
+                index.insert(patched_key, entry);
+            }
+        }
+
+        changed |= index.len() != self.index.len();
+        self.index = index;
+        self.hashtable = self
+            .index
+            .iter()
+            .filter(|(_, entry)| entry.patch.as_ref().is_none_or(|patch| patch.applies))
+            .filter_map(|(path, entry)| {
+                return EmuRsPath::from_str(path)
+                    .ok()
+                    .map(|path| (entry.digests.blake2s, path));
+            })
+            .collect();
+
+        if changed {
+            self.save_index()?;
+        }
+
+        self.name_files()?;
+        self.place_playlists(playlists);
+        self.patched = None;
+        self.seen = Some(seen);
+        return Ok(());
+    }
+
+    /// Give every playlist a entry of its own, in the folder of the system its first disc is for
+    fn place_playlists(&mut self, playlists: Vec<EmuRsPath>) {
+        self.playlists.clear();
+        self.discs.clear();
+
+        for real_path in playlists {
+            // A playlist we can't read just doesn't show up, like a patch that doesn't apply
+            let playlist =
+                match EmuRsPlaylist::open(&self.context().fs.borrow().global(), &real_path) {
+                    Ok(playlist) => playlist,
+                    Err(_) => continue,
+                };
+
+            let system = self
+                .index
+                .get(&playlist.entries[0].path.to_string())
+                .and_then(|entry| {
+                    return match self.known.get(&entry.digests.blake2s) {
+                        Some(record) if !record.system.is_empty() => {
+                            Some(sanitize_name(&record.system))
+                        }
+                        _ => entry.info.as_ref().map(|info| String::from(info.system())),
+                    };
+                });
+
+            let directory = match system {
+                Some(system) => EmuRsPath::default().join_segment(&system),
+                None => EmuRsPath::default(),
+            };
+            let path = directory.join_segment(&real_path.file_name());
+
+            // The same playlist name in two search paths, the first one found wins
+            if self.playlists.contains_key(&path) || self.names.contains_key(&path) {
+                continue;
+            }
+
+            self.discs
+                .extend(playlist.entries.iter().map(|entry| entry.path.clone()));
+            self.playlists.insert(path, (real_path, playlist));
+        }
+    }
+
+    /// Whether the file with this hash is a disc of some playlist
+    fn is_disc(&self, hash: &[u8; 32]) -> bool {
+        return self
+            .hashtable
+            .get(hash)
+            .is_some_and(|path| self.discs.contains(path));
+    }
+
+    /// The playlist at a path in this filesystem, with every disc in it as a absolute path
+    pub fn playlist(&mut self, file: &EmuRsPath) -> Result<EmuRsPlaylist, EmuRsError> {
+        let path = file.normalize()?;
+        self.refresh()?;
+
+        return self
+            .playlists
+            .get(&path)
+            .map(|(_, playlist)| playlist.clone())
+            .ok_or(EmuRsError {
+                reason: EmuRsErrorReason::FileNotFound,
+            });
+    }
+
+    /// Give everything roms.db knows or that has a header we recognize a readable name
+    fn name_files(&mut self) -> Result<(), EmuRsError> {
+        self.known.clear();
+        self.names.clear();
+
+        // No database just means only the headers are there to go off of
+        let database = self.database_path.as_ref().and_then(|database_path| {
+            return EmuRsRomDatabase::open(self.os_context.clone().unwrap(), database_path.clone())
+                .ok();
+        });
+
+        for (hash, real_path) in self.hashtable.iter() {
+            let record = match database.as_ref() {
+                Some(database) => database.lookup(hash)?,
+                None => None,
+            };
+            let info = self
+                .index
+                .get(&real_path.to_string())
+                .and_then(|entry| entry.info.as_ref());
+
+            let (system, mut name) = match (&record, info) {
+                (Some(record), _) => {
+                    let system = if record.system.is_empty() {
+                        "unknown".into()
+                    } else {
+                        sanitize_name(&record.system)
+                    };
+
+                    let mut name = sanitize_name(&record.title);
+                    for detail in [&record.region, &record.revision] {
+                        if !detail.is_empty() {
+                            write!(name, " ({})", sanitize_name(detail)).unwrap();
+                        }
+                    }
+
+                    (system, name)
+                }
+                (None, Some(info)) => (
+                    info.system().into(),
+                    sanitize_name(info.title().unwrap_or_default()),
+                ),
+                (None, None) => continue,
+            };
+
+            // Keep the extension so whatever opens the file can still guess what it is
+            let extension = real_path
+                .file_name()
+                .rsplit_once('.')
+                .map(|(_, extension)| String::from(".") + extension)
+                .unwrap_or_default();
+
+            let directory = EmuRsPath::default().join_segment(&system);
+            let mut path = directory.join_segment(&(name.clone() + &extension));
+
+            // Two different dumps of the same game, or nothing to call it
+            if self.names.contains_key(&path) || name.is_empty() {
+                write!(
+                    name,
+                    " [{:02x}{:02x}{:02x}{:02x}]",
+                    hash[0], hash[1], hash[2], hash[3]
+                )
                 .unwrap();
+                path = directory.join_segment(&(String::from(name.trim_start()) + &extension));
+            }
 
-            files.extend(
-                directory_contents
-                    .iter()
-                    .filter(|filename| {
-                        return self
-                            .os_context
-                            .as_ref()
-                            .unwrap()
-                            .fs
-                            .borrow()
-                            .metadata(&filename)
-                            .unwrap()
-                            .kind
-                            .unwrap()
-                            == EmuRsFileKind::File;
-                    })
-                    .map(|file| {
-                        let file_len = self
-                            .os_context
-                            .as_ref()
-                            .unwrap()
-                            .fs
-                            .borrow()
-                            .metadata(&file)
-                            .unwrap()
-                            .size
-                            .unwrap();
-
-                        // FIXME: REALLY GOOD WAY TO FILL RAM
-                        let buffer = Vec::with_capacity(file_len);
-                        let mut hasher = Blake2s256::new();
-                        hasher.update(&buffer);
-                        return (hasher.finalize()[..].try_into().unwrap(), file.clone());
-                    }),
-            );
-        }
-        return files;
+            self.names.insert(path, *hash);
+            if let Some(record) = record {
+                self.known.insert(*hash, record);
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// What roms.db says about a file, if it knows it
+    pub fn record(&self, hash: &[u8; 32]) -> Option<&EmuRsRomRecord> {
+        return self.known.get(hash);
+    }
+
+    /// Whether this is one of the folders known files are sorted into
+    fn is_system_directory(&self, directory: &EmuRsPath) -> bool {
+        return self
+            .listed()
+            .any(|path| path.parent().as_ref() == Some(directory));
+    }
+
+    /// Everything that shows up under a readable name, which is named files that aren't a disc
+    /// of a playlist and the playlists themselves
+    fn listed(&self) -> impl Iterator<Item = &EmuRsPath> {
+        return self
+            .names
+            .iter()
+            .filter(|(_, hash)| !self.is_disc(hash))
+            .map(|(path, _)| path)
+            .chain(self.playlists.keys());
+    }
+
+    /// Where the file with this hash is, if it is in the search paths
+    ///
+    /// Patched ROMs only exist inside of this filesystem, so they are never found here
+    pub fn find(&mut self, hash: &[u8; 32]) -> Result<Option<EmuRsPath>, EmuRsError> {
+        self.refresh()?;
+
+        return Ok(self.hashtable.get(hash).cloned().filter(|path| {
+            return self
+                .index
+                .get(&path.to_string())
+                .is_some_and(|entry| entry.patch.is_none());
+        }));
+    }
+
+    /// The digests of every file in the search paths, for matching against DAT files
+    pub fn digests(&mut self) -> Result<Vec<EmuRsFileDigests>, EmuRsError> {
+        self.refresh()?;
+
+        return Ok(self
+            .index
+            .values()
+            .filter(|entry| entry.patch.as_ref().is_none_or(|patch| patch.applies))
+            .map(|entry| entry.digests)
+            .collect());
+    }
+
+    /// The size and modification time of a file, or nothing if it isn't a file or we can't tell
+    fn stamp(&self, file: &EmuRsPath) -> Option<EmuRsGameFsStamp> {
+        let metadata = self.context().fs.borrow().global().metadata(file).ok()?;
+
+        if metadata.kind != Some(EmuRsFileKind::File) {
+            return None;
+        }
+
+        return Some(EmuRsGameFsStamp {
+            size: metadata.size.unwrap_or(0),
+            modification_time: metadata
+                .modification_time
+                .map(|time| time.unix_timestamp_nanos()),
+        });
+    }
+
+    fn scan_patched(
+        &self,
+        stamp: EmuRsGameFsStamp,
+        mut patch: EmuRsGameFsPatch,
+    ) -> Result<EmuRsGameFsIndexEntry, EmuRsError> {
+        let patched = apply_patch_file(
+            self.context(),
+            &EmuRsPath::from_str(&patch.rom)?,
+            &EmuRsPath::from_str(&patch.patch)?,
+        );
+
+        // A broken patch or one for some other version of the ROM just doesn't show up
+        let (digests, info) = match patched {
+            Ok(data) => scan(data.len(), &mut |buffer, offset| {
+                buffer.copy_from_slice(&data[offset..offset + buffer.len()]);
+                return Ok(());
+            })?,
+            Err(_) => {
+                patch.applies = false;
+                (EmuRsFileDigests::default(), None)
+            }
+        };
+
+        return Ok(EmuRsGameFsIndexEntry {
+            stamp,
+            patch: Some(patch),
+            digests,
+            info,
+        });
+    }
+
+    /// A missing or unreadable index just means everything gets hashed again, which is also what
+    /// happens to a index from before a digest was added
+    fn load_index(&mut self) {
+        let index_path = match self.index_path.as_ref() {
+            Some(index_path) => index_path,
+            None => return,
+        };
+
+        let context = self.os_context.clone().unwrap();
+        let fs = context.fs.borrow();
+        let fs = fs.global();
+        let size = match fs.metadata(index_path).map(|metadata| metadata.size) {
+            Ok(Some(size)) => size,
+            _ => return,
+        };
+
+        let mut encoded = vec![0; size];
+        if fs.read(index_path, &mut encoded, 0).is_err() {
+            return;
+        }
+
+        if let Ok(index) = ciborium::de::from_reader(encoded.as_slice()) {
+            self.index = index;
+        }
+    }
+
+    fn save_index(&self) -> Result<(), EmuRsError> {
+        let index_path = match self.index_path.as_ref() {
+            Some(index_path) => index_path,
+            None => return Ok(()),
+        };
+
+        let mut encoded = Vec::new();
+        ciborium::ser::into_writer(&self.index, &mut encoded).map_err(|_| EmuRsError {
+            reason: EmuRsErrorReason::Unknown,
+        })?;
+
+        // Start from a empty file so a smaller index doesn't leave the end of the old one behind
+        let fs = self.context().fs.borrow();
+        let fs = fs.global();
+        let _ = fs.delete(index_path);
+        fs.create(index_path)?;
+        return fs.write(index_path, &encoded, 0);
+    }
+
+    /// The playlist at a path in this filesystem along with where the real one is
+    fn playlist_at(
+        &mut self,
+        file: &EmuRsPath,
+    ) -> Result<Option<(EmuRsPath, EmuRsPlaylist)>, EmuRsError> {
+        let path = file.normalize()?;
+        self.refresh()?;
+
+        return Ok(self.playlists.get(&path).cloned());
+    }
+
+    /// Find the real file behind a hash named one
+    fn real_path(&mut self, file: &EmuRsPath) -> Result<EmuRsPath, EmuRsError> {
+        let path = file.normalize()?;
+        self.refresh()?;
+
+        if let Some(hash) = self.names.get(&path) {
+            return Ok(self.hashtable[hash].clone());
+        }
+
+        let hash = match path.segments.len() {
+            2 => parse_hash(&path.segments[1]),
+            _ => None,
+        };
+
+        return hash
+            .and_then(|hash| self.hashtable.get(&hash).cloned())
+            .ok_or(EmuRsError {
+                reason: EmuRsErrorReason::FileNotFound,
+            });
     }
 }
 
@@ -90,11 +575,14 @@ impl EmuRsDriver for EmuRsGameFs {
     }
 
     fn get_preference(&mut self) -> EmuRsDriverPreference {
-        todo!()
+        return EmuRsDriverPreference::Preferred;
     }
 
     fn get_claimed(&mut self) -> EmuRsDevice {
-        todo!()
+        // Everything comes out of the VFS, never hardware
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
     }
 
     fn init(&mut self, context: Rc<EmuRsContext>) {
@@ -105,32 +593,346 @@ impl EmuRsDriver for EmuRsGameFs {
 impl EmuRsFsDriver for EmuRsGameFs {
     fn read(
         &mut self,
-        _file: &EmuRsPath,
-        _buffer: &mut [u8],
-        _offset: usize,
+        file: &EmuRsPath,
+        buffer: &mut [u8],
+        offset: usize,
     ) -> Result<(), EmuRsError> {
-        return Err(EmuRsError {
-            reason: EmuRsErrorReason::OperationNotSupported,
-        });
+        // Playlists are written back out with absolute paths, so the discs can still be found
+        // from in here
+        if let Some((_, playlist)) = self.playlist_at(file)? {
+            let text = playlist.to_m3u();
+            let bytes = text
+                .as_bytes()
+                .get(offset..offset + buffer.len())
+                .ok_or(EmuRsError {
+                    reason: EmuRsErrorReason::EndOfFileHit,
+                })?;
+            buffer.copy_from_slice(bytes);
+
+            return Ok(());
+        }
+
+        let real_path = self.real_path(file)?;
+        let patch = self
+            .index
+            .get(&real_path.to_string())
+            .and_then(|entry| entry.patch.clone());
+
+        let patch = match patch {
+            Some(patch) => patch,
+            None => {
+                return self
+                    .context()
+                    .fs
+                    .borrow()
+                    .global()
+                    .read(&real_path, buffer, offset)
+            }
+        };
+
+        if self
+            .patched
+            .as_ref()
+            .is_none_or(|(patched, _)| *patched != real_path)
+        {
+            let data = apply_patch_file(
+                self.context(),
+                &EmuRsPath::from_str(&patch.rom)?,
+                &EmuRsPath::from_str(&patch.patch)?,
+            )?;
+            self.patched = Some((real_path, data));
+        }
+
+        let (_, data) = self.patched.as_ref().unwrap();
+        let bytes = data.get(offset..offset + buffer.len()).ok_or(EmuRsError {
+            reason: EmuRsErrorReason::EndOfFileHit,
+        })?;
+        buffer.copy_from_slice(bytes);
+
+        return Ok(());
     }
 
-    fn list_directory(&mut self, _file: &EmuRsPath) -> Result<TinyVec<[EmuRsPath; 10]>, EmuRsError> {
-        return Ok(self
-            .get_hashtable()
-            .into_keys()
-            .map(|key| {
-                let mut string = String::new();
-                for byte in key {
-                    write!(string, "{:x}", byte);
-                }
-                return EmuRsPath::from_str(&string).unwrap();
-            })
-            .collect());
+    fn list_directory(&mut self, file: &EmuRsPath) -> Result<TinyVec<[EmuRsPath; 10]>, EmuRsError> {
+        let directory = file.normalize()?;
+        self.refresh()?;
+
+        if !directory.is_root() {
+            if !self.is_system_directory(&directory) {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::FileNotFound,
+                });
+            }
+
+            return Ok(self
+                .listed()
+                .filter(|path| {
+                    return path.parent().as_ref() == Some(&directory);
+                })
+                .cloned()
+                .collect());
+        }
+
+        // Playlists without a system are the only names that go straight in the root
+        let mut listing: TinyVec<[EmuRsPath; 10]> = self
+            .listed()
+            .filter_map(|path| path.parent())
+            .filter(|parent| !parent.is_root())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .chain(
+                self.playlists
+                    .keys()
+                    .filter(|path| {
+                        return path.parent().as_ref() == Some(&directory);
+                    })
+                    .cloned(),
+            )
+            .collect();
+
+        let named: BTreeSet<_> = self.names.values().collect();
+        listing.extend(
+            self.hashtable
+                .keys()
+                .filter(|key| !named.contains(key) && !self.is_disc(key))
+                .map(|key| {
+                    let mut string = String::new();
+                    for byte in key {
+                        write!(string, "{:02x}", byte).unwrap();
+                    }
+                    return directory.join_segment(&string);
+                }),
+        );
+
+        return Ok(listing);
     }
 
-    fn metadata(&mut self, _file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
-        return Err(EmuRsError {
-            reason: EmuRsErrorReason::OperationNotSupported,
+    fn metadata(&mut self, file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
+        let path = file.normalize()?;
+        self.refresh()?;
+
+        if path.is_root() || self.is_system_directory(&path) {
+            return Ok(EmuRsFileMetadata {
+                size: None,
+                modification_time: None,
+                kind: Some(EmuRsFileKind::Folder),
+                rom: None,
+            });
+        }
+
+        if let Some((real_path, playlist)) = self.playlist_at(file)? {
+            let metadata = self.context().fs.borrow().global().metadata(&real_path)?;
+
+            // The first disc stands for the whole game
+            let rom = self
+                .index
+                .get(&playlist.entries[0].path.to_string())
+                .and_then(|entry| entry.info.clone());
+
+            return Ok(EmuRsFileMetadata {
+                size: Some(playlist.to_m3u().len()),
+                rom,
+                ..metadata
+            });
+        }
+
+        let real_path = self.real_path(file)?;
+        let entry = self.index.get(&real_path.to_string()).cloned();
+
+        // Patched ROMs take everything but their size from the ROM they come from
+        let (metadata_path, size) = match entry.as_ref().and_then(|entry| entry.patch.as_ref()) {
+            Some(patch) => (
+                EmuRsPath::from_str(&patch.rom)?,
+                entry.as_ref().map(|entry| entry.digests.size),
+            ),
+            None => (real_path, None),
+        };
+        let metadata = self
+            .context()
+            .fs
+            .borrow()
+            .global()
+            .metadata(&metadata_path)?;
+
+        return Ok(EmuRsFileMetadata {
+            size: size.or(metadata.size),
+            rom: entry.and_then(|entry| entry.info),
+            ..metadata
         });
     }
 }
+
+/// Names from roms.db can have anything in them, but not every character can be in a path
+fn sanitize_name(name: &str) -> String {
+    return name
+        .chars()
+        .map(|character| match character {
+            '/' | '\\' => '_',
+            character => character,
+        })
+        .collect();
+}
+
+/// Turn a hash named file back into the hash
+fn parse_hash(name: &str) -> Option<[u8; 32]> {
+    if name.len() != 64 {
+        return None;
+    }
+
+    let mut hash = [0; 32];
+    for (index, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(name.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+
+    return Some(hash);
+}
+
+/// Work out the digests and header of a file, reading it through `read` a chunk at a time so big
+/// ROMs don't need to fit in RAM
+fn scan(
+    size: usize,
+    read: &mut EmuRsReadSource,
+) -> Result<(EmuRsFileDigests, Option<EmuRsRomInfo>), EmuRsError> {
+    let mut blake2s = Blake2s256::new();
+    let mut crc32 = crc32fast::Hasher::new();
+    let mut md5 = Md5::new();
+    let mut sha1 = Sha1::new();
+    let mut buffer = vec![0; HASH_CHUNK_SIZE.min(size)];
+    let mut offset = 0;
+
+    while offset < size {
+        let length = (size - offset).min(HASH_CHUNK_SIZE);
+        read(&mut buffer[..length], offset)?;
+        blake2s.update(&buffer[..length]);
+        crc32.update(&buffer[..length]);
+        md5.update(&buffer[..length]);
+        sha1.update(&buffer[..length]);
+        offset += length;
+    }
+
+    let digests = EmuRsFileDigests {
+        size,
+        blake2s: blake2s.finalize().into(),
+        crc32: crc32.finalize(),
+        md5: md5.finalize().into(),
+        sha1: sha1.finalize().into(),
+    };
+
+    return Ok((digests, EmuRsRomInfo::identify(size, read)?));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use crate::drivers::cborfs::EmuRsCborFs;
+    use crate::vfs::EmuRsNamespace;
+    use crate::EmuRsContextBuilder;
+    use core::cell::RefCell;
+
+    /// A filesystem where everything fails, for things in a search path that can't be stat'd
+    struct BrokenFs;
+
+    impl EmuRsDriver for BrokenFs {
+        fn name(&self) -> &'static str {
+            return "Broken Filesystem";
+        }
+
+        fn get_preference(&mut self) -> EmuRsDriverPreference {
+            return EmuRsDriverPreference::Preferred;
+        }
+
+        fn get_claimed(&mut self) -> EmuRsDevice {
+            return EmuRsDevice {
+                memory: TinyVec::new(),
+            };
+        }
+    }
+
+    impl EmuRsFsDriver for BrokenFs {}
+
+    #[test]
+    fn rescans_when_the_search_paths_change() {
+        let context = EmuRsContextBuilder::default().done();
+        let disk = Rc::new(RefCell::new(VecDisk(vec![0; 8192])));
+        EmuRsCborFs::format(&mut *disk.borrow_mut()).unwrap();
+        let mut roms = EmuRsCborFs::default();
+        roms.mount(disk).unwrap();
+
+        let roms_path = EmuRsPath::from_str("ROOT/roms").unwrap();
+        context
+            .fs
+            .borrow_mut()
+            .mount(&roms_path, Rc::new(RefCell::new(roms)))
+            .unwrap();
+
+        let write_rom = |name: &str, data: &[u8]| {
+            let fs = context.fs.borrow();
+            let fs = fs.global();
+            let path = roms_path.join_segment(name);
+            let _ = fs.create(&path);
+            fs.write(&path, data, 0).unwrap();
+        };
+        write_rom("a.bin", &[1; 100]);
+
+        let mut game_fs = EmuRsGameFs {
+            search_paths: vec![roms_path.clone()],
+            ..Default::default()
+        };
+        game_fs.init(context.clone());
+
+        // A program running in its own namespace doesn't change where the search paths are
+        let previous = context
+            .fs
+            .borrow_mut()
+            .swap_namespace(EmuRsNamespace::profile_saves("player").unwrap());
+
+        let root = EmuRsPath::default();
+        assert_eq!(game_fs.list_directory(&root).unwrap().len(), 1);
+
+        write_rom("b.bin", &[2; 100]);
+        assert_eq!(game_fs.list_directory(&root).unwrap().len(), 2);
+
+        // Growing a file changes its stamp, so it gets hashed again
+        let before = game_fs.list_directory(&root).unwrap();
+        write_rom("b.bin", &[3; 200]);
+        let after = game_fs.list_directory(&root).unwrap();
+        assert_eq!(after.len(), 2);
+        assert_ne!(before.as_slice(), after.as_slice());
+
+        context.fs.borrow_mut().swap_namespace(previous);
+    }
+
+    #[test]
+    fn skips_files_it_cant_stat() {
+        let context = EmuRsContextBuilder::default().done();
+        let disk = Rc::new(RefCell::new(VecDisk(vec![0; 8192])));
+        EmuRsCborFs::format(&mut *disk.borrow_mut()).unwrap();
+        let mut roms = EmuRsCborFs::default();
+        roms.mount(disk).unwrap();
+
+        let roms_path = EmuRsPath::from_str("ROOT/roms").unwrap();
+        let mut fs = context.fs.borrow_mut();
+        fs.mount(&roms_path, Rc::new(RefCell::new(roms))).unwrap();
+        fs.mount(
+            &roms_path.join_segment("broken"),
+            Rc::new(RefCell::new(BrokenFs)),
+        )
+        .unwrap();
+
+        let rom = roms_path.join_segment("a.bin");
+        fs.global().create(&rom).unwrap();
+        fs.global().write(&rom, &[1; 100], 0).unwrap();
+        drop(fs);
+
+        let mut game_fs = EmuRsGameFs {
+            search_paths: vec![roms_path.clone()],
+            ..Default::default()
+        };
+        game_fs.init(context.clone());
+
+        assert_eq!(
+            game_fs.list_directory(&EmuRsPath::default()).unwrap().len(),
+            1
+        );
+    }
+}
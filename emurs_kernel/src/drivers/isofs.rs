@@ -0,0 +1,1009 @@
+use crate::device::EmuRsDevice;
+use crate::disk::EmuRsDiskDriver;
+use crate::driver::EmuRsDriverPreference;
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::vfs::{EmuRsFileKind, EmuRsFileMetadata};
+use crate::{
+    driver::EmuRsDriver,
+    vfs::{EmuRsFsDriver, EmuRsPath},
+};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+use tinyvec::TinyVec;
+
+// https://wiki.osdev.org/ISO_9660
+// https://problemkaputt.de/psxspx-cdrom-iso-volume-descriptors.htm
+// Rock Ridge is described in IEEE P1281 (SUSP) and P1282 (RRIP)
+
+/// Volume descriptors always use 2048 byte sectors, whatever the volume's block size is
+const SECTOR_SIZE: usize = 2048;
+/// The first 16 sectors are left for the system to boot from
+const FIRST_VOLUME_DESCRIPTOR: usize = 16;
+
+const VOLUME_DESCRIPTOR_PRIMARY: u8 = 1;
+const VOLUME_DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const VOLUME_DESCRIPTOR_TERMINATOR: u8 = 255;
+
+const FLAG_DIRECTORY: u8 = 1 << 1;
+const FLAG_ASSOCIATED: u8 = 1 << 2;
+const FLAG_MULTI_EXTENT: u8 = 1 << 7;
+
+/// Continuation areas can point at each other, so give up after following this many
+const MAX_CONTINUATION_AREAS: usize = 16;
+
+/// A SUSP entry's signature and data
+type EmuRsSystemUseEntry = ([u8; 2], Vec<u8>);
+
+/// Where a file lives on the disc
+#[derive(Debug, Clone)]
+struct EmuRsIsoEntry {
+    extent: u32,
+    size: usize,
+    kind: EmuRsFileKind,
+    modification_time: Option<OffsetDateTime>,
+}
+
+/// Which set of names the disc is read with, best first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmuRsIsoNaming {
+    RockRidge {
+        /// How many bytes of every system use area to skip, from the `SP` entry
+        skip: usize,
+    },
+    Joliet,
+    Plain,
+}
+
+/// A ISO 9660 filesystem, as found on CDs and DVDs. Joliet and Rock Ridge names are used when the disc has them
+///
+/// Directories are found through the path table, so a disc with directory records pointing in circles can't trip us up
+#[derive(Default)]
+pub struct EmuRsIsoFs {
+    disk: Option<Rc<RefCell<dyn EmuRsDiskDriver>>>,
+    index: BTreeMap<EmuRsPath, EmuRsIsoEntry>,
+    block_size: usize,
+}
+
+impl EmuRsIsoFs {
+    fn build_index(&mut self, disk: &mut dyn EmuRsDiskDriver) -> Result<(), EmuRsError> {
+        let mut primary = None;
+        let mut joliet = None;
+
+        for sector in FIRST_VOLUME_DESCRIPTOR.. {
+            let mut descriptor = vec![0; SECTOR_SIZE];
+            disk.read(&mut descriptor, sector * SECTOR_SIZE)?;
+
+            if &descriptor[1..6] != b"CD001" {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem,
+                });
+            }
+
+            match descriptor[0] {
+                VOLUME_DESCRIPTOR_PRIMARY if primary.is_none() => primary = Some(descriptor),
+                // Joliet is a supplementary descriptor that says it uses UCS-2
+                VOLUME_DESCRIPTOR_SUPPLEMENTARY
+                    if joliet.is_none()
+                        && matches!(&descriptor[88..91], b"%/@" | b"%/C" | b"%/E") =>
+                {
+                    joliet = Some(descriptor)
+                }
+                VOLUME_DESCRIPTOR_TERMINATOR => break,
+                _ => {}
+            }
+        }
+
+        let primary = primary.ok_or(EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        })?;
+        self.block_size = read_u16(&primary, 128) as usize;
+
+        if self.block_size == 0 {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        // Rock Ridge discs start the root directory's first record with a `SP` entry
+        let mut naming = match joliet {
+            Some(_) => EmuRsIsoNaming::Joliet,
+            None => EmuRsIsoNaming::Plain,
+        };
+        let root = &primary[156..190];
+        let mut first_record = vec![0; self.block_size];
+        disk.read(&mut first_record, self.block_offset(read_u32(root, 2)))?;
+
+        if let Some(system_use) = system_use_area(&first_record) {
+            if system_use.len() >= 7
+                && &system_use[0..2] == b"SP"
+                && system_use[4..6] == [0xbe, 0xef]
+            {
+                naming = EmuRsIsoNaming::RockRidge {
+                    skip: system_use[6] as usize,
+                };
+            }
+        }
+
+        let descriptor = match (naming, &joliet) {
+            (EmuRsIsoNaming::Joliet, Some(joliet)) => joliet,
+            _ => &primary,
+        };
+
+        let directories = self.read_path_table(disk, descriptor)?;
+        let known_directories: BTreeSet<u32> = directories.iter().copied().collect();
+        let mut children = BTreeMap::new();
+
+        for extent in directories.iter() {
+            children.insert(*extent, self.read_directory(disk, *extent, naming)?);
+        }
+
+        self.index.clear();
+        self.index.insert(
+            EmuRsPath::default(),
+            EmuRsIsoEntry {
+                extent: directories[0],
+                size: 0,
+                kind: EmuRsFileKind::Folder,
+                modification_time: None,
+            },
+        );
+
+        // Put the tree together from the root. Every directory gets a path at most once
+        let mut visited = BTreeSet::new();
+        let mut pending = vec![(EmuRsPath::default(), directories[0])];
+        visited.insert(directories[0]);
+
+        while let Some((path, extent)) = pending.pop() {
+            for (name, entry) in children.remove(&extent).unwrap_or_default() {
+                let child = path.join_segment(&name);
+
+                if !child.is_valid() {
+                    continue;
+                }
+
+                if entry.kind == EmuRsFileKind::Folder {
+                    if !known_directories.contains(&entry.extent) || !visited.insert(entry.extent) {
+                        continue;
+                    }
+
+                    pending.push((child.clone(), entry.extent));
+                }
+
+                self.index.insert(child, entry);
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// The extents of every directory on the disc, root first
+    fn read_path_table(
+        &self,
+        disk: &mut dyn EmuRsDiskDriver,
+        descriptor: &[u8],
+    ) -> Result<Vec<u32>, EmuRsError> {
+        let mut table = vec![0; read_u32(descriptor, 132) as usize];
+        disk.read(&mut table, self.block_offset(read_u32(descriptor, 140)))?;
+
+        let mut directories = Vec::new();
+        let mut offset = 0;
+
+        // Little endian table entries are the name length, extended attribute length, extent, parent number and name
+        while offset + 8 <= table.len() {
+            let name_length = table[offset] as usize;
+
+            if name_length == 0 {
+                break;
+            }
+
+            directories.push(read_u32(&table, offset + 2));
+            offset += 8 + name_length + name_length % 2;
+        }
+
+        if directories.is_empty() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        return Ok(directories);
+    }
+
+    /// Everything in a directory other than itself and its parent
+    fn read_directory(
+        &self,
+        disk: &mut dyn EmuRsDiskDriver,
+        extent: u32,
+        naming: EmuRsIsoNaming,
+    ) -> Result<Vec<(String, EmuRsIsoEntry)>, EmuRsError> {
+        // The size of the directory is only known from its own `.` record
+        let mut data = vec![0; self.block_size];
+        disk.read(&mut data, self.block_offset(extent))?;
+
+        if data[0] < 34 {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        let size = read_u32(&data, 10) as usize;
+        data.resize(size.max(self.block_size), 0);
+        disk.read(
+            &mut data[self.block_size..],
+            self.block_offset(extent) + self.block_size,
+        )?;
+
+        let mut entries: Vec<(String, EmuRsIsoEntry)> = Vec::new();
+        let mut offset = 0;
+        let mut continues_previous = false;
+
+        while offset < size {
+            let length = data[offset] as usize;
+
+            // Records never cross a sector, the rest of the sector is padded with zeros
+            if length == 0 {
+                offset = (offset / SECTOR_SIZE + 1) * SECTOR_SIZE;
+                continue;
+            }
+
+            if length < 34 || offset + length > data.len() {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem,
+                });
+            }
+
+            let record = &data[offset..offset + length];
+            offset += length;
+
+            let flags = record[25];
+            let name_length = record[32] as usize;
+            let identifier = record.get(33..33 + name_length).ok_or(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            })?;
+
+            // Files over 4GiB are split into several records, one after another
+            if continues_previous {
+                continues_previous = flags & FLAG_MULTI_EXTENT != 0;
+
+                if let Some((_, previous)) = entries.last_mut() {
+                    previous.size += read_u32(record, 10) as usize;
+                }
+
+                continue;
+            }
+
+            continues_previous = flags & FLAG_MULTI_EXTENT != 0;
+
+            if identifier == [0] || identifier == [1] || flags & FLAG_ASSOCIATED != 0 {
+                continue;
+            }
+
+            let mut entry = EmuRsIsoEntry {
+                extent: read_u32(record, 2),
+                size: read_u32(record, 10) as usize,
+                kind: if flags & FLAG_DIRECTORY != 0 {
+                    EmuRsFileKind::Folder
+                } else {
+                    EmuRsFileKind::File
+                },
+                modification_time: parse_short_time(&record[18..25]),
+            };
+
+            let mut name: String = match naming {
+                EmuRsIsoNaming::Joliet => char::decode_utf16(
+                    identifier
+                        .chunks_exact(2)
+                        .map(|unit| u16::from_be_bytes([unit[0], unit[1]])),
+                )
+                .map(|character| character.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+                _ => String::from_utf8_lossy(identifier).into(),
+            };
+
+            // `NAME.EXT;1` is just `NAME.EXT`, and `NAME.;1` is just `NAME`
+            if let Some((stem, _)) = name.rsplit_once(';') {
+                name = stem.into();
+            }
+            if name.ends_with('.') {
+                name.pop();
+            }
+
+            if let EmuRsIsoNaming::RockRidge { skip } = naming {
+                let mut rock_ridge_name = String::new();
+                let mut relocated = false;
+
+                for (signature, field) in self.system_use_entries(disk, record, skip)? {
+                    match &signature {
+                        // Alternate name, possibly split over several entries
+                        b"NM" if !field.is_empty() && field[0] & 0b110 == 0 => {
+                            rock_ridge_name.push_str(&String::from_utf8_lossy(&field[1..]));
+                        }
+                        // Directories too deep for ISO 9660 are moved elsewhere and linked back to
+                        b"CL" if field.len() >= 4 => {
+                            entry.extent = read_u32(&field, 0);
+                            entry.kind = EmuRsFileKind::Folder;
+                        }
+                        b"RE" => relocated = true,
+                        b"TF" if !field.is_empty() => {
+                            if let Some(time) = parse_rock_ridge_time(&field) {
+                                entry.modification_time = Some(time);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+
+                // Relocated directories show up where they were linked from instead
+                if relocated {
+                    continue;
+                }
+
+                if !rock_ridge_name.is_empty() {
+                    name = rock_ridge_name;
+                }
+            }
+
+            if entry.kind == EmuRsFileKind::Folder {
+                entry.size = 0;
+            }
+
+            entries.push((name, entry));
+        }
+
+        return Ok(entries);
+    }
+
+    /// Every SUSP entry in a record's system use area and the continuation areas it points to
+    fn system_use_entries(
+        &self,
+        disk: &mut dyn EmuRsDiskDriver,
+        record: &[u8],
+        skip: usize,
+    ) -> Result<Vec<EmuRsSystemUseEntry>, EmuRsError> {
+        let mut entries = Vec::new();
+        let mut area: Vec<u8> = system_use_area(record)
+            .and_then(|area| area.get(skip..))
+            .unwrap_or_default()
+            .into();
+
+        for _ in 0..MAX_CONTINUATION_AREAS {
+            let mut continuation = None;
+            let mut offset = 0;
+
+            while offset + 4 <= area.len() {
+                let signature = [area[offset], area[offset + 1]];
+                let length = area[offset + 2] as usize;
+
+                if length < 4 || offset + length > area.len() || &signature == b"ST" {
+                    break;
+                }
+
+                let field = &area[offset + 4..offset + length];
+
+                if &signature == b"CE" && field.len() >= 24 {
+                    continuation = Some((
+                        read_u32(field, 0),
+                        read_u32(field, 8) as usize,
+                        read_u32(field, 16) as usize,
+                    ));
+                } else {
+                    entries.push((signature, field.into()));
+                }
+
+                offset += length;
+            }
+
+            match continuation {
+                Some((block, offset, length)) => {
+                    area = vec![0; length];
+                    disk.read(&mut area, self.block_offset(block) + offset)?;
+                }
+                None => break,
+            }
+        }
+
+        return Ok(entries);
+    }
+
+    fn block_offset(&self, block: u32) -> usize {
+        return block as usize * self.block_size;
+    }
+
+    fn entry(&self, file: &EmuRsPath) -> Result<&EmuRsIsoEntry, EmuRsError> {
+        return self.index.get(&file.normalize()?).ok_or(EmuRsError {
+            reason: EmuRsErrorReason::FileNotFound,
+        });
+    }
+}
+
+impl EmuRsDriver for EmuRsIsoFs {
+    fn name(&self) -> &'static str {
+        return "ISO 9660 Filesystem";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        // Filesystems only talk to disks, never hardware
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsFsDriver for EmuRsIsoFs {
+    fn mount(&mut self, disk: Rc<RefCell<dyn EmuRsDiskDriver>>) -> Result<(), EmuRsError> {
+        self.build_index(&mut *disk.borrow_mut())?;
+        self.disk = Some(disk);
+        return Ok(());
+    }
+
+    fn read(
+        &mut self,
+        file: &EmuRsPath,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        let entry = self.entry(file)?;
+
+        if entry.kind != EmuRsFileKind::File {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        if offset
+            .checked_add(buffer.len())
+            .is_none_or(|end| end > entry.size)
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfFileHit,
+            });
+        }
+
+        return self
+            .disk
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .read(buffer, self.block_offset(entry.extent) + offset);
+    }
+
+    fn list_directory(&mut self, file: &EmuRsPath) -> Result<TinyVec<[EmuRsPath; 10]>, EmuRsError> {
+        let directory = file.normalize()?;
+
+        if self.entry(&directory)?.kind != EmuRsFileKind::Folder {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        return Ok(self
+            .index
+            .keys()
+            .filter(|path| {
+                return path.parent().as_ref() == Some(&directory);
+            })
+            .cloned()
+            .collect());
+    }
+
+    fn metadata(&mut self, file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
+        let entry = self.entry(file)?;
+
+        return Ok(EmuRsFileMetadata {
+            size: Some(entry.size),
+            modification_time: entry.modification_time,
+            kind: Some(entry.kind),
+            rom: None,
+        });
+    }
+}
+
+/// What comes after the name in a directory record
+fn system_use_area(record: &[u8]) -> Option<&[u8]> {
+    let name_length = *record.get(32)? as usize;
+    // The name is padded to a even length
+    let start = 33 + name_length + (name_length + 1) % 2;
+    let length = (*record.first()? as usize).min(record.len());
+
+    return record.get(start..length);
+}
+
+/// Directory records keep time as years since 1900, month, day, hour, minute, second and offset in 15 minute steps
+fn parse_short_time(time: &[u8]) -> Option<OffsetDateTime> {
+    let date = Date::from_calendar_date(
+        1900 + time[0] as i32,
+        Month::try_from(time[1]).ok()?,
+        time[2],
+    )
+    .ok()?;
+    let clock = Time::from_hms(time[3], time[4], time[5]).ok()?;
+    let offset = UtcOffset::from_whole_seconds(time[6] as i8 as i32 * 15 * 60).ok()?;
+
+    return Some(PrimitiveDateTime::new(date, clock).assume_offset(offset));
+}
+
+/// Volume descriptors and long form Rock Ridge times are ASCII digits, `YYYYMMDDHHMMSScc` and the offset
+fn parse_long_time(time: &[u8]) -> Option<OffsetDateTime> {
+    let number = |range: core::ops::Range<usize>| {
+        return core::str::from_utf8(&time[range]).ok()?.parse::<u32>().ok();
+    };
+
+    let date = Date::from_calendar_date(
+        number(0..4)? as i32,
+        Month::try_from(number(4..6)? as u8).ok()?,
+        number(6..8)? as u8,
+    )
+    .ok()?;
+    let clock = Time::from_hms_milli(
+        number(8..10)? as u8,
+        number(10..12)? as u8,
+        number(12..14)? as u8,
+        number(14..16)? as u16 * 10,
+    )
+    .ok()?;
+    let offset = UtcOffset::from_whole_seconds(time[16] as i8 as i32 * 15 * 60).ok()?;
+
+    return Some(PrimitiveDateTime::new(date, clock).assume_offset(offset));
+}
+
+/// Pick the modification time out of a `TF` entry, which holds whichever times its flags say in a fixed order
+fn parse_rock_ridge_time(field: &[u8]) -> Option<OffsetDateTime> {
+    const MODIFY: u8 = 1 << 1;
+    const LONG_FORM: u8 = 1 << 7;
+
+    let flags = field[0];
+
+    if flags & MODIFY == 0 {
+        return None;
+    }
+
+    let size = if flags & LONG_FORM != 0 { 17 } else { 7 };
+    // Only the creation time can come before it
+    let start = 1 + (flags & 1) as usize * size;
+    let time = field.get(start..start + size)?;
+
+    if flags & LONG_FORM != 0 {
+        return parse_long_time(time);
+    }
+
+    return parse_short_time(time);
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    return u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+}
+
+/// Most numbers are stored both little and big endian, we only look at the little endian half
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use alloc::format;
+    use alloc::string::ToString;
+    use core::str::FromStr;
+
+    // There is no genisoimage where the tests run, so the discs are put together here a sector at
+    // a time. Everything is little endian only, which is all we read anyway
+
+    /// 2023-11-14 22:13:20 UTC, the same time the other fixtures use
+    const TIME: [u8; 7] = [123, 11, 14, 22, 13, 20, 0];
+
+    /// Sector 16 is the primary descriptor, 17 the Joliet one or the terminator, 18 the
+    /// terminator, 19 and 20 the path tables. Everything else starts at 21
+    struct Disc(Vec<u8>);
+
+    impl Disc {
+        fn new(sectors: usize) -> Self {
+            let mut disc = Self(vec![0; sectors * SECTOR_SIZE]);
+            // Discs without Joliet just end a sector early
+            for sector in [17, 18] {
+                disc.put(
+                    sector,
+                    &descriptor(VOLUME_DESCRIPTOR_TERMINATOR, b"", 0, 0, 0, &[]),
+                );
+            }
+            return disc;
+        }
+
+        fn put(&mut self, sector: u32, data: &[u8]) {
+            let start = sector as usize * SECTOR_SIZE;
+            self.0[start..start + data.len()].copy_from_slice(data);
+        }
+
+        /// Root directory first, the path table only needs the extents
+        fn volume(&mut self, joliet: bool, directories: &[(u32, &[u8])]) {
+            let (sector, path_table, kind, escape) = match joliet {
+                true => (17, 20, VOLUME_DESCRIPTOR_SUPPLEMENTARY, b"%/E".as_slice()),
+                false => (16, 19, VOLUME_DESCRIPTOR_PRIMARY, b"".as_slice()),
+            };
+
+            let mut table = Vec::new();
+            for (extent, name) in directories {
+                table.push(name.len().max(1) as u8);
+                table.push(0);
+                table.extend_from_slice(&extent.to_le_bytes());
+                table.extend_from_slice(&1u16.to_le_bytes());
+                table.extend_from_slice(if name.is_empty() { &[0] } else { name });
+                if table.len() % 2 == 1 {
+                    table.push(0);
+                }
+            }
+
+            let root = directories[0].0;
+            let root_size = read_u32(&self.0, root as usize * SECTOR_SIZE + 10);
+            self.put(path_table, &table);
+            self.put(
+                sector,
+                &descriptor(kind, escape, root, root_size, path_table, &table),
+            );
+        }
+    }
+
+    fn descriptor(
+        kind: u8,
+        escape: &[u8],
+        root: u32,
+        root_size: u32,
+        path_table: u32,
+        table: &[u8],
+    ) -> Vec<u8> {
+        let mut descriptor = vec![0; SECTOR_SIZE];
+        descriptor[0] = kind;
+        descriptor[1..6].copy_from_slice(b"CD001");
+        descriptor[6] = 1;
+        descriptor[88..88 + escape.len()].copy_from_slice(escape);
+        descriptor[128..130].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        descriptor[132..136].copy_from_slice(&(table.len() as u32).to_le_bytes());
+        descriptor[140..144].copy_from_slice(&path_table.to_le_bytes());
+        descriptor[156..190].copy_from_slice(&record(
+            root,
+            root_size as usize,
+            FLAG_DIRECTORY,
+            &[0],
+            &[],
+        ));
+        return descriptor;
+    }
+
+    fn record(
+        extent: u32,
+        size: usize,
+        flags: u8,
+        identifier: &[u8],
+        system_use: &[u8],
+    ) -> Vec<u8> {
+        let mut record = vec![0; 33];
+        record[2..6].copy_from_slice(&extent.to_le_bytes());
+        record[10..14].copy_from_slice(&(size as u32).to_le_bytes());
+        record[18..25].copy_from_slice(&TIME);
+        record[25] = flags;
+        record[28..30].copy_from_slice(&1u16.to_le_bytes());
+        record[32] = identifier.len() as u8;
+        record.extend_from_slice(identifier);
+        if identifier.len() % 2 == 0 {
+            record.push(0);
+        }
+        record.extend_from_slice(system_use);
+        if record.len() % 2 == 1 {
+            record.push(0);
+        }
+        record[0] = record.len() as u8;
+        return record;
+    }
+
+    /// A directory's records packed into sectors the way mastering tools do it, with its `.` and
+    /// `..` records in front
+    fn directory(extent: u32, dot_system_use: &[u8], records: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = record(extent, 0, FLAG_DIRECTORY, &[0], dot_system_use);
+        data.extend(record(extent, 0, FLAG_DIRECTORY, &[1], &[]));
+
+        for record in records {
+            if data.len() % SECTOR_SIZE + record.len() > SECTOR_SIZE {
+                data.resize(data.len().next_multiple_of(SECTOR_SIZE), 0);
+            }
+            data.extend_from_slice(record);
+        }
+
+        data.resize(data.len().next_multiple_of(SECTOR_SIZE), 0);
+        let size = data.len() as u32;
+        data[10..14].copy_from_slice(&size.to_le_bytes());
+        return data;
+    }
+
+    /// A SUSP entry
+    fn entry(signature: &[u8; 2], data: &[u8]) -> Vec<u8> {
+        let mut entry = vec![signature[0], signature[1], 4 + data.len() as u8, 1];
+        entry.extend_from_slice(data);
+        return entry;
+    }
+
+    fn ucs2(name: &str) -> Vec<u8> {
+        return name
+            .encode_utf16()
+            .flat_map(|unit| unit.to_be_bytes())
+            .collect();
+    }
+
+    fn mount(disc: Disc) -> EmuRsIsoFs {
+        let mut fs = EmuRsIsoFs::default();
+        fs.mount(Rc::new(RefCell::new(VecDisk(disc.0)))).unwrap();
+        return fs;
+    }
+
+    fn path(path: &str) -> EmuRsPath {
+        return EmuRsPath::from_str(path).unwrap();
+    }
+
+    fn listing(fs: &mut EmuRsIsoFs, directory: &str) -> Vec<String> {
+        let mut listed: Vec<String> = fs
+            .list_directory(&path(directory))
+            .unwrap()
+            .iter()
+            .map(|path| path.to_string())
+            .collect();
+        listed.sort();
+        return listed;
+    }
+
+    fn read_all(fs: &mut EmuRsIsoFs, file: &str) -> Vec<u8> {
+        let mut data = vec![0; fs.metadata(&path(file)).unwrap().size.unwrap()];
+        fs.read(&path(file), &mut data, 0).unwrap();
+        return data;
+    }
+
+    #[test]
+    fn reads_primary_names() {
+        let mut disc = Disc::new(26);
+        disc.put(
+            21,
+            &directory(
+                21,
+                &[],
+                &[
+                    record(22, 0, FLAG_DIRECTORY, b"GAMES", &[]),
+                    record(23, 6, 0, b"HELLO.TXT;1", &[]),
+                    record(25, 0, 0, b"NOEXT.;1", &[]),
+                ],
+            ),
+        );
+        disc.put(
+            22,
+            &directory(22, &[], &[record(24, 4, 0, b"ROM.GBA;1", &[])]),
+        );
+        disc.put(23, b"hello\n");
+        disc.put(24, b"\x2e\x00\x00\xea");
+        disc.volume(false, &[(21, b""), (22, b"GAMES")]);
+
+        let mut fs = mount(disc);
+
+        assert_eq!(
+            listing(&mut fs, "ROOT"),
+            ["ROOT/GAMES", "ROOT/HELLO.TXT", "ROOT/NOEXT"]
+        );
+        assert_eq!(listing(&mut fs, "ROOT/GAMES"), ["ROOT/GAMES/ROM.GBA"]);
+        assert_eq!(read_all(&mut fs, "ROOT/HELLO.TXT"), b"hello\n");
+        assert_eq!(read_all(&mut fs, "ROOT/GAMES/ROM.GBA"), b"\x2e\x00\x00\xea");
+        assert_eq!(read_all(&mut fs, "ROOT/NOEXT"), b"");
+
+        let metadata = fs.metadata(&path("ROOT/HELLO.TXT")).unwrap();
+        assert_eq!(metadata.kind, Some(EmuRsFileKind::File));
+        assert_eq!(
+            metadata.modification_time.unwrap().unix_timestamp(),
+            1700000000
+        );
+        assert_eq!(
+            fs.metadata(&path("ROOT/GAMES")).unwrap().kind,
+            Some(EmuRsFileKind::Folder)
+        );
+
+        let mut buffer = [0; 4];
+        assert!(matches!(
+            fs.read(&path("ROOT/HELLO.TXT"), &mut buffer, 4),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfFileHit
+            })
+        ));
+        assert!(matches!(
+            fs.read(&path("ROOT/HELLO.TXT"), &mut buffer, usize::MAX),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfFileHit
+            })
+        ));
+        assert!(matches!(
+            fs.read(&path("ROOT/GAMES"), &mut buffer, 0),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath
+            })
+        ));
+    }
+
+    #[test]
+    fn prefers_joliet_names() {
+        let mut disc = Disc::new(27);
+        disc.put(
+            21,
+            &directory(
+                21,
+                &[],
+                &[
+                    record(22, 0, FLAG_DIRECTORY, b"SAVED_GA", &[]),
+                    record(24, 6, 0, b"HELLO_TH.TXT;1", &[]),
+                ],
+            ),
+        );
+        disc.put(
+            22,
+            &directory(22, &[], &[record(26, 3, 0, b"POKEMON.SAV;1", &[])]),
+        );
+        disc.put(
+            23,
+            &directory(
+                23,
+                &[],
+                &[
+                    record(25, 0, FLAG_DIRECTORY, &ucs2("Saved Games"), &[]),
+                    record(24, 6, 0, &ucs2("Hello there.txt;1"), &[]),
+                ],
+            ),
+        );
+        disc.put(
+            25,
+            &directory(25, &[], &[record(26, 3, 0, &ucs2("Pokémon.sav;1"), &[])]),
+        );
+        disc.put(24, b"hello\n");
+        disc.put(26, b"sav");
+        disc.volume(false, &[(21, b""), (22, b"SAVED_GA")]);
+        disc.volume(true, &[(23, b""), (25, &ucs2("Saved Games"))]);
+
+        let mut fs = mount(disc);
+
+        assert_eq!(
+            listing(&mut fs, "ROOT"),
+            ["ROOT/Hello there.txt", "ROOT/Saved Games"]
+        );
+        assert_eq!(
+            listing(&mut fs, "ROOT/Saved Games"),
+            ["ROOT/Saved Games/Pokémon.sav"]
+        );
+        assert_eq!(read_all(&mut fs, "ROOT/Saved Games/Pokémon.sav"), b"sav");
+        assert_eq!(read_all(&mut fs, "ROOT/Hello there.txt"), b"hello\n");
+    }
+
+    #[test]
+    fn prefers_rock_ridge_names() {
+        // The name is split in two, with the second half and the time in a continuation area
+        let mut continuation = entry(b"NM", b"\x00name.txt");
+        let mut time = vec![1 << 1];
+        time.extend_from_slice(&[124, 1, 2, 3, 4, 5, 0]);
+        continuation.extend(entry(b"TF", &time));
+
+        let mut continues = Vec::new();
+        for number in [23u32, 100, continuation.len() as u32] {
+            continues.extend_from_slice(&number.to_le_bytes());
+            continues.extend_from_slice(&number.to_be_bytes());
+        }
+
+        let mut file = entry(b"NM", b"\x01a long ");
+        file.extend(entry(b"CE", &continues));
+
+        let mut disc = Disc::new(27);
+        disc.put(
+            21,
+            &directory(
+                21,
+                &entry(b"SP", &[0xbe, 0xef, 0]),
+                &[
+                    record(
+                        22,
+                        0,
+                        FLAG_DIRECTORY,
+                        b"SAVED_GA",
+                        &entry(b"NM", b"\x00Saved Games"),
+                    ),
+                    record(24, 6, 0, b"A_LONG_N.TXT;1", &file),
+                ],
+            ),
+        );
+        disc.put(
+            22,
+            &directory(
+                22,
+                &[],
+                &[record(
+                    25,
+                    3,
+                    0,
+                    b"SAVE.SAV;1",
+                    &entry(b"NM", b"\x00save file.sav"),
+                )],
+            ),
+        );
+        disc.put(23, &[[0; 100].as_slice(), &continuation].concat());
+        disc.put(24, b"hello\n");
+        disc.put(25, b"sav");
+        disc.volume(false, &[(21, b""), (22, b"SAVED_GA")]);
+
+        // Rock Ridge still wins over Joliet when a disc has both
+        disc.put(
+            26,
+            &directory(26, &[], &[record(24, 6, 0, &ucs2("joliet.txt;1"), &[])]),
+        );
+        disc.volume(true, &[(26, b"")]);
+
+        let mut fs = mount(disc);
+
+        assert_eq!(
+            listing(&mut fs, "ROOT"),
+            ["ROOT/Saved Games", "ROOT/a long name.txt"]
+        );
+        assert_eq!(
+            listing(&mut fs, "ROOT/Saved Games"),
+            ["ROOT/Saved Games/save file.sav"]
+        );
+        assert_eq!(read_all(&mut fs, "ROOT/a long name.txt"), b"hello\n");
+        assert_eq!(read_all(&mut fs, "ROOT/Saved Games/save file.sav"), b"sav");
+        assert_eq!(
+            fs.metadata(&path("ROOT/a long name.txt"))
+                .unwrap()
+                .modification_time
+                .unwrap()
+                .unix_timestamp(),
+            1704164645
+        );
+    }
+
+    #[test]
+    fn joins_extents_and_reads_directories_over_several_sectors() {
+        let mut records = vec![
+            record(23, SECTOR_SIZE, FLAG_MULTI_EXTENT, b"BIG.BIN;1", &[]),
+            record(24, 100, 0, b"BIG.BIN;1", &[]),
+        ];
+        for number in 0..50 {
+            records.push(record(
+                25,
+                4,
+                0,
+                format!("FILE{:02}.BIN;1", number).as_bytes(),
+                &[],
+            ));
+        }
+
+        let root = directory(21, &[], &records);
+        // Records don't cross sectors, so the first one ends in padding
+        assert_eq!(root.len(), 2 * SECTOR_SIZE);
+        assert_eq!(root[SECTOR_SIZE - 1], 0);
+
+        let mut disc = Disc::new(26);
+        disc.put(21, &root);
+        disc.put(23, &[0xaa; SECTOR_SIZE]);
+        disc.put(24, &[0xbb; 100]);
+        disc.put(25, b"file");
+        disc.volume(false, &[(21, b"")]);
+
+        let mut fs = mount(disc);
+
+        let listed = listing(&mut fs, "ROOT");
+        assert_eq!(listed.len(), 51);
+        assert_eq!(listed[0], "ROOT/BIG.BIN");
+        assert_eq!(listed[50], "ROOT/FILE49.BIN");
+        assert_eq!(read_all(&mut fs, "ROOT/FILE49.BIN"), b"file");
+
+        let big = read_all(&mut fs, "ROOT/BIG.BIN");
+        assert_eq!(big.len(), SECTOR_SIZE + 100);
+        assert!(big[..SECTOR_SIZE].iter().all(|byte| *byte == 0xaa));
+        assert!(big[SECTOR_SIZE..].iter().all(|byte| *byte == 0xbb));
+    }
+}
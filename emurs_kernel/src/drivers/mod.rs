@@ -1,3 +1,8 @@
 pub mod ustarfs;
 pub mod gamefs;
 pub mod cborfs;
+pub mod fatfs;
+pub mod zipfs;
+pub mod compressedfs;
+pub mod isofs;
+pub mod ext2fs;
@@ -1,32 +1,1012 @@
-
+use crate::device::EmuRsDevice;
+use crate::disk::EmuRsDiskDriver;
+use crate::driver::EmuRsDriverPreference;
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::vfs::{EmuRsFileKind, EmuRsFileMetadata};
 use crate::{
     driver::EmuRsDriver,
-    vfs::{EmuRsFsDriver},
+    vfs::{EmuRsFsDriver, EmuRsPath},
 };
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::String;
+use core::cell::RefCell;
+use core::str::FromStr;
+use time::OffsetDateTime;
+use tinyvec::TinyVec;
 
+// https://wiki.osdev.org/USTAR
+// https://www.ibm.com/docs/en/aix/7.1?topic=files-tarh-file
 
+/// Tar archives are made out of blocks of this size, headers included
+pub const USTAR_BLOCK_SIZE: usize = 512;
 
+/// The parts of a USTAR header we care about
+#[derive(Debug, Clone)]
+pub struct EmuRsUstarHeader {
+    pub name: String,
+    pub link_name: String,
+    pub size: usize,
+    pub modification_time: u64,
+    pub kind: u8,
+}
 
-// https://wiki.osdev.org/USTAR
-// https://www.ibm.com/docs/en/aix/7.1?topic=files-tarh-file
+impl EmuRsUstarHeader {
+    /// Parse a header block. Returns [None] for the zeroed blocks that mark the end of the archive
+    pub fn parse(block: &[u8; USTAR_BLOCK_SIZE]) -> Result<Option<Self>, EmuRsError> {
+        if block.iter().all(|byte| *byte == 0) {
+            return Ok(None);
+        }
+
+        if parse_octal(&block[148..156])? as u32 != header_checksum(block) {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        let mut name = parse_string(&block[0..100])?;
+
+        // Old style tar archives do not have the prefix field
+        if &block[257..262] == b"ustar" {
+            let prefix = parse_string(&block[345..500])?;
+
+            if !prefix.is_empty() {
+                name = format!("{}/{}", prefix, name);
+            }
+        }
+
+        return Ok(Some(Self {
+            name,
+            link_name: parse_string(&block[157..257])?,
+            size: parse_octal(&block[124..136])? as usize,
+            modification_time: parse_octal(&block[136..148])?,
+            kind: block[156],
+        }));
+    }
+
+    /// Build the header block for this entry, checksum included
+    pub fn to_block(&self) -> Result<[u8; USTAR_BLOCK_SIZE], EmuRsError> {
+        let mut block = [0; USTAR_BLOCK_SIZE];
+        let (prefix, name) = split_name(&self.name)?;
+
+        if self.link_name.len() > 100 {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        block[0..name.len()].copy_from_slice(name.as_bytes());
+        write_octal(
+            &mut block[100..108],
+            if self.kind == b'5' { 0o755 } else { 0o644 },
+        )?;
+        write_octal(&mut block[108..116], 0)?;
+        write_octal(&mut block[116..124], 0)?;
+        write_octal(&mut block[124..136], self.size as u64)?;
+        write_octal(&mut block[136..148], self.modification_time)?;
+        block[156] = self.kind;
+        block[157..157 + self.link_name.len()].copy_from_slice(self.link_name.as_bytes());
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+        block[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+        // The checksum is six digits, a null, and then a space for historical reasons
+        let checksum = header_checksum(&block);
+        write_octal(&mut block[148..155], checksum as u64)?;
+        block[155] = b' ';
+
+        return Ok(block);
+    }
+
+    /// How many bytes the file data takes up in the archive, padding included
+    pub fn padded_size(&self) -> usize {
+        return self.size.div_ceil(USTAR_BLOCK_SIZE) * USTAR_BLOCK_SIZE;
+    }
+
+    pub fn file_kind(&self) -> Option<EmuRsFileKind> {
+        return match self.kind {
+            b'0' | b'\0' | b'7' => Some(EmuRsFileKind::File),
+            b'5' => Some(EmuRsFileKind::Folder),
+            b'3' | b'4' => Some(EmuRsFileKind::Device),
+            // Links, fifos and extension headers
+            _ => None,
+        };
+    }
+
+    /// The path this header refers to inside of the archive
+    pub fn path(&self) -> Result<EmuRsPath, EmuRsError> {
+        return EmuRsPath::from_str(&format!("ROOT/{}", self.name))?.normalize();
+    }
+}
+
+/// The checksum is the sum of every byte in the header with the checksum field itself counted as spaces
+pub fn header_checksum(block: &[u8; USTAR_BLOCK_SIZE]) -> u32 {
+    return block
+        .iter()
+        .enumerate()
+        .map(|(index, byte)| {
+            if (148..156).contains(&index) {
+                return b' ' as u32;
+            }
+
+            return *byte as u32;
+        })
+        .sum();
+}
+
+/// Numbers are stored as octal text terminated by a null or a space
+///
+/// GNU tar stores numbers too big for the field in base 256 with the high bit of the first byte set
+fn parse_octal(field: &[u8]) -> Result<u64, EmuRsError> {
+    if field.first().is_some_and(|byte| byte & 0x80 != 0) {
+        return Ok(field[1..]
+            .iter()
+            .fold((field[0] & 0x7f) as u64, |number, byte| {
+                return (number << 8) | *byte as u64;
+            }));
+    }
+
+    let mut number: u64 = 0;
+
+    for byte in field
+        .iter()
+        .skip_while(|byte| **byte == b' ')
+        .take_while(|byte| **byte != 0 && **byte != b' ')
+    {
+        if !(b'0'..=b'7').contains(byte) {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        number = (number << 3) | (byte - b'0') as u64;
+    }
+
+    return Ok(number);
+}
+
+/// Write a null terminated, zero padded octal number that fills the field
+fn write_octal(field: &mut [u8], number: u64) -> Result<(), EmuRsError> {
+    let digits = field.len() - 1;
+    let mut remaining = number;
+
+    for byte in field[..digits].iter_mut().rev() {
+        *byte = b'0' + (remaining & 0o7) as u8;
+        remaining >>= 3;
+    }
+
+    if remaining != 0 {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::OperationNotSupported,
+        });
+    }
+
+    field[digits] = 0;
+    return Ok(());
+}
+
+/// Names longer than 100 bytes have to be split on a `/` into the 155 byte prefix and the name
+fn split_name(name: &str) -> Result<(&str, &str), EmuRsError> {
+    if name.len() <= 100 {
+        return Ok(("", name));
+    }
+
+    return name
+        .match_indices('/')
+        .map(|(index, _)| index)
+        .find(|index| *index <= 155 && (1..=100).contains(&(name.len() - index - 1)))
+        .map(|index| (&name[..index], &name[index + 1..]))
+        .ok_or(EmuRsError {
+            reason: EmuRsErrorReason::InvalidPath,
+        });
+}
+
+fn parse_string(field: &[u8]) -> Result<String, EmuRsError> {
+    let length = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
 
+    return core::str::from_utf8(&field[..length])
+        .map(String::from)
+        .map_err(|_| EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        });
+}
+
+/// Copy bytes around on the disk. Overlapping copies only work when moving towards the start
+fn copy_within_disk(
+    disk: &mut dyn EmuRsDiskDriver,
+    source: usize,
+    destination: usize,
+    length: usize,
+) -> Result<(), EmuRsError> {
+    if source == destination {
+        return Ok(());
+    }
+
+    let mut block = [0; USTAR_BLOCK_SIZE];
+    let mut copied = 0;
+
+    while copied < length {
+        let chunk = (length - copied).min(USTAR_BLOCK_SIZE);
+        disk.read(&mut block[..chunk], source + copied)?;
+        disk.write(&block[..chunk], destination + copied)?;
+        copied += chunk;
+    }
+
+    return Ok(());
+}
+
+fn zero_disk(
+    disk: &mut dyn EmuRsDiskDriver,
+    offset: usize,
+    length: usize,
+) -> Result<(), EmuRsError> {
+    let block = [0; USTAR_BLOCK_SIZE];
+    let mut written = 0;
+
+    while written < length {
+        let chunk = (length - written).min(USTAR_BLOCK_SIZE);
+        disk.write(&block[..chunk], offset + written)?;
+        written += chunk;
+    }
+
+    return Ok(());
+}
+
+/// Where a file lives in the archive
+#[derive(Debug, Clone)]
+struct EmuRsUstarEntry {
+    /// Directories that only exist because something is inside of them have no header
+    header_offset: Option<usize>,
+    data_offset: usize,
+    size: usize,
+    kind: EmuRsFileKind,
+    modification_time: Option<OffsetDateTime>,
+}
+
+/// A USTAR filesystem. The whole archive is indexed when mounted so lookups never touch the disk
+///
+/// Writing works the way `tar --append` does. New versions of a file are added to the end of the archive and supersede the old ones,
+/// and once the disk is full the archive is compacted to throw away everything that was superseded.
+/// The archive is always left readable by a normal `tar`
 #[derive(Default)]
-pub struct EmuRsUstarFs;
+pub struct EmuRsUstarFs {
+    disk: Option<Rc<RefCell<dyn EmuRsDiskDriver>>>,
+    index: BTreeMap<EmuRsPath, EmuRsUstarEntry>,
+    /// Where the end of archive blocks start, which is where new entries go
+    end_offset: usize,
+}
+
+impl EmuRsUstarFs {
+    /// Walk every header in the archive and remember where its data is
+    fn build_index(
+        disk: &mut dyn EmuRsDiskDriver,
+    ) -> Result<(BTreeMap<EmuRsPath, EmuRsUstarEntry>, usize), EmuRsError> {
+        let mut index = BTreeMap::new();
+        let disk_size = disk.get_total_size();
+        let mut offset = 0;
+
+        index.insert(
+            EmuRsPath::default(),
+            EmuRsUstarEntry {
+                header_offset: None,
+                data_offset: 0,
+                size: 0,
+                kind: EmuRsFileKind::Folder,
+                modification_time: None,
+            },
+        );
+
+        // Archives that are missing the end blocks just stop at the end of the disk
+        while offset + USTAR_BLOCK_SIZE <= disk_size {
+            let mut block = [0; USTAR_BLOCK_SIZE];
+            disk.read(&mut block, offset)?;
+
+            let header = match EmuRsUstarHeader::parse(&block)? {
+                Some(header) => header,
+                None => break,
+            };
+
+            let header_offset = offset;
+            let data_offset = offset + USTAR_BLOCK_SIZE;
+            offset = data_offset + header.padded_size();
+
+            let path = match header.path() {
+                Ok(path) if !path.is_root() => path,
+                _ => continue,
+            };
+
+            let entry = match header.kind {
+                // Hard links share the data of a earlier entry
+                b'1' => {
+                    let target = EmuRsPath::from_str(&format!("ROOT/{}", header.link_name))
+                        .and_then(|target| target.normalize())
+                        .ok()
+                        .and_then(|target| index.get(&target).cloned());
+
+                    match target {
+                        Some(target) => EmuRsUstarEntry {
+                            header_offset: Some(header_offset),
+                            ..target
+                        },
+                        None => continue,
+                    }
+                }
+                _ => match header.file_kind() {
+                    Some(kind) => EmuRsUstarEntry {
+                        header_offset: Some(header_offset),
+                        data_offset,
+                        size: if kind == EmuRsFileKind::File {
+                            header.size
+                        } else {
+                            0
+                        },
+                        kind,
+                        modification_time: OffsetDateTime::from_unix_timestamp(
+                            header.modification_time as i64,
+                        )
+                        .ok(),
+                    },
+                    None => continue,
+                },
+            };
+
+            // Archives are not required to have entries for every directory
+            let mut parent = path.parent();
+            while let Some(directory) = parent {
+                if index.contains_key(&directory) {
+                    break;
+                }
+
+                index.insert(
+                    directory.clone(),
+                    EmuRsUstarEntry {
+                        header_offset: None,
+                        data_offset: 0,
+                        size: 0,
+                        kind: EmuRsFileKind::Folder,
+                        modification_time: None,
+                    },
+                );
+                parent = directory.parent();
+            }
+
+            // Later entries replace earlier ones, which is how tar handles appending
+            index.insert(path, entry);
+        }
+
+        return Ok((index, offset.min(disk_size)));
+    }
+
+    /// Add a new version of a file to the end of the archive
+    ///
+    /// The new contents are the old contents with `buffer` written over them at `offset`, growing the file if needed.
+    /// The header goes in last so losing power halfway through leaves the archive as it was
+    fn append(&mut self, path: &EmuRsPath, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        let old_size = self.index.get(path).map_or(0, |entry| entry.size);
+        let end = offset.checked_add(buffer.len()).ok_or(EmuRsError {
+            reason: EmuRsErrorReason::OutOfSpace,
+        })?;
+        let header = EmuRsUstarHeader {
+            name: path.segments[1..].join("/"),
+            link_name: String::new(),
+            size: old_size.max(end),
+            // FIXME: There is no clock yet so keep whatever time the file had
+            modification_time: self
+                .index
+                .get(path)
+                .and_then(|entry| entry.modification_time)
+                .map_or(0, |time| time.unix_timestamp().max(0) as u64),
+            kind: b'0',
+        };
+        let header_block = header.to_block()?;
+        let disk_size = self.disk.as_ref().unwrap().borrow_mut().get_total_size();
+
+        // Sizes that could never fit would overflow working out how much room they need
+        if header.size > disk_size {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::OutOfSpace,
+            });
+        }
+
+        let needed = USTAR_BLOCK_SIZE + header.padded_size() + USTAR_BLOCK_SIZE * 2;
+
+        if self.end_offset + needed > disk_size {
+            self.compact()?;
+
+            if self.end_offset + needed > disk_size {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::OutOfSpace,
+                });
+            }
+        }
+
+        // Compacting moves data around so this has to be looked up afterwards
+        let old_data_offset = self.index.get(path).map(|entry| entry.data_offset);
+        let header_offset = self.end_offset;
+        let data_offset = header_offset + USTAR_BLOCK_SIZE;
+        let mut disk = self.disk.as_ref().unwrap().borrow_mut();
+
+        if let Some(old_data_offset) = old_data_offset {
+            copy_within_disk(
+                &mut *disk,
+                old_data_offset,
+                data_offset,
+                old_size.min(offset),
+            )?;
+        }
+
+        // Writing past the end leaves a hole of zeros
+        zero_disk(
+            &mut *disk,
+            data_offset + old_size.min(offset),
+            offset.saturating_sub(old_size),
+        )?;
+        disk.write(buffer, data_offset + offset)?;
+
+        if let Some(old_data_offset) = old_data_offset {
+            let tail_start = offset + buffer.len();
+
+            if tail_start < old_size {
+                copy_within_disk(
+                    &mut *disk,
+                    old_data_offset + tail_start,
+                    data_offset + tail_start,
+                    old_size - tail_start,
+                )?;
+            }
+        }
+
+        let data_end = data_offset + header.size;
+        zero_disk(
+            &mut *disk,
+            data_end,
+            data_offset + header.padded_size() + USTAR_BLOCK_SIZE * 2 - data_end,
+        )?;
+        disk.write(&header_block, header_offset)?;
+        drop(disk);
+
+        self.end_offset = data_offset + header.padded_size();
+        self.index.insert(
+            path.clone(),
+            EmuRsUstarEntry {
+                header_offset: Some(header_offset),
+                data_offset,
+                size: header.size,
+                kind: EmuRsFileKind::File,
+                modification_time: OffsetDateTime::from_unix_timestamp(
+                    header.modification_time as i64,
+                )
+                .ok(),
+            },
+        );
+
+        return Ok(());
+    }
+
+    /// The first hard link still in the index that shares the data at `data_offset`
+    fn first_link_to(&self, data_offset: usize) -> Option<EmuRsPath> {
+        return self
+            .index
+            .iter()
+            .filter(|(_, entry)| {
+                return entry.data_offset == data_offset
+                    && entry.header_offset != Some(data_offset - USTAR_BLOCK_SIZE);
+            })
+            .min_by_key(|(_, entry)| entry.header_offset)
+            .map(|(path, _)| path.clone());
+    }
+
+    /// Rewrite the archive without the entries that were superseded or deleted
+    ///
+    /// Entries only ever move towards the start of the disk so this can be done in place
+    fn compact(&mut self) -> Result<(), EmuRsError> {
+        let mut disk = self.disk.as_ref().unwrap().borrow_mut();
+        let mut read_offset = 0;
+        let mut write_offset = 0;
+        // Files that are going away but still have hard links to them, and the link that takes
+        // over the data, by where the data is
+        let mut promoted: BTreeMap<usize, EmuRsPath> = BTreeMap::new();
+
+        while read_offset < self.end_offset {
+            let mut block = [0; USTAR_BLOCK_SIZE];
+            disk.read(&mut block, read_offset)?;
 
-impl EmuRsUstarFs {}
+            let mut header = match EmuRsUstarHeader::parse(&block)? {
+                Some(header) => header,
+                None => break,
+            };
+            let length = USTAR_BLOCK_SIZE + header.padded_size();
+            let entry = header
+                .path()
+                .ok()
+                .and_then(|path| self.index.get(&path))
+                .filter(|entry| entry.header_offset == Some(read_offset));
+
+            // Anything we do not understand is kept as is
+            let mut is_live =
+                header.file_kind().is_none() && header.kind != b'1' || entry.is_some();
+            let mut rewrite = false;
+
+            if !is_live && header.kind != b'1' {
+                // The first link becomes the file, so the data isn't lost along with the name
+                if let Some(link) = self.first_link_to(read_offset + USTAR_BLOCK_SIZE) {
+                    header.name = link.segments[1..].join("/");
+                    promoted.insert(read_offset + USTAR_BLOCK_SIZE, link);
+                    is_live = true;
+                    rewrite = true;
+                }
+            } else if let Some(owner) = entry.and_then(|entry| promoted.get(&entry.data_offset)) {
+                // A link to a file that was promoted, either the one that took its place or one
+                // that needs to point at it now
+                if header.path().ok().as_ref() == Some(owner) {
+                    is_live = false;
+                } else {
+                    header.link_name = owner.segments[1..].join("/");
+                    rewrite = true;
+                }
+            }
+
+            if is_live {
+                copy_within_disk(&mut *disk, read_offset, write_offset, length)?;
+                if rewrite {
+                    disk.write(&header.to_block()?, write_offset)?;
+                }
+                write_offset += length;
+            }
+
+            read_offset += length;
+        }
+
+        let disk_size = disk.get_total_size();
+        zero_disk(
+            &mut *disk,
+            write_offset,
+            (USTAR_BLOCK_SIZE * 2).min(disk_size - write_offset),
+        )?;
+
+        (self.index, self.end_offset) = Self::build_index(&mut *disk)?;
+        return Ok(());
+    }
+
+    fn entry(&self, file: &EmuRsPath) -> Result<&EmuRsUstarEntry, EmuRsError> {
+        return self.index.get(&file.normalize()?).ok_or(EmuRsError {
+            reason: EmuRsErrorReason::FileNotFound,
+        });
+    }
+}
 
 impl EmuRsDriver for EmuRsUstarFs {
     fn name(&self) -> &'static str {
-        todo!()
+        return "USTAR Filesystem";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        // Filesystems only talk to disks, never hardware
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsFsDriver for EmuRsUstarFs {
+    fn mount(&mut self, disk: Rc<RefCell<dyn EmuRsDiskDriver>>) -> Result<(), EmuRsError> {
+        (self.index, self.end_offset) = Self::build_index(&mut *disk.borrow_mut())?;
+        self.disk = Some(disk);
+        return Ok(());
+    }
+
+    fn read(
+        &mut self,
+        file: &EmuRsPath,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        let entry = self.entry(file)?;
+
+        if entry.kind != EmuRsFileKind::File {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        if offset
+            .checked_add(buffer.len())
+            .is_none_or(|end| end > entry.size)
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfFileHit,
+            });
+        }
+
+        return self
+            .disk
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .read(buffer, entry.data_offset + offset);
+    }
+
+    fn write(&mut self, file: &EmuRsPath, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        let path = file.normalize()?;
+        let entry = self.entry(&path)?.clone();
+
+        if entry.kind != EmuRsFileKind::File {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        // Writes that fit inside of the file can go right over the old data
+        if offset
+            .checked_add(buffer.len())
+            .is_some_and(|end| end <= entry.size)
+        {
+            return self
+                .disk
+                .as_ref()
+                .unwrap()
+                .borrow_mut()
+                .write(buffer, entry.data_offset + offset);
+        }
+
+        return self.append(&path, buffer, offset);
+    }
+
+    fn create(&mut self, file: &EmuRsPath) -> Result<(), EmuRsError> {
+        let path = file.normalize()?;
+
+        if path.is_root() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        if self.index.contains_key(&path) {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::FileAlreadyExists,
+            });
+        }
+
+        if let Some(parent) = path.parent() {
+            if self.entry(&parent)?.kind != EmuRsFileKind::Folder {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::InvalidPath,
+                });
+            }
+        }
+
+        return self.append(&path, &[], 0);
+    }
+
+    /// Tar has no way to mark something as deleted so the archive is compacted right away
+    fn delete(&mut self, file: &EmuRsPath) -> Result<(), EmuRsError> {
+        let path = file.normalize()?;
+
+        if path.is_root() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        self.entry(&path)?;
+
+        if self
+            .index
+            .keys()
+            .any(|other| other.parent().as_ref() == Some(&path))
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::DirectoryNotEmpty,
+            });
+        }
+
+        self.index.remove(&path);
+        return self.compact();
     }
 
-    fn get_preference(&mut self) -> crate::driver::EmuRsDriverPreference {
-        todo!()
+    fn list_directory(&mut self, file: &EmuRsPath) -> Result<TinyVec<[EmuRsPath; 10]>, EmuRsError> {
+        let directory = file.normalize()?;
+
+        if self.entry(&directory)?.kind != EmuRsFileKind::Folder {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        return Ok(self
+            .index
+            .keys()
+            .filter(|path| {
+                return path.parent().as_ref() == Some(&directory);
+            })
+            .cloned()
+            .collect());
     }
 
-    fn get_claimed(&mut self) -> crate::device::EmuRsDevice {
-        todo!()
+    fn metadata(&mut self, file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
+        let entry = self.entry(file)?;
+
+        return Ok(EmuRsFileMetadata {
+            size: Some(entry.size),
+            modification_time: entry.modification_time,
+            kind: Some(entry.kind),
+            rom: None,
+        });
     }
 }
 
-impl EmuRsFsDriver for EmuRsUstarFs {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    /// Made by `fixtures/make.sh` with GNU tar
+    const HOST_ARCHIVE: &[u8] = include_bytes!("../../fixtures/ustar.tar");
+
+    fn mount(archive: &[u8]) -> Result<EmuRsUstarFs, EmuRsError> {
+        let mut fs = EmuRsUstarFs::default();
+        fs.mount(Rc::new(RefCell::new(VecDisk(archive.to_vec()))))?;
+        return Ok(fs);
+    }
+
+    fn path(path: &str) -> EmuRsPath {
+        return EmuRsPath::from_str(path).unwrap();
+    }
+
+    fn read_all(fs: &mut EmuRsUstarFs, file: &str) -> Vec<u8> {
+        let mut data = vec![0; fs.metadata(&path(file)).unwrap().size.unwrap()];
+        fs.read(&path(file), &mut data, 0).unwrap();
+        return data;
+    }
+
+    #[test]
+    fn reads_a_host_archive() {
+        let mut fs = mount(HOST_ARCHIVE).unwrap();
+
+        assert_eq!(
+            fs.list_directory(&path("ROOT/games")).unwrap().as_slice(),
+            [
+                path("ROOT/games/first.bin"),
+                path("ROOT/games/gba"),
+                path("ROOT/games/second.bin")
+            ]
+        );
+        assert_eq!(
+            read_all(&mut fs, "ROOT/games/gba/hello.txt"),
+            b"hello tar\n"
+        );
+
+        // The hard link has the data of what it links to
+        assert_eq!(read_all(&mut fs, "ROOT/games/second.bin"), b"shared data\n");
+
+        let metadata = fs.metadata(&path("ROOT/games/gba")).unwrap();
+        assert_eq!(metadata.kind, Some(EmuRsFileKind::Folder));
+        assert_eq!(
+            metadata.modification_time.unwrap().unix_timestamp(),
+            1700000000
+        );
+    }
+
+    #[test]
+    fn reads_names_split_into_the_prefix() {
+        let mut fs = mount(HOST_ARCHIVE).unwrap();
+        let directory = format!("ROOT/{}/{}", "a".repeat(60), "b".repeat(60));
+
+        assert_eq!(
+            read_all(&mut fs, &format!("{}/file.txt", directory)),
+            b"long name\n"
+        );
+        assert_eq!(fs.list_directory(&path(&directory)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let long_name = format!("{}/{}/file.txt", "a".repeat(60), "b".repeat(60));
+        let header = EmuRsUstarHeader {
+            name: long_name.clone(),
+            link_name: "linked".into(),
+            size: 1234,
+            modification_time: 1700000000,
+            kind: b'0',
+        };
+
+        let block = header.to_block().unwrap();
+        // Too long for the name field by itself, so part of it went in the prefix
+        assert_eq!(&block[345..405], "a".repeat(60).as_bytes());
+
+        let parsed = EmuRsUstarHeader::parse(&block).unwrap().unwrap();
+        assert_eq!(parsed.name, long_name);
+        assert_eq!(parsed.link_name, "linked");
+        assert_eq!(parsed.size, 1234);
+        assert_eq!(parsed.modification_time, 1700000000);
+        assert_eq!(parsed.padded_size(), 1536);
+
+        // Nothing to split on that leaves both halves short enough
+        let header = EmuRsUstarHeader {
+            name: "c".repeat(101),
+            ..header
+        };
+        assert!(header.to_block().is_err());
+
+        assert!(EmuRsUstarHeader::parse(&[0; USTAR_BLOCK_SIZE])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let mut archive = HOST_ARCHIVE.to_vec();
+        // Somewhere in the name of the first header
+        archive[3] ^= 1;
+
+        assert!(matches!(
+            mount(&archive),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+    }
+
+    #[test]
+    fn reading_past_the_end_fails() {
+        let mut fs = mount(HOST_ARCHIVE).unwrap();
+        let file = path("ROOT/games/gba/hello.txt");
+        let mut buffer = [0; 4];
+
+        fs.read(&file, &mut buffer, 6).unwrap();
+        assert_eq!(&buffer, b"tar\n");
+
+        for offset in [7, 1000, usize::MAX] {
+            assert!(matches!(
+                fs.read(&file, &mut buffer, offset),
+                Err(EmuRsError {
+                    reason: EmuRsErrorReason::EndOfFileHit
+                })
+            ));
+        }
+    }
+
+    /// The host archive with room after it to write into, and the disk so it can be mounted again
+    fn writable(size: usize) -> (Rc<RefCell<VecDisk>>, EmuRsUstarFs) {
+        let mut archive = HOST_ARCHIVE.to_vec();
+        archive.resize(size, 0);
+
+        let disk = Rc::new(RefCell::new(VecDisk(archive)));
+        let mut fs = EmuRsUstarFs::default();
+        fs.mount(disk.clone()).unwrap();
+        return (disk, fs);
+    }
+
+    fn remount(disk: &Rc<RefCell<VecDisk>>) -> EmuRsUstarFs {
+        let mut fs = EmuRsUstarFs::default();
+        fs.mount(disk.clone()).unwrap();
+        return fs;
+    }
+
+    #[test]
+    fn appends_new_files() {
+        let (disk, mut fs) = writable(16384);
+        let file = path("ROOT/games/gba/new.txt");
+
+        fs.create(&file).unwrap();
+        fs.write(&file, b"new file", 0).unwrap();
+        assert!(matches!(
+            fs.create(&file),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::FileAlreadyExists
+            })
+        ));
+
+        let mut fs = remount(&disk);
+        assert_eq!(read_all(&mut fs, "ROOT/games/gba/new.txt"), b"new file");
+        assert_eq!(
+            read_all(&mut fs, "ROOT/games/gba/hello.txt"),
+            b"hello tar\n"
+        );
+        assert_eq!(fs.list_directory(&path("ROOT/games/gba")).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn overwrites_in_place_and_grows_past_the_end() {
+        let (disk, mut fs) = writable(16384);
+        let file = path("ROOT/games/gba/hello.txt");
+
+        fs.write(&file, b"HELLO", 0).unwrap();
+        assert_eq!(
+            read_all(&mut remount(&disk), "ROOT/games/gba/hello.txt"),
+            b"HELLO tar\n"
+        );
+
+        // Past the end leaves a hole of zeros
+        fs.write(&file, b"!!", 12).unwrap();
+        let mut fs = remount(&disk);
+        assert_eq!(
+            read_all(&mut fs, "ROOT/games/gba/hello.txt"),
+            b"HELLO tar\n\0\0!!"
+        );
+
+        assert!(matches!(
+            fs.write(&file, b"!", usize::MAX),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::OutOfSpace
+            })
+        ));
+    }
+
+    #[test]
+    fn compacts_once_the_disk_is_full() {
+        // Just enough room for a few more versions of the file
+        let (disk, mut fs) = writable(HOST_ARCHIVE.len() + USTAR_BLOCK_SIZE * 6);
+        let file = path("ROOT/games/gba/hello.txt");
+
+        for length in 11..40 {
+            fs.write(&file, &[b'x'], length - 1).unwrap();
+        }
+
+        let mut fs = remount(&disk);
+        let data = read_all(&mut fs, "ROOT/games/gba/hello.txt");
+        assert_eq!(data.len(), 39);
+        assert!(data[10..].iter().all(|byte| *byte == b'x'));
+        assert_eq!(read_all(&mut fs, "ROOT/games/second.bin"), b"shared data\n");
+
+        // Something bigger than the whole disk can't fit however much is thrown away
+        assert!(matches!(
+            fs.write(&file, &[0; USTAR_BLOCK_SIZE * 8], 0),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::OutOfSpace
+            })
+        ));
+    }
+
+    #[test]
+    fn deleting_a_link_target_keeps_the_links() {
+        let mut archive = Vec::new();
+        let mut add = |name: &str, link_name: &str, data: &[u8]| {
+            let header = EmuRsUstarHeader {
+                name: name.into(),
+                link_name: link_name.into(),
+                size: data.len(),
+                modification_time: 0,
+                kind: if link_name.is_empty() { b'0' } else { b'1' },
+            };
+            archive.extend_from_slice(&header.to_block().unwrap());
+            archive.extend_from_slice(data);
+            archive.resize(archive.len().next_multiple_of(USTAR_BLOCK_SIZE), 0);
+        };
+        add("a.bin", "", b"shared");
+        add("b.bin", "a.bin", b"");
+        add("c.bin", "a.bin", b"");
+        add("d.bin", "", b"other");
+        archive.resize(8192, 0);
+
+        let disk = Rc::new(RefCell::new(VecDisk(archive)));
+        let mut fs = remount(&disk);
+        fs.delete(&path("ROOT/a.bin")).unwrap();
+
+        for fs in [&mut fs, &mut remount(&disk)] {
+            assert_eq!(
+                fs.list_directory(&EmuRsPath::default()).unwrap().as_slice(),
+                [path("ROOT/b.bin"), path("ROOT/c.bin"), path("ROOT/d.bin")]
+            );
+            assert_eq!(read_all(fs, "ROOT/b.bin"), b"shared");
+            assert_eq!(read_all(fs, "ROOT/c.bin"), b"shared");
+            assert_eq!(read_all(fs, "ROOT/d.bin"), b"other");
+        }
+
+        // The link that took over is a real file now, so the last link can outlive it too
+        fs.delete(&path("ROOT/b.bin")).unwrap();
+        let mut fs = remount(&disk);
+        assert_eq!(read_all(&mut fs, "ROOT/c.bin"), b"shared");
+        assert!(fs.metadata(&path("ROOT/b.bin")).is_err());
+    }
+}
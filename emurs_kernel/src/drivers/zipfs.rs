@@ -0,0 +1,821 @@
+use super::fatfs::dos_date_time;
+use crate::device::EmuRsDevice;
+use crate::disk::EmuRsDiskDriver;
+use crate::driver::EmuRsDriverPreference;
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::vfs::{EmuRsFileKind, EmuRsFileMetadata};
+use crate::{
+    driver::EmuRsDriver,
+    vfs::{EmuRsFsDriver, EmuRsPath},
+};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec;
+use core::cell::RefCell;
+use core::str::FromStr;
+use crc32fast::Hasher;
+use miniz_oxide::inflate::core::inflate_flags::TINFL_FLAG_HAS_MORE_INPUT;
+use miniz_oxide::inflate::core::{decompress, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+use time::OffsetDateTime;
+use tinyvec::TinyVec;
+
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE: usize = 56;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+const CENTRAL_DIRECTORY_ENTRY_SIZE: usize = 46;
+const LOCAL_HEADER_SIZE: usize = 30;
+
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+const UNICODE_PATH_EXTRA_FIELD: u16 = 0x7075;
+
+const FLAG_ENCRYPTED: u16 = 1 << 0;
+const FLAG_UTF8: u16 = 1 << 11;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+/// Deflate can reach back this far, so the decompressor needs a window at least this big
+const DEFLATE_WINDOW_SIZE: usize = 32 * 1024;
+/// How much compressed data is pulled off the disk at once
+const INPUT_CHUNK_SIZE: usize = 512;
+
+/// What the top half of code page 437 maps to, for archives that are not UTF-8
+#[rustfmt::skip]
+const CP437: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Where a file lives in the archive
+#[derive(Debug, Clone)]
+struct EmuRsZipEntry {
+    kind: EmuRsFileKind,
+    flags: u16,
+    method: u16,
+    crc32: u32,
+    compressed_size: usize,
+    size: usize,
+    data_offset: usize,
+    modification_time: Option<OffsetDateTime>,
+}
+
+impl EmuRsZipEntry {
+    fn directory() -> Self {
+        return Self {
+            kind: EmuRsFileKind::Folder,
+            flags: 0,
+            method: METHOD_STORED,
+            crc32: 0,
+            compressed_size: 0,
+            size: 0,
+            data_offset: 0,
+            modification_time: None,
+        };
+    }
+}
+
+/// Where we are in reading a file from start to end
+///
+/// Deflate can't be seeked, so reads carry on from the last one and only go back to the start when something earlier is asked for.
+/// Emulators read ROMs from the front so this is almost never needed
+struct EmuRsZipStream {
+    path: EmuRsPath,
+    decompressor: Box<DecompressorOxide>,
+    window: Box<[u8; DEFLATE_WINDOW_SIZE]>,
+    /// Where the decompressor writes next in the window
+    window_position: usize,
+    /// Bytes in the window that have not been handed out yet
+    pending_start: usize,
+    pending_length: usize,
+    input: [u8; INPUT_CHUNK_SIZE],
+    input_start: usize,
+    input_length: usize,
+    /// How much of the compressed data has been taken from the disk
+    compressed_position: usize,
+    /// Where in the file the next pending byte is
+    position: usize,
+    /// Gone if the file was not read from the very start, so the checksum can't be checked
+    hasher: Option<Hasher>,
+    finished: bool,
+}
+
+impl EmuRsZipStream {
+    fn new(path: EmuRsPath, position: usize) -> Self {
+        return Self {
+            path,
+            decompressor: Box::default(),
+            window: Box::new([0; DEFLATE_WINDOW_SIZE]),
+            window_position: 0,
+            pending_start: 0,
+            pending_length: 0,
+            input: [0; INPUT_CHUNK_SIZE],
+            input_start: 0,
+            input_length: 0,
+            compressed_position: position,
+            position,
+            hasher: if position == 0 {
+                Some(Hasher::new())
+            } else {
+                None
+            },
+            finished: false,
+        };
+    }
+
+    /// Get more of the file into the window
+    fn fill(
+        &mut self,
+        entry: &EmuRsZipEntry,
+        disk: &mut dyn EmuRsDiskDriver,
+    ) -> Result<(), EmuRsError> {
+        if entry.method == METHOD_STORED {
+            let length = (entry.size - self.position).min(DEFLATE_WINDOW_SIZE);
+
+            disk.read(
+                &mut self.window[..length],
+                entry.data_offset + self.compressed_position,
+            )?;
+            self.compressed_position += length;
+            self.pending_start = 0;
+            self.pending_length = length;
+            return Ok(());
+        }
+
+        if self.finished {
+            // The deflate stream ended before the file did
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        if self.input_length == 0 && self.compressed_position < entry.compressed_size {
+            let length = (entry.compressed_size - self.compressed_position).min(INPUT_CHUNK_SIZE);
+
+            disk.read(
+                &mut self.input[..length],
+                entry.data_offset + self.compressed_position,
+            )?;
+            self.compressed_position += length;
+            self.input_start = 0;
+            self.input_length = length;
+        }
+
+        let more_input = self.compressed_position < entry.compressed_size;
+        let (status, consumed, produced) = decompress(
+            &mut self.decompressor,
+            &self.input[self.input_start..self.input_start + self.input_length],
+            &mut self.window[..],
+            self.window_position,
+            if more_input {
+                TINFL_FLAG_HAS_MORE_INPUT
+            } else {
+                0
+            },
+        );
+
+        self.input_start += consumed;
+        self.input_length -= consumed;
+        self.pending_start = self.window_position;
+        self.pending_length = produced;
+        self.window_position = (self.window_position + produced) % DEFLATE_WINDOW_SIZE;
+
+        match status {
+            TINFLStatus::Done => self.finished = true,
+            TINFLStatus::HasMoreOutput => {}
+            TINFLStatus::NeedsMoreInput if more_input => {}
+            _ => {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem,
+                })
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Hand out the file starting at `offset`, which must not be behind the stream
+    fn read(
+        &mut self,
+        entry: &EmuRsZipEntry,
+        disk: &mut dyn EmuRsDiskDriver,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        let mut copied = 0;
+
+        while copied < buffer.len() {
+            if self.pending_length == 0 {
+                self.fill(entry, disk)?;
+                continue;
+            }
+
+            let wanted = if self.position < offset {
+                offset - self.position
+            } else {
+                buffer.len() - copied
+            };
+            let length = self.pending_length.min(wanted);
+            let bytes = &self.window[self.pending_start..self.pending_start + length];
+
+            if self.position >= offset {
+                buffer[copied..copied + length].copy_from_slice(bytes);
+                copied += length;
+            }
+
+            if let Some(hasher) = self.hasher.as_mut() {
+                hasher.update(bytes);
+            }
+
+            self.position += length;
+            self.pending_start += length;
+            self.pending_length -= length;
+
+            if self.position == entry.size {
+                self.verify(entry)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Check the whole file against the checksum stored in the archive
+    fn verify(&mut self, entry: &EmuRsZipEntry) -> Result<(), EmuRsError> {
+        if let Some(hasher) = self.hasher.take() {
+            if hasher.finalize() != entry.crc32 {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem,
+                });
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// A read only ZIP filesystem, for the ROM sets everyone already has lying around
+///
+/// The central directory is indexed when mounted. Stored and deflated files can be read, everything else is refused
+#[derive(Default)]
+pub struct EmuRsZipFs {
+    disk: Option<Rc<RefCell<dyn EmuRsDiskDriver>>>,
+    index: BTreeMap<EmuRsPath, EmuRsZipEntry>,
+    /// Only one file is decompressed at a time so the memory use stays small
+    stream: Option<EmuRsZipStream>,
+}
+
+impl EmuRsZipFs {
+    /// Find the central directory and remember where every file's data is
+    fn build_index(
+        disk: &mut dyn EmuRsDiskDriver,
+    ) -> Result<BTreeMap<EmuRsPath, EmuRsZipEntry>, EmuRsError> {
+        let mut index = BTreeMap::new();
+        index.insert(EmuRsPath::default(), EmuRsZipEntry::directory());
+
+        let (entry_count, directory_size, directory_offset) = find_central_directory(disk)?;
+        let mut directory = vec![0; directory_size];
+        disk.read(&mut directory, directory_offset)?;
+
+        let mut offset = 0;
+        for _ in 0..entry_count {
+            if offset + CENTRAL_DIRECTORY_ENTRY_SIZE > directory.len()
+                || read_u32(&directory, offset) != CENTRAL_DIRECTORY_SIGNATURE
+            {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem,
+                });
+            }
+
+            let record = &directory[offset..];
+            let name_length = read_u16(record, 28) as usize;
+            let extra_length = read_u16(record, 30) as usize;
+            let comment_length = read_u16(record, 32) as usize;
+            let record_size =
+                CENTRAL_DIRECTORY_ENTRY_SIZE + name_length + extra_length + comment_length;
+
+            if record.len() < record_size {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem,
+                });
+            }
+
+            offset += record_size;
+
+            let flags = read_u16(record, 8);
+            let raw_name =
+                &record[CENTRAL_DIRECTORY_ENTRY_SIZE..CENTRAL_DIRECTORY_ENTRY_SIZE + name_length];
+            let extra = &record[CENTRAL_DIRECTORY_ENTRY_SIZE + name_length
+                ..CENTRAL_DIRECTORY_ENTRY_SIZE + name_length + extra_length];
+
+            let mut compressed_size = read_u32(record, 20) as u64;
+            let mut size = read_u32(record, 24) as u64;
+            let mut header_offset = read_u32(record, 42) as u64;
+            let mut name = decode_name(raw_name, flags);
+
+            for (id, field) in extra_fields(extra) {
+                match id {
+                    // Only the values that overflowed are in here, in this order
+                    ZIP64_EXTRA_FIELD => {
+                        let mut values = field
+                            .chunks_exact(8)
+                            .map(|value| u64::from_le_bytes(value.try_into().unwrap()));
+
+                        if size == u32::MAX as u64 {
+                            size = values.next().ok_or(EmuRsError {
+                                reason: EmuRsErrorReason::CorruptedFilesystem,
+                            })?;
+                        }
+
+                        if compressed_size == u32::MAX as u64 {
+                            compressed_size = values.next().ok_or(EmuRsError {
+                                reason: EmuRsErrorReason::CorruptedFilesystem,
+                            })?;
+                        }
+
+                        if header_offset == u32::MAX as u64 {
+                            header_offset = values.next().ok_or(EmuRsError {
+                                reason: EmuRsErrorReason::CorruptedFilesystem,
+                            })?;
+                        }
+                    }
+                    // Info-ZIP stores a UTF-8 name here when the real one is not, as long as it still matches
+                    UNICODE_PATH_EXTRA_FIELD
+                        if field.len() > 5
+                            && field[0] == 1
+                            && crc32fast::hash(raw_name) == read_u32(field, 1) =>
+                    {
+                        if let Ok(unicode_name) = core::str::from_utf8(&field[5..]) {
+                            name = unicode_name.into();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            let kind = if name.ends_with('/') {
+                EmuRsFileKind::Folder
+            } else {
+                EmuRsFileKind::File
+            };
+
+            // Names like `../../etc` are not going anywhere
+            let path = match EmuRsPath::from_str(&format!("ROOT/{}", name.trim_end_matches('/')))
+                .and_then(|path| path.normalize())
+            {
+                Ok(path) if !path.is_root() => path,
+                _ => continue,
+            };
+
+            let entry = match kind {
+                EmuRsFileKind::Folder => EmuRsZipEntry {
+                    modification_time: dos_date_time(read_u16(record, 14), read_u16(record, 12)),
+                    ..EmuRsZipEntry::directory()
+                },
+                _ => EmuRsZipEntry {
+                    kind,
+                    flags,
+                    method: read_u16(record, 10),
+                    crc32: read_u32(record, 16),
+                    compressed_size: compressed_size as usize,
+                    size: size as usize,
+                    data_offset: local_data_offset(disk, header_offset as usize)?,
+                    modification_time: dos_date_time(read_u16(record, 14), read_u16(record, 12)),
+                },
+            };
+
+            // Archives are not required to have entries for every directory
+            let mut parent = path.parent();
+            while let Some(directory) = parent {
+                if index.contains_key(&directory) {
+                    break;
+                }
+
+                index.insert(directory.clone(), EmuRsZipEntry::directory());
+                parent = directory.parent();
+            }
+
+            index.insert(path, entry);
+        }
+
+        return Ok(index);
+    }
+
+    fn entry(&self, file: &EmuRsPath) -> Result<&EmuRsZipEntry, EmuRsError> {
+        return self.index.get(&file.normalize()?).ok_or(EmuRsError {
+            reason: EmuRsErrorReason::FileNotFound,
+        });
+    }
+}
+
+impl EmuRsDriver for EmuRsZipFs {
+    fn name(&self) -> &'static str {
+        return "ZIP Filesystem";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        // Filesystems only talk to disks, never hardware
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsFsDriver for EmuRsZipFs {
+    fn mount(&mut self, disk: Rc<RefCell<dyn EmuRsDiskDriver>>) -> Result<(), EmuRsError> {
+        self.index = Self::build_index(&mut *disk.borrow_mut())?;
+        self.stream = None;
+        self.disk = Some(disk);
+        return Ok(());
+    }
+
+    fn read(
+        &mut self,
+        file: &EmuRsPath,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        let path = file.normalize()?;
+        let entry = self.entry(&path)?.clone();
+
+        if entry.kind != EmuRsFileKind::File {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        if entry.flags & FLAG_ENCRYPTED != 0
+            || (entry.method != METHOD_STORED && entry.method != METHOD_DEFLATE)
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported,
+            });
+        }
+
+        if offset
+            .checked_add(buffer.len())
+            .is_none_or(|end| end > entry.size)
+        {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfFileHit,
+            });
+        }
+
+        // Stored files can jump straight to the offset, deflated ones have to start over
+        let reusable = self.stream.as_ref().is_some_and(|stream| {
+            return stream.path == path
+                && if entry.method == METHOD_STORED {
+                    stream.position == offset
+                } else {
+                    stream.position <= offset
+                };
+        });
+
+        if !reusable {
+            self.stream = Some(EmuRsZipStream::new(
+                path,
+                if entry.method == METHOD_STORED {
+                    offset
+                } else {
+                    0
+                },
+            ));
+        }
+
+        let result = self.stream.as_mut().unwrap().read(
+            &entry,
+            &mut *self.disk.as_ref().unwrap().borrow_mut(),
+            buffer,
+            offset,
+        );
+
+        // Don't carry on from a stream that went wrong
+        if result.is_err() {
+            self.stream = None;
+        }
+
+        return result;
+    }
+
+    fn list_directory(&mut self, file: &EmuRsPath) -> Result<TinyVec<[EmuRsPath; 10]>, EmuRsError> {
+        let directory = file.normalize()?;
+
+        if self.entry(&directory)?.kind != EmuRsFileKind::Folder {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        return Ok(self
+            .index
+            .keys()
+            .filter(|path| {
+                return path.parent().as_ref() == Some(&directory);
+            })
+            .cloned()
+            .collect());
+    }
+
+    fn metadata(&mut self, file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
+        let entry = self.entry(file)?;
+
+        return Ok(EmuRsFileMetadata {
+            size: Some(entry.size),
+            modification_time: entry.modification_time,
+            kind: Some(entry.kind),
+            rom: None,
+        });
+    }
+}
+
+/// Find the end of central directory record, which sits behind a comment of up to 64k at the end of the archive
+///
+/// Returns how many entries there are, how big the central directory is and where it is
+fn find_central_directory(
+    disk: &mut dyn EmuRsDiskDriver,
+) -> Result<(usize, usize, usize), EmuRsError> {
+    let disk_size = disk.get_total_size();
+
+    if disk_size < END_OF_CENTRAL_DIRECTORY_SIZE {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        });
+    }
+
+    let tail_size = disk_size.min(END_OF_CENTRAL_DIRECTORY_SIZE + u16::MAX as usize);
+    let tail_offset = disk_size - tail_size;
+    let mut tail = vec![0; tail_size];
+    disk.read(&mut tail, tail_offset)?;
+
+    let record_offset = (0..=tail_size - END_OF_CENTRAL_DIRECTORY_SIZE)
+        .rev()
+        .find(|offset| {
+            return read_u32(&tail, *offset) == END_OF_CENTRAL_DIRECTORY_SIGNATURE
+                && offset + END_OF_CENTRAL_DIRECTORY_SIZE + read_u16(&tail, offset + 20) as usize
+                    == tail_size;
+        })
+        .ok_or(EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        })?;
+    let record = &tail[record_offset..];
+
+    // Archives split over several disks are not something we can put back together
+    if read_u16(record, 4) != 0 && read_u16(record, 4) != u16::MAX {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::OperationNotSupported,
+        });
+    }
+
+    let mut entry_count = read_u16(record, 10) as u64;
+    let mut directory_size = read_u32(record, 12) as u64;
+    let mut directory_offset = read_u32(record, 16) as u64;
+
+    // ZIP64 archives leave a locator right before the normal record pointing at the real one
+    let record_offset = tail_offset + record_offset;
+    if record_offset >= ZIP64_LOCATOR_SIZE {
+        let mut locator = [0; ZIP64_LOCATOR_SIZE];
+        disk.read(&mut locator, record_offset - ZIP64_LOCATOR_SIZE)?;
+
+        if read_u32(&locator, 0) == ZIP64_LOCATOR_SIGNATURE {
+            let mut record = [0; ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE];
+            disk.read(&mut record, read_u64(&locator, 8) as usize)?;
+
+            if read_u32(&record, 0) != ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem,
+                });
+            }
+
+            if read_u32(&record, 16) != 0 {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::OperationNotSupported,
+                });
+            }
+
+            entry_count = read_u64(&record, 32);
+            directory_size = read_u64(&record, 40);
+            directory_offset = read_u64(&record, 48);
+        }
+    }
+
+    if directory_offset + directory_size > disk_size as u64 {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        });
+    }
+
+    return Ok((
+        entry_count as usize,
+        directory_size as usize,
+        directory_offset as usize,
+    ));
+}
+
+/// The local header repeats most of the central directory, but its extra field can be a different size
+fn local_data_offset(
+    disk: &mut dyn EmuRsDiskDriver,
+    header_offset: usize,
+) -> Result<usize, EmuRsError> {
+    let mut header = [0; LOCAL_HEADER_SIZE];
+    disk.read(&mut header, header_offset)?;
+
+    if read_u32(&header, 0) != LOCAL_HEADER_SIGNATURE {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        });
+    }
+
+    return Ok(header_offset
+        + LOCAL_HEADER_SIZE
+        + read_u16(&header, 26) as usize
+        + read_u16(&header, 28) as usize);
+}
+
+/// Names are UTF-8 if the archiver said so, and code page 437 otherwise
+fn decode_name(name: &[u8], flags: u16) -> String {
+    if flags & FLAG_UTF8 != 0 {
+        return String::from_utf8_lossy(name).into();
+    }
+
+    return name
+        .iter()
+        .map(|byte| {
+            if *byte < 0x80 {
+                return *byte as char;
+            }
+
+            return CP437[(*byte - 0x80) as usize];
+        })
+        .collect();
+}
+
+/// Split a extra field into its id and data pairs
+fn extra_fields(mut extra: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    return core::iter::from_fn(move || {
+        if extra.len() < 4 {
+            return None;
+        }
+
+        let id = read_u16(extra, 0);
+        let length = (read_u16(extra, 2) as usize).min(extra.len() - 4);
+        let field = &extra[4..4 + length];
+        extra = &extra[4 + length..];
+
+        return Some((id, field));
+    });
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    return u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    return u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    // Both made by Info-ZIP, see fixtures/make.sh
+    const ZIP: &[u8] = include_bytes!("../../fixtures/zip.zip");
+    const ZIP64: &[u8] = include_bytes!("../../fixtures/zip64.zip");
+    const STORED: &[u8] = b"stored, not squashed\n";
+
+    fn mount(bytes: &[u8]) -> EmuRsZipFs {
+        let mut fs = EmuRsZipFs::default();
+        fs.mount(Rc::new(RefCell::new(VecDisk(bytes.to_vec()))))
+            .unwrap();
+        return fs;
+    }
+
+    fn deflated() -> Vec<u8> {
+        return (1..=20000)
+            .flat_map(|number| format!("{}\n", number).into_bytes())
+            .collect();
+    }
+
+    fn read_all(fs: &mut EmuRsZipFs, path: &str) -> Result<Vec<u8>, EmuRsError> {
+        let path = EmuRsPath::from_str(path).unwrap();
+        let mut buffer = vec![0; fs.metadata(&path)?.size.unwrap()];
+        fs.read(&path, &mut buffer, 0)?;
+        return Ok(buffer);
+    }
+
+    fn check_archive(fs: &mut EmuRsZipFs) {
+        let mut listed = fs
+            .list_directory(&EmuRsPath::from_str("ROOT/roms").unwrap())
+            .unwrap()
+            .iter()
+            .map(|path| path.to_string())
+            .collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(listed, ["ROOT/roms/deflated.txt", "ROOT/roms/stored.txt"]);
+
+        assert_eq!(read_all(fs, "ROOT/roms/stored.txt").unwrap(), STORED);
+        assert_eq!(read_all(fs, "ROOT/roms/deflated.txt").unwrap(), deflated());
+
+        // Reading a deflated file in pieces keeps going from the same stream
+        let path = EmuRsPath::from_str("ROOT/roms/deflated.txt").unwrap();
+        let expected = deflated();
+        let mut buffer = vec![0; 1000];
+        for offset in [0, 1000, 50000, 100000] {
+            fs.read(&path, &mut buffer, offset).unwrap();
+            assert_eq!(buffer, expected[offset..offset + 1000]);
+        }
+
+        // Going backwards starts over
+        fs.read(&path, &mut buffer, 10).unwrap();
+        assert_eq!(buffer, expected[10..1010]);
+
+        assert!(matches!(
+            fs.read(&path, &mut buffer, expected.len() - 10),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfFileHit
+            })
+        ));
+        assert!(matches!(
+            fs.read(&path, &mut buffer, usize::MAX),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfFileHit
+            })
+        ));
+    }
+
+    #[test]
+    fn reads_stored_and_deflated_files() {
+        check_archive(&mut mount(ZIP));
+    }
+
+    #[test]
+    fn reads_zip64_archives() {
+        assert!(ZIP64
+            .windows(4)
+            .any(|window| window == ZIP64_LOCATOR_SIGNATURE.to_le_bytes()));
+        check_archive(&mut mount(ZIP64));
+    }
+
+    #[test]
+    fn rejects_crc_mismatches() {
+        let position = ZIP
+            .windows(STORED.len())
+            .position(|window| window == STORED)
+            .unwrap();
+        let mut bytes = ZIP.to_vec();
+        bytes[position] ^= 0x20;
+
+        let mut fs = mount(&bytes);
+        assert!(matches!(
+            read_all(&mut fs, "ROOT/roms/stored.txt"),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+
+        // A deflated file only finds out once it gets to the end. The central directory's copy of
+        // the CRC is the one that gets checked
+        let crc = fs
+            .entry(&EmuRsPath::from_str("ROOT/roms/deflated.txt").unwrap())
+            .unwrap()
+            .crc32
+            .to_le_bytes();
+        let mut bytes = ZIP.to_vec();
+        let position = bytes.windows(4).rposition(|window| window == crc).unwrap();
+        bytes[position] ^= 0xff;
+
+        let mut fs = mount(&bytes);
+        assert!(matches!(
+            read_all(&mut fs, "ROOT/roms/deflated.txt"),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+    }
+}
@@ -7,7 +7,32 @@ pub enum EmuRsErrorReason {
     Unknown,
     OperationNotSupported,
     InvalidPath,
+    FileNotFound,
+    FileAlreadyExists,
+    DirectoryNotEmpty,
+    OutOfSpace,
     EndOfDiskHit,
+    EndOfFileHit,
+    CorruptedFilesystem,
+    /// Firmware that roms.db has no record of, so it can never be verified
+    UnknownFirmware(String),
+    /// Firmware roms.db knows about but that isn't anywhere we looked
+    MissingFirmware(String),
+    /// Something that should be the firmware is there but it doesn't match roms.db
+    BadFirmwareDump(String),
+    /// A patch that was made for some other file than the one it is being applied to
+    PatchSourceMismatch,
+    /// A block device was asked for part of a sector
+    UnalignedAccess,
+    /// Hardware never finished what it was asked to do
+    HardwareTimeout,
+    /// Hardware said it couldn't do what it was asked to
+    HardwareFault,
+    /// A block of a disk doesn't match its checksum anymore, so what is on it is corrupted. Has the
+    /// number of the block
+    ChecksumMismatch(usize),
+    /// A subsystem was used before it was given the context
+    NotInitialized,
 }
 
 #[derive(Clone, Debug)]
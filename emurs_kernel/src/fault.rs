@@ -0,0 +1,357 @@
+use crate::device::EmuRsDevice;
+use crate::disk::{EmuRsDiskDriver, EmuRsDiskProperties};
+use crate::driver::{EmuRsDriver, EmuRsDriverPreference};
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::time::Duration;
+use tinyvec::TinyVec;
+
+// For testing how filesystems hold up on bad hardware. Everything random comes from the seed, so a
+// run that goes wrong can be run again exactly the same
+
+/// What can go wrong and how often. Rates go from 0 for never to 1 for every time
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct EmuRsFaultConfig {
+    pub read_error_rate: f32,
+    pub write_error_rate: f32,
+    /// The power going out partway through a write. Only the start of it lands, and the disk is
+    /// gone until [EmuRsFaultDisk::power_on]
+    pub torn_write_rate: f32,
+    /// A bit of what a read returns being wrong, without what is stored changing
+    pub read_bit_flip_rate: f32,
+    /// A bit of what a write stores being wrong
+    pub write_bit_flip_rate: f32,
+    /// How long every access takes
+    pub latency: Duration,
+    /// How much longer every byte makes a access take
+    pub latency_per_byte: Duration,
+    /// Up to how much longer a access randomly takes
+    pub jitter: Duration,
+}
+
+/// What has gone wrong so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EmuRsFaultStats {
+    pub read_errors: usize,
+    pub write_errors: usize,
+    pub torn_writes: usize,
+    pub bit_flips: usize,
+    /// How long all the accesses would have taken
+    pub elapsed: Duration,
+}
+
+/// A disk that fails, flips bits and takes its time, as told by a [EmuRsFaultConfig]
+///
+/// Failed accesses give [EmuRsErrorReason::HardwareFault]
+pub struct EmuRsFaultDisk {
+    disk: Rc<RefCell<dyn EmuRsDiskDriver>>,
+    pub config: EmuRsFaultConfig,
+    /// Called with how long each access takes, for really waiting. Without it the time is only
+    /// added up in the stats
+    pub delay: Option<fn(Duration)>,
+    stats: EmuRsFaultStats,
+    powered: bool,
+    /// xorshift64* state
+    random: u64,
+}
+
+impl EmuRsFaultDisk {
+    pub fn new(
+        disk: Rc<RefCell<dyn EmuRsDiskDriver>>,
+        config: EmuRsFaultConfig,
+        seed: u64,
+    ) -> Self {
+        return Self {
+            disk,
+            config,
+            delay: None,
+            stats: EmuRsFaultStats::default(),
+            powered: true,
+            // xorshift gets stuck on zero
+            random: seed ^ 0x9e3779b97f4a7c15,
+        };
+    }
+
+    pub fn stats(&self) -> EmuRsFaultStats {
+        return self.stats;
+    }
+
+    pub fn is_powered(&self) -> bool {
+        return self.powered;
+    }
+
+    /// Bring the disk back after a torn write cut the power
+    pub fn power_on(&mut self) {
+        self.powered = true;
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.random ^= self.random >> 12;
+        self.random ^= self.random << 25;
+        self.random ^= self.random >> 27;
+        return self.random.wrapping_mul(0x2545f4914f6cdd1d);
+    }
+
+    /// A number from 0 up to but not including `below`
+    fn random_below(&mut self, below: usize) -> usize {
+        return (self.next_random() % below.max(1) as u64) as usize;
+    }
+
+    /// Roll for something happening. This always takes a number, so changing a rate doesn't
+    /// change what happens with the others
+    fn chance(&mut self, rate: f32) -> bool {
+        return self.roll() < rate;
+    }
+
+    /// A number from 0 up to but not including 1
+    fn roll(&mut self) -> f32 {
+        return (self.next_random() >> 40) as f32 / (1 << 24) as f32;
+    }
+
+    fn flip_bit(&mut self, buffer: &mut [u8]) {
+        if buffer.is_empty() {
+            return;
+        }
+
+        let bit = self.random_below(buffer.len() * 8);
+        buffer[bit / 8] ^= 1 << (bit % 8);
+        self.stats.bit_flips += 1;
+    }
+
+    fn wait(&mut self, length: usize) {
+        let jitter = self.config.jitter.mul_f32(self.roll());
+        let time = self.config.latency + self.config.latency_per_byte * length as u32 + jitter;
+
+        self.stats.elapsed += time;
+        if let Some(delay) = self.delay {
+            delay(time);
+        }
+    }
+
+    fn check_powered(&self) -> Result<(), EmuRsError> {
+        if !self.powered {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::HardwareFault,
+            });
+        }
+
+        return Ok(());
+    }
+}
+
+impl EmuRsDriver for EmuRsFaultDisk {
+    fn name(&self) -> &'static str {
+        return "Fault Injection Disk";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        // The disk it is on already claimed the hardware
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsDiskDriver for EmuRsFaultDisk {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        self.check_powered()?;
+        self.wait(buffer.len());
+
+        if self.chance(self.config.read_error_rate) {
+            self.stats.read_errors += 1;
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::HardwareFault,
+            });
+        }
+
+        self.disk.borrow_mut().read(buffer, offset)?;
+
+        if self.chance(self.config.read_bit_flip_rate) {
+            self.flip_bit(buffer);
+        }
+
+        return Ok(());
+    }
+
+    fn write(&mut self, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        self.check_powered()?;
+        self.wait(buffer.len());
+
+        if self.chance(self.config.write_error_rate) {
+            self.stats.write_errors += 1;
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::HardwareFault,
+            });
+        }
+
+        if self.chance(self.config.torn_write_rate) {
+            let length = self.random_below(buffer.len());
+            self.disk.borrow_mut().write(&buffer[..length], offset)?;
+
+            self.stats.torn_writes += 1;
+            self.powered = false;
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::HardwareFault,
+            });
+        }
+
+        if self.chance(self.config.write_bit_flip_rate) {
+            let mut flipped = Vec::from(buffer);
+            self.flip_bit(&mut flipped);
+            return self.disk.borrow_mut().write(&flipped, offset);
+        }
+
+        return self.disk.borrow_mut().write(buffer, offset);
+    }
+
+    fn get_sector_size(&mut self) -> usize {
+        return self.disk.borrow_mut().get_sector_size();
+    }
+
+    fn get_total_size(&mut self) -> usize {
+        return self.disk.borrow_mut().get_total_size();
+    }
+
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        return self.disk.borrow_mut().get_properties();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use alloc::vec;
+
+    fn fault_disk(config: EmuRsFaultConfig, seed: u64) -> (Rc<RefCell<VecDisk>>, EmuRsFaultDisk) {
+        let disk = Rc::new(RefCell::new(VecDisk(vec![0; 4096])));
+        let fault_disk = EmuRsFaultDisk::new(disk.clone(), config, seed);
+        return (disk, fault_disk);
+    }
+
+    /// Do a bunch of accesses and write down how each went
+    fn run(seed: u64) -> (Vec<Option<u8>>, Vec<u8>, EmuRsFaultStats) {
+        let config = EmuRsFaultConfig {
+            read_error_rate: 0.1,
+            write_error_rate: 0.1,
+            torn_write_rate: 0.05,
+            read_bit_flip_rate: 0.1,
+            write_bit_flip_rate: 0.1,
+            jitter: Duration::from_micros(500),
+            ..Default::default()
+        };
+        let (disk, mut fault_disk) = fault_disk(config, seed);
+        let mut results = Vec::new();
+
+        for index in 0..500 {
+            let offset = (index * 37) % 4000;
+            let mut buffer = [index as u8; 64];
+
+            let result = if index % 2 == 0 {
+                fault_disk.write(&buffer, offset)
+            } else {
+                fault_disk.read(&mut buffer, offset)
+            };
+            results.push(result.ok().map(|_| buffer.iter().fold(0, |a, b| a ^ b)));
+
+            if !fault_disk.is_powered() {
+                fault_disk.power_on();
+            }
+        }
+
+        let contents = disk.borrow().0.clone();
+        return (results, contents, fault_disk.stats());
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let first = run(42);
+
+        assert_eq!(first, run(42));
+        assert_ne!(first, run(43));
+
+        let stats = first.2;
+        assert!(stats.read_errors > 0 && stats.write_errors > 0);
+        assert!(stats.torn_writes > 0 && stats.bit_flips > 0);
+    }
+
+    #[test]
+    fn nothing_happens_by_default() {
+        let (disk, mut fault_disk) = fault_disk(EmuRsFaultConfig::default(), 1);
+
+        for index in 0..100 {
+            fault_disk.write(&[index as u8; 40], index * 40).unwrap();
+        }
+
+        let mut buffer = vec![0; 4000];
+        fault_disk.read(&mut buffer, 0).unwrap();
+        assert_eq!(buffer, disk.borrow().0[..4000]);
+        assert_eq!(
+            fault_disk.stats(),
+            EmuRsFaultStats::default(),
+            "no faults and no time"
+        );
+    }
+
+    #[test]
+    fn torn_writes_land_partly_and_cut_the_power() {
+        let config = EmuRsFaultConfig {
+            torn_write_rate: 1.0,
+            ..Default::default()
+        };
+        let (disk, mut fault_disk) = fault_disk(config, 7);
+
+        let result = fault_disk.write(&[0xff; 1000], 100);
+        assert!(matches!(
+            result.map_err(|error| error.reason),
+            Err(EmuRsErrorReason::HardwareFault)
+        ));
+
+        let landed = disk.borrow().0.iter().filter(|byte| **byte == 0xff).count();
+        assert!(landed < 1000);
+        assert!(disk.borrow().0[100..100 + landed]
+            .iter()
+            .all(|byte| *byte == 0xff));
+
+        assert!(fault_disk.read(&mut [0; 1], 0).is_err());
+        fault_disk.power_on();
+        assert!(fault_disk.read(&mut [0; 1], 0).is_ok());
+    }
+
+    #[test]
+    fn bit_flips_change_one_bit() {
+        let config = EmuRsFaultConfig {
+            read_bit_flip_rate: 1.0,
+            ..Default::default()
+        };
+        let (disk, mut fault_disk) = fault_disk(config, 3);
+
+        let mut buffer = [0; 256];
+        fault_disk.read(&mut buffer, 0).unwrap();
+
+        let flipped: u32 = buffer.iter().map(|byte| byte.count_ones()).sum();
+        assert_eq!(flipped, 1);
+        assert!(disk.borrow().0.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn latency_adds_up() {
+        let config = EmuRsFaultConfig {
+            latency: Duration::from_millis(1),
+            latency_per_byte: Duration::from_micros(1),
+            ..Default::default()
+        };
+        let (_, mut fault_disk) = fault_disk(config, 5);
+
+        fault_disk.read(&mut [0; 100], 0).unwrap();
+        fault_disk.write(&[0; 400], 0).unwrap();
+
+        assert_eq!(fault_disk.stats().elapsed, Duration::from_micros(2500));
+    }
+}
@@ -0,0 +1,315 @@
+use crate::driver::EmuRsDriver;
+use crate::drivers::gamefs::EmuRsGameFs;
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::romdb::EmuRsRomDatabase;
+use crate::subsystem::EmuRsSubsystem;
+use crate::vfs::EmuRsPath;
+use crate::EmuRsContext;
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use blake2::{Blake2s256, Digest};
+use core::str::FromStr;
+
+/// Finds the firmware programs ask for and makes sure it is a good dump before they get it
+///
+/// Firmware is named by its title in roms.db. It is found by hash, so the file itself can be called anything
+#[derive(Clone)]
+pub struct EmuRsFirmwareSubsystem {
+    os_context: Option<Rc<EmuRsContext>>,
+    pub database_path: EmuRsPath,
+    /// Where firmware is looked for, not including subdirectories
+    pub search_paths: Vec<EmuRsPath>,
+    /// Where to keep the hashes of everything in the search paths between boots
+    pub index_path: Option<EmuRsPath>,
+    scanner: EmuRsGameFs,
+    loaded: BTreeMap<String, Rc<[u8]>>,
+}
+
+impl Default for EmuRsFirmwareSubsystem {
+    fn default() -> Self {
+        return Self {
+            os_context: None,
+            database_path: EmuRsPath::from_str("ROOT/roms.db").unwrap(),
+            search_paths: vec![EmuRsPath::from_str("ROOT/roms").unwrap()],
+            index_path: Some(EmuRsPath::from_str("ROOT/firmware.cbor").unwrap()),
+            scanner: EmuRsGameFs::default(),
+            loaded: BTreeMap::new(),
+        };
+    }
+}
+
+impl EmuRsFirmwareSubsystem {
+    /// Find and verify every firmware in `names`, stopping at the first one that can't be used
+    pub fn resolve(&mut self, names: &[&str]) -> Result<(), EmuRsError> {
+        let names: Vec<&str> = names
+            .iter()
+            .copied()
+            .filter(|name| !self.loaded.contains_key(*name))
+            .collect();
+
+        if names.is_empty() {
+            return Ok(());
+        }
+
+        let context = self.os_context.clone().unwrap();
+
+        // Without roms.db nothing can be verified, which makes every firmware unknown
+        let records = EmuRsRomDatabase::open(context.clone(), self.database_path.clone())
+            .and_then(|database| database.records())
+            .unwrap_or_default();
+
+        // A search path that doesn't exist just has nothing in it
+        self.scanner.search_paths = self
+            .search_paths
+            .iter()
+            .filter(|path| context.fs.borrow().global().metadata(path).is_ok())
+            .cloned()
+            .collect();
+        self.scanner.index_path = self.index_path.clone();
+
+        for name in names {
+            let known: Vec<_> = records
+                .iter()
+                .filter(|record| record.firmware && record.title == name)
+                .collect();
+
+            if known.is_empty() {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::UnknownFirmware(name.to_string()),
+                });
+            }
+
+            let mut found = None;
+            for record in known {
+                if let Some(path) = self.scanner.find(&record.hash)? {
+                    found = Some((record, path));
+                    break;
+                }
+            }
+
+            let (record, path) = match found {
+                Some(found) => found,
+                // A file with the right name but a hash we don't know is most likely a bad dump
+                None if self.find_by_name(name)?.is_some() => {
+                    return Err(EmuRsError {
+                        reason: EmuRsErrorReason::BadFirmwareDump(name.to_string()),
+                    });
+                }
+                None => {
+                    return Err(EmuRsError {
+                        reason: EmuRsErrorReason::MissingFirmware(name.to_string()),
+                    });
+                }
+            };
+
+            let fs = context.fs.borrow();
+            let fs = fs.global();
+            let mut data = vec![0; fs.metadata(&path)?.size.unwrap_or(0)];
+            fs.read(&path, &mut data, 0)?;
+
+            // The scan only rehashes files that look changed, so check what we actually read
+            if Blake2s256::digest(&data).as_slice() != record.hash {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::BadFirmwareDump(name.to_string()),
+                });
+            }
+
+            self.loaded.insert(name.to_string(), data.into());
+        }
+
+        return Ok(());
+    }
+
+    /// The contents of firmware that has been resolved
+    pub fn get(&self, name: &str) -> Option<Rc<[u8]>> {
+        return self.loaded.get(name).cloned();
+    }
+
+    /// Forget everything that has been resolved, so it is read and checked again next time
+    pub fn clear(&mut self) {
+        self.loaded.clear();
+    }
+
+    /// A file in the search paths called `name`, whatever the extension
+    fn find_by_name(&self, name: &str) -> Result<Option<EmuRsPath>, EmuRsError> {
+        let fs = self.os_context.as_ref().unwrap().fs.borrow();
+        let fs = fs.global();
+
+        for directory in self.search_paths.iter() {
+            let files = match fs.list_directory(directory) {
+                Ok(files) => files,
+                Err(_) => continue,
+            };
+
+            let found = files.into_iter().find(|file| {
+                let file_name = file.file_name();
+                let stem = file_name
+                    .rsplit_once('.')
+                    .map_or(file_name.as_str(), |(stem, _)| stem);
+                return stem == name;
+            });
+
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+
+        return Ok(None);
+    }
+}
+
+impl EmuRsSubsystem for EmuRsFirmwareSubsystem {
+    fn init(&mut self, context: Rc<EmuRsContext>) {
+        self.scanner.init(context.clone());
+        self.os_context = Some(context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use crate::drivers::cborfs::EmuRsCborFs;
+    use crate::romdb::{EmuRsRomDatabaseWriter, EmuRsRomRecord};
+    use crate::vfs::{EmuRsFsDriver, EmuRsNamespace};
+    use crate::EmuRsContextBuilder;
+    use core::cell::RefCell;
+
+    const GOOD_DUMP: &[u8] = &[0x18; 1024];
+
+    /// roms.db knows `gba_bios` and `gb_bios`, but only a good dump of `gba_bios` is there
+    fn context() -> Rc<EmuRsContext> {
+        let context = EmuRsContextBuilder::default().done();
+        let disk = Rc::new(RefCell::new(VecDisk(vec![0; 32768])));
+        EmuRsCborFs::format(&mut *disk.borrow_mut()).unwrap();
+        let mut root = EmuRsCborFs::default();
+        root.mount(disk).unwrap();
+        context
+            .fs
+            .borrow_mut()
+            .mount(&EmuRsPath::default(), Rc::new(RefCell::new(root)))
+            .unwrap();
+
+        let mut writer = EmuRsRomDatabaseWriter::default();
+        for (title, data) in [("gba_bios", GOOD_DUMP), ("gb_bios", &[0x31; 256])] {
+            writer.add(EmuRsRomRecord {
+                hash: Blake2s256::digest(data).into(),
+                title: title.into(),
+                system: "gba".into(),
+                firmware: true,
+                ..Default::default()
+            });
+        }
+        // Games don't count, even with the same name
+        writer.add(EmuRsRomRecord {
+            hash: [0x55; 32],
+            title: "nds_bios".into(),
+            ..Default::default()
+        });
+        writer
+            .save(&context, &EmuRsPath::from_str("ROOT/roms.db").unwrap())
+            .unwrap();
+
+        let fs = context.fs.borrow();
+        fs.create_directory(&EmuRsPath::from_str("ROOT/roms").unwrap())
+            .unwrap();
+        let path = EmuRsPath::from_str("ROOT/roms/whatever.bin").unwrap();
+        fs.create(&path).unwrap();
+        fs.write(&path, GOOD_DUMP, 0).unwrap();
+        drop(fs);
+
+        return context;
+    }
+
+    fn firmware(context: &Rc<EmuRsContext>) -> EmuRsFirmwareSubsystem {
+        let mut firmware = EmuRsFirmwareSubsystem::default();
+        firmware.init(context.clone());
+        return firmware;
+    }
+
+    #[test]
+    fn resolves_good_dumps_by_hash() {
+        let context = context();
+        let mut firmware = firmware(&context);
+
+        // From inside of a program's namespace, where ROOT/roms is somewhere else entirely
+        context
+            .fs
+            .borrow_mut()
+            .swap_namespace(EmuRsNamespace::profile_saves("player").unwrap());
+
+        firmware.resolve(&["gba_bios"]).unwrap();
+        assert_eq!(firmware.get("gba_bios").as_deref(), Some(GOOD_DUMP));
+        assert_eq!(firmware.get("gb_bios"), None);
+    }
+
+    #[test]
+    fn unknown_firmware() {
+        let context = context();
+        let mut firmware = firmware(&context);
+
+        for name in ["psx_bios", "nds_bios"] {
+            assert!(matches!(
+                firmware.resolve(&["gba_bios", name]),
+                Err(EmuRsError {
+                    reason: EmuRsErrorReason::UnknownFirmware(unknown)
+                }) if unknown == name
+            ));
+        }
+
+        // Everything is unknown without roms.db
+        firmware.clear();
+        firmware.database_path = EmuRsPath::from_str("ROOT/nothing.db").unwrap();
+        assert!(matches!(
+            firmware.resolve(&["gba_bios"]),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::UnknownFirmware(_)
+            })
+        ));
+    }
+
+    #[test]
+    fn missing_firmware() {
+        let context = context();
+        let mut firmware = firmware(&context);
+
+        assert!(matches!(
+            firmware.resolve(&["gb_bios"]),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::MissingFirmware(missing)
+            }) if missing == "gb_bios"
+        ));
+
+        // A search path that isn't there is the same as a empty one
+        firmware.search_paths = vec![EmuRsPath::from_str("ROOT/bios").unwrap()];
+        assert!(matches!(
+            firmware.resolve(&["gba_bios"]),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::MissingFirmware(_)
+            })
+        ));
+    }
+
+    #[test]
+    fn bad_firmware_dump() {
+        let context = context();
+        {
+            let fs = context.fs.borrow();
+            let path = EmuRsPath::from_str("ROOT/roms/gb_bios.gb").unwrap();
+            fs.create(&path).unwrap();
+            fs.write(&path, &[0x31; 255], 0).unwrap();
+        }
+
+        let mut firmware = firmware(&context);
+        assert!(matches!(
+            firmware.resolve(&["gb_bios"]),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::BadFirmwareDump(bad)
+            }) if bad == "gb_bios"
+        ));
+        assert_eq!(firmware.get("gb_bios"), None);
+    }
+}
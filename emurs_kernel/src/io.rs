@@ -0,0 +1,361 @@
+use crate::device::EmuRsDevice;
+use crate::disk::EmuRsDiskDriver;
+use crate::driver::{EmuRsDriver, EmuRsDriverPreference};
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+// Disk access that doesn't make everything else wait, so the launcher can keep drawing while a
+// big ROM comes off of a slow card. Requests are submitted, the disk is polled to get on with
+// them, and the results are picked up when they are done
+
+/// How much [EmuRsIoQueue] does every poll unless told otherwise
+const DEFAULT_CHUNK_SIZE: usize = 4096;
+
+/// The buffer is handed over with the request and given back when it is done, filled in for
+/// reads, so nothing is borrowed while the request is in flight
+#[derive(Debug)]
+pub enum EmuRsIoRequest {
+    Read { offset: usize, buffer: Vec<u8> },
+    Write { offset: usize, buffer: Vec<u8> },
+}
+
+impl EmuRsIoRequest {
+    fn offset(&self) -> usize {
+        return match self {
+            EmuRsIoRequest::Read { offset, .. } | EmuRsIoRequest::Write { offset, .. } => *offset,
+        };
+    }
+
+    fn length(&self) -> usize {
+        return match self {
+            EmuRsIoRequest::Read { buffer, .. } | EmuRsIoRequest::Write { buffer, .. } => {
+                buffer.len()
+            }
+        };
+    }
+
+    fn into_buffer(self) -> Vec<u8> {
+        return match self {
+            EmuRsIoRequest::Read { buffer, .. } | EmuRsIoRequest::Write { buffer, .. } => buffer,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EmuRsIoTicket(pub usize);
+
+/// A disk that can be asked to do something and left to it
+///
+/// Hardware with DMA can start the transfer in [EmuRsAsyncDiskDriver::submit] and notice it
+/// finishing in [EmuRsAsyncDiskDriver::poll] or a interrupt. Everything else can go through a
+/// [EmuRsIoQueue]
+pub trait EmuRsAsyncDiskDriver: EmuRsDriver {
+    fn submit(&mut self, request: EmuRsIoRequest) -> Result<EmuRsIoTicket, EmuRsError>;
+    /// Get on with the requests for a bit. Returns if there are any left
+    fn poll(&mut self) -> bool;
+    /// Take the buffer back from a finished request. [None] while it is still going, and forever
+    /// after it has been taken once
+    fn take(&mut self, ticket: EmuRsIoTicket) -> Option<Result<Vec<u8>, EmuRsError>>;
+    /// Forget about a request nobody is waiting on anymore. One that is still going is stopped,
+    /// or has what it gives back thrown away when it finishes if the hardware can't stop
+    fn cancel(&mut self, ticket: EmuRsIoTicket);
+    /// Wake `waker` when the request is done. Returns false if the disk can't, in which case
+    /// whoever is waiting has to keep polling
+    fn wake_on_completion(&mut self, _ticket: EmuRsIoTicket, _waker: &Waker) -> bool {
+        return false;
+    }
+}
+
+/// Makes any [EmuRsDiskDriver] asynchronous by doing a chunk of the oldest request every poll
+///
+/// Nothing happens between polls, so it never wakes anyone by itself
+pub struct EmuRsIoQueue {
+    disk: Rc<RefCell<dyn EmuRsDiskDriver>>,
+    /// How many bytes a poll gets through at most. Rounded down to whole sectors
+    pub chunk_size: usize,
+    /// Requests and how far into them we are
+    queue: VecDeque<(EmuRsIoTicket, EmuRsIoRequest, usize)>,
+    finished: BTreeMap<EmuRsIoTicket, Result<Vec<u8>, EmuRsError>>,
+    next_ticket: usize,
+}
+
+impl EmuRsIoQueue {
+    pub fn new(disk: Rc<RefCell<dyn EmuRsDiskDriver>>) -> Self {
+        return Self {
+            disk,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            queue: VecDeque::new(),
+            finished: BTreeMap::new(),
+            next_ticket: 0,
+        };
+    }
+
+    pub fn pending(&self) -> usize {
+        return self.queue.len();
+    }
+}
+
+impl EmuRsDriver for EmuRsIoQueue {
+    fn name(&self) -> &'static str {
+        return "I/O Queue";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Fallback;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        return self.disk.borrow_mut().get_claimed();
+    }
+}
+
+impl EmuRsAsyncDiskDriver for EmuRsIoQueue {
+    fn submit(&mut self, request: EmuRsIoRequest) -> Result<EmuRsIoTicket, EmuRsError> {
+        // Catch this now instead of after half of it is done
+        if request.offset() + request.length() > self.disk.borrow_mut().get_total_size() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit,
+            });
+        }
+
+        let ticket = EmuRsIoTicket(self.next_ticket);
+        self.next_ticket += 1;
+        self.queue.push_back((ticket, request, 0));
+        return Ok(ticket);
+    }
+
+    fn poll(&mut self) -> bool {
+        let Some((ticket, mut request, done)) = self.queue.pop_front() else {
+            return false;
+        };
+
+        let sector_size = self.disk.borrow_mut().get_sector_size().max(1);
+        let chunk_size = (self.chunk_size - self.chunk_size % sector_size).max(sector_size);
+
+        let offset = request.offset() + done;
+        let result = match &mut request {
+            EmuRsIoRequest::Read { buffer, .. } => {
+                let length = chunk_size.min(buffer.len() - done);
+                self.disk
+                    .borrow_mut()
+                    .read(&mut buffer[done..done + length], offset)
+                    .map(|_| length)
+            }
+            EmuRsIoRequest::Write { buffer, .. } => {
+                let length = chunk_size.min(buffer.len() - done);
+                self.disk
+                    .borrow_mut()
+                    .write(&buffer[done..done + length], offset)
+                    .map(|_| length)
+            }
+        };
+
+        match result {
+            Ok(length) if done + length < request.length() => {
+                self.queue.push_front((ticket, request, done + length));
+            }
+            Ok(_) => {
+                self.finished.insert(ticket, Ok(request.into_buffer()));
+            }
+            Err(error) => {
+                self.finished.insert(ticket, Err(error));
+            }
+        }
+
+        return !self.queue.is_empty();
+    }
+
+    fn take(&mut self, ticket: EmuRsIoTicket) -> Option<Result<Vec<u8>, EmuRsError>> {
+        return self.finished.remove(&ticket);
+    }
+
+    fn cancel(&mut self, ticket: EmuRsIoTicket) {
+        // Writes that were partway through stay partway through
+        self.queue.retain(|(queued, _, _)| *queued != ticket);
+        self.finished.remove(&ticket);
+    }
+}
+
+/// A request as a [Future], for waiting on it in a executor
+///
+/// Every time it is polled it polls the disk too, so disks that need polling make progress as
+/// long as someone is waiting on them. Dropping it before it is done cancels the request
+pub struct EmuRsIoFuture {
+    disk: Rc<RefCell<dyn EmuRsAsyncDiskDriver>>,
+    ticket: EmuRsIoTicket,
+    done: bool,
+}
+
+impl EmuRsIoFuture {
+    pub fn submit(
+        disk: Rc<RefCell<dyn EmuRsAsyncDiskDriver>>,
+        request: EmuRsIoRequest,
+    ) -> Result<Self, EmuRsError> {
+        let ticket = disk.borrow_mut().submit(request)?;
+        return Ok(Self {
+            disk,
+            ticket,
+            done: false,
+        });
+    }
+
+    pub fn read(
+        disk: Rc<RefCell<dyn EmuRsAsyncDiskDriver>>,
+        buffer: Vec<u8>,
+        offset: usize,
+    ) -> Result<Self, EmuRsError> {
+        return Self::submit(disk, EmuRsIoRequest::Read { offset, buffer });
+    }
+
+    pub fn write(
+        disk: Rc<RefCell<dyn EmuRsAsyncDiskDriver>>,
+        buffer: Vec<u8>,
+        offset: usize,
+    ) -> Result<Self, EmuRsError> {
+        return Self::submit(disk, EmuRsIoRequest::Write { offset, buffer });
+    }
+
+    pub fn ticket(&self) -> EmuRsIoTicket {
+        return self.ticket;
+    }
+}
+
+impl Future for EmuRsIoFuture {
+    type Output = Result<Vec<u8>, EmuRsError>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        let ticket = self.ticket;
+        let mut disk = self.disk.borrow_mut();
+
+        // Only one chunk at a time, so everything else waiting gets a turn too
+        disk.poll();
+        if let Some(result) = disk.take(ticket) {
+            drop(disk);
+            self.done = true;
+            return Poll::Ready(result);
+        }
+
+        // Nothing will wake us, so ask to be polled again straight away
+        if !disk.wake_on_completion(ticket, context.waker()) {
+            context.waker().wake_by_ref();
+        }
+
+        return Poll::Pending;
+    }
+}
+
+impl Drop for EmuRsIoFuture {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        // Dropped from inside the disk somehow, so the result has to be left behind
+        if let Ok(mut disk) = self.disk.try_borrow_mut() {
+            disk.cancel(self.ticket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use alloc::vec;
+    use core::pin::pin;
+
+    /// Poll `future` until it is done, giving back what it gave and how many polls it took
+    fn block_on<FUTURE: Future>(future: FUTURE) -> (FUTURE::Output, usize) {
+        let mut future = pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        let mut polls = 0;
+
+        loop {
+            polls += 1;
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return (output, polls);
+            }
+        }
+    }
+
+    fn queue(chunk_size: usize) -> (Rc<RefCell<VecDisk>>, Rc<RefCell<EmuRsIoQueue>>) {
+        let disk = Rc::new(RefCell::new(VecDisk(
+            (0..10000).map(|index| (index % 251) as u8).collect(),
+        )));
+        let mut queue = EmuRsIoQueue::new(disk.clone());
+        queue.chunk_size = chunk_size;
+        return (disk, Rc::new(RefCell::new(queue)));
+    }
+
+    #[test]
+    fn reads_a_chunk_every_poll() {
+        let (disk, queue) = queue(1000);
+
+        let future = EmuRsIoFuture::read(queue.clone(), vec![0; 3500], 123).unwrap();
+        let (result, polls) = block_on(future);
+
+        assert_eq!(result.unwrap(), disk.borrow().0[123..3623]);
+        assert_eq!(polls, 4);
+        assert_eq!(queue.borrow().pending(), 0);
+    }
+
+    #[test]
+    fn writes_read_back() {
+        let (disk, queue) = queue(512);
+        let data: Vec<u8> = (0..2000).map(|index| (index % 7) as u8).collect();
+
+        let future = EmuRsIoFuture::write(queue.clone(), data.clone(), 4321).unwrap();
+        let (result, polls) = block_on(future);
+        assert_eq!(result.unwrap(), data, "the buffer comes back");
+        assert_eq!(polls, 4);
+        assert_eq!(disk.borrow().0[4321..6321], data);
+
+        let future = EmuRsIoFuture::read(queue, vec![0; 2000], 4321).unwrap();
+        assert_eq!(block_on(future).0.unwrap(), data);
+    }
+
+    #[test]
+    fn requests_past_the_end_fail_straight_away() {
+        let (_, queue) = queue(1000);
+
+        assert!(matches!(
+            EmuRsIoFuture::read(queue, vec![0; 10], 9995).map(|_| ()),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit
+            })
+        ));
+    }
+
+    #[test]
+    fn dropping_a_future_cancels_it() {
+        let (disk, queue) = queue(100);
+        let mut context = Context::from_waker(Waker::noop());
+
+        // Partway through, so it is still queued
+        let mut write = EmuRsIoFuture::write(queue.clone(), vec![0xff; 1000], 0).unwrap();
+        assert!(Pin::new(&mut write).poll(&mut context).is_pending());
+        let waiting = EmuRsIoFuture::read(queue.clone(), vec![0; 10], 5000).unwrap();
+        drop(write);
+
+        assert_eq!(queue.borrow().pending(), 1);
+        assert_eq!(block_on(waiting).0.unwrap(), disk.borrow().0[5000..5010]);
+        assert_eq!(
+            disk.borrow().0[100..200],
+            (100..200).map(|index| index as u8).collect::<Vec<u8>>()
+        );
+
+        // Finished but never picked up
+        let read = EmuRsIoFuture::read(queue.clone(), vec![0; 10], 0).unwrap();
+        let ticket = read.ticket();
+        while queue.borrow_mut().poll() {}
+        drop(read);
+
+        assert!(queue.borrow_mut().take(ticket).is_none());
+    }
+}
@@ -6,6 +6,8 @@
 
 extern crate alloc;
 
+use crate::firmware::EmuRsFirmwareSubsystem;
+use crate::media::EmuRsMediaSubsystem;
 use crate::mem::EmuRsMemoryTableEntry;
 use crate::vfs::EmuRsFilesystemSubsystem;
 use alloc::rc::Rc;
@@ -16,24 +18,44 @@ use core::cell::RefCell;
 use core::str::FromStr;
 use disk::EmuRsDiskDriver;
 use driver::EmuRsDriver;
+use drivers::cborfs::EmuRsCborFs;
+use drivers::ext2fs::EmuRsExt2Fs;
+use drivers::fatfs::EmuRsFatFs;
 use drivers::gamefs::EmuRsGameFs;
+use drivers::isofs::EmuRsIsoFs;
 use drivers::ustarfs::EmuRsUstarFs;
+use drivers::zipfs::EmuRsZipFs;
 use nalgebra::{DMatrix, Point2};
+use partition::partition_disks;
+use program::{EmuRsProgram, EmuRsProgramInstance};
 use subsystem::EmuRsSubsystem;
-use vfs::{EmuRsFsDriver, EmuRsPath};
+use vfs::{EmuRsFsDriver, EmuRsNamespace, EmuRsPath};
 use video::{
     EmuRsColorFormatGrey1, EmuRsColorFormatRgb888, EmuRsGenericColor, EmuRsGreyColor, EmuRsTexture,
 };
 use video::{EmuRsRgbColor, EmuRsVideoDriver};
 
+pub mod checksum;
+pub mod compression;
+pub mod cue;
+pub mod dat;
 pub mod device;
 pub mod disk;
 pub mod driver;
-mod drivers;
+pub mod drivers;
 pub mod error;
+pub mod fault;
+pub mod firmware;
+pub mod io;
+pub mod media;
 pub mod mem;
+pub mod partition;
+pub mod patch;
 pub mod prelude;
 pub mod program;
+pub mod romdb;
+pub mod romheader;
+pub mod snapshot;
 pub mod subsystem;
 pub mod vfs;
 pub mod video;
@@ -67,21 +89,34 @@ impl EmuRsContextBuilder {
     pub fn done(self) -> Rc<EmuRsContext> {
         let context = Rc::new(EmuRsContext {
             fs: RefCell::new(EmuRsFilesystemSubsystem::default()),
+            firmware: RefCell::new(EmuRsFirmwareSubsystem::default()),
+            media: RefCell::new(EmuRsMediaSubsystem::default()),
             video_drivers: self.video_drivers,
-            disk_drivers: self.disk_drivers,
+            disk_drivers: RefCell::new(self.disk_drivers),
             fs_drivers: self.fs_drivers,
         });
 
         context.fs.borrow_mut().init(context.clone());
+        context.firmware.borrow_mut().init(context.clone());
+        context.media.borrow_mut().init(context.clone());
 
         for driver in context.video_drivers.iter() {
             driver.as_ref().borrow_mut().init(context.clone());
         }
 
-        for driver in context.disk_drivers.iter() {
+        for driver in context.disk_drivers.borrow().iter() {
             driver.as_ref().borrow_mut().init(context.clone());
         }
 
+        // Every partition is a disk of its own, so filesystems are looked for on each of them
+        let mut partitions: Vec<Rc<RefCell<dyn EmuRsDiskDriver>>> = Vec::new();
+        for driver in context.disk_drivers.borrow().iter() {
+            for partition in partition_disks(driver).unwrap_or_default() {
+                partitions.push(Rc::new(RefCell::new(partition)));
+            }
+        }
+        context.disk_drivers.borrow_mut().extend(partitions);
+
         for driver in context.fs_drivers.iter() {
             driver.as_ref().borrow_mut().init(context.clone());
         }
@@ -93,8 +128,13 @@ impl EmuRsContextBuilder {
 #[derive(Clone)]
 pub struct EmuRsContext {
     pub fs: RefCell<EmuRsFilesystemSubsystem>,
+    /// Firmware the running program asked for, already checked against roms.db
+    pub firmware: RefCell<EmuRsFirmwareSubsystem>,
+    /// The discs of the running game, which the program can swap between
+    pub media: RefCell<EmuRsMediaSubsystem>,
     pub video_drivers: Vec<Rc<RefCell<dyn EmuRsVideoDriver>>>,
-    pub disk_drivers: Vec<Rc<RefCell<dyn EmuRsDiskDriver>>>,
+    /// Whole disks followed by the partitions found on them
+    pub disk_drivers: RefCell<Vec<Rc<RefCell<dyn EmuRsDiskDriver>>>>,
     pub fs_drivers: Vec<Rc<RefCell<dyn EmuRsFsDriver>>>,
 }
 
@@ -102,7 +142,9 @@ pub struct EmuRsContext {
 ///
 /// Currently there is a restriction that no memory allocation may occur before the memory allocator is fed a memory table
 /// Later I will add a small space of memory inside of the allocator for pre setup allocations by the bootloader
-pub fn emurs_main(
+///
+/// Once everything is set up `PROGRAM` runs forever, inside of the save folder of the default profile
+pub fn emurs_main<PROGRAM: EmuRsProgram>(
     memory_table_entries: &[EmuRsMemoryTableEntry],
     driver_setup_callback: fn(&mut EmuRsContextBuilder),
 ) -> ! {
@@ -117,7 +159,12 @@ pub fn emurs_main(
     // Add some fs drivers
     builder
         .add_fs_driver::<EmuRsGameFs>()
-        .add_fs_driver::<EmuRsUstarFs>();
+        .add_fs_driver::<EmuRsUstarFs>()
+        .add_fs_driver::<EmuRsCborFs>()
+        .add_fs_driver::<EmuRsFatFs>()
+        .add_fs_driver::<EmuRsZipFs>()
+        .add_fs_driver::<EmuRsIsoFs>()
+        .add_fs_driver::<EmuRsExt2Fs>();
 
     let context = builder.done();
 
@@ -136,5 +183,15 @@ pub fn emurs_main(
         .list_directory(&EmuRsPath::from_str("/").unwrap())
         .unwrap();
 
-    loop {}
+    // There is only the one profile until there is a way to pick one
+    let namespace = EmuRsNamespace::profile_saves("default").unwrap();
+    let mut program = match EmuRsProgramInstance::<PROGRAM>::new(&context, namespace) {
+        Ok(program) => program,
+        Err(error) => panic!("Couldn't start the program: {:?}", error.reason),
+    };
+
+    loop {
+        program.step(&context);
+        program.vsync(&context);
+    }
 }
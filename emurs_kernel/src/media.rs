@@ -0,0 +1,340 @@
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::subsystem::EmuRsSubsystem;
+use crate::vfs::{EmuRsFileMetadata, EmuRsFilesystemSubsystem, EmuRsFilesystemView, EmuRsPath};
+use crate::EmuRsContext;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::Ref;
+use core::fmt::Write;
+
+// Games that come on more than one disc. The discs are listed in a `.m3u` playlist and the running
+// program swaps between them like a player would swap the disc in the drive
+
+/// One disc out of a playlist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmuRsPlaylistEntry {
+    /// Always absolute, whatever the playlist itself said
+    pub path: EmuRsPath,
+    /// What the playlist calls this disc, if it says
+    pub label: Option<String>,
+}
+
+/// A `.m3u` playlist, one image path per line
+///
+/// Lines starting with `#` are comments apart from `#EXTINF`, whose title labels the next image.
+/// A image can also be labeled with `path|label`. Relative paths are relative to the playlist
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EmuRsPlaylist {
+    pub entries: Vec<EmuRsPlaylistEntry>,
+}
+
+impl EmuRsPlaylist {
+    /// Parse a playlist that lives in `directory`
+    pub fn parse(text: &str, directory: &EmuRsPath) -> Result<Self, EmuRsError> {
+        let mut entries = Vec::new();
+        let mut next_label = None;
+
+        for line in text.lines() {
+            let line = line.trim_start_matches('\u{feff}').trim();
+
+            if let Some(info) = line.strip_prefix("#EXTINF:") {
+                next_label = info
+                    .split_once(',')
+                    .map(|(_, title)| title.trim())
+                    .filter(|title| !title.is_empty())
+                    .map(String::from);
+                continue;
+            }
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (file, label) = match line.split_once('|') {
+                Some((file, label)) => (file.trim(), Some(String::from(label.trim()))),
+                None => (line, next_label.take()),
+            };
+
+            // Playlists made on Windows use the other slash
+            let file = file.replace('\\', "/");
+            let (mut path, relative) = match file.strip_prefix("ROOT/") {
+                Some(relative) => (EmuRsPath::default(), relative),
+                None => (directory.clone(), file.as_str()),
+            };
+            for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+                path = path.join_segment(segment);
+            }
+
+            entries.push(EmuRsPlaylistEntry {
+                path: path.normalize()?,
+                label: label.filter(|label| !label.is_empty()),
+            });
+        }
+
+        if entries.is_empty() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        return Ok(Self { entries });
+    }
+
+    /// Read and parse a playlist out of the VFS
+    pub fn open(fs: &EmuRsFilesystemView, path: &EmuRsPath) -> Result<Self, EmuRsError> {
+        let mut text = vec![0; fs.metadata(path)?.size.unwrap_or(0)];
+        fs.read(path, &mut text, 0)?;
+
+        let text = core::str::from_utf8(&text).map_err(|_| EmuRsError {
+            reason: EmuRsErrorReason::CorruptedFilesystem,
+        })?;
+
+        return Self::parse(text, &path.parent().unwrap_or_default());
+    }
+
+    /// Write the playlist back out. Every path is absolute, so it can be read from anywhere
+    pub fn to_m3u(&self) -> String {
+        let mut text = String::from("#EXTM3U\n");
+
+        for entry in self.entries.iter() {
+            if let Some(label) = entry.label.as_ref() {
+                writeln!(text, "#EXTINF:-1,{}", label).unwrap();
+            }
+            writeln!(text, "{}", entry.path).unwrap();
+        }
+
+        return text;
+    }
+}
+
+pub fn is_playlist(file: &EmuRsPath) -> bool {
+    return file
+        .file_name()
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| {
+            return extension.eq_ignore_ascii_case("m3u");
+        });
+}
+
+/// The discs of the game that is running and which one is in the drive
+///
+/// Programs read the disc through here instead of the VFS since the images are usually outside of
+/// their namespace. Swapping is up to the program since only it knows when the game lets you
+#[derive(Clone, Default)]
+pub struct EmuRsMediaSubsystem {
+    os_context: Option<Rc<EmuRsContext>>,
+    playlist: EmuRsPlaylist,
+    /// Which entry is in the drive, if there is one
+    inserted: Option<usize>,
+    /// Goes up every time the disc changes, so a program can notice a swap it didn't ask for
+    generation: usize,
+}
+
+impl EmuRsMediaSubsystem {
+    /// Load a game with the first disc inserted. A `.m3u` is loaded as every disc it lists,
+    /// anything else as a game with a single disc
+    pub fn load(&mut self, path: &EmuRsPath) -> Result<(), EmuRsError> {
+        // The path is taken from the root of the VFS, the images are usually outside of the
+        // program's namespace
+        let path = EmuRsPath::default().join(path).normalize()?;
+
+        let playlist = if is_playlist(&path) {
+            EmuRsPlaylist::open(&self.fs()?.global(), &path)?
+        } else {
+            EmuRsPlaylist {
+                entries: vec![EmuRsPlaylistEntry { path, label: None }],
+            }
+        };
+
+        self.playlist = playlist;
+        self.inserted = None;
+        self.insert(0)?;
+        return Ok(());
+    }
+
+    /// Forget the game, leaving the drive empty
+    pub fn unload(&mut self) {
+        self.playlist = EmuRsPlaylist::default();
+        self.eject();
+    }
+
+    pub fn playlist(&self) -> &EmuRsPlaylist {
+        return &self.playlist;
+    }
+
+    pub fn disc_count(&self) -> usize {
+        return self.playlist.entries.len();
+    }
+
+    /// Which disc is in the drive
+    pub fn inserted(&self) -> Option<usize> {
+        return self.inserted;
+    }
+
+    pub fn generation(&self) -> usize {
+        return self.generation;
+    }
+
+    /// Take the disc out, like opening the tray
+    pub fn eject(&mut self) {
+        if self.inserted.take().is_some() {
+            self.generation += 1;
+        }
+    }
+
+    /// Put a disc in, taking out whatever was there. The image has to exist
+    pub fn insert(&mut self, index: usize) -> Result<&EmuRsPlaylistEntry, EmuRsError> {
+        let entry = self.playlist.entries.get(index).ok_or(EmuRsError {
+            reason: EmuRsErrorReason::FileNotFound,
+        })?;
+
+        self.fs()?.global().metadata(&entry.path)?;
+
+        self.inserted = Some(index);
+        self.generation += 1;
+        return Ok(&self.playlist.entries[index]);
+    }
+
+    /// Swap to the disc after this one, going back around to the first
+    pub fn next_disc(&mut self) -> Result<&EmuRsPlaylistEntry, EmuRsError> {
+        let index = self.inserted.map_or(0, |index| index + 1) % self.disc_count().max(1);
+        return self.insert(index);
+    }
+
+    /// The disc in the drive
+    pub fn current(&self) -> Option<&EmuRsPlaylistEntry> {
+        return self.inserted.map(|index| &self.playlist.entries[index]);
+    }
+
+    /// Read from the disc in the drive
+    pub fn read(&self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        let entry = self.current().ok_or(EmuRsError {
+            reason: EmuRsErrorReason::FileNotFound,
+        })?;
+
+        return self.fs()?.global().read(&entry.path, buffer, offset);
+    }
+
+    pub fn metadata(&self) -> Result<EmuRsFileMetadata, EmuRsError> {
+        let entry = self.current().ok_or(EmuRsError {
+            reason: EmuRsErrorReason::FileNotFound,
+        })?;
+
+        return self.fs()?.global().metadata(&entry.path);
+    }
+
+    fn fs(&self) -> Result<Ref<'_, EmuRsFilesystemSubsystem>, EmuRsError> {
+        let context = self.os_context.as_ref().ok_or(EmuRsError {
+            reason: EmuRsErrorReason::NotInitialized,
+        })?;
+
+        return Ok(context.fs.borrow());
+    }
+}
+
+impl EmuRsSubsystem for EmuRsMediaSubsystem {
+    fn init(&mut self, context: Rc<EmuRsContext>) {
+        self.os_context = Some(context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::str::FromStr;
+
+    fn path(path: &str) -> EmuRsPath {
+        return EmuRsPath::from_str(path).unwrap();
+    }
+
+    fn entry(file: &str, label: Option<&str>) -> EmuRsPlaylistEntry {
+        return EmuRsPlaylistEntry {
+            path: path(file),
+            label: label.map(String::from),
+        };
+    }
+
+    #[test]
+    fn parses_labels() {
+        let text = "#EXTM3U\n\
+            #EXTINF:-1,Disc One\n\
+            Game (Disc 1).cue\n\
+            # Just a comment, not a label\n\
+            Game (Disc 2).cue|Disc Two\n\
+            #EXTINF:-1,\n\
+            Game (Disc 3).cue\n\
+            \n\
+            Game (Disc 4).cue |  \n";
+
+        assert_eq!(
+            EmuRsPlaylist::parse(text, &path("ROOT/games"))
+                .unwrap()
+                .entries,
+            [
+                entry("ROOT/games/Game (Disc 1).cue", Some("Disc One")),
+                entry("ROOT/games/Game (Disc 2).cue", Some("Disc Two")),
+                entry("ROOT/games/Game (Disc 3).cue", None),
+                entry("ROOT/games/Game (Disc 4).cue", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_paths() {
+        // Made on Windows, so with a BOM, CRLF and backslashes
+        let text = "\u{feff}discs\\Game (Disc 1).chd\r\n\
+            ..\\other\\Game (Disc 2).chd\r\n\
+            ROOT/elsewhere/Game (Disc 3).chd\r\n";
+
+        assert_eq!(
+            EmuRsPlaylist::parse(text, &path("ROOT/games"))
+                .unwrap()
+                .entries,
+            [
+                entry("ROOT/games/discs/Game (Disc 1).chd", None),
+                entry("ROOT/other/Game (Disc 2).chd", None),
+                entry("ROOT/elsewhere/Game (Disc 3).chd", None),
+            ]
+        );
+
+        assert!(matches!(
+            EmuRsPlaylist::parse("#EXTM3U\n# Nothing\n", &path("ROOT/games")),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+    }
+
+    #[test]
+    fn round_trips_through_m3u() {
+        let playlist = EmuRsPlaylist {
+            entries: vec![
+                entry("ROOT/games/Game (Disc 1).cue", Some("Disc One")),
+                entry("ROOT/games/Game (Disc 2).cue", None),
+            ],
+        };
+
+        // Every path is absolute, so where it is read from doesn't matter
+        let text = playlist.to_m3u();
+        assert_eq!(
+            EmuRsPlaylist::parse(&text, &path("ROOT/somewhere/else")).unwrap(),
+            playlist
+        );
+    }
+
+    #[test]
+    fn needs_init_first() {
+        let mut media = EmuRsMediaSubsystem::default();
+
+        assert!(matches!(
+            media.load(&path("ROOT/games/game.iso")),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::NotInitialized
+            })
+        ));
+        assert_eq!(media.inserted(), None);
+        assert!(media.metadata().is_err());
+    }
+}
@@ -10,8 +10,9 @@ use tinyvec::{array_vec, ArrayVec};
 /// The number of slabs the allocator can hold at a time before it panics
 const SLAB_COUNT: usize = 128;
 
-/// The global allocator for the operating system
-#[global_allocator]
+/// The global allocator for the operating system. The kernel's own tests and host crates that turn on
+/// `host-allocator` for their tests get the host's allocator instead
+#[cfg_attr(not(any(test, feature = "host-allocator")), global_allocator)]
 pub static mut EMURS_GLOBAL_MEMORY_ALLOCATOR: EmuRsAllocator = EmuRsAllocator::new();
 
 fn align_address_upward(alignment: usize, addr: usize) -> usize {
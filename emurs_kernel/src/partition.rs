@@ -0,0 +1,666 @@
+use crate::device::EmuRsDevice;
+use crate::disk::{EmuRsDiskDriver, EmuRsDiskProperties};
+use crate::driver::{EmuRsDriver, EmuRsDriverPreference};
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use alloc::collections::BTreeSet;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use tinyvec::TinyVec;
+
+// https://wiki.osdev.org/MBR_(x86)
+// https://wiki.osdev.org/GPT
+// https://en.wikipedia.org/wiki/Extended_boot_record
+
+/// What LBAs count in when the disk doesn't have a sector size that makes sense for it
+const DEFAULT_LBA_SIZE: usize = 512;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Logical partitions are a linked list on the disk, so give up after following this many
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Anything bigger than this is a broken header, not a big partition table
+const MAX_GPT_ENTRIES_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmuRsPartitionScheme {
+    Mbr,
+    Gpt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmuRsPartitionKind {
+    /// The partition type byte, like `0x0c` for FAT32 or `0x83` for Linux
+    Mbr(u8),
+    /// GUIDs as they are stored on the disk, which is mixed endian
+    Gpt {
+        type_guid: [u8; 16],
+        unique_guid: [u8; 16],
+    },
+}
+
+/// A partition and where it is on the disk, in bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmuRsPartition {
+    /// Counting from 1. Logical partitions inside of a extended partition start at 5
+    pub number: usize,
+    pub offset: usize,
+    pub size: usize,
+    pub kind: EmuRsPartitionKind,
+    /// Only GPT partitions have names
+    pub name: String,
+    pub bootable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmuRsPartitionTable {
+    pub scheme: EmuRsPartitionScheme,
+    pub partitions: Vec<EmuRsPartition>,
+}
+
+impl EmuRsPartitionTable {
+    /// Read the partition table of a disk, or [None] if it doesn't have one and is just a
+    /// filesystem by itself
+    pub fn read(disk: &mut dyn EmuRsDiskDriver) -> Result<Option<Self>, EmuRsError> {
+        let lba_size = match disk.get_sector_size() {
+            size @ (512 | 1024 | 2048 | 4096) => size,
+            _ => DEFAULT_LBA_SIZE,
+        };
+        let disk_size = disk.get_total_size();
+
+        if disk_size < lba_size * 2 {
+            return Ok(None);
+        }
+
+        let mut mbr = vec![0; lba_size];
+        disk.read(&mut mbr, 0)?;
+
+        if mbr[510..512] != MBR_SIGNATURE {
+            return Ok(None);
+        }
+
+        let entries: Vec<EmuRsMbrEntry> = (0..4)
+            .map(|index| EmuRsMbrEntry::parse(&mbr[MBR_ENTRIES + index * 16..]))
+            .collect();
+
+        // A filesystem boot sector has the same signature, but its code doesn't look like
+        // a partition table
+        let used: Vec<&EmuRsMbrEntry> = entries.iter().filter(|entry| !entry.is_empty()).collect();
+        if used.is_empty()
+            || entries
+                .iter()
+                .any(|entry| entry.status != 0 && entry.status != 0x80)
+        {
+            return Ok(None);
+        }
+
+        // GPT disks have a MBR with a single partition covering everything so old tools leave
+        // it alone
+        if used.iter().any(|entry| entry.kind == MBR_TYPE_PROTECTIVE) {
+            return read_gpt(disk, lba_size, disk_size).map(Some);
+        }
+
+        if used.iter().any(|entry| {
+            return entry.start == 0 || entry.end(lba_size).is_none_or(|end| end > disk_size);
+        }) {
+            return Ok(None);
+        }
+
+        let mut partitions = Vec::new();
+
+        for (index, entry) in entries.iter().enumerate() {
+            if entry.is_empty() {
+                continue;
+            }
+
+            if MBR_TYPES_EXTENDED.contains(&entry.kind) {
+                read_logical_partitions(disk, lba_size, disk_size, entry, &mut partitions)?;
+                continue;
+            }
+
+            partitions.extend(entry.to_partition(index + 1, 0, lba_size));
+        }
+
+        partitions.sort_by_key(|partition| partition.number);
+
+        return Ok(Some(Self {
+            scheme: EmuRsPartitionScheme::Mbr,
+            partitions,
+        }));
+    }
+}
+
+/// A entry of a MBR or EBR, with the start in LBAs relative to whatever it is relative to
+struct EmuRsMbrEntry {
+    status: u8,
+    kind: u8,
+    start: u32,
+    sectors: u32,
+}
+
+impl EmuRsMbrEntry {
+    fn parse(entry: &[u8]) -> Self {
+        return Self {
+            status: entry[0],
+            kind: entry[4],
+            start: read_u32(entry, 8),
+            sectors: read_u32(entry, 12),
+        };
+    }
+
+    fn is_empty(&self) -> bool {
+        return self.kind == 0 || self.sectors == 0;
+    }
+
+    /// Where the entry ends in bytes. On 32 bit systems a big enough disk doesn't fit in a usize
+    fn end(&self, lba_size: usize) -> Option<usize> {
+        return (self.start as usize)
+            .checked_add(self.sectors as usize)?
+            .checked_mul(lba_size);
+    }
+
+    /// The size is left as big as it goes for logical partitions to cut down to the disk
+    fn to_partition(&self, number: usize, base: usize, lba_size: usize) -> Option<EmuRsPartition> {
+        return Some(EmuRsPartition {
+            number,
+            offset: base
+                .checked_add(self.start as usize)?
+                .checked_mul(lba_size)?,
+            size: (self.sectors as usize).saturating_mul(lba_size),
+            kind: EmuRsPartitionKind::Mbr(self.kind),
+            name: String::new(),
+            bootable: self.status == 0x80,
+        });
+    }
+}
+
+/// Follow the chain of EBRs in a extended partition
+fn read_logical_partitions(
+    disk: &mut dyn EmuRsDiskDriver,
+    lba_size: usize,
+    disk_size: usize,
+    extended: &EmuRsMbrEntry,
+    partitions: &mut Vec<EmuRsPartition>,
+) -> Result<(), EmuRsError> {
+    let extended_start = extended.start as usize;
+    let mut ebr = extended_start;
+    let mut seen = BTreeSet::new();
+    let mut sector = vec![0; lba_size];
+
+    while seen.len() < MAX_LOGICAL_PARTITIONS && seen.insert(ebr) {
+        if ebr
+            .checked_add(1)
+            .and_then(|end| end.checked_mul(lba_size))
+            .is_none_or(|end| end > disk_size)
+        {
+            break;
+        }
+
+        disk.read(&mut sector, ebr * lba_size)?;
+        if sector[510..512] != MBR_SIGNATURE {
+            break;
+        }
+
+        // The first entry is relative to this EBR, the link to the next one is relative to the
+        // extended partition
+        let logical = EmuRsMbrEntry::parse(&sector[MBR_ENTRIES..]);
+        let next = EmuRsMbrEntry::parse(&sector[MBR_ENTRIES + 16..]);
+
+        let number = 5 + partitions
+            .iter()
+            .filter(|partition| partition.number >= 5)
+            .count();
+        if let Some(mut partition) = logical
+            .to_partition(number, ebr, lba_size)
+            .filter(|partition| !logical.is_empty() && partition.offset < disk_size)
+        {
+            partition.size = partition.size.min(disk_size - partition.offset);
+            partitions.push(partition);
+        }
+
+        if next.is_empty() {
+            break;
+        }
+        ebr = match extended_start.checked_add(next.start as usize) {
+            Some(ebr) => ebr,
+            None => break,
+        };
+    }
+
+    return Ok(());
+}
+
+/// Read the GPT, falling back to the backup at the end of the disk if the main one is damaged
+fn read_gpt(
+    disk: &mut dyn EmuRsDiskDriver,
+    lba_size: usize,
+    disk_size: usize,
+) -> Result<EmuRsPartitionTable, EmuRsError> {
+    let last_lba = disk_size / lba_size - 1;
+
+    let partitions = match read_gpt_header(disk, lba_size, disk_size, 1) {
+        Ok(partitions) => partitions,
+        Err(_) => read_gpt_header(disk, lba_size, disk_size, last_lba)?,
+    };
+
+    return Ok(EmuRsPartitionTable {
+        scheme: EmuRsPartitionScheme::Gpt,
+        partitions,
+    });
+}
+
+fn read_gpt_header(
+    disk: &mut dyn EmuRsDiskDriver,
+    lba_size: usize,
+    disk_size: usize,
+    lba: usize,
+) -> Result<Vec<EmuRsPartition>, EmuRsError> {
+    let mut header = vec![0; lba_size];
+    disk.read(&mut header, lba * lba_size)?;
+
+    let header_size = read_u32(&header, 12) as usize;
+    if &header[0..8] != GPT_SIGNATURE || !(92..=lba_size).contains(&header_size) {
+        return Err(corrupted());
+    }
+
+    // The checksum covers the header with the checksum itself zeroed
+    let checksum = read_u32(&header, 16);
+    header[16..20].fill(0);
+    if crc32fast::hash(&header[..header_size]) != checksum {
+        return Err(corrupted());
+    }
+
+    let entries_offset = usize::try_from(read_u64(&header, 72))
+        .ok()
+        .and_then(|entries_lba| entries_lba.checked_mul(lba_size))
+        .ok_or_else(corrupted)?;
+    let entry_count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    let entries_size = entry_count
+        .checked_mul(entry_size)
+        .filter(|entries_size| *entries_size <= MAX_GPT_ENTRIES_SIZE)
+        .ok_or_else(corrupted)?;
+
+    if entry_size < 128 {
+        return Err(corrupted());
+    }
+
+    let mut entries = vec![0; entries_size];
+    disk.read(&mut entries, entries_offset)?;
+    if crc32fast::hash(&entries) != read_u32(&header, 88) {
+        return Err(corrupted());
+    }
+
+    let mut partitions = Vec::new();
+
+    for (index, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid: [u8; 16] = entry[0..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+
+        // The last LBA is part of the partition
+        let first = usize::try_from(read_u64(entry, 32)).map_err(|_| corrupted())?;
+        let last = usize::try_from(read_u64(entry, 40)).map_err(|_| corrupted())?;
+        let end = last
+            .checked_add(1)
+            .and_then(|end| end.checked_mul(lba_size))
+            .filter(|end| *end <= disk_size);
+        if last < first || end.is_none() {
+            return Err(corrupted());
+        }
+
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|character| u16::from_le_bytes([character[0], character[1]]))
+            .take_while(|character| *character != 0)
+            .collect();
+
+        partitions.push(EmuRsPartition {
+            number: index + 1,
+            offset: first * lba_size,
+            size: (last + 1 - first) * lba_size,
+            kind: EmuRsPartitionKind::Gpt {
+                type_guid,
+                unique_guid: entry[16..32].try_into().unwrap(),
+            },
+            name: String::from_utf16_lossy(&name),
+            // Legacy BIOS bootable attribute
+            bootable: read_u64(entry, 48) & (1 << 2) != 0,
+        });
+    }
+
+    return Ok(partitions);
+}
+
+/// A disk for every partition on `disk`, or none if it isn't partitioned
+pub fn partition_disks(
+    disk: &Rc<RefCell<dyn EmuRsDiskDriver>>,
+) -> Result<Vec<EmuRsPartitionDisk>, EmuRsError> {
+    let table = EmuRsPartitionTable::read(&mut *disk.borrow_mut())?;
+
+    return Ok(table
+        .map(|table| table.partitions)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|partition| EmuRsPartitionDisk::new(disk.clone(), partition))
+        .collect());
+}
+
+/// One partition of a disk, as a disk of its own
+pub struct EmuRsPartitionDisk {
+    disk: Rc<RefCell<dyn EmuRsDiskDriver>>,
+    partition: EmuRsPartition,
+}
+
+impl EmuRsPartitionDisk {
+    pub fn new(disk: Rc<RefCell<dyn EmuRsDiskDriver>>, partition: EmuRsPartition) -> Self {
+        return Self { disk, partition };
+    }
+
+    pub fn partition(&self) -> &EmuRsPartition {
+        return &self.partition;
+    }
+
+    fn check_bounds(&self, offset: usize, length: usize) -> Result<(), EmuRsError> {
+        if offset + length > self.partition.size {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit,
+            });
+        }
+
+        return Ok(());
+    }
+}
+
+impl EmuRsDriver for EmuRsPartitionDisk {
+    fn name(&self) -> &'static str {
+        return "Partition";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        // The disk it is on already claimed the hardware
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsDiskDriver for EmuRsPartitionDisk {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        self.check_bounds(offset, buffer.len())?;
+        return self
+            .disk
+            .borrow_mut()
+            .read(buffer, self.partition.offset + offset);
+    }
+
+    fn write(&mut self, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        self.check_bounds(offset, buffer.len())?;
+        return self
+            .disk
+            .borrow_mut()
+            .write(buffer, self.partition.offset + offset);
+    }
+
+    fn get_sector_size(&mut self) -> usize {
+        return self.disk.borrow_mut().get_sector_size();
+    }
+
+    fn get_total_size(&mut self) -> usize {
+        return self.partition.size;
+    }
+
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        return self.disk.borrow_mut().get_properties();
+    }
+}
+
+fn corrupted() -> EmuRsError {
+    return EmuRsError {
+        reason: EmuRsErrorReason::CorruptedFilesystem,
+    };
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    return u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+
+    /// [VecDisk] has byte sized sectors, so LBAs are the default size
+    const LBA: usize = DEFAULT_LBA_SIZE;
+
+    fn disk(lbas: usize) -> VecDisk {
+        return VecDisk(vec![0; lbas * LBA]);
+    }
+
+    /// Fill in a entry of the MBR or EBR at `lba`
+    fn mbr_entry(disk: &mut VecDisk, lba: usize, index: usize, kind: u8, start: u32, sectors: u32) {
+        let sector = &mut disk.0[lba * LBA..(lba + 1) * LBA];
+        let entry = &mut sector[MBR_ENTRIES + index * 16..MBR_ENTRIES + (index + 1) * 16];
+
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+        sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    fn gpt_entry(entries: &mut [u8], index: usize, first: u64, last: u64, name: &str) {
+        let entry = &mut entries[index * 128..(index + 1) * 128];
+
+        entry[0..16].fill(0xaa);
+        entry[16..32].fill(index as u8);
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        for (character, bytes) in name.encode_utf16().zip(entry[56..128].chunks_exact_mut(2)) {
+            bytes.copy_from_slice(&character.to_le_bytes());
+        }
+    }
+
+    /// Write a GPT header at `lba` with `entries` at `entries_lba`
+    fn gpt_header(disk: &mut VecDisk, lba: usize, entries_lba: usize, entries: &[u8]) {
+        disk.0[entries_lba * LBA..entries_lba * LBA + entries.len()].copy_from_slice(entries);
+
+        let header = &mut disk.0[lba * LBA..lba * LBA + 92];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[72..80].copy_from_slice(&(entries_lba as u64).to_le_bytes());
+        header[80..84].copy_from_slice(&((entries.len() / 128) as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32fast::hash(entries).to_le_bytes());
+
+        let checksum = crc32fast::hash(header);
+        header[16..20].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    /// A protective MBR and a GPT with a copy at each end
+    fn gpt_disk(entries: &[u8]) -> VecDisk {
+        let mut disk = disk(64);
+        mbr_entry(&mut disk, 0, 0, MBR_TYPE_PROTECTIVE, 1, 63);
+        gpt_header(&mut disk, 1, 2, entries);
+        gpt_header(&mut disk, 63, 62, entries);
+        return disk;
+    }
+
+    fn read(disk: &mut VecDisk) -> Result<Option<EmuRsPartitionTable>, EmuRsError> {
+        return EmuRsPartitionTable::read(disk);
+    }
+
+    #[test]
+    fn follows_the_ebr_chain() {
+        let mut disk = disk(64);
+        mbr_entry(&mut disk, 0, 0, 0x0c, 1, 9);
+        disk.0[MBR_ENTRIES] = 0x80;
+        mbr_entry(&mut disk, 0, 1, 0x0f, 10, 50);
+
+        // Logical partitions start relative to their EBR, links relative to the extended
+        // partition. The last link goes back to the first EBR, which has to be ignored
+        mbr_entry(&mut disk, 10, 0, 0x83, 1, 9);
+        mbr_entry(&mut disk, 10, 1, 0x05, 20, 20);
+        mbr_entry(&mut disk, 30, 0, 0x07, 2, 10);
+        mbr_entry(&mut disk, 30, 1, 0x05, 0, 20);
+
+        let table = read(&mut disk).unwrap().unwrap();
+        assert_eq!(table.scheme, EmuRsPartitionScheme::Mbr);
+
+        let placed: Vec<(usize, usize, usize, EmuRsPartitionKind, bool)> = table
+            .partitions
+            .iter()
+            .map(|partition| {
+                return (
+                    partition.number,
+                    partition.offset / LBA,
+                    partition.size / LBA,
+                    partition.kind,
+                    partition.bootable,
+                );
+            })
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                (1, 1, 9, EmuRsPartitionKind::Mbr(0x0c), true),
+                (5, 11, 9, EmuRsPartitionKind::Mbr(0x83), false),
+                (6, 32, 10, EmuRsPartitionKind::Mbr(0x07), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn partition_disks_read_their_own_part() {
+        let mut disk = disk(64);
+        mbr_entry(&mut disk, 0, 0, 0x83, 8, 8);
+        disk.0[8 * LBA..16 * LBA].fill(0x5a);
+
+        let disk: Rc<RefCell<dyn EmuRsDiskDriver>> = Rc::new(RefCell::new(disk));
+        let mut partitions = partition_disks(&disk).unwrap();
+        assert_eq!(partitions.len(), 1);
+
+        let mut buffer = vec![0; 8 * LBA];
+        partitions[0].read(&mut buffer, 0).unwrap();
+        assert!(buffer.iter().all(|byte| *byte == 0x5a));
+        assert!(partitions[0].read(&mut [0], 8 * LBA).is_err());
+    }
+
+    #[test]
+    fn falls_back_to_the_backup_gpt() {
+        let mut entries = vec![0; 4 * 128];
+        gpt_entry(&mut entries, 0, 10, 19, "saves");
+        gpt_entry(&mut entries, 2, 20, 39, "games");
+        entries[2 * 128 + 48] = 1 << 2;
+
+        let mut disk = gpt_disk(&entries);
+        let expected = read(&mut disk).unwrap().unwrap();
+        assert_eq!(expected.scheme, EmuRsPartitionScheme::Gpt);
+
+        let placed: Vec<(usize, usize, usize, &str, bool)> = expected
+            .partitions
+            .iter()
+            .map(|partition| {
+                return (
+                    partition.number,
+                    partition.offset / LBA,
+                    partition.size / LBA,
+                    partition.name.as_str(),
+                    partition.bootable,
+                );
+            })
+            .collect();
+        assert_eq!(
+            placed,
+            [(1, 10, 10, "saves", false), (3, 20, 20, "games", true)]
+        );
+
+        // The main header no longer matches its checksum
+        disk.0[LBA + 40] ^= 1;
+        assert_eq!(read(&mut disk).unwrap().unwrap(), expected);
+
+        // And then the backup's entries don't either
+        disk.0[62 * LBA] ^= 1;
+        assert!(matches!(
+            read(&mut disk),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+    }
+
+    #[test]
+    fn gpt_numbers_that_overflow_are_corrupted() {
+        let mut entries = vec![0; 4 * 128];
+        gpt_entry(&mut entries, 0, 10, u64::MAX, "huge");
+        let mut disk = gpt_disk(&entries);
+
+        assert!(matches!(
+            read(&mut disk),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+
+        // Entries that would be past the end of memory
+        let mut disk = gpt_disk(&[0; 4 * 128]);
+        for lba in [1, 63] {
+            let header = &mut disk.0[lba * LBA..lba * LBA + 92];
+            header[72..80].copy_from_slice(&u64::MAX.to_le_bytes());
+            header[16..20].fill(0);
+            let checksum = crc32fast::hash(header);
+            header[16..20].copy_from_slice(&checksum.to_le_bytes());
+        }
+
+        assert!(matches!(
+            read(&mut disk),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+    }
+
+    #[test]
+    fn mbr_numbers_that_overflow_are_ignored() {
+        // Too big for the disk, and past the end of memory on 32 bit systems
+        let mut huge = disk(64);
+        mbr_entry(&mut huge, 0, 0, 0x83, u32::MAX, u32::MAX);
+        assert_eq!(read(&mut huge).unwrap(), None);
+
+        // Logical partitions and links that go past the end are dropped, the rest are kept
+        let mut disk = disk(64);
+        mbr_entry(&mut disk, 0, 0, 0x0f, 10, 50);
+        mbr_entry(&mut disk, 10, 0, 0x83, u32::MAX, u32::MAX);
+        mbr_entry(&mut disk, 10, 1, 0x05, 20, 20);
+        mbr_entry(&mut disk, 30, 0, 0x07, 2, u32::MAX);
+        mbr_entry(&mut disk, 30, 1, 0x05, u32::MAX, 20);
+
+        let table = read(&mut disk).unwrap().unwrap();
+        let placed: Vec<(usize, usize, usize)> = table
+            .partitions
+            .iter()
+            .map(|partition| {
+                return (
+                    partition.number,
+                    partition.offset / LBA,
+                    partition.size / LBA,
+                );
+            })
+            .collect();
+        assert_eq!(placed, [(5, 32, 32)]);
+    }
+}
@@ -0,0 +1,654 @@
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::vfs::EmuRsPath;
+use crate::EmuRsContext;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// Soft patching, which is how translations and hacks are usually distributed. The ROM on disk is
+// never changed, the patch is applied in memory every time the ROM is loaded
+
+/// Patches sitting next to a ROM with one of these extensions get applied to it, the first one
+/// found winning
+pub const PATCH_EXTENSIONS: [&str; 3] = ["bps", "ups", "ips"];
+
+/// IPS has no checksums, UPS and BPS check the source, the result and the patch itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmuRsPatchKind {
+    Ips,
+    Ups,
+    Bps,
+}
+
+/// A patch file held in memory
+pub struct EmuRsPatch<'a> {
+    kind: EmuRsPatchKind,
+    data: &'a [u8],
+}
+
+impl<'a> EmuRsPatch<'a> {
+    /// Work out what kind of patch this is and make sure it wasn't damaged
+    pub fn parse(data: &'a [u8]) -> Result<Self, EmuRsError> {
+        let kind = if data.starts_with(b"PATCH") {
+            EmuRsPatchKind::Ips
+        } else if data.starts_with(b"UPS1") {
+            EmuRsPatchKind::Ups
+        } else if data.starts_with(b"BPS1") {
+            EmuRsPatchKind::Bps
+        } else {
+            return Err(corrupted());
+        };
+
+        if kind != EmuRsPatchKind::Ips {
+            // The patch checksum covers everything but itself
+            if data.len() < 16 || crc32fast::hash(&data[..data.len() - 4]) != read_u32(data, 4) {
+                return Err(corrupted());
+            }
+        }
+
+        return Ok(Self { kind, data });
+    }
+
+    pub fn kind(&self) -> EmuRsPatchKind {
+        return self.kind;
+    }
+
+    /// Apply the patch to `source`, giving the patched file
+    pub fn apply(&self, source: &[u8]) -> Result<Vec<u8>, EmuRsError> {
+        return match self.kind {
+            EmuRsPatchKind::Ips => self.apply_ips(source),
+            EmuRsPatchKind::Ups => self.apply_ups(source),
+            EmuRsPatchKind::Bps => self.apply_bps(source),
+        };
+    }
+
+    fn apply_ips(&self, source: &[u8]) -> Result<Vec<u8>, EmuRsError> {
+        let data = self.data;
+        let mut target = source.to_vec();
+        let mut position = 5;
+
+        loop {
+            let record = data.get(position..position + 3).ok_or_else(corrupted)?;
+            position += 3;
+
+            if record == b"EOF" {
+                break;
+            }
+
+            let offset =
+                usize::from(record[0]) << 16 | usize::from(record[1]) << 8 | usize::from(record[2]);
+            let size = read_u16_be(data, &mut position)?;
+
+            // A size of zero means a run of the same byte
+            let (size, run) = match size {
+                0 => {
+                    let size = read_u16_be(data, &mut position)?;
+                    let value = *data.get(position).ok_or_else(corrupted)?;
+                    position += 1;
+                    (size, Some(value))
+                }
+                size => (size, None),
+            };
+
+            // Patches can make the file bigger
+            grow(&mut target, offset + size)?;
+
+            match run {
+                Some(value) => target[offset..offset + size].fill(value),
+                None => {
+                    let bytes = data.get(position..position + size).ok_or_else(corrupted)?;
+                    target[offset..offset + size].copy_from_slice(bytes);
+                    position += size;
+                }
+            }
+        }
+
+        // Some patches end with the size the file should be cut down to
+        if let Some(size) = data.get(position..position + 3) {
+            target.truncate(
+                usize::from(size[0]) << 16 | usize::from(size[1]) << 8 | usize::from(size[2]),
+            );
+        }
+
+        return Ok(target);
+    }
+
+    fn apply_ups(&self, source: &[u8]) -> Result<Vec<u8>, EmuRsError> {
+        let data = self.data;
+        let end = data.len() - 12;
+        let mut position = 4;
+
+        let source_size = read_number(data, &mut position)?;
+        let target_size = read_number(data, &mut position)?;
+        check_source(source, source_size, read_u32(data, 12))?;
+
+        // Bytes past the end of the source count as zeros. The target only grows as far as the
+        // patch writes, the size it claims could be anything
+        let mut target = source[..source.len().min(target_size)].to_vec();
+
+        let mut offset: usize = 0;
+
+        while position < end {
+            offset = offset
+                .checked_add(read_number(data, &mut position)?)
+                .ok_or_else(corrupted)?;
+
+            // Runs of bytes to XOR with, ending with a zero
+            loop {
+                if position >= end {
+                    return Err(corrupted());
+                }
+
+                let byte = data[position];
+                position += 1;
+
+                if offset < target_size {
+                    grow(&mut target, offset + 1)?;
+                    target[offset] ^= byte;
+                }
+                offset = offset.checked_add(1).ok_or_else(corrupted)?;
+
+                if byte == 0 {
+                    break;
+                }
+            }
+        }
+
+        // Whatever is left is zeros
+        grow(&mut target, target_size)?;
+
+        check_target(&target, read_u32(data, 8))?;
+        return Ok(target);
+    }
+
+    fn apply_bps(&self, source: &[u8]) -> Result<Vec<u8>, EmuRsError> {
+        let data = self.data;
+        let end = data.len() - 12;
+        let mut position = 4;
+
+        let source_size = read_number(data, &mut position)?;
+        let target_size = read_number(data, &mut position)?;
+        let metadata_size = read_number(data, &mut position)?;
+        position = position.checked_add(metadata_size).ok_or_else(corrupted)?;
+        check_source(source, source_size, read_u32(data, 12))?;
+
+        // Only trust the size as far as the patch could plausibly make it, past that it grows as
+        // it goes
+        let mut target: Vec<u8> = Vec::with_capacity(target_size.min(source.len() + data.len()));
+        let mut source_offset = 0;
+        let mut target_offset = 0;
+
+        while position < end {
+            let action = read_number(data, &mut position)?;
+            let length = (action >> 2) + 1;
+
+            if target.len() + length > target_size {
+                return Err(corrupted());
+            }
+
+            match action & 0b11 {
+                // Bytes from the same place in the source
+                0 => {
+                    let start = target.len();
+                    let bytes = source.get(start..start + length).ok_or_else(corrupted)?;
+                    target.extend_from_slice(bytes);
+                }
+                // Bytes from the patch itself
+                1 => {
+                    let bytes = data
+                        .get(position..position + length)
+                        .filter(|_| position + length <= end);
+                    target.extend_from_slice(bytes.ok_or_else(corrupted)?);
+                    position += length;
+                }
+                // Bytes from somewhere else in the source
+                2 => {
+                    source_offset = read_relative_offset(data, &mut position, source_offset)?;
+                    let bytes = source
+                        .get(source_offset..source_offset + length)
+                        .ok_or_else(corrupted)?;
+                    target.extend_from_slice(bytes);
+                    source_offset += length;
+                }
+                // Bytes from earlier in the target, which can overlap what is being written. This
+                // is the one action that can ask for more than the patch and source add up to
+                _ => {
+                    target_offset = read_relative_offset(data, &mut position, target_offset)?;
+                    target.try_reserve(length).map_err(|_| out_of_space())?;
+                    for _ in 0..length {
+                        let byte = *target.get(target_offset).ok_or_else(corrupted)?;
+                        target.push(byte);
+                        target_offset += 1;
+                    }
+                }
+            }
+        }
+
+        if target.len() != target_size {
+            return Err(corrupted());
+        }
+
+        check_target(&target, read_u32(data, 8))?;
+        return Ok(target);
+    }
+}
+
+/// Read `file` and `patch` out of the VFS and give the patched file. The paths are from the root
+/// of the VFS, whatever namespace is in
+pub fn apply_patch_file(
+    os_context: &EmuRsContext,
+    file: &EmuRsPath,
+    patch: &EmuRsPath,
+) -> Result<Vec<u8>, EmuRsError> {
+    let fs = os_context.fs.borrow();
+    let fs = fs.global();
+
+    let mut source = vec![0; fs.metadata(file)?.size.unwrap_or(0)];
+    fs.read(file, &mut source, 0)?;
+    let mut patch_data = vec![0; fs.metadata(patch)?.size.unwrap_or(0)];
+    fs.read(patch, &mut patch_data, 0)?;
+
+    return EmuRsPatch::parse(&patch_data)?.apply(&source);
+}
+
+pub fn is_patch(file: &EmuRsPath) -> bool {
+    return file
+        .file_name()
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| {
+            return PATCH_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str());
+        });
+}
+
+/// The patch for `file` out of the other files in its directory, which is one with the same
+/// name apart from the extension
+pub fn find_patch(directory_contents: &[EmuRsPath], file: &EmuRsPath) -> Option<EmuRsPath> {
+    if is_patch(file) {
+        return None;
+    }
+
+    let file_name = file.file_name();
+    let stem = file_name
+        .rsplit_once('.')
+        .map_or(file_name.as_str(), |(stem, _)| stem);
+
+    for extension in PATCH_EXTENSIONS {
+        let found = directory_contents.iter().find(|candidate| {
+            return candidate.parent() == file.parent()
+                && candidate.file_name().rsplit_once('.').is_some_and(
+                    |(candidate_stem, candidate_extension)| {
+                        return candidate_stem == stem
+                            && candidate_extension.eq_ignore_ascii_case(extension);
+                    },
+                );
+        });
+
+        if found.is_some() {
+            return found.cloned();
+        }
+    }
+
+    return None;
+}
+
+/// What the patched version of `file` is called, `game.sfc` becoming `game (patched).sfc`
+pub fn patched_path(file: &EmuRsPath) -> EmuRsPath {
+    let file_name = file.file_name();
+    let patched_name = match file_name.rsplit_once('.') {
+        Some((stem, extension)) => format!("{} (patched).{}", stem, extension),
+        None => format!("{} (patched)", file_name),
+    };
+
+    return file
+        .parent()
+        .unwrap_or_default()
+        .join_segment(&patched_name);
+}
+
+fn corrupted() -> EmuRsError {
+    return EmuRsError {
+        reason: EmuRsErrorReason::CorruptedFilesystem,
+    };
+}
+
+fn out_of_space() -> EmuRsError {
+    return EmuRsError {
+        reason: EmuRsErrorReason::OutOfSpace,
+    };
+}
+
+/// Pad `target` with zeros up to `size`. Sizes and offsets come from the patch, so a broken one
+/// can ask for more than there is memory for, which has to be a error instead of a abort
+fn grow(target: &mut Vec<u8>, size: usize) -> Result<(), EmuRsError> {
+    if target.len() < size {
+        target
+            .try_reserve_exact(size - target.len())
+            .map_err(|_| out_of_space())?;
+        target.resize(size, 0);
+    }
+
+    return Ok(());
+}
+
+fn check_source(source: &[u8], size: usize, crc32: u32) -> Result<(), EmuRsError> {
+    if source.len() != size || crc32fast::hash(source) != crc32 {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::PatchSourceMismatch,
+        });
+    }
+
+    return Ok(());
+}
+
+fn check_target(target: &[u8], crc32: u32) -> Result<(), EmuRsError> {
+    if crc32fast::hash(target) != crc32 {
+        return Err(corrupted());
+    }
+
+    return Ok(());
+}
+
+/// The checksums at the end of UPS and BPS patches, counting back from the end
+fn read_u32(data: &[u8], from_end: usize) -> u32 {
+    let start = data.len() - from_end;
+    return u32::from_le_bytes(data[start..start + 4].try_into().unwrap());
+}
+
+fn read_u16_be(data: &[u8], position: &mut usize) -> Result<usize, EmuRsError> {
+    let bytes = data.get(*position..*position + 2).ok_or_else(corrupted)?;
+    *position += 2;
+    return Ok(usize::from(bytes[0]) << 8 | usize::from(bytes[1]));
+}
+
+/// The variable length numbers UPS and BPS use. Every byte but the last has the top bit clear,
+/// and each byte after the first also adds one to what it continues
+fn read_number(data: &[u8], position: &mut usize) -> Result<usize, EmuRsError> {
+    let mut number: u64 = 0;
+    let mut shift: u64 = 1;
+
+    loop {
+        let byte = *data.get(*position).ok_or_else(corrupted)?;
+        *position += 1;
+
+        number = u64::from(byte & 0x7f)
+            .checked_mul(shift)
+            .and_then(|value| number.checked_add(value))
+            .ok_or_else(corrupted)?;
+
+        if byte & 0x80 != 0 {
+            return usize::try_from(number).map_err(|_| corrupted());
+        }
+
+        shift = shift.checked_mul(0x80).ok_or_else(corrupted)?;
+        number = number.checked_add(shift).ok_or_else(corrupted)?;
+    }
+}
+
+/// BPS copies move a cursor by a signed amount, stored with the sign in the lowest bit
+fn read_relative_offset(
+    data: &[u8],
+    position: &mut usize,
+    offset: usize,
+) -> Result<usize, EmuRsError> {
+    let number = read_number(data, position)?;
+    let distance = number >> 1;
+
+    let offset = if number & 1 != 0 {
+        offset.checked_sub(distance)
+    } else {
+        offset.checked_add(distance)
+    };
+
+    return offset.ok_or_else(corrupted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The other way around from [read_number]
+    fn write_number(patch: &mut Vec<u8>, mut number: usize) {
+        loop {
+            let byte = (number & 0x7f) as u8;
+            number >>= 7;
+
+            if number == 0 {
+                patch.push(byte | 0x80);
+                return;
+            }
+
+            patch.push(byte);
+            number -= 1;
+        }
+    }
+
+    /// A UPS or BPS patch with `body` after the sizes and the checksums on the end
+    fn make_patch(
+        magic: &[u8],
+        source: &[u8],
+        target_size: usize,
+        target_crc32: u32,
+        body: &[u8],
+    ) -> Vec<u8> {
+        let mut patch = Vec::from(magic);
+        write_number(&mut patch, source.len());
+        write_number(&mut patch, target_size);
+        patch.extend_from_slice(body);
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&target_crc32.to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(&patch).to_le_bytes());
+        return patch;
+    }
+
+    #[test]
+    fn ups_grows_the_file() {
+        let source = b"hello world";
+        let target = b"hello World\0\0!";
+
+        // Skip to the W and XOR it, then skip past the zeros to the !. The zero ending a run counts
+        // as a byte too
+        let mut body = Vec::new();
+        write_number(&mut body, 6);
+        body.extend_from_slice(&[b'w' ^ b'W', 0]);
+        write_number(&mut body, 5);
+        body.extend_from_slice(&[b'!', 0]);
+
+        let patch = make_patch(
+            b"UPS1",
+            source,
+            target.len(),
+            crc32fast::hash(target),
+            &body,
+        );
+        let patched = EmuRsPatch::parse(&patch).unwrap().apply(source).unwrap();
+        assert_eq!(patched, target);
+    }
+
+    #[test]
+    fn ups_offsets_past_the_end_of_memory_are_corrupted() {
+        let source = b"hello";
+
+        let mut body = Vec::new();
+        write_number(&mut body, usize::MAX);
+        body.extend_from_slice(&[1, 0]);
+
+        let patch = make_patch(b"UPS1", source, 5, 0, &body);
+        let result = EmuRsPatch::parse(&patch).unwrap().apply(source);
+        assert!(matches!(
+            result,
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+    }
+
+    #[test]
+    fn huge_sizes_are_not_allocated_up_front() {
+        let source = b"hello";
+
+        let patch = make_patch(b"UPS1", source, 1 << 60, 0, &[]);
+        let result = EmuRsPatch::parse(&patch).unwrap().apply(source);
+        assert!(matches!(
+            result,
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::OutOfSpace
+            })
+        ));
+
+        // No metadata and no actions
+        let patch = make_patch(b"BPS1", source, 1 << 60, 0, &[0x80]);
+        let result = EmuRsPatch::parse(&patch).unwrap().apply(source);
+        assert!(matches!(
+            result,
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+    }
+
+    /// A IPS record, a RLE one if `run` is set
+    fn ips_record(patch: &mut Vec<u8>, offset: usize, bytes: &[u8], run: Option<usize>) {
+        patch.extend_from_slice(&offset.to_be_bytes()[5..]);
+
+        match run {
+            Some(size) => {
+                patch.extend_from_slice(&[0, 0]);
+                patch.extend_from_slice(&(size as u16).to_be_bytes());
+                patch.push(bytes[0]);
+            }
+            None => {
+                patch.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+                patch.extend_from_slice(bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn ips_writes_records_and_runs() {
+        let source = b"hello world";
+
+        let mut patch = Vec::from(*b"PATCH");
+        ips_record(&mut patch, 0, b"J", None);
+        ips_record(&mut patch, 6, b"W", None);
+        // Past the end, so the file grows
+        ips_record(&mut patch, 11, b"!", Some(3));
+        patch.extend_from_slice(b"EOF");
+
+        let patch = EmuRsPatch::parse(&patch).unwrap();
+        assert_eq!(patch.kind(), EmuRsPatchKind::Ips);
+        assert_eq!(patch.apply(source).unwrap(), b"Jello World!!!");
+    }
+
+    #[test]
+    fn ips_truncates_to_the_size_after_eof() {
+        let source = b"hello world";
+
+        let mut patch = Vec::from(*b"PATCH");
+        ips_record(&mut patch, 0, b"H", None);
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0, 0, 5]);
+
+        let patched = EmuRsPatch::parse(&patch).unwrap().apply(source).unwrap();
+        assert_eq!(patched, b"Hello");
+    }
+
+    #[test]
+    fn ips_without_eof_is_corrupted() {
+        let source = b"hello world";
+
+        let mut patch = Vec::from(*b"PATCH");
+        ips_record(&mut patch, 0, b"H", None);
+
+        // A record cut off half way through is just as broken
+        let mut cut_off = patch.clone();
+        cut_off.extend_from_slice(&[0, 0, 1, 0, 4, b'a']);
+
+        for patch in [patch, cut_off] {
+            let result = EmuRsPatch::parse(&patch).unwrap().apply(source);
+            assert!(matches!(
+                result,
+                Err(EmuRsError {
+                    reason: EmuRsErrorReason::CorruptedFilesystem
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn bps_builds_the_target_from_every_action() {
+        let source = b"hello world";
+        let target = b"hello, big world! world!";
+
+        let mut body = Vec::new();
+        // No metadata
+        write_number(&mut body, 0);
+        // "hello" from the same place in the source
+        write_number(&mut body, (5 - 1) << 2);
+        // ", big" from the patch
+        write_number(&mut body, ((5 - 1) << 2) | 1);
+        body.extend_from_slice(b", big");
+        // " world" from the source, which is 5 bytes ahead of where the source cursor starts
+        write_number(&mut body, ((6 - 1) << 2) | 2);
+        write_number(&mut body, 5 << 1);
+        // "!" from the patch
+        write_number(&mut body, 1);
+        body.push(b'!');
+        // " world!" from earlier in the target, going back from the start to " world!"
+        write_number(&mut body, ((7 - 1) << 2) | 3);
+        write_number(&mut body, 10 << 1);
+
+        let patch = make_patch(
+            b"BPS1",
+            source,
+            target.len(),
+            crc32fast::hash(target),
+            &body,
+        );
+        let patch = EmuRsPatch::parse(&patch).unwrap();
+        assert_eq!(patch.kind(), EmuRsPatchKind::Bps);
+        assert_eq!(patch.apply(source).unwrap(), target);
+
+        // Patches only apply to the file they were made for
+        assert!(matches!(
+            patch.apply(b"hello World"),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::PatchSourceMismatch
+            })
+        ));
+    }
+
+    #[test]
+    fn huge_writes_are_out_of_space() {
+        let source = b"hello";
+
+        // A UPS offset far past the end of the source, inside of a target that claims to be huge
+        let mut body = Vec::new();
+        write_number(&mut body, 1 << 50);
+        body.extend_from_slice(&[1, 0]);
+
+        let patch = make_patch(b"UPS1", source, 1 << 60, 0, &body);
+        let result = EmuRsPatch::parse(&patch).unwrap().apply(source);
+        assert!(matches!(
+            result,
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::OutOfSpace
+            })
+        ));
+
+        // A BPS copy of the target over and over again, which could go on forever
+        let mut body = Vec::new();
+        write_number(&mut body, 0);
+        write_number(&mut body, (5 - 1) << 2);
+        write_number(&mut body, ((1 << 55) << 2) | 3);
+        write_number(&mut body, 0);
+
+        let patch = make_patch(b"BPS1", source, 1 << 60, 0, &body);
+        let result = EmuRsPatch::parse(&patch).unwrap().apply(source);
+        assert!(matches!(
+            result,
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::OutOfSpace
+            })
+        ));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::error::EmuRsError;
+use crate::vfs::EmuRsNamespace;
 use crate::EmuRsContext;
 use alloc::rc::Rc;
 use alloc::vec::Vec;
@@ -8,6 +10,7 @@ pub trait EmuRsProgram: Sync {
     fn new() -> Self
     where
         Self: Sized;
+    /// Titles of firmware in roms.db this needs. They are found and verified before [EmuRsProgram::new] is called and can be read from [EmuRsContext::firmware]
     fn required_firmware() -> Vec<&'static str> {
         return Vec::new();
     }
@@ -15,3 +18,128 @@ pub trait EmuRsProgram: Sync {
     fn vsync(&mut self, os_context: &EmuRsContext);
     fn exit(&mut self);
 }
+
+/// A running [EmuRsProgram] along with the state the kernel keeps for it
+///
+/// Every call into the program happens inside of its own [EmuRsNamespace], so it has its own working directory and cannot see outside of its namespace root
+pub struct EmuRsProgramInstance<PROGRAM: EmuRsProgram> {
+    pub program: PROGRAM,
+    pub namespace: EmuRsNamespace,
+}
+
+impl<PROGRAM: EmuRsProgram> EmuRsProgramInstance<PROGRAM> {
+    /// Start the program, failing if any of its firmware is missing or a bad dump
+    pub fn new(os_context: &EmuRsContext, namespace: EmuRsNamespace) -> Result<Self, EmuRsError> {
+        os_context
+            .firmware
+            .borrow_mut()
+            .resolve(&PROGRAM::required_firmware())?;
+
+        return Ok(Self {
+            program: PROGRAM::new(),
+            namespace,
+        });
+    }
+
+    pub fn step(&mut self, os_context: &EmuRsContext) {
+        self.with_namespace(os_context, |program| {
+            program.step(os_context);
+        });
+    }
+
+    pub fn vsync(&mut self, os_context: &EmuRsContext) {
+        self.with_namespace(os_context, |program| {
+            program.vsync(os_context);
+        });
+    }
+
+    pub fn exit(&mut self) {
+        self.program.exit();
+    }
+
+    /// Swap our namespace in for the duration of the call, keeping whatever the program did to its working directory
+    fn with_namespace(&mut self, os_context: &EmuRsContext, callback: impl FnOnce(&mut PROGRAM)) {
+        let previous = os_context
+            .fs
+            .borrow_mut()
+            .swap_namespace(self.namespace.clone());
+
+        callback(&mut self.program);
+
+        self.namespace = os_context.fs.borrow_mut().swap_namespace(previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use crate::drivers::cborfs::EmuRsCborFs;
+    use crate::vfs::{EmuRsFsDriver, EmuRsPath};
+    use crate::EmuRsContextBuilder;
+    use alloc::vec;
+    use core::cell::RefCell;
+    use core::str::FromStr;
+
+    /// Goes into a folder on the first step and checks it is still there on vsync
+    struct WanderingProgram;
+
+    impl EmuRsProgram for WanderingProgram {
+        fn new() -> Self {
+            return Self;
+        }
+
+        fn step(&mut self, os_context: &EmuRsContext) {
+            os_context
+                .fs
+                .borrow_mut()
+                .change_directory(&EmuRsPath::from_str("game").unwrap())
+                .unwrap();
+        }
+
+        fn vsync(&mut self, os_context: &EmuRsContext) {
+            let fs = os_context.fs.borrow();
+            assert_eq!(
+                fs.namespace().cwd,
+                EmuRsPath::from_str("ROOT/game").unwrap()
+            );
+        }
+
+        fn exit(&mut self) {}
+    }
+
+    #[test]
+    fn runs_inside_of_its_namespace() {
+        let context = EmuRsContextBuilder::default().done();
+        let disk = Rc::new(RefCell::new(VecDisk(vec![0; 8192])));
+        EmuRsCborFs::format(&mut *disk.borrow_mut()).unwrap();
+        let mut root = EmuRsCborFs::default();
+        root.mount(disk).unwrap();
+        context
+            .fs
+            .borrow_mut()
+            .mount(&EmuRsPath::default(), Rc::new(RefCell::new(root)))
+            .unwrap();
+
+        let mut path = EmuRsPath::default();
+        for segment in ["profiles", "player", "saves", "game"] {
+            path = path.join_segment(segment);
+            context.fs.borrow().create_directory(&path).unwrap();
+        }
+
+        let namespace = EmuRsNamespace::profile_saves("player").unwrap();
+        let mut instance =
+            EmuRsProgramInstance::<WanderingProgram>::new(&context, namespace).unwrap();
+
+        instance.step(&context);
+        instance.vsync(&context);
+
+        // The kernel is back in the global namespace between calls, and the program keeps its
+        // working directory
+        assert_eq!(*context.fs.borrow().namespace(), EmuRsNamespace::default());
+        assert_eq!(
+            instance.namespace.cwd,
+            EmuRsPath::from_str("ROOT/game").unwrap()
+        );
+    }
+}
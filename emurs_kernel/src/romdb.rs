@@ -0,0 +1,364 @@
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use crate::vfs::EmuRsPath;
+use crate::EmuRsContext;
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// The layout of roms.db, all numbers little endian
+//
+// Header, 16 bytes
+//   magic          "EMURSRDB"
+//   version        u16, currently 1
+//   reserved       u16
+//   record count   u32
+//
+// Record table, 40 bytes per record, sorted by hash so it can be binary searched in place
+//   hash           BLAKE2s-256 of the whole file
+//   details offset u32, from the start of the file
+//   flags          u8, bit 0 is set for firmware
+//   reserved       [u8; 3]
+//
+// Details, one per record wherever the record points
+//   title, system, region and revision, each a u16 length followed by that much UTF-8
+
+const MAGIC: &[u8; 8] = b"EMURSRDB";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 16;
+const RECORD_SIZE: usize = 40;
+const FLAG_FIRMWARE: u8 = 1 << 0;
+
+/// A ROM or firmware file we know about
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EmuRsRomRecord {
+    pub hash: [u8; 32],
+    pub title: String,
+    /// Short name of the system, like `gba` or `nes`
+    pub system: String,
+    pub region: String,
+    pub revision: String,
+    pub firmware: bool,
+}
+
+/// A roms.db file in the VFS. Lookups read only the records they need
+///
+/// The path is from the root of the VFS, so lookups work from inside of any program's namespace
+pub struct EmuRsRomDatabase {
+    pub os_context: Rc<EmuRsContext>,
+    pub path: EmuRsPath,
+    count: usize,
+}
+
+impl EmuRsRomDatabase {
+    pub fn open(os_context: Rc<EmuRsContext>, path: EmuRsPath) -> Result<Self, EmuRsError> {
+        let mut header = [0; HEADER_SIZE];
+        os_context
+            .fs
+            .borrow()
+            .global()
+            .read(&path, &mut header, 0)?;
+
+        if &header[0..8] != MAGIC {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            });
+        }
+
+        if u16::from_le_bytes([header[8], header[9]]) != VERSION {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported,
+            });
+        }
+
+        return Ok(Self {
+            os_context,
+            path,
+            count: u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        return self.count;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.count == 0;
+    }
+
+    /// Find what we know about a file from its hash
+    pub fn lookup(&self, hash: &[u8; 32]) -> Result<Option<EmuRsRomRecord>, EmuRsError> {
+        let mut low = 0;
+        let mut high = self.count;
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let record = self.read_record(middle)?;
+
+            match record[0..32].cmp(hash) {
+                core::cmp::Ordering::Less => low = middle + 1,
+                core::cmp::Ordering::Greater => high = middle,
+                core::cmp::Ordering::Equal => return self.read_details(&record).map(Some),
+            }
+        }
+
+        return Ok(None);
+    }
+
+    /// Every record in the database, in hash order
+    pub fn records(&self) -> Result<Vec<EmuRsRomRecord>, EmuRsError> {
+        return (0..self.count)
+            .map(|index| self.read_details(&self.read_record(index)?))
+            .collect();
+    }
+
+    fn read_record(&self, index: usize) -> Result<[u8; RECORD_SIZE], EmuRsError> {
+        let mut record = [0; RECORD_SIZE];
+        self.os_context.fs.borrow().global().read(
+            &self.path,
+            &mut record,
+            HEADER_SIZE + index * RECORD_SIZE,
+        )?;
+        return Ok(record);
+    }
+
+    fn read_details(&self, record: &[u8; RECORD_SIZE]) -> Result<EmuRsRomRecord, EmuRsError> {
+        let fs = self.os_context.fs.borrow();
+        let fs = fs.global();
+        let mut offset = u32::from_le_bytes(record[32..36].try_into().unwrap()) as usize;
+        let mut strings: [String; 4] = Default::default();
+
+        for string in strings.iter_mut() {
+            let mut length = [0; 2];
+            fs.read(&self.path, &mut length, offset)?;
+
+            let mut bytes = vec![0; u16::from_le_bytes(length) as usize];
+            fs.read(&self.path, &mut bytes, offset + 2)?;
+            offset += 2 + bytes.len();
+
+            *string = String::from_utf8(bytes).map_err(|_| EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem,
+            })?;
+        }
+
+        let [title, system, region, revision] = strings;
+
+        return Ok(EmuRsRomRecord {
+            hash: record[0..32].try_into().unwrap(),
+            title,
+            system,
+            region,
+            revision,
+            firmware: record[36] & FLAG_FIRMWARE != 0,
+        });
+    }
+}
+
+/// Builds a roms.db. Adding a hash that is already there replaces the old record
+#[derive(Debug, Clone, Default)]
+pub struct EmuRsRomDatabaseWriter {
+    records: BTreeMap<[u8; 32], EmuRsRomRecord>,
+}
+
+impl EmuRsRomDatabaseWriter {
+    /// Start from the records of a existing database
+    pub fn from_database(database: &EmuRsRomDatabase) -> Result<Self, EmuRsError> {
+        let mut writer = Self::default();
+
+        for record in database.records()? {
+            writer.add(record);
+        }
+
+        return Ok(writer);
+    }
+
+    pub fn add(&mut self, record: EmuRsRomRecord) {
+        self.records.insert(record.hash, record);
+    }
+
+    pub fn remove(&mut self, hash: &[u8; 32]) -> Option<EmuRsRomRecord> {
+        return self.records.remove(hash);
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EmuRsError> {
+        let too_big = EmuRsError {
+            reason: EmuRsErrorReason::OutOfSpace,
+        };
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&[0; 2]);
+        bytes.extend_from_slice(
+            &u32::try_from(self.records.len())
+                .map_err(|_| too_big.clone())?
+                .to_le_bytes(),
+        );
+
+        let mut details = Vec::new();
+        let details_start = HEADER_SIZE + self.records.len() * RECORD_SIZE;
+
+        for record in self.records.values() {
+            let details_offset =
+                u32::try_from(details_start + details.len()).map_err(|_| too_big.clone())?;
+
+            bytes.extend_from_slice(&record.hash);
+            bytes.extend_from_slice(&details_offset.to_le_bytes());
+            bytes.push(if record.firmware { FLAG_FIRMWARE } else { 0 });
+            bytes.extend_from_slice(&[0; 3]);
+
+            for string in [
+                &record.title,
+                &record.system,
+                &record.region,
+                &record.revision,
+            ] {
+                let length = u16::try_from(string.len()).map_err(|_| too_big.clone())?;
+                details.extend_from_slice(&length.to_le_bytes());
+                details.extend_from_slice(string.as_bytes());
+            }
+        }
+
+        bytes.extend_from_slice(&details);
+        return Ok(bytes);
+    }
+
+    /// Replace whatever is at `path` with this database. Like [EmuRsRomDatabase::open] the path is
+    /// from the root of the VFS
+    pub fn save(&self, os_context: &EmuRsContext, path: &EmuRsPath) -> Result<(), EmuRsError> {
+        let bytes = self.to_bytes()?;
+        let fs = os_context.fs.borrow();
+        let fs = fs.global();
+
+        let _ = fs.delete(path);
+        fs.create(path)?;
+        return fs.write(path, &bytes, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+    use crate::drivers::cborfs::EmuRsCborFs;
+    use crate::vfs::{EmuRsFsDriver, EmuRsNamespace};
+    use crate::EmuRsContextBuilder;
+    use core::cell::RefCell;
+    use core::str::FromStr;
+
+    fn record(first_byte: u8, title: &str, firmware: bool) -> EmuRsRomRecord {
+        let mut hash = [0x55; 32];
+        hash[0] = first_byte;
+
+        return EmuRsRomRecord {
+            hash,
+            title: title.into(),
+            system: "gba".into(),
+            region: if firmware { "" } else { "USA" }.into(),
+            revision: "Rev 1".into(),
+            firmware,
+        };
+    }
+
+    #[test]
+    fn round_trips_through_the_vfs() {
+        let context = EmuRsContextBuilder::default().done();
+        let disk = Rc::new(RefCell::new(VecDisk(vec![0; 8192])));
+        EmuRsCborFs::format(&mut *disk.borrow_mut()).unwrap();
+        let mut root = EmuRsCborFs::default();
+        root.mount(disk).unwrap();
+        context
+            .fs
+            .borrow_mut()
+            .mount(&EmuRsPath::default(), Rc::new(RefCell::new(root)))
+            .unwrap();
+
+        // Added out of order so the writer has to sort them for the binary search
+        let records = [
+            record(0x90, "Some Game", false),
+            record(0x10, "gba_bios.bin", true),
+            record(0xf0, "Another Game", false),
+            record(0x40, "Third Game", false),
+            record(0x00, "", false),
+        ];
+        let mut writer = EmuRsRomDatabaseWriter::default();
+        for record in records.iter() {
+            writer.add(record.clone());
+        }
+
+        // Saving from inside of a program's namespace still puts it at the root
+        let path = EmuRsPath::from_str("ROOT/roms.db").unwrap();
+        let previous = context
+            .fs
+            .borrow_mut()
+            .swap_namespace(EmuRsNamespace::profile_saves("player").unwrap());
+        writer.save(&context, &path).unwrap();
+
+        let database = EmuRsRomDatabase::open(context.clone(), path).unwrap();
+        context.fs.borrow_mut().swap_namespace(previous);
+
+        assert_eq!(database.len(), records.len());
+        for record in records.iter() {
+            assert_eq!(
+                database.lookup(&record.hash).unwrap().as_ref(),
+                Some(record)
+            );
+        }
+
+        let mut missing = records[0].hash;
+        missing[31] = 0;
+        assert_eq!(database.lookup(&missing).unwrap(), None);
+        assert_eq!(database.lookup(&[0xff; 32]).unwrap(), None);
+
+        let listed = database.records().unwrap();
+        assert!(listed.windows(2).all(|pair| pair[0].hash < pair[1].hash));
+        assert_eq!(
+            listed
+                .iter()
+                .filter(|record| record.firmware)
+                .map(|record| record.title.as_str())
+                .collect::<Vec<_>>(),
+            ["gba_bios.bin"]
+        );
+
+        // Reading a database back into a writer doesn't change it
+        assert_eq!(
+            EmuRsRomDatabaseWriter::from_database(&database)
+                .unwrap()
+                .to_bytes()
+                .unwrap(),
+            writer.to_bytes().unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let context = EmuRsContextBuilder::default().done();
+        let disk = Rc::new(RefCell::new(VecDisk(vec![0; 8192])));
+        EmuRsCborFs::format(&mut *disk.borrow_mut()).unwrap();
+        let mut root = EmuRsCborFs::default();
+        root.mount(disk).unwrap();
+        context
+            .fs
+            .borrow_mut()
+            .mount(&EmuRsPath::default(), Rc::new(RefCell::new(root)))
+            .unwrap();
+
+        let path = EmuRsPath::from_str("ROOT/roms.db").unwrap();
+        let mut bytes = EmuRsRomDatabaseWriter::default().to_bytes().unwrap();
+        bytes[0] = b'X';
+        {
+            let fs = context.fs.borrow();
+            fs.create(&path).unwrap();
+            fs.write(&path, &bytes, 0).unwrap();
+        }
+
+        assert!(matches!(
+            EmuRsRomDatabase::open(context.clone(), path),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::CorruptedFilesystem
+            })
+        ));
+    }
+}
@@ -0,0 +1,792 @@
+use crate::compression::EmuRsReadSource;
+use crate::error::EmuRsError;
+use alloc::string::String;
+use serde::{Deserialize, Serialize};
+
+// Works out what a ROM is from the header most systems put somewhere near the start, so files
+// don't have to be named right. Everything is checked in order of how sure we can be, with SNES
+// last since it has no magic number and has to be guessed at
+
+const GAME_BOY_LOGO: [u8; 48] = [
+    0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
+    0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e, 0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
+    0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+];
+
+/// Dumps made with old copiers have this much of their own header in front of the ROM
+const SNES_COPIER_HEADER_SIZE: usize = 0x200;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmuRsGbaHeader {
+    pub title: String,
+    /// Like `AGBE`, the last letter being the region
+    pub game_code: String,
+    pub maker: String,
+    pub version: u8,
+    pub complement_valid: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EmuRsGameBoyColorSupport {
+    None,
+    Supported,
+    Required,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmuRsGameBoyHeader {
+    pub title: String,
+    pub color: EmuRsGameBoyColorSupport,
+    pub super_game_boy: bool,
+    /// What mapper and extra hardware is on the cartridge
+    pub cartridge_type: u8,
+    pub rom_size: Option<usize>,
+    pub ram_size: Option<usize>,
+    /// Two letters for newer games, two hex digits for older ones
+    pub licensee: String,
+    pub version: u8,
+    pub header_checksum_valid: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmuRsNesHeader {
+    /// NES 2.0 rather than plain iNES
+    pub nes2: bool,
+    pub mapper: u16,
+    /// Only NES 2.0 has these
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub vertical_mirroring: bool,
+    pub four_screen: bool,
+    pub battery: bool,
+    pub trainer: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EmuRsSnesMapping {
+    LoRom,
+    HiRom,
+    ExHiRom,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmuRsSnesHeader {
+    pub title: String,
+    pub mapping: EmuRsSnesMapping,
+    /// The ROM has a 512 byte copier header in front of it
+    pub copier_header: bool,
+    pub fast_rom: bool,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub region: u8,
+    pub version: u8,
+    /// Only the checksum and its complement agreeing, the checksum itself isn't calculated
+    pub complement_valid: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmuRsMegaDriveHeader {
+    /// Usually `SEGA MEGA DRIVE` or `SEGA GENESIS`
+    pub console: String,
+    pub domestic_title: String,
+    pub overseas_title: String,
+    /// Like `GM 00001009-00`
+    pub serial: String,
+    pub regions: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EmuRsN64ByteOrder {
+    /// `.z64`, how the cartridge actually is
+    BigEndian,
+    /// `.v64`, every pair of bytes swapped
+    ByteSwapped,
+    /// `.n64`, every four bytes reversed
+    LittleEndian,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmuRsN64Header {
+    pub byte_order: EmuRsN64ByteOrder,
+    pub title: String,
+    /// Like `NSME`, the last letter being the region
+    pub game_code: String,
+    pub version: u8,
+}
+
+/// What a ROM header says about the ROM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EmuRsRomInfo {
+    GameBoyAdvance(EmuRsGbaHeader),
+    GameBoy(EmuRsGameBoyHeader),
+    Nes(EmuRsNesHeader),
+    Snes(EmuRsSnesHeader),
+    MegaDrive(EmuRsMegaDriveHeader),
+    Nintendo64(EmuRsN64Header),
+}
+
+impl EmuRsRomInfo {
+    /// Short name of the system, the same ones roms.db uses
+    pub fn system(&self) -> &'static str {
+        return match self {
+            EmuRsRomInfo::GameBoyAdvance(_) => "gba",
+            EmuRsRomInfo::GameBoy(header) if header.color != EmuRsGameBoyColorSupport::None => {
+                "gbc"
+            }
+            EmuRsRomInfo::GameBoy(_) => "gb",
+            EmuRsRomInfo::Nes(_) => "nes",
+            EmuRsRomInfo::Snes(_) => "snes",
+            EmuRsRomInfo::MegaDrive(_) => "md",
+            EmuRsRomInfo::Nintendo64(_) => "n64",
+        };
+    }
+
+    /// The title stored in the header. iNES headers don't have one
+    pub fn title(&self) -> Option<&str> {
+        let title = match self {
+            EmuRsRomInfo::GameBoyAdvance(header) => &header.title,
+            EmuRsRomInfo::GameBoy(header) => &header.title,
+            EmuRsRomInfo::Nes(_) => return None,
+            EmuRsRomInfo::Snes(header) => &header.title,
+            EmuRsRomInfo::MegaDrive(header) if !header.overseas_title.is_empty() => {
+                &header.overseas_title
+            }
+            EmuRsRomInfo::MegaDrive(header) => &header.domestic_title,
+            EmuRsRomInfo::Nintendo64(header) => &header.title,
+        };
+
+        return Some(title.as_str()).filter(|title| !title.is_empty());
+    }
+
+    /// Read whatever headers the file could have through `read` and work out what it is
+    pub fn identify(size: usize, read: &mut EmuRsReadSource) -> Result<Option<Self>, EmuRsError> {
+        // Every header is in here except for ExHiROM SNES ones
+        let mut start = [0; 0x200];
+        let start_size = size.min(start.len());
+        read(&mut start[..start_size], 0)?;
+        let start = &start[..start_size];
+
+        let identified = identify_gba(start)
+            .or_else(|| identify_game_boy(start))
+            .or_else(|| identify_nes(start))
+            .or_else(|| identify_n64(start))
+            .or_else(|| identify_mega_drive(start));
+
+        if identified.is_some() {
+            return Ok(identified);
+        }
+
+        return identify_snes(size, read);
+    }
+}
+
+/// Header text is meant to be ASCII padded with spaces or zeros, but isn't always
+fn ascii(bytes: &[u8]) -> String {
+    return bytes
+        .iter()
+        .map(|byte| match byte {
+            0x20..=0x7e => *byte as char,
+            0 => ' ',
+            _ => '?',
+        })
+        .collect::<String>()
+        .trim()
+        .into();
+}
+
+fn identify_gba(header: &[u8]) -> Option<EmuRsRomInfo> {
+    // A branch over the header to start with, and the fixed value every cartridge has
+    if header.len() < 0xc0 || header[0x03] != 0xea || header[0xb2] != 0x96 {
+        return None;
+    }
+
+    let complement = header[0xa0..=0xbc]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_sub(*byte))
+        .wrapping_sub(0x19);
+
+    return Some(EmuRsRomInfo::GameBoyAdvance(EmuRsGbaHeader {
+        title: ascii(&header[0xa0..0xac]),
+        game_code: ascii(&header[0xac..0xb0]),
+        maker: ascii(&header[0xb0..0xb2]),
+        version: header[0xbc],
+        complement_valid: complement == header[0xbd],
+    }));
+}
+
+fn identify_game_boy(header: &[u8]) -> Option<EmuRsRomInfo> {
+    if header.len() < 0x150 || header[0x104..0x134] != GAME_BOY_LOGO {
+        return None;
+    }
+
+    let color = match header[0x143] {
+        0xc0 => EmuRsGameBoyColorSupport::Required,
+        0x80 => EmuRsGameBoyColorSupport::Supported,
+        _ => EmuRsGameBoyColorSupport::None,
+    };
+
+    // Color games took the last byte of the title for the color flag
+    let title = match color {
+        EmuRsGameBoyColorSupport::None => &header[0x134..0x144],
+        _ => &header[0x134..0x143],
+    };
+
+    let licensee = match header[0x14b] {
+        0x33 => ascii(&header[0x144..0x146]),
+        old => alloc::format!("{:02X}", old),
+    };
+
+    let ram_size = match header[0x149] {
+        0 => Some(0),
+        1 => Some(0x800),
+        2 => Some(0x2000),
+        3 => Some(0x8000),
+        4 => Some(0x20000),
+        5 => Some(0x10000),
+        _ => None,
+    };
+
+    let checksum = header[0x134..=0x14c]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+
+    return Some(EmuRsRomInfo::GameBoy(EmuRsGameBoyHeader {
+        title: ascii(title),
+        color,
+        super_game_boy: header[0x146] == 0x03,
+        cartridge_type: header[0x147],
+        rom_size: 0x8000usize.checked_shl(header[0x148].into()),
+        ram_size,
+        licensee,
+        version: header[0x14c],
+        header_checksum_valid: checksum == header[0x14d],
+    }));
+}
+
+fn identify_nes(header: &[u8]) -> Option<EmuRsRomInfo> {
+    if header.len() < 16 || header[0..4] != *b"NES\x1a" {
+        return None;
+    }
+
+    let nes2 = header[7] & 0x0c == 0x08;
+
+    // Some old dumping tools wrote their name over the end of the header, which means the
+    // upper mapper bits can't be trusted
+    let mut mapper = u16::from(header[6] >> 4);
+    if nes2 || header[12..16].iter().all(|byte| *byte == 0) {
+        mapper |= u16::from(header[7] & 0xf0);
+    }
+
+    let (submapper, prg_rom_size, chr_rom_size) = if nes2 {
+        mapper |= u16::from(header[8] & 0x0f) << 8;
+        (
+            header[8] >> 4,
+            nes2_rom_size(header[4], header[9] & 0x0f, 0x4000),
+            nes2_rom_size(header[5], header[9] >> 4, 0x2000),
+        )
+    } else {
+        (
+            0,
+            usize::from(header[4]) * 0x4000,
+            usize::from(header[5]) * 0x2000,
+        )
+    };
+
+    return Some(EmuRsRomInfo::Nes(EmuRsNesHeader {
+        nes2,
+        mapper,
+        submapper,
+        prg_rom_size,
+        chr_rom_size,
+        vertical_mirroring: header[6] & 0x01 != 0,
+        battery: header[6] & 0x02 != 0,
+        trainer: header[6] & 0x04 != 0,
+        four_screen: header[6] & 0x08 != 0,
+    }));
+}
+
+/// NES 2.0 sizes are either a count of units or, when the high nibble is all ones, a exponent
+fn nes2_rom_size(low: u8, high: u8, unit: usize) -> usize {
+    if high == 0x0f {
+        let multiplier = usize::from(low & 0x03) * 2 + 1;
+        return 1usize
+            .checked_shl((low >> 2).into())
+            .map_or(0, |size| size * multiplier);
+    }
+
+    return (usize::from(high) << 8 | usize::from(low)) * unit;
+}
+
+fn identify_n64(header: &[u8]) -> Option<EmuRsRomInfo> {
+    if header.len() < 0x40 {
+        return None;
+    }
+
+    let byte_order = match header[0..4] {
+        [0x80, 0x37, 0x12, 0x40] => EmuRsN64ByteOrder::BigEndian,
+        [0x37, 0x80, 0x40, 0x12] => EmuRsN64ByteOrder::ByteSwapped,
+        [0x40, 0x12, 0x37, 0x80] => EmuRsN64ByteOrder::LittleEndian,
+        _ => return None,
+    };
+
+    // Put the header back the way the console sees it
+    let mut normalized = [0; 0x40];
+    normalized.copy_from_slice(&header[..0x40]);
+    match byte_order {
+        EmuRsN64ByteOrder::BigEndian => {}
+        EmuRsN64ByteOrder::ByteSwapped => normalized.chunks_mut(2).for_each(|pair| pair.swap(0, 1)),
+        EmuRsN64ByteOrder::LittleEndian => normalized.chunks_mut(4).for_each(|word| word.reverse()),
+    }
+
+    return Some(EmuRsRomInfo::Nintendo64(EmuRsN64Header {
+        byte_order,
+        title: ascii(&normalized[0x20..0x34]),
+        game_code: ascii(&normalized[0x3b..0x3f]),
+        version: normalized[0x3f],
+    }));
+}
+
+fn identify_mega_drive(header: &[u8]) -> Option<EmuRsRomInfo> {
+    // Some games put a space in front
+    if header.len() < 0x200
+        || !(header[0x100..0x104] == *b"SEGA" || header[0x101..0x105] == *b"SEGA")
+    {
+        return None;
+    }
+
+    return Some(EmuRsRomInfo::MegaDrive(EmuRsMegaDriveHeader {
+        console: ascii(&header[0x100..0x110]),
+        domestic_title: ascii(&header[0x120..0x150]),
+        overseas_title: ascii(&header[0x150..0x180]),
+        serial: ascii(&header[0x180..0x18e]),
+        regions: ascii(&header[0x1f0..0x1f3]),
+    }));
+}
+
+fn identify_snes(
+    size: usize,
+    read: &mut EmuRsReadSource,
+) -> Result<Option<EmuRsRomInfo>, EmuRsError> {
+    // Copiers made every dump a multiple of 1K, plus their own header
+    let copier_header = size % 0x400 == SNES_COPIER_HEADER_SIZE;
+    let base = if copier_header {
+        SNES_COPIER_HEADER_SIZE
+    } else {
+        0
+    };
+
+    let mut best: Option<(u8, EmuRsSnesHeader)> = None;
+
+    for (mapping, location) in [
+        (EmuRsSnesMapping::LoRom, 0x7fc0),
+        (EmuRsSnesMapping::HiRom, 0xffc0),
+        (EmuRsSnesMapping::ExHiRom, 0x40ffc0),
+    ] {
+        let mut header = [0; 0x40];
+        if base + location + header.len() > size {
+            continue;
+        }
+        read(&mut header, base + location)?;
+
+        let (score, parsed) = score_snes_header(&header, mapping, copier_header);
+        if best.as_ref().is_none_or(|(best, _)| score > *best) {
+            best = Some((score, parsed));
+        }
+    }
+
+    // Anything that is only a bit like a SNES header is more likely to be something else
+    return Ok(best
+        .filter(|(score, _)| *score >= 5)
+        .map(|(_, header)| EmuRsRomInfo::Snes(header)));
+}
+
+/// How much a possible SNES header looks like the real thing
+fn score_snes_header(
+    header: &[u8; 0x40],
+    mapping: EmuRsSnesMapping,
+    copier_header: bool,
+) -> (u8, EmuRsSnesHeader) {
+    let map_mode = header[0x15];
+    let complement = u16::from_le_bytes([header[0x1c], header[0x1d]]);
+    let checksum = u16::from_le_bytes([header[0x1e], header[0x1f]]);
+    let reset_vector = u16::from_le_bytes([header[0x3c], header[0x3d]]);
+    let complement_valid = complement ^ checksum == 0xffff;
+
+    let mut score = 0;
+
+    if complement_valid {
+        score += 4;
+    }
+
+    let mapping_matches = match mapping {
+        EmuRsSnesMapping::LoRom => map_mode & 0xef == 0x20,
+        EmuRsSnesMapping::HiRom => map_mode & 0xef == 0x21,
+        EmuRsSnesMapping::ExHiRom => map_mode & 0xef == 0x25,
+    };
+    if mapping_matches {
+        score += 2;
+    }
+
+    // The CPU starts in the first bank, where ROM is only mapped from 0x8000 up
+    if reset_vector >= 0x8000 {
+        score += 1;
+    }
+
+    if header[0..0x15]
+        .iter()
+        .all(|byte| (0x20..0x7f).contains(byte))
+    {
+        score += 1;
+    }
+
+    return (
+        score,
+        EmuRsSnesHeader {
+            title: ascii(&header[0..0x15]),
+            mapping,
+            copier_header,
+            fast_rom: map_mode & 0x10 != 0,
+            rom_size: 0x400usize.checked_shl(header[0x17].into()).unwrap_or(0),
+            ram_size: match header[0x18] {
+                0 => 0,
+                ram_size => 0x400usize.checked_shl(ram_size.into()).unwrap_or(0),
+            },
+            region: header[0x19],
+            version: header[0x1b],
+            complement_valid,
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    // No real ROMs can go in the repository, so these are just the headers with the rest left
+    // blank. The fields are the ones the real games have
+
+    fn identify(rom: &[u8]) -> Option<EmuRsRomInfo> {
+        return EmuRsRomInfo::identify(rom.len(), &mut |buffer, offset| {
+            buffer.copy_from_slice(&rom[offset..offset + buffer.len()]);
+            return Ok(());
+        })
+        .unwrap();
+    }
+
+    fn put(rom: &mut [u8], offset: usize, bytes: &[u8]) {
+        rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn gba() -> Vec<u8> {
+        let mut rom = vec![0; 0x200];
+        rom[0x03] = 0xea;
+        put(&mut rom, 0xa0, b"POKEMON RUBY");
+        put(&mut rom, 0xac, b"AXVE01");
+        rom[0xb2] = 0x96;
+        rom[0xbc] = 1;
+        rom[0xbd] = (0xa0..=0xbc)
+            .fold(0u8, |sum, index| sum.wrapping_sub(rom[index]))
+            .wrapping_sub(0x19);
+        return rom;
+    }
+
+    fn game_boy(title: &[u8], color: u8, licensee: &[u8], old_licensee: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        put(&mut rom, 0x104, &GAME_BOY_LOGO);
+        put(&mut rom, 0x134, title);
+        rom[0x143] = color;
+        put(&mut rom, 0x144, licensee);
+        rom[0x14b] = old_licensee;
+        rom[0x14d] = (0x134..=0x14c).fold(0u8, |sum, index| {
+            return sum.wrapping_sub(rom[index]).wrapping_sub(1);
+        });
+        return rom;
+    }
+
+    fn snes(size: usize, location: usize, map_mode: u8) -> Vec<u8> {
+        let mut rom = vec![0; size];
+        put(&mut rom, location, b"SUPER MARIO WORLD    ");
+        rom[location + 0x15] = map_mode;
+        rom[location + 0x17] = 0x09;
+        rom[location + 0x18] = 0x01;
+        rom[location + 0x19] = 0x01;
+        put(&mut rom, location + 0x1c, &[0x25, 0x5f, 0xda, 0xa0]);
+        put(&mut rom, location + 0x3c, &0x8000u16.to_le_bytes());
+        return rom;
+    }
+
+    fn snes_header(rom: &[u8]) -> EmuRsSnesHeader {
+        return match identify(rom) {
+            Some(EmuRsRomInfo::Snes(header)) => header,
+            other => panic!("{:?}", other),
+        };
+    }
+
+    #[test]
+    fn identifies_gba() {
+        let mut rom = gba();
+        let info = identify(&rom).unwrap();
+
+        assert_eq!(info.system(), "gba");
+        assert_eq!(info.title(), Some("POKEMON RUBY"));
+        assert_eq!(
+            info,
+            EmuRsRomInfo::GameBoyAdvance(EmuRsGbaHeader {
+                title: "POKEMON RUBY".into(),
+                game_code: "AXVE".into(),
+                maker: "01".into(),
+                version: 1,
+                complement_valid: true,
+            })
+        );
+
+        rom[0xa0] = b'D';
+        match identify(&rom) {
+            Some(EmuRsRomInfo::GameBoyAdvance(header)) => assert!(!header.complement_valid),
+            other => panic!("{:?}", other),
+        }
+
+        // Without the fixed value it isn't a GBA game at all
+        rom[0xb2] = 0;
+        assert_eq!(identify(&rom), None);
+    }
+
+    #[test]
+    fn identifies_game_boy() {
+        let mut rom = game_boy(b"TETRIS", 0, b"", 0x01);
+        let info = identify(&rom).unwrap();
+
+        assert_eq!(info.system(), "gb");
+        assert_eq!(
+            info,
+            EmuRsRomInfo::GameBoy(EmuRsGameBoyHeader {
+                title: "TETRIS".into(),
+                color: EmuRsGameBoyColorSupport::None,
+                super_game_boy: false,
+                cartridge_type: 0,
+                rom_size: Some(0x8000),
+                ram_size: Some(0),
+                licensee: "01".into(),
+                version: 0,
+                header_checksum_valid: true,
+            })
+        );
+
+        rom[0x14c] = 1;
+        match identify(&rom) {
+            Some(EmuRsRomInfo::GameBoy(header)) => {
+                assert_eq!(header.version, 1);
+                assert!(!header.header_checksum_valid);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // A broken logo won't boot, so it isn't one
+        rom[0x104] = 0;
+        assert_eq!(identify(&rom), None);
+    }
+
+    #[test]
+    fn identifies_game_boy_color() {
+        let mut rom = game_boy(b"POKEMON_SLVAAXE", 0x80, b"01", 0x33);
+        rom[0x146] = 0x03;
+        rom[0x147] = 0x10;
+        rom[0x148] = 0x06;
+        rom[0x149] = 0x03;
+        rom[0x14d] = (0x134..=0x14c).fold(0u8, |sum, index| {
+            return sum.wrapping_sub(rom[index]).wrapping_sub(1);
+        });
+
+        let info = identify(&rom).unwrap();
+        assert_eq!(info.system(), "gbc");
+
+        match info {
+            EmuRsRomInfo::GameBoy(header) => {
+                // The last byte of the title is the color flag
+                assert_eq!(header.title, "POKEMON_SLVAAXE");
+                assert_eq!(header.color, EmuRsGameBoyColorSupport::Supported);
+                assert!(header.super_game_boy);
+                assert_eq!(header.cartridge_type, 0x10);
+                assert_eq!(header.rom_size, Some(0x200000));
+                assert_eq!(header.ram_size, Some(0x8000));
+                assert_eq!(header.licensee, "01");
+                assert!(header.header_checksum_valid);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn identifies_ines() {
+        let mut rom = vec![0; 16 + 2 * 0x4000 + 0x2000];
+        put(&mut rom, 0, b"NES\x1a\x02\x01\x43\x00");
+
+        assert_eq!(
+            identify(&rom),
+            Some(EmuRsRomInfo::Nes(EmuRsNesHeader {
+                nes2: false,
+                mapper: 4,
+                submapper: 0,
+                prg_rom_size: 0x8000,
+                chr_rom_size: 0x2000,
+                vertical_mirroring: true,
+                four_screen: false,
+                battery: true,
+                trainer: false,
+            }))
+        );
+        assert_eq!(identify(&rom).unwrap().title(), None);
+
+        // DiskDude! wrote over the end of the header, so the upper mapper bits are garbage
+        put(&mut rom, 7, b"DiskDude!");
+        match identify(&rom) {
+            Some(EmuRsRomInfo::Nes(header)) => assert_eq!(header.mapper, 4),
+            other => panic!("{:?}", other),
+        }
+
+        put(&mut rom, 7, &[0x40, 0, 0, 0, 0, 0, 0, 0, 0]);
+        match identify(&rom) {
+            Some(EmuRsRomInfo::Nes(header)) => assert_eq!(header.mapper, 0x44),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn identifies_nes2() {
+        let mut rom = vec![0; 0x200];
+        // Mapper 0x15a submapper 2, 1MiB of PRG ROM as a exponent and 0x102 banks of CHR ROM
+        put(
+            &mut rom,
+            0,
+            b"NES\x1a\x50\x02\xa8\x58\x21\x1f\x00\x00\x00\x00\x00\x00",
+        );
+
+        assert_eq!(
+            identify(&rom),
+            Some(EmuRsRomInfo::Nes(EmuRsNesHeader {
+                nes2: true,
+                mapper: 0x15a,
+                submapper: 2,
+                prg_rom_size: 0x100000,
+                chr_rom_size: 0x102 * 0x2000,
+                vertical_mirroring: false,
+                four_screen: true,
+                battery: false,
+                trainer: false,
+            }))
+        );
+    }
+
+    #[test]
+    fn identifies_n64_in_every_byte_order() {
+        let mut big_endian = vec![0; 0x1000];
+        put(&mut big_endian, 0, &[0x80, 0x37, 0x12, 0x40]);
+        put(&mut big_endian, 0x20, b"SUPER MARIO 64      ");
+        put(&mut big_endian, 0x3b, b"NSME");
+
+        let mut byte_swapped = big_endian.clone();
+        byte_swapped.chunks_mut(2).for_each(|pair| pair.swap(0, 1));
+        let mut little_endian = big_endian.clone();
+        little_endian.chunks_mut(4).for_each(|word| word.reverse());
+
+        for (rom, byte_order) in [
+            (big_endian, EmuRsN64ByteOrder::BigEndian),
+            (byte_swapped, EmuRsN64ByteOrder::ByteSwapped),
+            (little_endian, EmuRsN64ByteOrder::LittleEndian),
+        ] {
+            assert_eq!(
+                identify(&rom),
+                Some(EmuRsRomInfo::Nintendo64(EmuRsN64Header {
+                    byte_order,
+                    title: "SUPER MARIO 64".into(),
+                    game_code: "NSME".into(),
+                    version: 0,
+                }))
+            );
+        }
+    }
+
+    #[test]
+    fn identifies_mega_drive() {
+        let mut rom = vec![0; 0x400];
+        put(&mut rom, 0x100, b"SEGA MEGA DRIVE ");
+        put(&mut rom, 0x120, b"SONIC THE               HEDGEHOG");
+        put(&mut rom, 0x150, b"SONIC THE               HEDGEHOG");
+        put(&mut rom, 0x180, b"GM 00001009-00");
+        put(&mut rom, 0x1f0, b"JUE");
+
+        let info = identify(&rom).unwrap();
+        assert_eq!(info.system(), "md");
+        assert_eq!(
+            info,
+            EmuRsRomInfo::MegaDrive(EmuRsMegaDriveHeader {
+                console: "SEGA MEGA DRIVE".into(),
+                domestic_title: "SONIC THE               HEDGEHOG".into(),
+                overseas_title: "SONIC THE               HEDGEHOG".into(),
+                serial: "GM 00001009-00".into(),
+                regions: "JUE".into(),
+            })
+        );
+
+        // Some put a space in front, and only have a domestic title
+        put(&mut rom, 0x100, b" SEGA GENESIS   ");
+        put(&mut rom, 0x150, &[b' '; 0x30]);
+        let info = identify(&rom).unwrap();
+        assert_eq!(info.title(), Some("SONIC THE               HEDGEHOG"));
+        match info {
+            EmuRsRomInfo::MegaDrive(header) => assert_eq!(header.console, "SEGA GENESIS"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn identifies_snes_mappings() {
+        let header = snes_header(&snes(0x80000, 0x7fc0, 0x20));
+        assert_eq!(
+            header,
+            EmuRsSnesHeader {
+                title: "SUPER MARIO WORLD".into(),
+                mapping: EmuRsSnesMapping::LoRom,
+                copier_header: false,
+                fast_rom: false,
+                rom_size: 0x80000,
+                ram_size: 0x800,
+                region: 1,
+                version: 0,
+                complement_valid: true,
+            }
+        );
+
+        let header = snes_header(&snes(0x80000, 0xffc0, 0x31));
+        assert_eq!(header.mapping, EmuRsSnesMapping::HiRom);
+        assert!(header.fast_rom);
+
+        let header = snes_header(&snes(0x410000, 0x40ffc0, 0x35));
+        assert_eq!(header.mapping, EmuRsSnesMapping::ExHiRom);
+        assert!(header.fast_rom);
+    }
+
+    #[test]
+    fn identifies_snes_copier_headers() {
+        let mut rom = vec![0; SNES_COPIER_HEADER_SIZE];
+        rom.extend(snes(0x80000, 0x7fc0, 0x20));
+
+        let header = snes_header(&rom);
+        assert!(header.copier_header);
+        assert_eq!(header.mapping, EmuRsSnesMapping::LoRom);
+        assert_eq!(header.title, "SUPER MARIO WORLD");
+    }
+
+    #[test]
+    fn needs_snes_checksums_to_agree() {
+        let mut rom = snes(0x80000, 0x7fc0, 0x20);
+        rom[0x7fc0 + 0x1c] ^= 1;
+
+        // The mapping, reset vector and title alone aren't enough
+        assert_eq!(identify(&rom), None);
+        assert_eq!(identify(&vec![0; 0x80000]), None);
+    }
+}
@@ -0,0 +1,547 @@
+use crate::device::EmuRsDevice;
+use crate::disk::{EmuRsDiskDriver, EmuRsDiskProperties};
+use crate::driver::{EmuRsDriver, EmuRsDriverPreference};
+use crate::error::{EmuRsError, EmuRsErrorReason};
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use tinyvec::TinyVec;
+
+/// How big the copied blocks are when the disk doesn't have a sector size worth using
+const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// Where the blocks changed since a snapshot go
+pub enum EmuRsSnapshotStore {
+    Memory,
+    /// Another disk, which has to be big enough to hold every changed block
+    Disk(Rc<RefCell<dyn EmuRsDiskDriver>>),
+}
+
+/// Copies of blocks, in numbered slots that get reused once freed
+struct EmuRsSlots {
+    store: EmuRsSnapshotStore,
+    memory: Vec<Vec<u8>>,
+    free: Vec<usize>,
+    used: usize,
+    block_size: usize,
+}
+
+impl EmuRsSlots {
+    fn allocate(&mut self) -> Result<usize, EmuRsError> {
+        if let Some(slot) = self.free.pop() {
+            return Ok(slot);
+        }
+
+        if let EmuRsSnapshotStore::Disk(disk) = &self.store {
+            if (self.used + 1) * self.block_size > disk.borrow_mut().get_total_size() {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::OutOfSpace,
+                });
+            }
+        }
+
+        self.used += 1;
+        return Ok(self.used - 1);
+    }
+
+    fn free(&mut self, slot: usize) {
+        if let EmuRsSnapshotStore::Memory = self.store {
+            self.memory[slot] = Vec::new();
+        }
+
+        self.free.push(slot);
+    }
+
+    fn read(&mut self, slot: usize, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        return match &self.store {
+            EmuRsSnapshotStore::Memory => {
+                buffer.copy_from_slice(&self.memory[slot][offset..offset + buffer.len()]);
+                Ok(())
+            }
+            EmuRsSnapshotStore::Disk(disk) => disk
+                .borrow_mut()
+                .read(buffer, slot * self.block_size + offset),
+        };
+    }
+
+    fn write(&mut self, slot: usize, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        return match &self.store {
+            EmuRsSnapshotStore::Memory => {
+                if self.memory.len() <= slot {
+                    self.memory.resize(slot + 1, Vec::new());
+                }
+                if self.memory[slot].is_empty() {
+                    self.memory[slot] = vec![0; self.block_size];
+                }
+
+                self.memory[slot][offset..offset + buffer.len()].copy_from_slice(buffer);
+                Ok(())
+            }
+            EmuRsSnapshotStore::Disk(disk) => disk
+                .borrow_mut()
+                .write(buffer, slot * self.block_size + offset),
+        };
+    }
+}
+
+/// A snapshot and the blocks that have changed since it was taken
+struct EmuRsSnapshot {
+    name: String,
+    /// Block number to the slot its copy is in
+    blocks: BTreeMap<usize, usize>,
+}
+
+/// Lets the state of a disk be saved before doing something risky, and gone back to if it goes
+/// wrong
+///
+/// Taking a snapshot is free. After that writes go into a copy of the block instead of the disk,
+/// and the disk itself doesn't change until the snapshot is committed. Snapshots stack, each one
+/// holding what changed between it and the next
+pub struct EmuRsSnapshotDisk {
+    disk: Rc<RefCell<dyn EmuRsDiskDriver>>,
+    slots: EmuRsSlots,
+    /// Oldest first
+    snapshots: Vec<EmuRsSnapshot>,
+    block_size: usize,
+    size: usize,
+    /// Where blocks go while they are being copied
+    scratch: Vec<u8>,
+}
+
+impl EmuRsSnapshotDisk {
+    pub fn new(disk: Rc<RefCell<dyn EmuRsDiskDriver>>, store: EmuRsSnapshotStore) -> Self {
+        let (sector_size, size) = {
+            let mut disk = disk.borrow_mut();
+            (disk.get_sector_size(), disk.get_total_size())
+        };
+        // Byte sized sectors would make a copy for every byte
+        let block_size = if sector_size < DEFAULT_BLOCK_SIZE {
+            DEFAULT_BLOCK_SIZE
+        } else {
+            sector_size
+        };
+
+        return Self {
+            disk,
+            slots: EmuRsSlots {
+                store,
+                memory: Vec::new(),
+                free: Vec::new(),
+                used: 0,
+                block_size,
+            },
+            snapshots: Vec::new(),
+            block_size,
+            size,
+            scratch: vec![0; block_size],
+        };
+    }
+
+    /// Save the state the disk is in now under `name`
+    pub fn snapshot(&mut self, name: &str) -> Result<(), EmuRsError> {
+        if self.position(name).is_ok() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::FileAlreadyExists,
+            });
+        }
+
+        self.snapshots.push(EmuRsSnapshot {
+            name: String::from(name),
+            blocks: BTreeMap::new(),
+        });
+        return Ok(());
+    }
+
+    /// The names of the snapshots, oldest first
+    pub fn snapshots(&self) -> impl Iterator<Item = &str> {
+        return self.snapshots.iter().map(|snapshot| snapshot.name.as_str());
+    }
+
+    /// Go back to how the disk was when `name` was taken, throwing away it and every later snapshot
+    pub fn discard(&mut self, name: &str) -> Result<(), EmuRsError> {
+        let position = self.position(name)?;
+
+        for snapshot in self.snapshots.drain(position..) {
+            for slot in snapshot.blocks.into_values() {
+                self.slots.free(slot);
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Keep what has changed since `name` was taken, folding it and every later snapshot into the
+    /// one before it, or into the disk itself if it was the first
+    ///
+    /// If writing to the disk fails partway nothing is thrown away, so it can be tried again
+    pub fn commit(&mut self, name: &str) -> Result<(), EmuRsError> {
+        let position = self.position(name)?;
+
+        // Newer copies of a block win over older ones
+        let mut merged = BTreeMap::new();
+        let mut replaced = Vec::new();
+        for snapshot in self.snapshots[position..].iter() {
+            for (block, slot) in snapshot.blocks.iter() {
+                replaced.extend(merged.insert(*block, *slot));
+            }
+        }
+
+        if position == 0 {
+            for (block, slot) in merged.iter() {
+                let length = self.block_length(*block);
+                self.slots.read(*slot, &mut self.scratch[..length], 0)?;
+                self.disk
+                    .borrow_mut()
+                    .write(&self.scratch[..length], block * self.block_size)?;
+            }
+
+            replaced.extend(merged.into_values());
+        } else {
+            let below = &mut self.snapshots[position - 1].blocks;
+            for (block, slot) in merged {
+                replaced.extend(below.insert(block, slot));
+            }
+        }
+
+        self.snapshots.truncate(position);
+        for slot in replaced {
+            self.slots.free(slot);
+        }
+
+        return Ok(());
+    }
+
+    fn position(&self, name: &str) -> Result<usize, EmuRsError> {
+        return self
+            .snapshots
+            .iter()
+            .position(|snapshot| snapshot.name == name)
+            .ok_or(EmuRsError {
+                reason: EmuRsErrorReason::FileNotFound,
+            });
+    }
+
+    /// The last block can be cut short by the end of the disk
+    fn block_length(&self, block: usize) -> usize {
+        return self.block_size.min(self.size - block * self.block_size);
+    }
+
+    /// Read part of a block as it is now, from the newest copy of it in `snapshots` or the disk
+    fn read_block(
+        &mut self,
+        snapshots: usize,
+        block: usize,
+        buffer: &mut [u8],
+        within: usize,
+    ) -> Result<(), EmuRsError> {
+        let slot = self.snapshots[..snapshots]
+            .iter()
+            .rev()
+            .find_map(|snapshot| snapshot.blocks.get(&block));
+
+        return match slot {
+            Some(slot) => self.slots.read(*slot, buffer, within),
+            None => self
+                .disk
+                .borrow_mut()
+                .read(buffer, block * self.block_size + within),
+        };
+    }
+
+    /// Copy a block as it is in `snapshots` into a new slot
+    fn copy_block(
+        &mut self,
+        snapshots: usize,
+        block: usize,
+        copy: &mut [u8],
+    ) -> Result<usize, EmuRsError> {
+        let copy = &mut copy[..self.block_length(block)];
+        self.read_block(snapshots, block, copy, 0)?;
+
+        let slot = self.slots.allocate()?;
+        if let Err(error) = self.slots.write(slot, copy, 0) {
+            self.slots.free(slot);
+            return Err(error);
+        }
+
+        return Ok(slot);
+    }
+
+    fn check_bounds(&self, offset: usize, length: usize) -> Result<(), EmuRsError> {
+        if offset + length > self.size {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit,
+            });
+        }
+
+        return Ok(());
+    }
+}
+
+impl EmuRsDriver for EmuRsSnapshotDisk {
+    fn name(&self) -> &'static str {
+        return "Snapshot Disk";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        // The disk it is on already claimed the hardware
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsDiskDriver for EmuRsSnapshotDisk {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        self.check_bounds(offset, buffer.len())?;
+
+        let mut done = 0;
+        while done < buffer.len() {
+            let block = (offset + done) / self.block_size;
+            let within = (offset + done) % self.block_size;
+            let length = (self.block_size - within).min(buffer.len() - done);
+
+            self.read_block(
+                self.snapshots.len(),
+                block,
+                &mut buffer[done..done + length],
+                within,
+            )?;
+            done += length;
+        }
+
+        return Ok(());
+    }
+
+    fn write(&mut self, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        self.check_bounds(offset, buffer.len())?;
+
+        if self.snapshots.is_empty() {
+            return self.disk.borrow_mut().write(buffer, offset);
+        }
+
+        let newest = self.snapshots.len() - 1;
+        let mut done = 0;
+        while done < buffer.len() {
+            let block = (offset + done) / self.block_size;
+            let within = (offset + done) % self.block_size;
+            let length = (self.block_size - within).min(buffer.len() - done);
+
+            let slot = match self.snapshots[newest].blocks.get(&block) {
+                Some(slot) => *slot,
+                None => {
+                    // First time this block is written since the snapshot, so copy it first
+                    let mut copy = core::mem::take(&mut self.scratch);
+                    let result = self.copy_block(newest, block, &mut copy);
+                    self.scratch = copy;
+
+                    let slot = result?;
+                    self.snapshots[newest].blocks.insert(block, slot);
+                    slot
+                }
+            };
+
+            self.slots
+                .write(slot, &buffer[done..done + length], within)?;
+            done += length;
+        }
+
+        return Ok(());
+    }
+
+    fn get_sector_size(&mut self) -> usize {
+        return self.disk.borrow_mut().get_sector_size();
+    }
+
+    fn get_total_size(&mut self) -> usize {
+        return self.size;
+    }
+
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        let mut properties = self.disk.borrow_mut().get_properties();
+
+        // Writes only go to the copies until a commit
+        properties.read_only &= self.snapshots.is_empty();
+        return properties;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::tests::VecDisk;
+
+    /// Smaller than a block at the end, so the last block is cut short
+    const DISK_SIZE: usize = 4 * DEFAULT_BLOCK_SIZE + 100;
+
+    fn original() -> Vec<u8> {
+        return (0..DISK_SIZE).map(|index| (index / 7) as u8).collect();
+    }
+
+    /// Every way of storing the copies, with the disk they are on if there is one
+    fn stores() -> Vec<(EmuRsSnapshotStore, Option<Rc<RefCell<VecDisk>>>)> {
+        let store = Rc::new(RefCell::new(VecDisk(vec![0; 16 * DEFAULT_BLOCK_SIZE])));
+
+        return vec![
+            (EmuRsSnapshotStore::Memory, None),
+            (EmuRsSnapshotStore::Disk(store.clone()), Some(store)),
+        ];
+    }
+
+    fn snapshot_disk(store: EmuRsSnapshotStore) -> (Rc<RefCell<VecDisk>>, EmuRsSnapshotDisk) {
+        let disk = Rc::new(RefCell::new(VecDisk(original())));
+        let snapshot_disk = EmuRsSnapshotDisk::new(disk.clone(), store);
+        return (disk, snapshot_disk);
+    }
+
+    fn contents(snapshot_disk: &mut EmuRsSnapshotDisk) -> Vec<u8> {
+        let mut buffer = vec![0; DISK_SIZE];
+        snapshot_disk.read(&mut buffer, 0).unwrap();
+        return buffer;
+    }
+
+    /// Write `value` at `offset` both to the snapshot disk and to what it should look like
+    fn write(
+        snapshot_disk: &mut EmuRsSnapshotDisk,
+        expected: &mut [u8],
+        offset: usize,
+        length: usize,
+        value: u8,
+    ) {
+        snapshot_disk.write(&vec![value; length], offset).unwrap();
+        expected[offset..offset + length].fill(value);
+    }
+
+    #[test]
+    fn discarding_goes_back() {
+        for (store, _) in stores() {
+            let (disk, mut snapshot_disk) = snapshot_disk(store);
+            let mut expected = original();
+
+            snapshot_disk.snapshot("first").unwrap();
+            write(&mut snapshot_disk, &mut expected, 500, 100, 1);
+            let at_second = expected.clone();
+
+            snapshot_disk.snapshot("second").unwrap();
+            // Across a block boundary, and into the short block at the end
+            write(&mut snapshot_disk, &mut expected, 520, 600, 2);
+            write(&mut snapshot_disk, &mut expected, DISK_SIZE - 50, 50, 3);
+
+            assert_eq!(contents(&mut snapshot_disk), expected);
+            assert_eq!(disk.borrow().0, original(), "the disk waits for a commit");
+
+            snapshot_disk.discard("second").unwrap();
+            assert_eq!(contents(&mut snapshot_disk), at_second);
+            assert!(snapshot_disk.snapshots().eq(["first"]));
+
+            snapshot_disk.discard("first").unwrap();
+            assert_eq!(contents(&mut snapshot_disk), original());
+            assert_eq!(snapshot_disk.snapshots().count(), 0);
+        }
+    }
+
+    #[test]
+    fn committing_the_newest_folds_it_into_the_one_before() {
+        for (store, _) in stores() {
+            let (disk, mut snapshot_disk) = snapshot_disk(store);
+            let mut expected = original();
+
+            snapshot_disk.snapshot("first").unwrap();
+            write(&mut snapshot_disk, &mut expected, 0, 700, 1);
+            snapshot_disk.snapshot("second").unwrap();
+            write(&mut snapshot_disk, &mut expected, 600, 700, 2);
+
+            snapshot_disk.commit("second").unwrap();
+            assert!(snapshot_disk.snapshots().eq(["first"]));
+            assert_eq!(contents(&mut snapshot_disk), expected);
+            assert_eq!(disk.borrow().0, original());
+
+            // Throwing away the first now throws away both sets of changes
+            snapshot_disk.discard("first").unwrap();
+            assert_eq!(contents(&mut snapshot_disk), original());
+        }
+    }
+
+    #[test]
+    fn committing_the_oldest_writes_everything_to_the_disk() {
+        for (store, _) in stores() {
+            let (disk, mut snapshot_disk) = snapshot_disk(store);
+            let mut expected = original();
+
+            snapshot_disk.snapshot("first").unwrap();
+            write(&mut snapshot_disk, &mut expected, 10, 1000, 1);
+            snapshot_disk.snapshot("second").unwrap();
+            write(&mut snapshot_disk, &mut expected, 900, 200, 2);
+            write(&mut snapshot_disk, &mut expected, DISK_SIZE - 1, 1, 3);
+
+            snapshot_disk.commit("first").unwrap();
+            assert_eq!(snapshot_disk.snapshots().count(), 0);
+            assert_eq!(disk.borrow().0, expected);
+            assert_eq!(contents(&mut snapshot_disk), expected);
+
+            // No snapshots left, so writes go straight through
+            write(&mut snapshot_disk, &mut expected, 0, 10, 4);
+            assert_eq!(disk.borrow().0, expected);
+        }
+    }
+
+    #[test]
+    fn freed_slots_get_reused() {
+        for (store, store_disk) in stores() {
+            let (_, mut snapshot_disk) = snapshot_disk(store);
+            let mut expected = original();
+
+            // Far more blocks get copied over all of these than the store has room for at once
+            for round in 0..20 {
+                snapshot_disk.snapshot("round").unwrap();
+                write(&mut snapshot_disk, &mut expected, 0, DISK_SIZE, round);
+                snapshot_disk.discard("round").unwrap();
+            }
+
+            assert_eq!(contents(&mut snapshot_disk), original());
+            assert!(snapshot_disk.slots.used <= 5);
+
+            if let Some(store_disk) = store_disk {
+                // Copies really went to the store
+                assert!(store_disk.borrow().0.contains(&19));
+            }
+        }
+    }
+
+    #[test]
+    fn a_full_store_fails_the_write() {
+        let store = Rc::new(RefCell::new(VecDisk(vec![0; 2 * DEFAULT_BLOCK_SIZE])));
+        let (_, mut snapshot_disk) = snapshot_disk(EmuRsSnapshotStore::Disk(store));
+
+        snapshot_disk.snapshot("first").unwrap();
+        snapshot_disk.write(&[1; 2], 0).unwrap();
+        snapshot_disk.write(&[1; 2], DEFAULT_BLOCK_SIZE).unwrap();
+
+        assert!(matches!(
+            snapshot_disk.write(&[1; 2], 2 * DEFAULT_BLOCK_SIZE),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::OutOfSpace
+            })
+        ));
+
+        // Blocks that already have a copy can still be written
+        snapshot_disk.write(&[2; 2], 10).unwrap();
+    }
+
+    #[test]
+    fn snapshot_names_are_unique() {
+        let (_, mut snapshot_disk) = snapshot_disk(EmuRsSnapshotStore::Memory);
+
+        snapshot_disk.snapshot("first").unwrap();
+        assert!(snapshot_disk.snapshot("first").is_err());
+        assert!(snapshot_disk.commit("missing").is_err());
+        assert!(snapshot_disk.discard("missing").is_err());
+    }
+}
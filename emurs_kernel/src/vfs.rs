@@ -1,15 +1,18 @@
+use crate::disk::EmuRsDiskDriver;
 use crate::error::EmuRsErrorReason;
+use crate::romheader::EmuRsRomInfo;
 use crate::subsystem::EmuRsSubsystem;
 use crate::EmuRsContext;
 use crate::{driver::EmuRsDriver, error::EmuRsError};
+use alloc::borrow::Cow;
 use alloc::collections::BTreeMap;
 use alloc::rc::Rc;
 use alloc::string::String;
 use alloc::string::ToString;
-use alloc::vec;
+use core::cell::RefCell;
 use core::fmt::Display;
 use core::str::FromStr;
-use time::Date;
+use time::OffsetDateTime;
 use tinyvec::{tiny_vec, TinyVec};
 
 /// The VFS implementation of the operating system
@@ -21,44 +24,36 @@ use tinyvec::{tiny_vec, TinyVec};
 /// - `ROOT/profiles/(profile name)/saves`  : The save files for that particular profile
 /// - `ROOT/system.toml`                    : The whole operating system config file
 /// - `ROOT/roms`                           : The rom collection for the operating system. Contains firmware for the roms too. Roms may be selected from other locations
-/// - `ROOT/roms.db`                        : The database containing blake2s hashes of known roms. Firmware has to appear here to be used but roms do not.
+/// - `ROOT/roms.db`                        : The database containing blake2s hashes of known roms. Firmware has to appear here to be used but roms do not. See [crate::romdb] for the format
+/// - `ROOT/firmware.cbor`                  : Hashes of everything firmware is searched for in, so it only has to be hashed again when it changes
 #[derive(Clone, Default)]
 pub struct EmuRsFilesystemSubsystem {
     os_context: Option<Rc<EmuRsContext>>,
-    fsdriver_to_diskdriver: BTreeMap<usize, usize>,
-    mountpounts: BTreeMap<EmuRsPath, usize>,
-    cwd: EmuRsPath,
+    mountpoints: BTreeMap<EmuRsPath, Rc<RefCell<dyn EmuRsFsDriver>>>,
+    /// The namespace paths are currently resolved in. The kernel swaps this out when it runs a program
+    namespace: EmuRsNamespace,
 }
 
 impl EmuRsFilesystemSubsystem {
-    /// Normalize a path
-    /// Wildly incomplete
+    /// Normalize a path, resolving it against `context_path` if it is relative
+    ///
+    /// `..` at `ROOT` stays at `ROOT`, which is what keeps programs inside of their namespace
     pub fn normalize_path(
         &self,
         context_path: Option<&EmuRsPath>,
         relative_path: &EmuRsPath,
     ) -> Result<EmuRsPath, EmuRsError> {
-        let mut return_path = EmuRsPath::default();
-
-        if context_path.is_some() {
-            if !context_path.unwrap().is_absolute() {
-                return Err(EmuRsError {
-                    reason: EmuRsErrorReason::InvalidPath,
-                });
-            }
-        } else {
-            if relative_path.segments[0] != "ROOT" {
+        if let Some(context_path) = context_path {
+            if !context_path.is_absolute() {
                 return Err(EmuRsError {
                     reason: EmuRsErrorReason::InvalidPath,
                 });
             }
 
-            return_path.segments.retain(|segment| {
-                return segment != ".";
-            });
+            return context_path.join(relative_path).normalize();
         }
 
-        return Ok(return_path);
+        return relative_path.normalize();
     }
 
     pub fn is_child_of(
@@ -66,38 +61,289 @@ impl EmuRsFilesystemSubsystem {
         potential_parent: &EmuRsPath,
         potential_child: &EmuRsPath,
     ) -> Result<bool, EmuRsError> {
-        if !potential_parent.is_absolute() {
+        if !potential_parent.is_absolute() || !potential_child.is_absolute() {
             return Err(EmuRsError {
                 reason: EmuRsErrorReason::InvalidPath,
             });
         }
 
-        return Err(EmuRsError {
-            reason: EmuRsErrorReason::OperationNotSupported,
-        });
+        let parent = potential_parent.normalize()?;
+        let child = potential_child.normalize()?;
+
+        return Ok(child.segments.len() > parent.segments.len() && child.starts_with(&parent));
+    }
+
+    /// The namespace paths are currently being resolved in
+    pub fn namespace(&self) -> &EmuRsNamespace {
+        return &self.namespace;
+    }
+
+    /// Switch the namespace paths are resolved in, returning the old one so it can be restored
+    pub fn swap_namespace(&mut self, namespace: EmuRsNamespace) -> EmuRsNamespace {
+        return core::mem::replace(&mut self.namespace, namespace);
+    }
+
+    /// Change the working directory of the current namespace
+    pub fn change_directory(&mut self, path: &EmuRsPath) -> Result<(), EmuRsError> {
+        let new_cwd = self.normalize_path(Some(&self.namespace.cwd), path)?;
+
+        if self.metadata(&new_cwd)?.kind != Some(EmuRsFileKind::Folder) {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        self.namespace.cwd = new_cwd;
+        return Ok(());
+    }
+
+    /// Attach a filesystem at a absolute path. This ignores the current namespace
+    ///
+    /// Filesystems that live on a disk need to be given it with [EmuRsFsDriver::mount] first
+    pub fn mount(
+        &mut self,
+        path: &EmuRsPath,
+        driver: Rc<RefCell<dyn EmuRsFsDriver>>,
+    ) -> Result<(), EmuRsError> {
+        let path = self.normalize_path(None, path)?;
+
+        if self.mountpoints.contains_key(&path) {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        self.mountpoints.insert(path, driver);
+        return Ok(());
+    }
+
+    pub fn unmount(&mut self, path: &EmuRsPath) -> Result<(), EmuRsError> {
+        let path = self.normalize_path(None, path)?;
+
+        if self.mountpoints.remove(&path).is_none() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::FileNotFound,
+            });
+        }
+
+        return Ok(());
+    }
+
+    /// The VFS as the running program sees it. The methods on the subsystem itself go through this
+    pub fn current(&self) -> EmuRsFilesystemView<'_> {
+        return EmuRsFilesystemView {
+            fs: self,
+            namespace: Cow::Borrowed(&self.namespace),
+        };
+    }
+
+    /// The whole VFS whatever namespace is swapped in, for the kernel to get at images and
+    /// databases that live outside of the running program's namespace
+    pub fn global(&self) -> EmuRsFilesystemView<'_> {
+        return EmuRsFilesystemView {
+            fs: self,
+            namespace: Cow::Owned(EmuRsNamespace::default()),
+        };
     }
 
     pub fn read(
         &self,
         path: &EmuRsPath,
-        _buffer: &mut [u8],
-        _offset: usize,
+        buffer: &mut [u8],
+        offset: usize,
     ) -> Result<(), EmuRsError> {
-        let norm_path = self.normalize_path(Some(&self.cwd), path);
+        return self.current().read(path, buffer, offset);
+    }
 
-        return Err(EmuRsError {
-            reason: EmuRsErrorReason::OperationNotSupported,
-        });
+    pub fn write(&self, path: &EmuRsPath, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        return self.current().write(path, buffer, offset);
+    }
+
+    pub fn create(&self, path: &EmuRsPath) -> Result<(), EmuRsError> {
+        return self.current().create(path);
     }
 
+    pub fn create_directory(&self, path: &EmuRsPath) -> Result<(), EmuRsError> {
+        return self.current().create_directory(path);
+    }
+
+    pub fn delete(&self, path: &EmuRsPath) -> Result<(), EmuRsError> {
+        return self.current().delete(path);
+    }
+
+    /// List a directory. The returned paths are in the current namespace
     pub fn list_directory(&self, file: &EmuRsPath) -> Result<TinyVec<[EmuRsPath; 10]>, EmuRsError> {
-        return Ok(TinyVec::new());
+        return self.current().list_directory(file);
     }
 
-    pub fn metadata(&self, _file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
-        return Err(EmuRsError {
-            reason: EmuRsErrorReason::OperationNotSupported,
-        });
+    pub fn metadata(&self, file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
+        return self.current().metadata(file);
+    }
+}
+
+/// The VFS seen from one namespace, see [EmuRsFilesystemSubsystem::current] and
+/// [EmuRsFilesystemSubsystem::global]
+pub struct EmuRsFilesystemView<'a> {
+    fs: &'a EmuRsFilesystemSubsystem,
+    namespace: Cow<'a, EmuRsNamespace>,
+}
+
+impl EmuRsFilesystemView<'_> {
+    pub fn namespace(&self) -> &EmuRsNamespace {
+        return &self.namespace;
+    }
+
+    /// Find the filesystem that serves a path and the path relative to that filesystem's root
+    fn resolve(
+        &self,
+        path: &EmuRsPath,
+    ) -> Result<(Rc<RefCell<dyn EmuRsFsDriver>>, EmuRsPath), EmuRsError> {
+        let global_path = self.namespace.resolve(path)?;
+
+        let (mountpoint, driver) = self
+            .fs
+            .mountpoints
+            .iter()
+            .filter(|(mountpoint, _)| {
+                return global_path.starts_with(mountpoint);
+            })
+            .max_by_key(|(mountpoint, _)| {
+                return mountpoint.segments.len();
+            })
+            .ok_or(EmuRsError {
+                reason: EmuRsErrorReason::FileNotFound,
+            })?;
+
+        let mut driver_path = EmuRsPath::default();
+        driver_path.segments.extend(
+            global_path.segments[mountpoint.segments.len()..]
+                .iter()
+                .cloned(),
+        );
+
+        return Ok((driver.clone(), driver_path));
+    }
+
+    pub fn read(
+        &self,
+        path: &EmuRsPath,
+        buffer: &mut [u8],
+        offset: usize,
+    ) -> Result<(), EmuRsError> {
+        let (driver, driver_path) = self.resolve(path)?;
+        return driver.borrow_mut().read(&driver_path, buffer, offset);
+    }
+
+    pub fn write(&self, path: &EmuRsPath, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        let (driver, driver_path) = self.resolve(path)?;
+        return driver.borrow_mut().write(&driver_path, buffer, offset);
+    }
+
+    pub fn create(&self, path: &EmuRsPath) -> Result<(), EmuRsError> {
+        let (driver, driver_path) = self.resolve(path)?;
+        return driver.borrow_mut().create(&driver_path);
+    }
+
+    pub fn create_directory(&self, path: &EmuRsPath) -> Result<(), EmuRsError> {
+        let (driver, driver_path) = self.resolve(path)?;
+        return driver.borrow_mut().create_directory(&driver_path);
+    }
+
+    pub fn delete(&self, path: &EmuRsPath) -> Result<(), EmuRsError> {
+        let (driver, driver_path) = self.resolve(path)?;
+        return driver.borrow_mut().delete(&driver_path);
+    }
+
+    /// List a directory. The returned paths are in this view's namespace
+    pub fn list_directory(&self, file: &EmuRsPath) -> Result<TinyVec<[EmuRsPath; 10]>, EmuRsError> {
+        let directory = self.fs.normalize_path(Some(&self.namespace.cwd), file)?;
+        let global_directory = self.namespace.resolve(&directory)?;
+
+        let mut entries: TinyVec<[EmuRsPath; 10]> = match self.resolve(&directory) {
+            Ok((driver, driver_path)) => driver
+                .borrow_mut()
+                .list_directory(&driver_path)?
+                .iter()
+                .map(|entry| {
+                    return directory.join_segment(&entry.file_name());
+                })
+                .collect(),
+            // Nothing is mounted here but the root always exists
+            Err(_) if directory.is_root() => TinyVec::new(),
+            Err(error) => return Err(error),
+        };
+
+        // Mountpoints show up as entries of the directory they are in
+        for mountpoint in self.fs.mountpoints.keys() {
+            if mountpoint.parent().as_ref() != Some(&global_directory) {
+                continue;
+            }
+
+            let entry = directory.join_segment(&mountpoint.file_name());
+            if !entries.contains(&entry) {
+                entries.push(entry);
+            }
+        }
+
+        return Ok(entries);
+    }
+
+    pub fn metadata(&self, file: &EmuRsPath) -> Result<EmuRsFileMetadata, EmuRsError> {
+        let (driver, driver_path) = self.resolve(file)?;
+        return driver.borrow_mut().metadata(&driver_path);
+    }
+}
+
+/// The view of the VFS that a program gets
+///
+/// Paths are resolved against `cwd` and then placed under `root`, so `ROOT` for a program in this namespace is `root` for everyone else.
+/// Both `cwd` and the paths given to [EmuRsNamespace::resolve] are relative to the namespace, not the real VFS
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmuRsNamespace {
+    pub root: EmuRsPath,
+    pub cwd: EmuRsPath,
+}
+
+impl EmuRsNamespace {
+    pub fn new(root: EmuRsPath) -> Self {
+        return Self {
+            root,
+            cwd: EmuRsPath::default(),
+        };
+    }
+
+    /// A namespace rooted in a profile's save folder
+    pub fn profile_saves(profile_name: &str) -> Result<Self, EmuRsError> {
+        let root = EmuRsPath::default()
+            .join_segment("profiles")
+            .join_segment(profile_name)
+            .join_segment("saves");
+
+        if !root.is_valid() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        return Ok(Self::new(root));
+    }
+
+    /// Turn a path inside of this namespace into a path on the real VFS
+    pub fn resolve(&self, path: &EmuRsPath) -> Result<EmuRsPath, EmuRsError> {
+        let namespace_path = self.cwd.join(path).normalize()?;
+
+        let mut global_path = self.root.normalize()?;
+        global_path
+            .segments
+            .extend(namespace_path.segments.iter().skip(1).cloned());
+
+        return Ok(global_path);
+    }
+}
+
+impl Default for EmuRsNamespace {
+    fn default() -> Self {
+        return Self::new(EmuRsPath::default());
     }
 }
 
@@ -112,13 +358,22 @@ impl EmuRsSubsystem for EmuRsFilesystemSubsystem {
 #[derive(Debug, Clone)]
 pub struct EmuRsFileMetadata {
     pub size: Option<usize>,
-    pub modification_time: Option<Date>,
+    pub modification_time: Option<OffsetDateTime>,
     pub kind: Option<EmuRsFileKind>,
+    /// What the header of a ROM says it is, from filesystems that look
+    pub rom: Option<EmuRsRomInfo>,
 }
 
 /// The driver for a file system implementation
-/// This will most likely be ustar on many, many embedded devices until I hammer out Fat or something even better
+/// This will most likely be ustar on many, many embedded devices, with Fat for cards made on a computer
 pub trait EmuRsFsDriver: EmuRsDriver {
+    /// Attach the filesystem to the disk it lives on. This must happen before it is mounted in the VFS
+    fn mount(&mut self, _disk: Rc<RefCell<dyn EmuRsDiskDriver>>) -> Result<(), EmuRsError> {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::OperationNotSupported,
+        });
+    }
+
     fn read(
         &mut self,
         _file: &EmuRsPath,
@@ -153,6 +408,12 @@ pub trait EmuRsFsDriver: EmuRsDriver {
         });
     }
 
+    fn create_directory(&mut self, _file: &EmuRsPath) -> Result<(), EmuRsError> {
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::OperationNotSupported,
+        });
+    }
+
     fn list_directory(
         &mut self,
         _file: &EmuRsPath,
@@ -184,23 +445,20 @@ pub struct EmuRsFile {
 
 impl EmuRsFile {}
 
-#[derive(Debug, Clone, PartialEq, Hash)]
-/// A path with `/` seperators
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// A path with `/` seperators. Absolute paths start with `ROOT`
 pub struct EmuRsPath {
     pub segments: TinyVec<[String; 3]>,
 }
 
 impl EmuRsPath {
     pub fn is_absolute(&self) -> bool {
-        return self.segments[0] == "ROOT"
-            || (self.is_root()
-                && !self.segments.iter().any(|segment| {
-                    return *segment == ".." || *segment == ".";
-                }));
+        return self.segments.first().map(String::as_str) == Some("ROOT");
     }
 
+    /// If this is the path `ROOT` itself
     pub fn is_root(&self) -> bool {
-        return self.segments[0] == "ROOT";
+        return self.is_absolute() && self.segments.len() == 1;
     }
 
     pub fn file_name(&self) -> String {
@@ -208,32 +466,107 @@ impl EmuRsPath {
     }
 
     pub fn is_valid(&self) -> bool {
-        for segment in self.segments.iter() {
-            if segment == "/" {
+        for (index, segment) in self.segments.iter().enumerate() {
+            if segment.is_empty() || segment.contains('/') || (index != 0 && segment == "ROOT") {
                 return false;
             }
         }
 
         return true;
     }
+
+    /// Append `other` to this path. If `other` is absolute it replaces this path entirely
+    pub fn join(&self, other: &EmuRsPath) -> EmuRsPath {
+        if other.is_absolute() {
+            return other.clone();
+        }
+
+        let mut path = self.clone();
+        path.segments.extend(other.segments.iter().cloned());
+        return path;
+    }
+
+    pub fn join_segment(&self, segment: &str) -> EmuRsPath {
+        let mut path = self.clone();
+        path.segments.push(segment.to_string());
+        return path;
+    }
+
+    /// The directory this path is in, or [None] for `ROOT` and single segment relative paths
+    pub fn parent(&self) -> Option<EmuRsPath> {
+        if self.segments.len() < 2 {
+            return None;
+        }
+
+        let mut path = self.clone();
+        path.segments.pop();
+        return Some(path);
+    }
+
+    pub fn starts_with(&self, prefix: &EmuRsPath) -> bool {
+        return self.segments.starts_with(&prefix.segments);
+    }
+
+    /// Remove `.` and resolve `..` in a absolute path. `..` at `ROOT` stays at `ROOT`
+    pub fn normalize(&self) -> Result<EmuRsPath, EmuRsError> {
+        if !self.is_absolute() || !self.is_valid() {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::InvalidPath,
+            });
+        }
+
+        let mut path = EmuRsPath::default();
+
+        for segment in self.segments.iter().skip(1) {
+            match segment.as_str() {
+                "." => {}
+                ".." => {
+                    if !path.is_root() {
+                        path.segments.pop();
+                    }
+                }
+                _ => path.segments.push(segment.clone()),
+            }
+        }
+
+        return Ok(path);
+    }
 }
 
 impl FromStr for EmuRsPath {
     type Err = EmuRsError;
 
-    // TODO: Complete this
+    /// Parse a path. A leading `/` is treated the same as a leading `ROOT`
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut segments = s.split("/");
+        let mut segments = TinyVec::new();
 
-        if segments.next() != Some("ROOT") {
+        if s.starts_with('/') {
+            segments.push("ROOT".to_string());
+        }
+
+        for segment in s.split('/').filter(|segment| !segment.is_empty()) {
+            // ROOT only makes sense at the very start
+            if segment == "ROOT" && segments.is_empty() {
+                segments.push(segment.to_string());
+                continue;
+            }
+
+            if segment == "ROOT" {
+                return Err(EmuRsError {
+                    reason: EmuRsErrorReason::InvalidPath,
+                });
+            }
+
+            segments.push(segment.to_string());
+        }
+
+        if segments.is_empty() {
             return Err(EmuRsError {
                 reason: EmuRsErrorReason::InvalidPath,
             });
         }
 
-        return Err(EmuRsError {
-            reason: EmuRsErrorReason::InvalidPath,
-        });
+        return Ok(Self { segments });
     }
 }
 
@@ -257,3 +590,73 @@ pub struct EmuRsPermission {
     pub read: bool,
     pub write: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &str) -> EmuRsPath {
+        return EmuRsPath::from_str(path).unwrap();
+    }
+
+    #[test]
+    fn parses_paths() {
+        assert_eq!(path("/games/a.gba"), path("ROOT/games/a.gba"));
+        assert_eq!(path("ROOT//games/"), path("ROOT/games"));
+
+        let relative = path("games/a.gba");
+        assert!(!relative.is_absolute());
+        assert_eq!(relative.segments.len(), 2);
+
+        assert!(EmuRsPath::from_str("").is_err());
+        assert!(path("///").is_root());
+        assert!(EmuRsPath::from_str("ROOT/games/ROOT").is_err());
+        assert!(EmuRsPath::from_str("games/ROOT").is_err());
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(
+            path("ROOT/games/./gba/../nes/a.nes").normalize().unwrap(),
+            path("ROOT/games/nes/a.nes")
+        );
+
+        // There is nothing above ROOT to go to
+        assert_eq!(
+            path("ROOT/../../games").normalize().unwrap(),
+            path("ROOT/games")
+        );
+        assert!(path("ROOT/..").normalize().unwrap().is_root());
+
+        // Relative paths have to be joined onto something first
+        assert!(path("games/../a").normalize().is_err());
+    }
+
+    #[test]
+    fn namespaces_cant_be_escaped() {
+        let mut namespace = EmuRsNamespace::profile_saves("player").unwrap();
+        let saves = path("ROOT/profiles/player/saves");
+
+        assert_eq!(namespace.resolve(&path("ROOT")).unwrap(), saves);
+        assert_eq!(
+            namespace.resolve(&path("ROOT/a.sav")).unwrap(),
+            saves.join_segment("a.sav")
+        );
+        assert_eq!(
+            namespace.resolve(&path("ROOT/../../../roms.db")).unwrap(),
+            saves.join_segment("roms.db")
+        );
+
+        namespace.cwd = path("ROOT/game");
+        assert_eq!(
+            namespace.resolve(&path("b.sav")).unwrap(),
+            saves.join_segment("game").join_segment("b.sav")
+        );
+        assert_eq!(
+            namespace.resolve(&path("../../../../b.sav")).unwrap(),
+            saves.join_segment("b.sav")
+        );
+
+        assert!(EmuRsNamespace::profile_saves("a/b").is_err());
+    }
+}
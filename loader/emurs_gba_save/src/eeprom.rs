@@ -0,0 +1,218 @@
+use crate::GbaSaveBus;
+use emurs_kernel::device::EmuRsDevice;
+use emurs_kernel::disk::{check_sector_access, EmuRsBlockDevice, EmuRsDiskProperties};
+use emurs_kernel::driver::{EmuRsDriver, EmuRsDriverPreference};
+use emurs_kernel::error::{EmuRsError, EmuRsErrorReason};
+use emurs_kernel::mem::EmuRsMemoryRange;
+use emurs_kernel::prelude::tinyvec::TinyVec;
+
+pub const EEPROM_SMALL: usize = 0x200;
+pub const EEPROM_LARGE: usize = 0x2000;
+/// The EEPROM is read and written 64 bits at a time
+pub const EEPROM_BLOCK_SIZE: usize = 8;
+/// How many times to check on a write before giving up. Far more than the ~10 ms a write takes
+const EEPROM_POLL_LIMIT: usize = 0x10000;
+
+// The largest requests there are, with 14 address bits
+const READ_REQUEST_BITS: usize = 2 + 14 + 1;
+const WRITE_REQUEST_BITS: usize = 2 + 14 + 64 + 1;
+/// 4 junk bits then the data
+const READ_RESPONSE_BITS: usize = 4 + 64;
+
+/// Check if there is a EEPROM by asking for the first block. A EEPROM starts its answer with 4
+/// zero bits, while nothing at all reads back as bits that go back and forth
+pub(super) fn probe(bus: &mut impl GbaSaveBus) -> bool {
+    let mut request = [0; READ_REQUEST_BITS];
+    request[..2].copy_from_slice(&[1, 1]);
+    bus.eeprom_send(&request);
+
+    let mut response = [0; READ_RESPONSE_BITS];
+    bus.eeprom_receive(&mut response);
+
+    return response[..4].iter().all(|bit| bit & 1 == 0);
+}
+
+/// A serial EEPROM, only reachable with DMA one bit per halfword
+pub struct GbaEeprom<BUS: GbaSaveBus> {
+    bus: BUS,
+    size: usize,
+}
+
+impl<BUS: GbaSaveBus> GbaEeprom<BUS> {
+    pub fn new(bus: BUS, size: usize) -> Self {
+        return Self { bus, size };
+    }
+
+    /// The small one takes 6 address bits, the large one 14 even though it only uses 10
+    fn address_bits(&self) -> usize {
+        return if self.size > EEPROM_SMALL { 14 } else { 6 };
+    }
+
+    /// Put the command bits and then the address at the start of `request`, returning how much
+    /// of it that took
+    fn start_request(&self, request: &mut [u16], command: [u16; 2], block: usize) -> usize {
+        let address_bits = self.address_bits();
+        request[..2].copy_from_slice(&command);
+
+        for bit in 0..address_bits {
+            request[2 + bit] = (block >> (address_bits - 1 - bit)) as u16 & 1;
+        }
+
+        return 2 + address_bits;
+    }
+
+    fn read_block(&mut self, block: usize, buffer: &mut [u8]) {
+        let mut request = [0; READ_REQUEST_BITS];
+        let length = self.start_request(&mut request, [1, 1], block);
+        // Ends with a zero, which it already is
+        self.bus.eeprom_send(&request[..length + 1]);
+
+        let mut response = [0; READ_RESPONSE_BITS];
+        self.bus.eeprom_receive(&mut response);
+
+        for (index, byte) in buffer.iter_mut().enumerate() {
+            *byte = response[4 + index * 8..4 + index * 8 + 8]
+                .iter()
+                .fold(0, |byte, bit| byte << 1 | (*bit & 1) as u8);
+        }
+    }
+
+    fn write_block(&mut self, block: usize, buffer: &[u8]) -> Result<(), EmuRsError> {
+        let mut request = [0; WRITE_REQUEST_BITS];
+        let mut length = self.start_request(&mut request, [1, 0], block);
+
+        for byte in buffer {
+            for bit in (0..8).rev() {
+                request[length] = (*byte >> bit) as u16 & 1;
+                length += 1;
+            }
+        }
+        self.bus.eeprom_send(&request[..length + 1]);
+
+        // The chip reads back a 1 once the write is done
+        let mut ready = [0];
+        for _ in 0..EEPROM_POLL_LIMIT {
+            self.bus.eeprom_receive(&mut ready);
+
+            if ready[0] & 1 == 1 {
+                return Ok(());
+            }
+        }
+
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::HardwareTimeout,
+        });
+    }
+}
+
+impl<BUS: GbaSaveBus> EmuRsDriver for GbaEeprom<BUS> {
+    fn name(&self) -> &'static str {
+        return "Game Boy Advance EEPROM";
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        let mut memory = TinyVec::new();
+        memory.push(EmuRsMemoryRange::new(0xdffff00, 0xdffffff));
+
+        return EmuRsDevice { memory };
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+}
+
+impl<BUS: GbaSaveBus> EmuRsBlockDevice for GbaEeprom<BUS> {
+    fn read_sectors(&mut self, sector: usize, buffer: &mut [u8]) -> Result<(), EmuRsError> {
+        check_sector_access(self, sector, buffer.len())?;
+
+        for (index, block) in buffer.chunks_mut(EEPROM_BLOCK_SIZE).enumerate() {
+            self.read_block(sector + index, block);
+        }
+
+        return Ok(());
+    }
+
+    fn write_sectors(&mut self, sector: usize, buffer: &[u8]) -> Result<(), EmuRsError> {
+        check_sector_access(self, sector, buffer.len())?;
+
+        let mut old = [0; EEPROM_BLOCK_SIZE];
+        for (index, block) in buffer.chunks(EEPROM_BLOCK_SIZE).enumerate() {
+            // Writes are slow and wear the chip, so skip the ones that change nothing
+            self.read_block(sector + index, &mut old);
+            if old != block {
+                self.write_block(sector + index, block)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn get_sector_size(&mut self) -> usize {
+        return EEPROM_BLOCK_SIZE;
+    }
+
+    fn get_sector_count(&mut self) -> usize {
+        return self.size / EEPROM_BLOCK_SIZE;
+    }
+
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        return EmuRsDiskProperties {
+            read_only: false,
+            erase_block_size: None,
+            slow_writes: true,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulated::SimulatedCartridge;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use emurs_kernel::disk::{EmuRsBlockDisk, EmuRsDiskDriver};
+
+    #[test]
+    fn reads_back_writes_for_both_sizes() {
+        for size in [EEPROM_SMALL, EEPROM_LARGE] {
+            let mut disk =
+                EmuRsBlockDisk::new(GbaEeprom::new(SimulatedCartridge::eeprom(size), size));
+            let data: Vec<u8> = (0..40).map(|index| index as u8 ^ 0xa5).collect();
+
+            disk.write(&data, size - 43).unwrap();
+
+            let mut read = vec![0; data.len()];
+            disk.read(&mut read, size - 43).unwrap();
+            assert_eq!(read, data);
+            assert!(disk.write(&data, size - 39).is_err());
+
+            // Both ends of the chip are where they should be, not wrapped around onto each other
+            let cartridge = &disk.device().bus;
+            assert_eq!(cartridge.memory()[size - 43..size - 3], data);
+            assert_eq!(cartridge.memory()[..8], [0xff; 8]);
+        }
+    }
+
+    #[test]
+    fn skips_unchanged_blocks() {
+        let mut eeprom = GbaEeprom::new(SimulatedCartridge::eeprom(EEPROM_LARGE), EEPROM_LARGE);
+
+        eeprom.write_sectors(4, &[1; 16]).unwrap();
+        eeprom.write_sectors(4, &[1; 16]).unwrap();
+        assert_eq!(eeprom.bus.programs, 2);
+    }
+
+    #[test]
+    fn gives_up_on_a_stuck_chip() {
+        let mut cartridge = SimulatedCartridge::eeprom(EEPROM_SMALL);
+        cartridge.stuck = true;
+        let mut eeprom = GbaEeprom::new(cartridge, EEPROM_SMALL);
+
+        let result = eeprom.write_sectors(0, &[0; 8]);
+        assert!(matches!(
+            result.map_err(|error| error.reason),
+            Err(EmuRsErrorReason::HardwareTimeout)
+        ));
+    }
+}
@@ -0,0 +1,366 @@
+use crate::{GbaSaveBus, GbaSaveKind, SAVE_MEMORY};
+use emurs_kernel::device::EmuRsDevice;
+use emurs_kernel::disk::{check_sector_access, EmuRsBlockDevice, EmuRsDiskProperties};
+use emurs_kernel::driver::{EmuRsDriver, EmuRsDriverPreference};
+use emurs_kernel::error::{EmuRsError, EmuRsErrorReason};
+use emurs_kernel::mem::EmuRsMemoryRange;
+use emurs_kernel::prelude::tinyvec::TinyVec;
+
+/// The least the chip can erase
+pub const FLASH_SECTOR_SIZE: usize = 0x1000;
+/// How much of the chip can be seen at once. 128 KiB chips switch between two of them
+pub const FLASH_BANK_SIZE: usize = 0x10000;
+/// Atmel chips are written a page at a time and erase it themselves
+const ATMEL_PAGE_SIZE: usize = 128;
+/// How many times to check on a erase or write before giving up. Far more than the ~20 ms the
+/// slowest chips need
+const FLASH_POLL_LIMIT: usize = 0x80000;
+
+const COMMAND_FIRST: usize = 0x5555;
+const COMMAND_SECOND: usize = 0x2aaa;
+const ENTER_ID: u8 = 0x90;
+const EXIT_ID: u8 = 0xf0;
+const ERASE: u8 = 0x80;
+const ERASE_SECTOR: u8 = 0x30;
+const PROGRAM: u8 = 0xa0;
+const SWITCH_BANK: u8 = 0xb0;
+
+/// Manufacturer and device ids of the chips carts were made with
+const KNOWN_CHIPS: [(u8, u8, usize); 6] = [
+    // SST
+    (0xbf, 0xd4, 0x10000),
+    // Panasonic
+    (0x32, 0x1b, 0x10000),
+    // Atmel
+    (0x1f, 0x3d, 0x10000),
+    // Macronix
+    (0xc2, 0x1c, 0x10000),
+    // Sanyo
+    (0x62, 0x13, 0x20000),
+    // Macronix
+    (0xc2, 0x09, 0x20000),
+];
+const ATMEL: u8 = 0x1f;
+
+/// Unlock the chip and send it a command
+fn command(bus: &mut impl GbaSaveBus, command: u8) {
+    bus.write_byte(COMMAND_FIRST, 0xaa);
+    bus.write_byte(COMMAND_SECOND, 0x55);
+    bus.write_byte(COMMAND_FIRST, command);
+}
+
+/// Ask the chip what it is. SRAM sees the command sequence as normal writes, so those bytes are
+/// put back if it isn't flash
+pub(super) fn identify(bus: &mut impl GbaSaveBus) -> Option<GbaSaveKind> {
+    let saved = [
+        bus.read_byte(0),
+        bus.read_byte(1),
+        bus.read_byte(COMMAND_FIRST),
+        bus.read_byte(COMMAND_SECOND),
+    ];
+
+    command(bus, ENTER_ID);
+    let id = (bus.read_byte(0), bus.read_byte(1));
+    command(bus, EXIT_ID);
+
+    // Whatever is saved at the start could look like a id, so it has to have changed too
+    let chip = KNOWN_CHIPS
+        .iter()
+        .find(|(manufacturer, device, _)| (*manufacturer, *device) == id)
+        .filter(|_| id != (saved[0], saved[1]));
+
+    if let Some((manufacturer, _, size)) = chip {
+        return Some(GbaSaveKind::Flash {
+            size: *size,
+            atmel: *manufacturer == ATMEL,
+        });
+    }
+
+    bus.write_byte(COMMAND_FIRST, saved[2]);
+    bus.write_byte(COMMAND_SECOND, saved[3]);
+    return None;
+}
+
+/// Flash memory, which has to be erased a sector at a time before being written
+pub struct GbaFlash<BUS: GbaSaveBus> {
+    bus: BUS,
+    size: usize,
+    atmel: bool,
+    /// The bank that can be seen right now, if we know
+    bank: Option<usize>,
+}
+
+impl<BUS: GbaSaveBus> GbaFlash<BUS> {
+    pub fn new(bus: BUS, size: usize, atmel: bool) -> Self {
+        return Self {
+            bus,
+            size,
+            atmel,
+            bank: None,
+        };
+    }
+
+    /// Make the bank `offset` is in visible, and return where in it `offset` is
+    fn select(&mut self, offset: usize) -> usize {
+        let bank = offset / FLASH_BANK_SIZE;
+
+        if self.size > FLASH_BANK_SIZE && self.bank != Some(bank) {
+            command(&mut self.bus, SWITCH_BANK);
+            self.bus.write_byte(0, bank as u8);
+            self.bank = Some(bank);
+        }
+
+        return offset % FLASH_BANK_SIZE;
+    }
+
+    /// Wait for a erase or write to finish, which is when the byte reads back as what it should be
+    fn poll(&mut self, offset: usize, value: u8) -> Result<(), EmuRsError> {
+        for _ in 0..FLASH_POLL_LIMIT {
+            if self.bus.read_byte(offset) == value {
+                return Ok(());
+            }
+        }
+
+        return Err(EmuRsError {
+            reason: EmuRsErrorReason::HardwareTimeout,
+        });
+    }
+
+    fn erase_sector(&mut self, offset: usize) -> Result<(), EmuRsError> {
+        let offset = self.select(offset);
+
+        command(&mut self.bus, ERASE);
+        self.bus.write_byte(COMMAND_FIRST, 0xaa);
+        self.bus.write_byte(COMMAND_SECOND, 0x55);
+        self.bus.write_byte(offset, ERASE_SECTOR);
+
+        return self.poll(offset, 0xff);
+    }
+
+    fn program_byte(&mut self, offset: usize, value: u8) -> Result<(), EmuRsError> {
+        let offset = self.select(offset);
+
+        command(&mut self.bus, PROGRAM);
+        self.bus.write_byte(offset, value);
+
+        return self.poll(offset, value);
+    }
+
+    /// Write a sector on a chip that needs erasing, only erasing if some bit has to go back to 1
+    fn write_sector(&mut self, offset: usize, old: &[u8], new: &[u8]) -> Result<(), EmuRsError> {
+        let erase = old.iter().zip(new).any(|(old, new)| old & new != *new);
+        if erase {
+            self.erase_sector(offset)?;
+        }
+
+        for (index, value) in new.iter().enumerate() {
+            let current = if erase { 0xff } else { old[index] };
+
+            if *value != current {
+                self.program_byte(offset + index, *value)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Write a sector on a Atmel chip, one page at a time
+    fn write_atmel_sector(
+        &mut self,
+        offset: usize,
+        old: &[u8],
+        new: &[u8],
+    ) -> Result<(), EmuRsError> {
+        for page in (0..new.len()).step_by(ATMEL_PAGE_SIZE) {
+            let range = page..page + ATMEL_PAGE_SIZE;
+            if old[range.clone()] == new[range.clone()] {
+                continue;
+            }
+
+            let start = self.select(offset + page);
+            command(&mut self.bus, PROGRAM);
+            for (index, value) in new[range].iter().enumerate() {
+                self.bus.write_byte(start + index, *value);
+            }
+
+            let last = start + ATMEL_PAGE_SIZE - 1;
+            self.poll(last, new[page + ATMEL_PAGE_SIZE - 1])?;
+        }
+
+        return Ok(());
+    }
+}
+
+impl<BUS: GbaSaveBus> EmuRsDriver for GbaFlash<BUS> {
+    fn name(&self) -> &'static str {
+        return "Game Boy Advance Flash";
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        let mut memory = TinyVec::new();
+        memory.push(EmuRsMemoryRange::new(
+            SAVE_MEMORY,
+            SAVE_MEMORY + FLASH_BANK_SIZE - 1,
+        ));
+
+        return EmuRsDevice { memory };
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+}
+
+impl<BUS: GbaSaveBus> EmuRsBlockDevice for GbaFlash<BUS> {
+    fn read_sectors(&mut self, sector: usize, buffer: &mut [u8]) -> Result<(), EmuRsError> {
+        check_sector_access(self, sector, buffer.len())?;
+
+        let start = sector * FLASH_SECTOR_SIZE;
+        for (index, byte) in buffer.iter_mut().enumerate() {
+            let offset = self.select(start + index);
+            *byte = self.bus.read_byte(offset);
+        }
+
+        return Ok(());
+    }
+
+    fn write_sectors(&mut self, sector: usize, buffer: &[u8]) -> Result<(), EmuRsError> {
+        check_sector_access(self, sector, buffer.len())?;
+
+        let mut old = [0; FLASH_SECTOR_SIZE];
+        for (index, new) in buffer.chunks(FLASH_SECTOR_SIZE).enumerate() {
+            self.read_sectors(sector + index, &mut old)?;
+
+            // Erasing wears the chip out, so leave alone what is already right
+            if old == new {
+                continue;
+            }
+
+            let offset = (sector + index) * FLASH_SECTOR_SIZE;
+            if self.atmel {
+                self.write_atmel_sector(offset, &old, new)?;
+            } else {
+                self.write_sector(offset, &old, new)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn get_sector_size(&mut self) -> usize {
+        return FLASH_SECTOR_SIZE;
+    }
+
+    fn get_sector_count(&mut self) -> usize {
+        return self.size / FLASH_SECTOR_SIZE;
+    }
+
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        return EmuRsDiskProperties {
+            read_only: false,
+            erase_block_size: Some(if self.atmel {
+                ATMEL_PAGE_SIZE
+            } else {
+                FLASH_SECTOR_SIZE
+            }),
+            slow_writes: true,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulated::SimulatedCartridge;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use emurs_kernel::disk::{EmuRsBlockDisk, EmuRsDiskDriver};
+
+    fn pattern(length: usize, seed: u8) -> Vec<u8> {
+        return (0..length)
+            .map(|index| (index as u8).wrapping_mul(31) ^ seed)
+            .collect();
+    }
+
+    #[test]
+    fn writes_across_banks() {
+        let cartridge = SimulatedCartridge::flash(0x62, 0x13, 0x20000, false);
+        let mut disk = EmuRsBlockDisk::new(GbaFlash::new(cartridge, 0x20000, false));
+
+        // Straddles the two banks and only covers part of the sectors on each end
+        let data = pattern(0x3000, 7);
+        disk.write(&data, 0xe800).unwrap();
+
+        let mut read = vec![0; data.len()];
+        disk.read(&mut read, 0xe800).unwrap();
+        assert_eq!(read, data);
+
+        let mut start = [0; 4];
+        disk.read(&mut start, 0x10000 - 4).unwrap();
+        assert_eq!(start, data[0x1800 - 4..0x1800]);
+    }
+
+    #[test]
+    fn only_erases_when_it_has_to() {
+        let mut flash = GbaFlash::new(
+            SimulatedCartridge::flash(0xbf, 0xd4, 0x10000, false),
+            0x10000,
+            false,
+        );
+        let mut sector = [0xff; FLASH_SECTOR_SIZE];
+
+        // Clearing bits never needs a erase
+        sector[10] = 0x0f;
+        flash.write_sectors(2, &sector).unwrap();
+        sector[10] = 0x03;
+        flash.write_sectors(2, &sector).unwrap();
+        assert_eq!(flash.bus.erases, 0);
+        assert_eq!(flash.bus.programs, 2);
+
+        // Nothing changing means nothing done
+        flash.write_sectors(2, &sector).unwrap();
+        assert_eq!(flash.bus.programs, 2);
+
+        // Setting them does
+        sector[10] = 0xf0;
+        flash.write_sectors(2, &sector).unwrap();
+        assert_eq!(flash.bus.erases, 1);
+
+        let mut read = [0; FLASH_SECTOR_SIZE];
+        flash.read_sectors(2, &mut read).unwrap();
+        assert_eq!(read, sector);
+    }
+
+    #[test]
+    fn atmel_writes_pages() {
+        let cartridge = SimulatedCartridge::flash(0x1f, 0x3d, 0x10000, true);
+        let mut disk = EmuRsBlockDisk::new(GbaFlash::new(cartridge, 0x10000, true));
+
+        disk.write(&pattern(0x100, 1), 0x1000).unwrap();
+        disk.write(&pattern(10, 2), 0x1005).unwrap();
+
+        let mut expected = pattern(0x100, 1);
+        expected[5..15].copy_from_slice(&pattern(10, 2));
+        let mut read = vec![0; 0x100];
+        disk.read(&mut read, 0x1000).unwrap();
+        assert_eq!(read, expected);
+
+        let flash = disk.into_inner();
+        assert_eq!(flash.bus.erases, 0);
+        // Two pages the first time, one the second
+        assert_eq!(flash.bus.pages, 3);
+    }
+
+    #[test]
+    fn gives_up_on_a_stuck_chip() {
+        let mut cartridge = SimulatedCartridge::flash(0xbf, 0xd4, 0x10000, false);
+        cartridge.stuck = true;
+        let mut flash = GbaFlash::new(cartridge, 0x10000, false);
+
+        let result = flash.write_sectors(0, &[0; FLASH_SECTOR_SIZE]);
+        assert!(matches!(
+            result.map_err(|error| error.reason),
+            Err(EmuRsErrorReason::HardwareTimeout)
+        ));
+    }
+}
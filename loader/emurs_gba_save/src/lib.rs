@@ -0,0 +1,152 @@
+#![no_std]
+
+// The save chips GBA carts come with, over a bus that can be the real cartridge or a simulated
+// one, so all of this can be tested off of the hardware
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use emurs_kernel::disk::{EmuRsBlockDisk, EmuRsDiskDriver};
+
+pub mod eeprom;
+pub mod flash;
+pub mod sram;
+
+#[cfg(test)]
+mod simulated;
+
+use eeprom::{GbaEeprom, EEPROM_LARGE};
+use flash::GbaFlash;
+use sram::GbaSram;
+
+// https://problemkaputt.de/gbatek-gba-cart-backup-ids.htm
+
+/// Where SRAM and Flash are mapped
+pub const SAVE_MEMORY: usize = 0xe000000;
+
+/// Everything the save chips need from the cartridge, so the chip logic can run against a
+/// simulated chip instead of the real bus
+pub trait GbaSaveBus {
+    /// Read a byte of the SRAM and Flash area. It is only ever 8 bits wide
+    fn read_byte(&mut self, offset: usize) -> u8;
+    fn write_byte(&mut self, offset: usize, value: u8);
+    /// Clock bits out to the EEPROM, one per halfword in the lowest bit
+    fn eeprom_send(&mut self, bits: &[u16]);
+    /// Clock bits in from the EEPROM, one per halfword in the lowest bit
+    fn eeprom_receive(&mut self, bits: &mut [u16]);
+}
+
+/// The kinds of save chip carts come with, and how big they are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GbaSaveKind {
+    Sram(usize),
+    /// Atmel chips write 128 byte pages without needing a erase first
+    Flash {
+        size: usize,
+        atmel: bool,
+    },
+    Eeprom(usize),
+}
+
+impl GbaSaveKind {
+    /// Work out what save chip is there by poking at it, leaving what is saved on it alone
+    ///
+    /// A 512 B EEPROM can't be told apart from a 8 KiB one without writing to it, so EEPROMs are
+    /// always taken to be 8 KiB. Carts with the small one have to be set up by hand
+    pub fn detect(bus: &mut impl GbaSaveBus) -> Option<Self> {
+        if let Some(kind) = flash::identify(bus) {
+            return Some(kind);
+        }
+
+        if let Some(size) = sram::probe(bus) {
+            return Some(GbaSaveKind::Sram(size));
+        }
+
+        if eeprom::probe(bus) {
+            return Some(GbaSaveKind::Eeprom(EEPROM_LARGE));
+        }
+
+        return None;
+    }
+}
+
+/// The disk driver for a save chip
+pub fn save_driver<BUS: GbaSaveBus + 'static>(
+    kind: GbaSaveKind,
+    bus: BUS,
+) -> Rc<RefCell<dyn EmuRsDiskDriver>> {
+    return match kind {
+        GbaSaveKind::Sram(size) => Rc::new(RefCell::new(GbaSram::new(bus, size))),
+        GbaSaveKind::Flash { size, atmel } => Rc::new(RefCell::new(EmuRsBlockDisk::new(
+            GbaFlash::new(bus, size, atmel),
+        ))),
+        GbaSaveKind::Eeprom(size) => {
+            Rc::new(RefCell::new(EmuRsBlockDisk::new(GbaEeprom::new(bus, size))))
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::simulated::SimulatedCartridge;
+    use super::*;
+
+    #[test]
+    fn detects_every_kind_of_chip() {
+        let cases = [
+            (
+                SimulatedCartridge::sram(0x8000),
+                Some(GbaSaveKind::Sram(0x8000)),
+            ),
+            (
+                SimulatedCartridge::sram(0x10000),
+                Some(GbaSaveKind::Sram(0x10000)),
+            ),
+            (
+                SimulatedCartridge::flash(0xbf, 0xd4, 0x10000, false),
+                Some(GbaSaveKind::Flash {
+                    size: 0x10000,
+                    atmel: false,
+                }),
+            ),
+            (
+                SimulatedCartridge::flash(0x62, 0x13, 0x20000, false),
+                Some(GbaSaveKind::Flash {
+                    size: 0x20000,
+                    atmel: false,
+                }),
+            ),
+            (
+                SimulatedCartridge::flash(0x1f, 0x3d, 0x10000, true),
+                Some(GbaSaveKind::Flash {
+                    size: 0x10000,
+                    atmel: true,
+                }),
+            ),
+            (
+                SimulatedCartridge::eeprom(EEPROM_LARGE),
+                Some(GbaSaveKind::Eeprom(EEPROM_LARGE)),
+            ),
+            (SimulatedCartridge::empty(), None),
+        ];
+
+        for (mut cartridge, expected) in cases {
+            assert_eq!(GbaSaveKind::detect(&mut cartridge), expected);
+        }
+    }
+
+    #[test]
+    fn detection_leaves_saves_alone() {
+        let mut cartridge = SimulatedCartridge::sram(0x8000);
+        cartridge.memory_mut()[0] = 0x5a;
+        cartridge.memory_mut()[0x2aaa] = 0x12;
+        cartridge.memory_mut()[0x5555] = 0x34;
+        cartridge.memory_mut()[0x7fff] = 0xa5;
+        let before = cartridge.memory_mut().to_vec();
+
+        GbaSaveKind::detect(&mut cartridge);
+
+        assert_eq!(cartridge.memory_mut(), before.as_slice());
+    }
+}
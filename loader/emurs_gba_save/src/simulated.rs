@@ -0,0 +1,287 @@
+use crate::eeprom::EEPROM_SMALL;
+use crate::flash::FLASH_BANK_SIZE;
+use crate::flash::FLASH_SECTOR_SIZE;
+use crate::GbaSaveBus;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// A pretend cartridge so the save drivers can be tested off of the hardware. It follows the
+// protocols closely enough to catch drivers getting them wrong, and no further
+
+/// How many polls a erase or write keeps the chip busy for
+const BUSY_POLLS: usize = 3;
+
+enum Chip {
+    None,
+    Sram,
+    Flash {
+        id: (u8, u8),
+        atmel: bool,
+        /// How far into a command sequence we are
+        unlocked: usize,
+        id_mode: bool,
+        erase_armed: bool,
+        switch_bank: bool,
+        /// How many bytes the next writes may program
+        program: usize,
+        bank: usize,
+    },
+    Eeprom {
+        response: Vec<u16>,
+    },
+}
+
+pub struct SimulatedCartridge {
+    chip: Chip,
+    memory: Vec<u8>,
+    busy: usize,
+    /// Never finish a erase or write
+    pub stuck: bool,
+    pub erases: usize,
+    /// Bytes programmed on flash, or blocks written on EEPROM
+    pub programs: usize,
+    /// Atmel pages written
+    pub pages: usize,
+}
+
+impl SimulatedCartridge {
+    fn new(chip: Chip, size: usize) -> Self {
+        return Self {
+            chip,
+            memory: vec![0xff; size],
+            busy: 0,
+            stuck: false,
+            erases: 0,
+            programs: 0,
+            pages: 0,
+        };
+    }
+
+    pub fn empty() -> Self {
+        return Self::new(Chip::None, 0);
+    }
+
+    pub fn sram(size: usize) -> Self {
+        return Self::new(Chip::Sram, size);
+    }
+
+    pub fn flash(manufacturer: u8, device: u8, size: usize, atmel: bool) -> Self {
+        let chip = Chip::Flash {
+            id: (manufacturer, device),
+            atmel,
+            unlocked: 0,
+            id_mode: false,
+            erase_armed: false,
+            switch_bank: false,
+            program: 0,
+            bank: 0,
+        };
+        return Self::new(chip, size);
+    }
+
+    pub fn eeprom(size: usize) -> Self {
+        return Self::new(
+            Chip::Eeprom {
+                response: Vec::new(),
+            },
+            size,
+        );
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        return &self.memory;
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        return &mut self.memory;
+    }
+
+    fn start_busy(&mut self) {
+        self.busy = if self.stuck { usize::MAX } else { BUSY_POLLS };
+    }
+}
+
+/// Read bits as a number, most significant first
+fn bits_to_number(bits: &[u16]) -> usize {
+    return bits
+        .iter()
+        .fold(0, |number, bit| number << 1 | (*bit & 1) as usize);
+}
+
+impl GbaSaveBus for SimulatedCartridge {
+    fn read_byte(&mut self, offset: usize) -> u8 {
+        let offset = offset % FLASH_BANK_SIZE;
+
+        match &self.chip {
+            Chip::Sram => return self.memory[offset % self.memory.len()],
+            Chip::Flash {
+                id, id_mode, bank, ..
+            } => {
+                if *id_mode && offset < 2 {
+                    return if offset == 0 { id.0 } else { id.1 };
+                }
+
+                let value = self.memory[bank * FLASH_BANK_SIZE + offset];
+                // Busy chips read back the opposite of the top bit they are going to have
+                if self.busy > 0 {
+                    self.busy -= 1;
+                    return value ^ 0x80;
+                }
+                return value;
+            }
+            _ => return 0xff,
+        }
+    }
+
+    fn write_byte(&mut self, offset: usize, value: u8) {
+        let offset = offset % FLASH_BANK_SIZE;
+        let size = self.memory.len();
+
+        let (atmel, unlocked, id_mode, erase_armed, switch_bank, program, bank) =
+            match &mut self.chip {
+                Chip::Sram => {
+                    self.memory[offset % size] = value;
+                    return;
+                }
+                Chip::Flash {
+                    atmel,
+                    unlocked,
+                    id_mode,
+                    erase_armed,
+                    switch_bank,
+                    program,
+                    bank,
+                    ..
+                } => (
+                    *atmel,
+                    unlocked,
+                    id_mode,
+                    erase_armed,
+                    switch_bank,
+                    program,
+                    bank,
+                ),
+                _ => return,
+            };
+
+        if *program > 0 {
+            let address = *bank * FLASH_BANK_SIZE + offset;
+            *program -= 1;
+
+            if atmel {
+                // Atmel chips erase the page as they go
+                self.memory[address] = value;
+                if *program == 0 {
+                    self.pages += 1;
+                    self.busy = if self.stuck { usize::MAX } else { BUSY_POLLS };
+                }
+            } else {
+                // Flash can only clear bits
+                self.memory[address] &= value;
+                self.programs += 1;
+                self.busy = if self.stuck { usize::MAX } else { BUSY_POLLS };
+            }
+            return;
+        }
+
+        if *switch_bank {
+            *switch_bank = false;
+            if offset == 0 {
+                *bank = value as usize % (size / FLASH_BANK_SIZE).max(1);
+            }
+            return;
+        }
+
+        match (*unlocked, offset, value) {
+            (0, 0x5555, 0xaa) => *unlocked = 1,
+            (1, 0x2aaa, 0x55) => *unlocked = 2,
+            (2, _, 0x30) if *erase_armed && offset % FLASH_SECTOR_SIZE == 0 => {
+                *unlocked = 0;
+                *erase_armed = false;
+
+                let start = *bank * FLASH_BANK_SIZE + offset;
+                self.memory[start..start + FLASH_SECTOR_SIZE].fill(0xff);
+                self.erases += 1;
+                self.busy = if self.stuck { usize::MAX } else { BUSY_POLLS };
+            }
+            (2, 0x5555, command) => {
+                *unlocked = 0;
+                *erase_armed = false;
+
+                match command {
+                    0x90 => *id_mode = true,
+                    0xf0 => *id_mode = false,
+                    0x80 => *erase_armed = true,
+                    0xa0 => *program = if atmel { 128 } else { 1 },
+                    0xb0 => *switch_bank = true,
+                    _ => {}
+                }
+            }
+            _ => *unlocked = 0,
+        }
+    }
+
+    fn eeprom_send(&mut self, bits: &[u16]) {
+        let Chip::Eeprom { response } = &mut self.chip else {
+            return;
+        };
+        let address_bits = if self.memory.len() > EEPROM_SMALL {
+            14
+        } else {
+            6
+        };
+        let blocks = self.memory.len() / 8;
+        response.clear();
+
+        match bits {
+            [1, 1, rest @ ..] if rest.len() == address_bits + 1 => {
+                let block = bits_to_number(&rest[..address_bits]) % blocks;
+                response.extend([0; 4]);
+                for byte in &self.memory[block * 8..block * 8 + 8] {
+                    response.extend((0..8).rev().map(|bit| (*byte >> bit) as u16 & 1));
+                }
+            }
+            [1, 1, ..] => {
+                // Takes the address bits it wants and answers with something
+                let block = bits_to_number(&bits[2..2 + address_bits]) % blocks;
+                response.extend([0; 4]);
+                response.extend((0..64).map(|bit| (block >> (bit % 10)) as u16 & 1));
+            }
+            [1, 0, rest @ ..] if rest.len() == address_bits + 64 + 1 => {
+                let block = bits_to_number(&rest[..address_bits]) % blocks;
+                for (index, byte) in rest[address_bits..address_bits + 64].chunks(8).enumerate() {
+                    self.memory[block * 8 + index] = bits_to_number(byte) as u8;
+                }
+                self.programs += 1;
+                self.start_busy();
+            }
+            _ => {}
+        }
+    }
+
+    fn eeprom_receive(&mut self, bits: &mut [u16]) {
+        let Chip::Eeprom { response } = &mut self.chip else {
+            // Nothing there, so it reads back the address bus, whose lowest bit flips every read
+            for (index, bit) in bits.iter_mut().enumerate() {
+                *bit = index as u16 & 1;
+            }
+            return;
+        };
+
+        if !response.is_empty() {
+            let length = bits.len().min(response.len());
+            bits[..length].copy_from_slice(&response[..length]);
+            response.drain(..length);
+            return;
+        }
+
+        for bit in bits.iter_mut() {
+            *bit = if self.busy > 0 {
+                self.busy -= 1;
+                0
+            } else {
+                1
+            };
+        }
+    }
+}
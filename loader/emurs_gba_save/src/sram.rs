@@ -0,0 +1,127 @@
+use crate::{GbaSaveBus, SAVE_MEMORY};
+use emurs_kernel::device::EmuRsDevice;
+use emurs_kernel::disk::EmuRsDiskDriver;
+use emurs_kernel::driver::{EmuRsDriver, EmuRsDriverPreference};
+use emurs_kernel::error::{EmuRsError, EmuRsErrorReason};
+use emurs_kernel::mem::EmuRsMemoryRange;
+use emurs_kernel::prelude::tinyvec::TinyVec;
+
+pub const SRAM_SMALL: usize = 0x8000;
+pub const SRAM_LARGE: usize = 0x10000;
+
+/// Battery backed SRAM, which is just memory on a 8 bit bus
+pub struct GbaSram<BUS: GbaSaveBus> {
+    bus: BUS,
+    size: usize,
+}
+
+impl<BUS: GbaSaveBus> GbaSram<BUS> {
+    pub fn new(bus: BUS, size: usize) -> Self {
+        return Self { bus, size };
+    }
+
+    fn check_bounds(&self, offset: usize, length: usize) -> Result<(), EmuRsError> {
+        if offset + length > self.size {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit,
+            });
+        }
+
+        return Ok(());
+    }
+}
+
+/// See if there is SRAM by writing to it and reading it back, and how much by seeing if the
+/// second half mirrors the first. Whatever was there is put back
+pub(super) fn probe(bus: &mut impl GbaSaveBus) -> Option<usize> {
+    let original = bus.read_byte(0);
+    let mut mirrored = true;
+
+    // Two different values so a byte that happened to match doesn't count
+    for value in [original ^ 0xff, original ^ 0x55] {
+        bus.write_byte(0, value);
+
+        if bus.read_byte(0) != value {
+            bus.write_byte(0, original);
+            return None;
+        }
+
+        mirrored &= bus.read_byte(SRAM_SMALL) == value;
+    }
+
+    bus.write_byte(0, original);
+
+    return Some(if mirrored { SRAM_SMALL } else { SRAM_LARGE });
+}
+
+impl<BUS: GbaSaveBus> EmuRsDriver for GbaSram<BUS> {
+    fn name(&self) -> &'static str {
+        return "Game Boy Advance SRAM";
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        let mut memory = TinyVec::new();
+        memory.push(EmuRsMemoryRange::new(
+            SAVE_MEMORY,
+            SAVE_MEMORY + self.size - 1,
+        ));
+
+        return EmuRsDevice { memory };
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+}
+
+impl<BUS: GbaSaveBus> EmuRsDiskDriver for GbaSram<BUS> {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        self.check_bounds(offset, buffer.len())?;
+
+        // The bus is only 8 bits wide so anything bigger would come back mangled
+        for (index, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.bus.read_byte(offset + index);
+        }
+
+        return Ok(());
+    }
+
+    fn write(&mut self, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        self.check_bounds(offset, buffer.len())?;
+
+        for (index, byte) in buffer.iter().enumerate() {
+            self.bus.write_byte(offset + index, *byte);
+        }
+
+        return Ok(());
+    }
+
+    fn get_sector_size(&mut self) -> usize {
+        return 1;
+    }
+
+    fn get_total_size(&mut self) -> usize {
+        return self.size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulated::SimulatedCartridge;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn reads_back_writes() {
+        let mut sram = GbaSram::new(SimulatedCartridge::sram(SRAM_SMALL), SRAM_SMALL);
+        let data: Vec<u8> = (0..300).map(|index| index as u8).collect();
+
+        sram.write(&data, 0x7e00).unwrap();
+
+        let mut read = vec![0; data.len()];
+        sram.read(&mut read, 0x7e00).unwrap();
+        assert_eq!(read, data);
+        assert!(sram.write(&data, 0x7f01).is_err());
+    }
+}
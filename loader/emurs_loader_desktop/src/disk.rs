@@ -0,0 +1,227 @@
+use emurs_kernel::device::EmuRsDevice;
+use emurs_kernel::disk::{EmuRsDiskDriver, EmuRsDiskProperties};
+use emurs_kernel::driver::{EmuRsDriver, EmuRsDriverPreference};
+use emurs_kernel::error::{EmuRsError, EmuRsErrorReason};
+use emurs_kernel::prelude::tinyvec::TinyVec;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DesktopDiskMode {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// A file on the host used as a disk, like a raw `.img`, a `.sav` dump or a tar
+///
+/// The size is whatever the file was when it was opened. Writes never grow it
+pub struct DesktopDiskImage {
+    file: File,
+    size: usize,
+    mode: DesktopDiskMode,
+}
+
+impl DesktopDiskImage {
+    pub fn open(path: impl AsRef<Path>, mode: DesktopDiskMode) -> Result<Self, EmuRsError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode == DesktopDiskMode::ReadWrite)
+            .open(path)
+            .map_err(io_error)?;
+        let size = file.metadata().map_err(io_error)?.len() as usize;
+
+        return Ok(Self { file, size, mode });
+    }
+
+    pub fn mode(&self) -> DesktopDiskMode {
+        return self.mode;
+    }
+
+    fn seek(&mut self, offset: usize, length: usize) -> Result<(), EmuRsError> {
+        if offset.checked_add(length).is_none_or(|end| end > self.size) {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::EndOfDiskHit,
+            });
+        }
+
+        self.file
+            .seek(SeekFrom::Start(offset as u64))
+            .map_err(io_error)?;
+        return Ok(());
+    }
+}
+
+fn io_error(error: std::io::Error) -> EmuRsError {
+    return EmuRsError {
+        reason: match error.kind() {
+            std::io::ErrorKind::NotFound => EmuRsErrorReason::FileNotFound,
+            std::io::ErrorKind::UnexpectedEof => EmuRsErrorReason::EndOfDiskHit,
+            _ => EmuRsErrorReason::Custom(error.to_string()),
+        },
+    };
+}
+
+impl EmuRsDriver for DesktopDiskImage {
+    fn name(&self) -> &'static str {
+        return "Desktop Disk Image";
+    }
+
+    fn get_preference(&mut self) -> EmuRsDriverPreference {
+        return EmuRsDriverPreference::Preferred;
+    }
+
+    fn get_claimed(&mut self) -> EmuRsDevice {
+        return EmuRsDevice {
+            memory: TinyVec::new(),
+        };
+    }
+}
+
+impl EmuRsDiskDriver for DesktopDiskImage {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), EmuRsError> {
+        self.seek(offset, buffer.len())?;
+
+        return self.file.read_exact(buffer).map_err(io_error);
+    }
+
+    fn write(&mut self, buffer: &[u8], offset: usize) -> Result<(), EmuRsError> {
+        if self.mode == DesktopDiskMode::ReadOnly {
+            return Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported,
+            });
+        }
+
+        self.seek(offset, buffer.len())?;
+
+        return self.file.write_all(buffer).map_err(io_error);
+    }
+
+    fn get_sector_size(&mut self) -> usize {
+        // The host deals with sectors for us
+        return 1;
+    }
+
+    fn get_total_size(&mut self) -> usize {
+        return self.size;
+    }
+
+    fn get_properties(&mut self) -> EmuRsDiskProperties {
+        return EmuRsDiskProperties {
+            read_only: self.mode == DesktopDiskMode::ReadOnly,
+            ..Default::default()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emurs_kernel::drivers::ustarfs::EmuRsUstarFs;
+    use emurs_kernel::vfs::{EmuRsFsDriver, EmuRsPath};
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::str::FromStr;
+
+    /// A image in the temporary directory, deleted again when dropped
+    struct TemporaryImage(PathBuf);
+
+    impl TemporaryImage {
+        fn new(name: &str, data: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "emurs_disk_{}_{}.img",
+                std::process::id(),
+                name
+            ));
+            std::fs::write(&path, data).unwrap();
+            return Self(path);
+        }
+    }
+
+    impl Drop for TemporaryImage {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn read_only_images_refuse_writes() {
+        let image = TemporaryImage::new("read_only", &[7; 1024]);
+        let mut disk = DesktopDiskImage::open(&image.0, DesktopDiskMode::ReadOnly).unwrap();
+
+        assert!(disk.get_properties().read_only);
+        assert!(matches!(
+            disk.write(&[0; 4], 0),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::OperationNotSupported
+            })
+        ));
+        assert_eq!(std::fs::read(&image.0).unwrap(), [7; 1024]);
+    }
+
+    #[test]
+    fn writes_stay_inside_of_the_image() {
+        let image = TemporaryImage::new("read_write", &[7; 1024]);
+        let mut disk = DesktopDiskImage::open(&image.0, DesktopDiskMode::ReadWrite).unwrap();
+
+        assert_eq!(disk.get_total_size(), 1024);
+        disk.write(&[1, 2, 3, 4], 1020).unwrap();
+
+        let mut buffer = [0; 8];
+        disk.read(&mut buffer, 1016).unwrap();
+        assert_eq!(buffer, [7, 7, 7, 7, 1, 2, 3, 4]);
+
+        for offset in [1017, 1025, usize::MAX] {
+            assert!(matches!(
+                disk.read(&mut buffer, offset),
+                Err(EmuRsError {
+                    reason: EmuRsErrorReason::EndOfDiskHit
+                })
+            ));
+            assert!(matches!(
+                disk.write(&buffer, offset),
+                Err(EmuRsError {
+                    reason: EmuRsErrorReason::EndOfDiskHit
+                })
+            ));
+        }
+
+        // Writes never grow the file
+        drop(disk);
+        assert_eq!(std::fs::metadata(&image.0).unwrap().len(), 1024);
+    }
+
+    #[test]
+    fn missing_images_are_not_found() {
+        assert!(matches!(
+            DesktopDiskImage::open(
+                std::env::temp_dir().join("emurs_disk_not_here.img"),
+                DesktopDiskMode::ReadOnly
+            ),
+            Err(EmuRsError {
+                reason: EmuRsErrorReason::FileNotFound
+            })
+        ));
+    }
+
+    #[test]
+    fn mounts_a_archive_made_by_tar() {
+        let disk = DesktopDiskImage::open(
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/../../emurs_kernel/fixtures/ustar.tar"
+            ),
+            DesktopDiskMode::ReadOnly,
+        )
+        .unwrap();
+
+        let mut fs = EmuRsUstarFs::default();
+        fs.mount(Rc::new(RefCell::new(disk))).unwrap();
+
+        let path = EmuRsPath::from_str("ROOT/games/gba/hello.txt").unwrap();
+        let mut buffer = [0; 10];
+        fs.read(&path, &mut buffer, 0).unwrap();
+        assert_eq!(&buffer, b"hello tar\n");
+    }
+}
@@ -1,6 +1,12 @@
 #![feature(test)]
 extern crate test;
 
+mod disk;
+
+use disk::{DesktopDiskImage, DesktopDiskMode};
+use std::cell::RefCell;
+use std::rc::Rc;
+
 #[allow(unused_imports)]
 use emurs_kernel::prelude::*;
 use emurs_kernel::video::EmuRsColorFormatRgb565;
@@ -18,7 +24,7 @@ use emurs_kernel::{
 pub fn main() {
     let mut buffer = [0_u8; 1000];
 
-    emurs_main(
+    emurs_main::<emurs_program_gol::Program>(
         &[EmuRsMemoryTableEntry {
             permissions: EmuRsMemoryPermission {
                 read: true,
@@ -31,7 +37,22 @@ pub fn main() {
             ),
             kind: EmuRsMemoryKind::Work,
         }],
-        |context| {},
+        |context| {
+            // Every argument is a disk image, opened read only if it comes after --read-only
+            let mut mode = DesktopDiskMode::ReadWrite;
+
+            for argument in std::env::args().skip(1) {
+                if argument == "--read-only" {
+                    mode = DesktopDiskMode::ReadOnly;
+                    continue;
+                }
+
+                match DesktopDiskImage::open(&argument, mode) {
+                    Ok(disk) => context.disk_drivers.push(Rc::new(RefCell::new(disk))),
+                    Err(error) => eprintln!("Couldn't open {}: {:?}", argument, error.reason),
+                }
+            }
+        },
     );
 }
 
@@ -5,21 +5,21 @@
 
 extern crate alloc;
 
+mod save;
 mod video;
 
 use core::cell::RefCell;
 use core::ptr::NonNull;
 
 use alloc::rc::Rc;
-use emurs_kernel::device::EmuRsDevice;
-use emurs_kernel::disk::{EmuRsDiskDriver, EmuRsMemoryDisk};
-use emurs_kernel::driver::{EmuRsDriver, EmuRsDriverPreference};
+use emurs_gba_save::{save_driver, GbaSaveKind};
 use emurs_kernel::mem::EmuRsMemoryKind;
 use emurs_kernel::mem::EmuRsMemoryPermission;
 use emurs_kernel::mem::EmuRsMemoryRange;
 use emurs_kernel::mem::EmuRsMemoryTableEntry;
 use emurs_kernel::prelude::*;
 use emurs_kernel::EmuRsContext;
+use save::GbaCartridgeBus;
 use video::GbaVideo;
 
 #[naked]
@@ -49,7 +49,7 @@ unsafe extern "C" fn _start() -> ! {
 // For now just setup external work ram as a heap
 #[no_mangle]
 pub extern "C" fn gba_loader() -> ! {
-    emurs_main(
+    emurs_main::<emurs_program_gol::Program>(
         &[EmuRsMemoryTableEntry {
             permissions: EmuRsMemoryPermission {
                 read: true,
@@ -60,32 +60,12 @@ pub extern "C" fn gba_loader() -> ! {
             kind: EmuRsMemoryKind::Work,
         }],
         |mut context| {
-            context
-                .add_video_driver::<GbaVideo>()
-                .add_disk_driver::<GbaSram>();
+            context.add_video_driver::<GbaVideo>();
+
+            let mut bus = GbaCartridgeBus::new();
+            if let Some(kind) = GbaSaveKind::detect(&mut bus) {
+                context.disk_drivers.push(save_driver(kind, bus));
+            }
         },
     );
 }
-
-#[derive(Default)]
-pub struct GbaSram;
-
-impl EmuRsDriver for GbaSram {
-    fn name(&self) -> &'static str {
-        return "Game Boy Advance SRAM";
-    }
-
-    fn get_claimed(&mut self) -> EmuRsDevice {
-        todo!()
-    }
-
-    fn get_preference(&mut self) -> EmuRsDriverPreference {
-        return EmuRsDriverPreference::Preferred;
-    }
-}
-
-impl EmuRsMemoryDisk for GbaSram {
-    fn get_memory(&self) -> &mut [u8] {
-        return unsafe { core::slice::from_raw_parts_mut(0xe000000 as *mut u8, 0xffff) };
-    }
-}
@@ -0,0 +1,56 @@
+use core::ptr::{read_volatile, write_volatile};
+use emurs_gba_save::{GbaSaveBus, SAVE_MEMORY};
+
+// The chip logic lives in emurs_gba_save so it can be tested on the host, this is just the real
+// cartridge for it to talk to
+
+/// The top of the ROM area. The EEPROM answers here whatever size the ROM is
+const EEPROM: usize = 0xdffff00;
+const WAITCNT: *mut u16 = 0x4000204 as *mut u16;
+const DMA3_SOURCE: *mut u32 = 0x40000d4 as *mut u32;
+const DMA3_DESTINATION: *mut u32 = 0x40000d8 as *mut u32;
+const DMA3_COUNT: *mut u16 = 0x40000dc as *mut u16;
+const DMA3_CONTROL: *mut u16 = 0x40000de as *mut u16;
+/// Start right away, 16 bits at a time, counting up on both sides
+const DMA_ENABLE: u16 = 1 << 15;
+
+/// The save chip on a real cartridge
+pub struct GbaCartridgeBus;
+
+impl GbaCartridgeBus {
+    pub fn new() -> Self {
+        // Save chips need the slowest wait states for SRAM and the part of ROM the EEPROM is in
+        unsafe { write_volatile(WAITCNT, read_volatile(WAITCNT) | 0b11 | 0b11 << 8) };
+        return Self;
+    }
+
+    /// The EEPROM only works over DMA, which holds the bus for the whole transfer
+    fn dma(&mut self, source: usize, destination: usize, count: usize) {
+        unsafe {
+            write_volatile(DMA3_SOURCE, source as u32);
+            write_volatile(DMA3_DESTINATION, destination as u32);
+            write_volatile(DMA3_COUNT, count as u16);
+            write_volatile(DMA3_CONTROL, DMA_ENABLE);
+
+            while read_volatile(DMA3_CONTROL) & DMA_ENABLE != 0 {}
+        }
+    }
+}
+
+impl GbaSaveBus for GbaCartridgeBus {
+    fn read_byte(&mut self, offset: usize) -> u8 {
+        return unsafe { read_volatile((SAVE_MEMORY + offset) as *const u8) };
+    }
+
+    fn write_byte(&mut self, offset: usize, value: u8) {
+        unsafe { write_volatile((SAVE_MEMORY + offset) as *mut u8, value) };
+    }
+
+    fn eeprom_send(&mut self, bits: &[u16]) {
+        self.dma(bits.as_ptr() as usize, EEPROM, bits.len());
+    }
+
+    fn eeprom_receive(&mut self, bits: &mut [u16]) {
+        self.dma(EEPROM, bits.as_mut_ptr() as usize, bits.len());
+    }
+}